@@ -123,6 +123,18 @@ vec3 Brightness(vec3 Cs, float amount) {
     return clamp(Cs.rgb * amount, vec3(0.0), vec3(1.0));
 }
 
+vec3 Posterize(vec3 Cs, float levels) {
+    // Fewer than two levels can't represent anything but a single color,
+    // so treat them as two.
+    float steps = max(levels - 1.0, 1.0);
+    return min(floor(Cs.rgb * (steps + 1.0)), vec3(steps)) / steps;
+}
+
+vec3 Threshold(vec3 Cs, float cutoff) {
+    float luminance = dot(Cs.rgb, vec3(0.2126, 0.7152, 0.0722));
+    return vec3(step(cutoff, luminance));
+}
+
 Fragment brush_fs() {
     vec4 Cs = texture(sColor0, vUv);
 
@@ -149,6 +161,15 @@ Fragment brush_fs() {
         case 8: // Opacity
             alpha *= vAmount;
             break;
+        case 12:
+            color = Posterize(color, vAmount);
+            break;
+        case 13:
+            color = Threshold(color, vAmount);
+            break;
+        case 14:
+            color = pow(color, vec3(1.0 / vAmount));
+            break;
         default:
             color = vColorMat * color + vColorOffset;
     }
@@ -17,6 +17,11 @@ flat varying vec4 vUvBounds;
 // Normalized bounds of the source image in the texture, adjusted to avoid
 // sampling artifacts.
 flat varying vec4 vUvSampleBounds;
+// The color space of the source image, converted to sRGB when sampled.
+flat varying int vColorSpace;
+
+#define COLOR_SPACE_SRGB        0
+#define COLOR_SPACE_DISPLAY_P3  1
 
 #ifdef WR_FEATURE_ALPHA_PASS
 flat varying vec4 vColor;
@@ -106,6 +111,7 @@ void brush_vs(
     }
 
     vUv.z = res.layer;
+    vColorSpace = user_data.z;
 
     // Handle case where the UV coords are inverted (e.g. from an
     // external image).
@@ -194,6 +200,36 @@ void brush_vs(
 
 #ifdef WR_FRAGMENT_SHADER
 
+vec3 srgb_to_linear(vec3 color) {
+    vec3 low = color / 12.92;
+    vec3 high = pow((color + 0.055) / 1.055, vec3(2.4));
+    return mix(low, high, step(vec3(0.04045), color));
+}
+
+vec3 linear_to_srgb(vec3 color) {
+    vec3 low = color * 12.92;
+    vec3 high = 1.055 * pow(color, vec3(1.0 / 2.4)) - 0.055;
+    return mix(low, high, step(vec3(0.0031308), color));
+}
+
+// Converts a premultiplied Display P3 color to sRGB. Both share the sRGB
+// transfer function, so only the primaries need converting in linear space.
+vec4 display_p3_to_srgb(vec4 texel) {
+    if (texel.a == 0.0) {
+        return texel;
+    }
+
+    const mat3 p3_to_srgb = mat3(
+        vec3(1.2249, -0.0420, -0.0197),
+        vec3(-0.2247, 1.0419, -0.0786),
+        vec3(0.0, 0.0, 1.0979)
+    );
+
+    vec3 color = srgb_to_linear(texel.rgb / texel.a);
+    color = clamp(p3_to_srgb * color, vec3(0.0), vec3(1.0));
+    return vec4(linear_to_srgb(color) * texel.a, texel.a);
+}
+
 Fragment brush_fs() {
     vec2 uv_size = vUvBounds.zw - vUvBounds.xy;
 
@@ -225,6 +261,10 @@ Fragment brush_fs() {
 
     vec4 texel = TEX_SAMPLE(sColor0, vec3(uv, vUv.z));
 
+    if (vColorSpace == COLOR_SPACE_DISPLAY_P3) {
+        texel = display_p3_to_srgb(texel);
+    }
+
     Fragment frag;
 
 #ifdef WR_FEATURE_ALPHA_PASS
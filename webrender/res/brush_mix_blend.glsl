@@ -9,6 +9,7 @@
 varying vec3 vSrcUv;
 varying vec3 vBackdropUv;
 flat varying int vOp;
+flat varying int vClamp;
 
 #ifdef WR_VERTEX_SHADER
 
@@ -24,7 +25,8 @@ void brush_vs(
     vec4 unused
 ) {
     vec2 texture_size = vec2(textureSize(sCacheRGBA8, 0));
-    vOp = user_data.x;
+    vOp = user_data.x & 0xffff;
+    vClamp = user_data.x >> 16;
 
     PictureTask src_task = fetch_picture_task(user_data.z);
     vec2 src_uv = vi.snapped_device_pos +
@@ -182,6 +184,14 @@ vec3 Luminosity(vec3 Cb, vec3 Cs) {
     return SetLum(Cb, Lum(Cs));
 }
 
+// Compresses values above a knee so that they approach 1.0 instead
+// of being clipped.
+vec3 SoftKnee(vec3 C) {
+    const float knee = 0.8;
+    vec3 over = max(C - knee, vec3(0.0));
+    return min(C, knee) + (1.0 - knee) * (1.0 - exp(-over / (1.0 - knee)));
+}
+
 const int MixBlendMode_Multiply    = 1;
 const int MixBlendMode_Screen      = 2;
 const int MixBlendMode_Overlay     = 3;
@@ -197,6 +207,9 @@ const int MixBlendMode_Hue         = 12;
 const int MixBlendMode_Saturation  = 13;
 const int MixBlendMode_Color       = 14;
 const int MixBlendMode_Luminosity  = 15;
+const int MixBlendMode_PlusLighter = 16;
+
+const int CompositeClamp_SoftKnee = 1;
 
 Fragment brush_fs() {
     vec4 Cb = textureLod(sCacheRGBA8, vBackdropUv, 0.0);
@@ -269,9 +282,18 @@ Fragment brush_fs() {
         case MixBlendMode_Luminosity:
             result.rgb = Luminosity(Cb.rgb, Cs.rgb);
             break;
+        case MixBlendMode_PlusLighter:
+            result.rgb = Cb.rgb + Cs.rgb;
+            break;
         default: break;
     }
 
+    if (vClamp == CompositeClamp_SoftKnee) {
+        result.rgb = SoftKnee(result.rgb);
+    } else {
+        result.rgb = clamp(result.rgb, vec3(0.0), vec3(1.0));
+    }
+
     result.rgb = (1.0 - Cb.a) * Cs.rgb + Cb.a * result.rgb;
     result.a = Cs.a;
 
@@ -7,6 +7,7 @@
 #include shared,prim_shared,brush
 
 flat varying int vGradientAddress;
+flat varying int vDitherOffset;
 flat varying float vGradientRepeat;
 
 flat varying vec2 vCenter;
@@ -68,6 +69,7 @@ void brush_vs(
     vRepeatedSize.y *=  gradient.ratio_xy;
 
     vGradientAddress = user_data.x;
+    vDitherOffset = user_data.y;
 
     // Whether to repeat the gradient instead of clamping.
     vGradientRepeat = float(gradient.extend_mode != EXTEND_MODE_CLAMP);
@@ -142,7 +144,8 @@ Fragment brush_fs() {
 
     vec4 color = sample_gradient(vGradientAddress,
                                  offset,
-                                 vGradientRepeat);
+                                 vGradientRepeat,
+                                 vDitherOffset);
 
 #ifdef WR_FEATURE_ALPHA_PASS
     color *= init_transform_fs(vLocalPos);
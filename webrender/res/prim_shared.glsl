@@ -420,35 +420,40 @@ float do_clip() {
 }
 
 #ifdef WR_FEATURE_DITHERING
-vec4 dither(vec4 color) {
+// The offset picks where in the dither matrix the pattern starts,
+// packed as x | y << 3.
+vec4 dither(vec4 color, int offset) {
     const int matrix_mask = 7;
 
-    ivec2 pos = ivec2(gl_FragCoord.xy) & ivec2(matrix_mask);
+    ivec2 start = ivec2(offset & matrix_mask, offset >> 3);
+    ivec2 pos = (ivec2(gl_FragCoord.xy) + start) & ivec2(matrix_mask);
     float noise_normalized = (texelFetch(sDither, pos, 0).r * 255.0 + 0.5) / 64.0;
     float noise = (noise_normalized - 0.5) / 256.0; // scale down to the unit length
 
     return color + vec4(noise, noise, noise, 0);
 }
 #else
-vec4 dither(vec4 color) {
+vec4 dither(vec4 color, int offset) {
     return color;
 }
 #endif //WR_FEATURE_DITHERING
 
-vec4 sample_gradient(int address, float offset, float gradient_repeat) {
+vec4 sample_gradient(int address, float offset, float gradient_repeat, int dither_offset) {
     // Modulo the offset if the gradient repeats.
     float x = mix(offset, fract(offset), gradient_repeat);
 
+    // The first block of the gradient data holds the number of entries
+    // in the LUT, which varies based on the distribution of stops in
+    // the gradient. The color entries follow it.
+    int gradient_entries = int(fetch_from_resource_cache_1(address).x);
+    address += 1;
+
     // Calculate the color entry index to use for this offset:
     //     offsets < 0 use the first color entry, 0
     //     offsets from [0, 1) use the color entries in the range of [1, N-1)
     //     offsets >= 1 use the last color entry, N-1
     //     so transform the range [0, 1) -> [1, N-1)
-
-    // TODO(gw): In the future we might consider making the size of the
-    // LUT vary based on number / distribution of stops in the gradient.
-    const int GRADIENT_ENTRIES = 128;
-    x = 1.0 + x * float(GRADIENT_ENTRIES);
+    x = 1.0 + x * float(gradient_entries);
 
     // Calculate the texel to index into the gradient color entries:
     //     floor(x) is the gradient color entry index
@@ -456,13 +461,13 @@ vec4 sample_gradient(int address, float offset, float gradient_repeat) {
     int lut_offset = 2 * int(floor(x));     // There is a [start, end] color per entry.
 
     // Ensure we don't fetch outside the valid range of the LUT.
-    lut_offset = clamp(lut_offset, 0, 2 * (GRADIENT_ENTRIES + 1));
+    lut_offset = clamp(lut_offset, 0, 2 * (gradient_entries + 1));
 
     // Fetch the start and end color.
     vec4 texels[2] = fetch_from_resource_cache_2(address + lut_offset);
 
     // Finally interpolate and apply dithering
-    return dither(mix(texels[0], texels[1], fract(x)));
+    return dither(mix(texels[0], texels[1], fract(x)), dither_offset);
 }
 
 #endif //WR_FRAGMENT_SHADER
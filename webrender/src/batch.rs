@@ -4,12 +4,14 @@
 
 use api::{AlphaType, ClipMode, DeviceIntRect, DeviceIntSize};
 use api::{DeviceUintRect, DeviceUintPoint, DeviceUintSize, ExternalImageType, FilterOp, ImageRendering, LayoutRect};
-use api::{DeviceIntPoint, SubpixelDirection, YuvColorSpace, YuvFormat};
+use api::{DeviceIntPoint, DeviceIntVector2D, ImageColorSpace, SubpixelDirection, YuvColorSpace};
+use api::YuvFormat;
 use api::{LayoutToWorldTransform, WorldPixel};
 use border::{BorderCornerInstance, BorderCornerSide, BorderEdgeKind};
 use clip::{ClipSource, ClipStore, ClipWorkItem};
 use clip_scroll_tree::{CoordinateSystemId};
 use euclid::{TypedTransform3D, vec3};
+use frame_builder::FadingSurface;
 use glyph_rasterizer::GlyphFormat;
 use gpu_cache::{GpuCache, GpuCacheAddress};
 use gpu_types::{BrushFlags, BrushInstance, ClipChainRectIndex, ClipMaskBorderCornerDotDash};
@@ -27,7 +29,7 @@ use render_task::{RenderTaskAddress, RenderTaskId, RenderTaskKind, RenderTaskTre
 use renderer::{BlendMode, ImageBufferKind};
 use renderer::{BLOCKS_PER_UV_RECT, ShaderColorMode};
 use resource_cache::{CacheItem, GlyphFetchResult, ImageRequest, ResourceCache};
-use scene::FilterOpHelpers;
+use scene::{FilterOpHelpers, MIN_FILTER_GAMMA};
 use std::{usize, f32, i32};
 use tiling::{RenderTargetContext};
 use util::{MatrixHelpers, TransformedRectKind};
@@ -104,6 +106,55 @@ impl BatchTextures {
     }
 }
 
+/// The number of times a texture is bound to one of the color samplers when
+/// batches with the given textures are drawn in this order. An invalid
+/// texture leaves the sampler bound to what it was before.
+pub fn texture_bind_count<'a, I>(textures: I) -> usize
+where
+    I: IntoIterator<Item = &'a BatchTextures>,
+{
+    let mut bound = [SourceTexture::Invalid; 3];
+    let mut count = 0;
+    for textures in textures {
+        for (bound, &texture) in bound.iter_mut().zip(textures.colors.iter()) {
+            if texture != SourceTexture::Invalid && texture != *bound {
+                *bound = texture;
+                count += 1;
+            }
+        }
+    }
+    count
+}
+
+/// The number of the given draw call rects that touch each tile of a grid
+/// over a target of the given size, in row major order. This is a coarse
+/// view of how many times the pixels of each tile are drawn.
+pub fn overdraw_grid<I>(target_size: DeviceIntSize, tile_size: i32, rects: I) -> Vec<u32>
+where
+    I: IntoIterator<Item = DeviceIntRect>,
+{
+    let columns = (target_size.width + tile_size - 1) / tile_size;
+    let rows = (target_size.height + tile_size - 1) / tile_size;
+    let mut grid = vec![0; (columns * rows) as usize];
+    let target_rect = DeviceIntRect::new(DeviceIntPoint::zero(), target_size);
+    for rect in rects {
+        let rect = match rect.intersection(&target_rect) {
+            Some(rect) => rect,
+            None => continue,
+        };
+        if rect.size.width <= 0 || rect.size.height <= 0 {
+            continue;
+        }
+
+        for row in rect.min_y() / tile_size .. (rect.max_y() - 1) / tile_size + 1 {
+            for column in rect.min_x() / tile_size .. (rect.max_x() - 1) / tile_size + 1 {
+                grid[(row * columns + column) as usize] += 1;
+            }
+        }
+    }
+    grid
+}
+
 #[derive(Copy, Clone, Debug)]
 #[cfg_attr(feature = "capture", derive(Serialize))]
 #[cfg_attr(feature = "replay", derive(Deserialize))]
@@ -138,6 +189,8 @@ fn textures_compatible(t1: SourceTexture, t2: SourceTexture) -> bool {
 pub struct AlphaBatchList {
     pub batches: Vec<PrimitiveBatch>,
     pub item_rects: Vec<Vec<DeviceIntRect>>,
+    /// Whether the batches keep the bounding rect of their instances.
+    record_bounds: bool,
 }
 
 impl AlphaBatchList {
@@ -145,12 +198,14 @@ impl AlphaBatchList {
         AlphaBatchList {
             batches: Vec::new(),
             item_rects: Vec::new(),
+            record_bounds: false,
         }
     }
 
     pub fn get_suitable_batch(
         &mut self,
         key: BatchKey,
+        scissor_rect: Option<DeviceIntRect>,
         task_relative_bounding_rect: &DeviceIntRect,
     ) -> &mut Vec<PrimitiveInstance> {
         let mut selected_batch_index = None;
@@ -167,7 +222,7 @@ impl AlphaBatchList {
                         }
                     }
 
-                    if batch.key.is_compatible_with(&key) {
+                    if batch.is_compatible_with(&key, scissor_rect) {
                         selected_batch_index = Some(batch_index);
                         break;
                     }
@@ -179,7 +234,7 @@ impl AlphaBatchList {
                     // other than the first batch we consider. If the first batch
                     // is compatible, then we know there isn't any potential overlap
                     // issues to worry about.
-                    if batch.key.is_compatible_with(&key) {
+                    if batch.is_compatible_with(&key, scissor_rect) {
                         selected_batch_index = Some(batch_index);
                         break;
                     }
@@ -195,7 +250,7 @@ impl AlphaBatchList {
         }
 
         if selected_batch_index.is_none() {
-            let new_batch = PrimitiveBatch::new(key);
+            let new_batch = PrimitiveBatch::new(key, scissor_rect);
             selected_batch_index = Some(self.batches.len());
             self.batches.push(new_batch);
             self.item_rects.push(Vec::new());
@@ -203,13 +258,19 @@ impl AlphaBatchList {
 
         let selected_batch_index = selected_batch_index.unwrap();
         self.item_rects[selected_batch_index].push(*task_relative_bounding_rect);
-        &mut self.batches[selected_batch_index].instances
+        let batch = &mut self.batches[selected_batch_index];
+        if self.record_bounds {
+            batch.add_bounding_rect(task_relative_bounding_rect);
+        }
+        &mut batch.instances
     }
 }
 
 pub struct OpaqueBatchList {
     pub pixel_area_threshold_for_new_batch: f32,
     pub batches: Vec<PrimitiveBatch>,
+    /// Whether the batches keep the bounding rect of their instances.
+    record_bounds: bool,
 }
 
 impl OpaqueBatchList {
@@ -217,12 +278,14 @@ impl OpaqueBatchList {
         OpaqueBatchList {
             batches: Vec::new(),
             pixel_area_threshold_for_new_batch,
+            record_bounds: false,
         }
     }
 
     pub fn get_suitable_batch(
         &mut self,
         key: BatchKey,
+        scissor_rect: Option<DeviceIntRect>,
         task_relative_bounding_rect: &DeviceIntRect
     ) -> &mut Vec<PrimitiveInstance> {
         let mut selected_batch_index = None;
@@ -234,14 +297,14 @@ impl OpaqueBatchList {
         // create a new one.
         if item_area > self.pixel_area_threshold_for_new_batch {
             if let Some(batch) = self.batches.last() {
-                if batch.key.is_compatible_with(&key) {
+                if batch.is_compatible_with(&key, scissor_rect) {
                     selected_batch_index = Some(self.batches.len() - 1);
                 }
             }
         } else {
             // Otherwise, look back through a reasonable number of batches.
             for (batch_index, batch) in self.batches.iter().enumerate().rev().take(10) {
-                if batch.key.is_compatible_with(&key) {
+                if batch.is_compatible_with(&key, scissor_rect) {
                     selected_batch_index = Some(batch_index);
                     break;
                 }
@@ -249,12 +312,15 @@ impl OpaqueBatchList {
         }
 
         if selected_batch_index.is_none() {
-            let new_batch = PrimitiveBatch::new(key);
+            let new_batch = PrimitiveBatch::new(key, scissor_rect);
             selected_batch_index = Some(self.batches.len());
             self.batches.push(new_batch);
         }
 
         let batch = &mut self.batches[selected_batch_index.unwrap()];
+        if self.record_bounds {
+            batch.add_bounding_rect(task_relative_bounding_rect);
+        }
 
         &mut batch.instances
     }
@@ -276,6 +342,14 @@ pub struct BatchList {
     pub alpha_batch_list: AlphaBatchList,
     pub opaque_batch_list: OpaqueBatchList,
     pub combined_bounding_rect: DeviceIntRect,
+    /// If Some(..), every batch a primitive is added to is
+    /// recorded here, in paint order.
+    pub paint_order: Option<Vec<PaintOrderItem>>,
+    /// The picture and primitive currently being batched.
+    paint_source: Option<(PictureIndex, PrimitiveIndex)>,
+    /// The scissor rect of the primitive currently being batched, when
+    /// its clip rect is applied with the hardware scissor.
+    scissor_rect: Option<DeviceIntRect>,
 }
 
 impl BatchList {
@@ -288,6 +362,33 @@ impl BatchList {
             alpha_batch_list: AlphaBatchList::new(),
             opaque_batch_list: OpaqueBatchList::new(batch_area_threshold),
             combined_bounding_rect: DeviceIntRect::zero(),
+            paint_order: None,
+            paint_source: None,
+            scissor_rect: None,
+        }
+    }
+
+    /// Make the batches keep the bounding rect of their instances, for
+    /// `PrimitiveBatch::drawn_rect`.
+    pub fn record_batch_bounds(&mut self) {
+        self.alpha_batch_list.record_bounds = true;
+        self.opaque_batch_list.record_bounds = true;
+    }
+
+    fn record_paint_order(&mut self, key: &BatchKey) {
+        if let (Some(items), Some((pic_index, prim_index))) =
+            (self.paint_order.as_mut(), self.paint_source) {
+            let item = PaintOrderItem {
+                batch_kind: key.kind,
+                blend_mode: key.blend_mode,
+                pic_index,
+                prim_index,
+            };
+            // Primitives made of several instances (tiles, segments) only
+            // need to show up once per batch.
+            if items.last() != Some(&item) {
+                items.push(item);
+            }
         }
     }
 
@@ -304,11 +405,12 @@ impl BatchList {
         task_relative_bounding_rect: &DeviceIntRect,
     ) -> &mut Vec<PrimitiveInstance> {
         self.add_bounding_rect(task_relative_bounding_rect);
+        self.record_paint_order(&key);
 
         match key.blend_mode {
             BlendMode::None => {
                 self.opaque_batch_list
-                    .get_suitable_batch(key, task_relative_bounding_rect)
+                    .get_suitable_batch(key, self.scissor_rect, task_relative_bounding_rect)
             }
             BlendMode::Alpha |
             BlendMode::PremultipliedAlpha |
@@ -317,7 +419,7 @@ impl BatchList {
             BlendMode::SubpixelWithBgColor |
             BlendMode::SubpixelDualSource => {
                 self.alpha_batch_list
-                    .get_suitable_batch(key, task_relative_bounding_rect)
+                    .get_suitable_batch(key, self.scissor_rect, task_relative_bounding_rect)
             }
         }
     }
@@ -332,15 +434,43 @@ impl BatchList {
 pub struct PrimitiveBatch {
     pub key: BatchKey,
     pub instances: Vec<PrimitiveInstance>,
+    /// The rect of the target that drawing is restricted to, when the clip
+    /// rect of the instances is applied with the hardware scissor rather
+    /// than in the shaders.
+    pub scissor_rect: Option<DeviceIntRect>,
+    /// The union of the task relative bounding rects of the instances, if
+    /// the batch list records them.
+    pub bounding_rect: DeviceIntRect,
 }
 
 impl PrimitiveBatch {
-    fn new(key: BatchKey) -> PrimitiveBatch {
+    fn new(key: BatchKey, scissor_rect: Option<DeviceIntRect>) -> PrimitiveBatch {
         PrimitiveBatch {
             key,
             instances: Vec::new(),
+            scissor_rect,
+            bounding_rect: DeviceIntRect::zero(),
         }
     }
+
+    fn add_bounding_rect(&mut self, task_relative_bounding_rect: &DeviceIntRect) {
+        self.bounding_rect = self.bounding_rect.union(task_relative_bounding_rect);
+    }
+
+    /// The rect of the target this batch draws to, once the scissor rect
+    /// is applied.
+    pub fn drawn_rect(&self) -> DeviceIntRect {
+        match self.scissor_rect {
+            Some(ref scissor_rect) => {
+                self.bounding_rect.intersection(scissor_rect).unwrap_or(DeviceIntRect::zero())
+            }
+            None => self.bounding_rect,
+        }
+    }
+
+    fn is_compatible_with(&self, key: &BatchKey, scissor_rect: Option<DeviceIntRect>) -> bool {
+        self.key.is_compatible_with(key) && self.scissor_rect == scissor_rect
+    }
 }
 
 #[cfg_attr(feature = "capture", derive(Serialize))]
@@ -363,12 +493,14 @@ impl AlphaBatchContainer {
     fn merge(&mut self, builder: AlphaBatchBuilder) {
         for other_batch in builder.batch_list.opaque_batch_list.batches {
             let batch_index = self.opaque_batches.iter().position(|batch| {
-                batch.key.is_compatible_with(&other_batch.key)
+                batch.is_compatible_with(&other_batch.key, other_batch.scissor_rect)
             });
 
             match batch_index {
                 Some(batch_index) => {
-                    self.opaque_batches[batch_index].instances.extend(other_batch.instances);
+                    let batch = &mut self.opaque_batches[batch_index];
+                    batch.add_bounding_rect(&other_batch.bounding_rect);
+                    batch.instances.extend(other_batch.instances);
                 }
                 None => {
                     self.opaque_batches.push(other_batch);
@@ -380,13 +512,15 @@ impl AlphaBatchContainer {
 
         for other_batch in builder.batch_list.alpha_batch_list.batches {
             let batch_index = self.alpha_batches.iter().skip(min_batch_index).position(|batch| {
-                batch.key.is_compatible_with(&other_batch.key)
+                batch.is_compatible_with(&other_batch.key, other_batch.scissor_rect)
             });
 
             match batch_index {
                 Some(batch_index) => {
                     let batch_index = batch_index + min_batch_index;
-                    self.alpha_batches[batch_index].instances.extend(other_batch.instances);
+                    let batch = &mut self.alpha_batches[batch_index];
+                    batch.add_bounding_rect(&other_batch.bounding_rect);
+                    batch.instances.extend(other_batch.instances);
                     min_batch_index = batch_index;
                 }
                 None => {
@@ -398,6 +532,18 @@ impl AlphaBatchContainer {
     }
 }
 
+/// Attributes a batched primitive to the picture (stacking context) it
+/// belongs to, for debugging z-order issues.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "capture", derive(Serialize))]
+#[cfg_attr(feature = "replay", derive(Deserialize))]
+pub struct PaintOrderItem {
+    pub batch_kind: BatchKind,
+    pub blend_mode: BlendMode,
+    pub pic_index: PictureIndex,
+    pub prim_index: PrimitiveIndex,
+}
+
 /// Encapsulates the logic of building batches for items that are blended.
 pub struct AlphaBatchBuilder {
     pub batch_list: BatchList,
@@ -409,9 +555,19 @@ impl AlphaBatchBuilder {
     pub fn new(
         screen_size: DeviceIntSize,
         target_rect: DeviceIntRect,
+        record_paint_order: bool,
+        record_batch_bounds: bool,
     ) -> Self {
+        let mut batch_list = BatchList::new(screen_size);
+        if record_paint_order {
+            batch_list.paint_order = Some(Vec::new());
+        }
+        if record_batch_bounds {
+            batch_list.record_batch_bounds();
+        }
+
         AlphaBatchBuilder {
-            batch_list: BatchList::new(screen_size),
+            batch_list,
             glyph_fetch_buffer: Vec::new(),
             target_rect,
         }
@@ -441,6 +597,7 @@ impl AlphaBatchBuilder {
 
     pub fn add_pic_to_batch(
         &mut self,
+        pic_index: PictureIndex,
         pic: &PicturePrimitive,
         task_id: RenderTaskId,
         ctx: &RenderTargetContext,
@@ -470,6 +627,7 @@ impl AlphaBatchBuilder {
             let scroll_node = &ctx.clip_scroll_tree.nodes[run.clip_and_scroll.scroll_node_id.0];
             let scroll_id = scroll_node.node_data_index;
             self.add_run_to_batch(
+                pic_index,
                 run,
                 scroll_id,
                 ctx,
@@ -504,6 +662,8 @@ impl AlphaBatchBuilder {
             let pic_metadata = &ctx.prim_store.cpu_metadata[prim_index.0];
             let brush = &ctx.prim_store.cpu_brushes[pic_metadata.cpu_prim_index.0];
             let pic = &ctx.prim_store.pictures[brush.get_picture_index().0];
+            self.batch_list.paint_source = Some((pic_index, prim_index));
+            self.batch_list.scissor_rect = None;
             let batch = self.batch_list.get_suitable_batch(key, &pic_metadata.screen_rect.as_ref().expect("bug").clipped);
 
             let source_task_id = pic
@@ -529,11 +689,62 @@ impl AlphaBatchBuilder {
         }
     }
 
+    // Composite the cached surfaces that are fading out, in front of
+    // everything else drawn into the task.
+    pub fn add_fading_surfaces(
+        &mut self,
+        surfaces: &[FadingSurface],
+        task_id: RenderTaskId,
+        ctx: &RenderTargetContext,
+        gpu_cache: &GpuCache,
+        render_tasks: &RenderTaskTree,
+        z_generator: &mut ZBufferIdGenerator,
+    ) {
+        let task_address = render_tasks.get_task_address(task_id);
+        let root_node = &ctx.clip_scroll_tree.nodes[ctx.clip_scroll_tree.root_reference_frame_index().0];
+
+        // Fading surfaces don't belong to any picture in the scene.
+        self.batch_list.paint_source = None;
+        self.batch_list.scissor_rect = None;
+
+        for surface in surfaces {
+            let rt_cache_entry = ctx.resource_cache.get_cached_render_task(&surface.handle);
+            let cache_item = ctx.resource_cache.get_texture_cache_item(&rt_cache_entry.handle);
+
+            let key = BatchKey::new(
+                BatchKind::Brush(BrushBatchKind::Image(ImageBufferKind::Texture2DArray)),
+                BlendMode::PremultipliedAlpha,
+                BatchTextures::color(cache_item.texture_id),
+            );
+            let batch = self.batch_list.get_suitable_batch(key, &surface.device_rect);
+
+            let instance = BrushInstance {
+                picture_address: task_address,
+                prim_address: gpu_cache.get_address(&surface.gpu_location),
+                clip_chain_rect_index: ClipChainRectIndex(0),
+                scroll_id: root_node.node_data_index,
+                clip_task_address: OPAQUE_TASK_ADDRESS,
+                z: z_generator.next(),
+                segment_index: 0,
+                edge_flags: EdgeAaSegmentMask::empty(),
+                brush_flags: BrushFlags::empty(),
+                user_data: [
+                    gpu_cache.get_address(&cache_item.uv_rect_handle).as_int(),
+                    (ShaderColorMode::ColorBitmap as i32) << 16 |
+                    RasterizationSpace::Local as i32,
+                    0,
+                ],
+            };
+            batch.push(PrimitiveInstance::from(instance));
+        }
+    }
+
     // Helper to add an entire primitive run to a batch list.
     // TODO(gw): Restructure this so the param list isn't quite
     //           so daunting!
     fn add_run_to_batch(
         &mut self,
+        pic_index: PictureIndex,
         run: &PrimitiveRun,
         scroll_id: ClipScrollNodeIndex,
         ctx: &RenderTargetContext,
@@ -546,13 +757,31 @@ impl AlphaBatchBuilder {
         content_origin: DeviceIntPoint,
         z_generator: &mut ZBufferIdGenerator,
     ) {
+        let run_scissor_rect = if ctx.scissor_clip_rects {
+            let (target_rect, _) = render_tasks[task_id].get_target_rect();
+            run_scissor_rect(run, ctx, target_rect, target_rect.origin - content_origin)
+        } else {
+            None
+        };
+
         for i in 0 .. run.count {
             let prim_index = PrimitiveIndex(run.base_prim_index.0 + i);
             let metadata = &ctx.prim_store.cpu_metadata[prim_index.0];
 
             if metadata.screen_rect.is_some() {
+                // Primitives with a clip rect either restrict their batch to it
+                // with the scissor, or have the shaders clip them.
+                let (clip_chain_rect_index, scissor_rect) = match run_scissor_rect {
+                    Some(rect) if metadata.clip_chain_rect_index != ClipChainRectIndex(0) => {
+                        (ClipChainRectIndex(0), Some(rect))
+                    }
+                    _ => (metadata.clip_chain_rect_index, None),
+                };
+
+                self.batch_list.paint_source = Some((pic_index, prim_index));
+                self.batch_list.scissor_rect = scissor_rect;
                 self.add_prim_to_batch(
-                    metadata.clip_chain_rect_index,
+                    clip_chain_rect_index,
                     scroll_id,
                     prim_index,
                     ctx,
@@ -688,7 +917,8 @@ impl AlphaBatchBuilder {
                             Some(PictureCompositeMode::Filter(filter)) => {
                                 assert!(filter.is_visible());
                                 match filter {
-                                    FilterOp::Blur(..) => {
+                                    FilterOp::Blur(..) |
+                                    FilterOp::Downscale(..) => {
                                         match picture.surface {
                                             Some(ref surface) => {
                                                 let kind = BatchKind::Brush(
@@ -816,6 +1046,12 @@ impl AlphaBatchBuilder {
                                     _ => {
                                         match picture.surface {
                                             Some(ref surface) => {
+                                                // Every filtered surface lives somewhere in the shared
+                                                // render target cache, and each instance addresses its
+                                                // own sub-rect (and layer) through its uv rect. The key
+                                                // is therefore independent of the source task, so
+                                                // composites with the same op merge into one batch as
+                                                // long as nothing in between overlaps them.
                                                 let key = BatchKey::new(
                                                     BatchKind::Brush(BrushBatchKind::Blend),
                                                     BlendMode::PremultipliedAlpha,
@@ -834,6 +1070,10 @@ impl AlphaBatchBuilder {
                                                     FilterOp::Opacity(..) => 8,
                                                     FilterOp::DropShadow(..) => 9,
                                                     FilterOp::ColorMatrix(..) => 10,
+                                                    FilterOp::Downscale(..) => 11,
+                                                    FilterOp::Posterize(..) => 12,
+                                                    FilterOp::Threshold(..) => 13,
+                                                    FilterOp::Gamma(..) => 14,
                                                 };
 
                                                 let user_data = match filter {
@@ -843,15 +1083,24 @@ impl AlphaBatchBuilder {
                                                     FilterOp::Saturate(amount) |
                                                     FilterOp::Sepia(amount) |
                                                     FilterOp::Brightness(amount) |
+                                                    FilterOp::Threshold(amount) |
                                                     FilterOp::Opacity(_, amount) => {
                                                         (amount * 65536.0) as i32
                                                     }
+                                                    FilterOp::Gamma(gamma) => {
+                                                        let gamma = gamma.max(MIN_FILTER_GAMMA);
+                                                        (gamma * 65536.0) as i32
+                                                    }
+                                                    FilterOp::Posterize(levels) => {
+                                                        (levels.min(256) as f32 * 65536.0) as i32
+                                                    }
                                                     FilterOp::HueRotate(angle) => {
                                                         (0.01745329251 * angle * 65536.0) as i32
                                                     }
                                                     // Go through different paths
                                                     FilterOp::Blur(..) |
-                                                    FilterOp::DropShadow(..) => {
+                                                    FilterOp::DropShadow(..) |
+                                                    FilterOp::Downscale(..) => {
                                                         unreachable!();
                                                     }
                                                     FilterOp::ColorMatrix(_) => {
@@ -924,7 +1173,8 @@ impl AlphaBatchBuilder {
                                     edge_flags: EdgeAaSegmentMask::empty(),
                                     brush_flags: BrushFlags::empty(),
                                     user_data: [
-                                        mode as u32 as i32,
+                                        mode as u32 as i32 |
+                                            (ctx.composite_clamp as i32) << 16,
                                         backdrop_task_address.0 as i32,
                                         source_task_address.0 as i32,
                                     ],
@@ -985,6 +1235,7 @@ impl AlphaBatchBuilder {
                         // no composition operation), recurse and add to the current batch list.
                         if add_to_parent_pic {
                             self.add_pic_to_batch(
+                                pic_index,
                                 picture,
                                 task_id,
                                 ctx,
@@ -995,13 +1246,16 @@ impl AlphaBatchBuilder {
                             );
                         }
                     }
-                    BrushKind::Image { request, ref visible_tiles, .. } if !visible_tiles.is_empty() => {
+                    BrushKind::Image { request, ref visible_tiles, color_space, .. }
+                        if !visible_tiles.is_empty() => {
                         for tile in visible_tiles {
                             if let Some((batch_kind, textures, user_data)) = get_image_tile_params(
                                     ctx.resource_cache,
                                     gpu_cache,
                                     deferred_resolves,
                                     request.with_tile(tile.tile_offset),
+                                    color_space,
+                                    ctx.missing_image_placeholders,
                             ) {
                                 let prim_cache_address = gpu_cache.get_address(&tile.handle);
                                 self.add_image_tile_to_batch(
@@ -1061,6 +1315,8 @@ impl AlphaBatchBuilder {
                                 gpu_cache,
                                 deferred_resolves,
                                 ctx.cached_gradients,
+                                ctx.dither_offset,
+                                ctx.missing_image_placeholders,
                         ) {
                             self.add_brush_to_batch(
                                 brush,
@@ -1328,8 +1584,10 @@ impl AlphaBatchBuilder {
                     textures,
                 };
 
+                let scissor_rect = self.batch_list.scissor_rect;
                 let alpha_batch = self.batch_list.alpha_batch_list.get_suitable_batch(
                     alpha_batch_key,
+                    scissor_rect,
                     task_relative_bounding_rect
                 );
 
@@ -1341,6 +1599,7 @@ impl AlphaBatchBuilder {
 
                 let opaque_batch = self.batch_list.opaque_batch_list.get_suitable_batch(
                     opaque_batch_key,
+                    scissor_rect,
                     task_relative_bounding_rect
                 );
 
@@ -1385,6 +1644,28 @@ impl AlphaBatchBuilder {
     }
 }
 
+/// The rect of the task at `task_rect` that the clip chain of a run covers,
+/// offset by `target_offset` from screen space, if the clip can be applied
+/// with the hardware scissor. That's only the case when the clips and the
+/// primitives are axis-aligned with the screen. The rect never reaches past
+/// the task, so that the run can't draw over other tasks of the target.
+fn run_scissor_rect(
+    run: &PrimitiveRun,
+    ctx: &RenderTargetContext,
+    task_rect: DeviceIntRect,
+    target_offset: DeviceIntVector2D,
+) -> Option<DeviceIntRect> {
+    let scroll_node = &ctx.clip_scroll_tree.nodes[run.clip_and_scroll.scroll_node_id.0];
+    let clip_chain = ctx.clip_scroll_tree.get_clip_chain(run.clip_and_scroll.clip_chain_index);
+    if scroll_node.coordinate_system_id != CoordinateSystemId::root() ||
+        clip_chain.has_non_root_coord_system {
+        return None;
+    }
+
+    let rect = clip_chain.combined_outer_screen_rect.translate(&target_offset);
+    Some(rect.intersection(&task_rect).unwrap_or(DeviceIntRect::zero()))
+}
+
 fn add_gradient_tiles(
     visible_tiles: &[VisibleGradientTile],
     gradient_index: CachedGradientIndex,
@@ -1411,7 +1692,7 @@ fn add_gradient_tiles(
     );
 
     let stops_handle = &ctx.cached_gradients[gradient_index.0].handle;
-    let user_data = [stops_handle.as_int(gpu_cache), 0, 0];
+    let user_data = [stops_handle.as_int(gpu_cache), ctx.dither_offset, 0];
 
     let base_instance = BrushInstance {
         picture_address: task_address,
@@ -1436,11 +1717,25 @@ fn add_gradient_tiles(
     }
 }
 
+// Images without a texture are left out, or drawn with the solid brush,
+// which fills them with the tint color their GPU data starts with.
+fn missing_image_params(
+    draw_placeholder: bool,
+) -> Option<(BrushBatchKind, BatchTextures, [i32; 3])> {
+    if draw_placeholder {
+        Some((BrushBatchKind::Solid, BatchTextures::no_texture(), [0; 3]))
+    } else {
+        None
+    }
+}
+
 fn get_image_tile_params(
     resource_cache: &ResourceCache,
     gpu_cache: &mut GpuCache,
     deferred_resolves: &mut Vec<DeferredResolve>,
     request: ImageRequest,
+    color_space: ImageColorSpace,
+    missing_image_placeholders: bool,
 ) -> Option<(BrushBatchKind, BatchTextures, [i32; 3])> {
 
     let cache_item = resolve_image(
@@ -1451,7 +1746,7 @@ fn get_image_tile_params(
     );
 
     if cache_item.texture_id == SourceTexture::Invalid {
-        None
+        missing_image_params(missing_image_placeholders)
     } else {
         let textures = BatchTextures::color(cache_item.texture_id);
         Some((
@@ -1461,7 +1756,7 @@ fn get_image_tile_params(
                 cache_item.uv_rect_handle.as_int(gpu_cache),
                 (ShaderColorMode::ColorBitmap as i32) << 16 |
                      RasterizationSpace::Local as i32,
-                0,
+                color_space as i32,
             ],
         ))
     }
@@ -1485,9 +1780,11 @@ impl BrushPrimitive {
         gpu_cache: &mut GpuCache,
         deferred_resolves: &mut Vec<DeferredResolve>,
         cached_gradients: &[CachedGradient],
+        dither_offset: i32,
+        missing_image_placeholders: bool,
     ) -> Option<(BrushBatchKind, BatchTextures, [i32; 3])> {
         match self.kind {
-            BrushKind::Image { request, ref source, .. } => {
+            BrushKind::Image { request, ref source, color_space, .. } => {
                 let cache_item = match *source {
                     ImageSource::Default => {
                         resolve_image(
@@ -1508,7 +1805,7 @@ impl BrushPrimitive {
                 };
 
                 if cache_item.texture_id == SourceTexture::Invalid {
-                    None
+                    missing_image_params(missing_image_placeholders)
                 } else {
                     let textures = BatchTextures::color(cache_item.texture_id);
 
@@ -1519,7 +1816,7 @@ impl BrushPrimitive {
                             cache_item.uv_rect_handle.as_int(gpu_cache),
                             (ShaderColorMode::ColorBitmap as i32) << 16|
                              RasterizationSpace::Local as i32,
-                            0,
+                            color_space as i32,
                         ],
                     ))
                 }
@@ -1573,7 +1870,7 @@ impl BrushPrimitive {
                     BatchTextures::no_texture(),
                     [
                         stops_handle.as_int(gpu_cache),
-                        0,
+                        dither_offset,
                         0,
                     ],
                 ))
@@ -1585,7 +1882,7 @@ impl BrushPrimitive {
                     BatchTextures::no_texture(),
                     [
                         stops_handle.as_int(gpu_cache),
-                        0,
+                        dither_offset,
                         0,
                     ],
                 ))
@@ -1829,6 +2126,13 @@ pub struct ClipBatcher {
     pub line_decorations: Vec<ClipMaskInstance>,
 }
 
+/// The entries of a map keyed by texture, in the same order on every run.
+fn ordered_by_texture<T>(map: &FastHashMap<SourceTexture, T>) -> Vec<(&SourceTexture, &T)> {
+    let mut ordered: Vec<_> = map.iter().collect();
+    ordered.sort_by_key(|&(texture_id, _)| texture_id.sort_key());
+    ordered
+}
+
 impl ClipBatcher {
     pub fn new() -> Self {
         ClipBatcher {
@@ -1841,6 +2145,16 @@ impl ClipBatcher {
         }
     }
 
+    /// The image mask draws, ordered by mask texture.
+    pub fn ordered_images(&self) -> Vec<(&SourceTexture, &Vec<ClipMaskInstance>)> {
+        ordered_by_texture(&self.images)
+    }
+
+    /// The box shadow mask draws, ordered by mask texture.
+    pub fn ordered_box_shadows(&self) -> Vec<(&SourceTexture, &Vec<ClipMaskInstance>)> {
+        ordered_by_texture(&self.box_shadows)
+    }
+
     pub fn add_clip_region(
         &mut self,
         task_address: RenderTaskAddress,
@@ -1994,3 +2308,180 @@ fn get_buffer_kind(texture: SourceTexture) -> ImageBufferKind {
         _ => ImageBufferKind::Texture2DArray,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use internal_types::CacheTextureId;
+
+    fn push_instance(
+        list: &mut AlphaBatchList,
+        key: BatchKey,
+        source_task: u32,
+        rect: DeviceIntRect,
+        z_generator: &mut ZBufferIdGenerator,
+    ) {
+        let instance = SimplePrimitiveInstance::new(
+            GpuCacheAddress::invalid(),
+            RenderTaskAddress(0),
+            RenderTaskAddress(0),
+            ClipChainRectIndex(0),
+            ClipScrollNodeIndex(0),
+            z_generator.next(),
+        ).build(source_task as i32, 0, 0);
+        list.get_suitable_batch(key, None, &rect).push(instance);
+    }
+
+    #[test]
+    fn disjoint_filter_composites_share_a_batch() {
+        let mut list = AlphaBatchList::new();
+        let mut z_generator = ZBufferIdGenerator::new();
+
+        let composite_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Blend),
+            BlendMode::PremultipliedAlpha,
+            BatchTextures::render_target_cache(),
+        );
+        let solid_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Solid),
+            BlendMode::PremultipliedAlpha,
+            BatchTextures::no_texture(),
+        );
+
+        // Two composites of different source tasks in the shared atlas,
+        // separated by an unrelated primitive that overlaps neither.
+        push_instance(
+            &mut list,
+            composite_key,
+            1,
+            DeviceIntRect::new(DeviceIntPoint::new(0, 0), DeviceIntSize::new(50, 50)),
+            &mut z_generator,
+        );
+        push_instance(
+            &mut list,
+            solid_key,
+            0,
+            DeviceIntRect::new(DeviceIntPoint::new(200, 0), DeviceIntSize::new(50, 50)),
+            &mut z_generator,
+        );
+        push_instance(
+            &mut list,
+            composite_key,
+            2,
+            DeviceIntRect::new(DeviceIntPoint::new(100, 0), DeviceIntSize::new(50, 50)),
+            &mut z_generator,
+        );
+
+        assert_eq!(list.batches.len(), 2);
+        assert_eq!(list.batches[0].instances.len(), 2);
+        assert_eq!(list.batches[1].instances.len(), 1);
+    }
+
+    #[test]
+    fn paint_order_attributes_batches_to_their_pictures() {
+        let mut list = BatchList::new(DeviceIntSize::new(800, 600));
+        list.paint_order = Some(Vec::new());
+
+        let solid_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Solid),
+            BlendMode::PremultipliedAlpha,
+            BatchTextures::no_texture(),
+        );
+        let composite_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Blend),
+            BlendMode::PremultipliedAlpha,
+            BatchTextures::render_target_cache(),
+        );
+        let rect = DeviceIntRect::new(DeviceIntPoint::zero(), DeviceIntSize::new(10, 10));
+        let instance = || SimplePrimitiveInstance::new(
+            GpuCacheAddress::invalid(),
+            RenderTaskAddress(0),
+            RenderTaskAddress(0),
+            ClipChainRectIndex(0),
+            ClipScrollNodeIndex(0),
+            ZBufferIdGenerator::new().next(),
+        ).build(0, 0, 0);
+
+        // The first stacking context draws a rect, then the second one
+        // is composited on top of it as two instances of one primitive.
+        list.paint_source = Some((PictureIndex(1), PrimitiveIndex(0)));
+        list.get_suitable_batch(solid_key, &rect).push(instance());
+        list.paint_source = Some((PictureIndex(2), PrimitiveIndex(1)));
+        list.get_suitable_batch(composite_key, &rect).push(instance());
+        list.get_suitable_batch(composite_key, &rect).push(instance());
+
+        let paint_order = list.paint_order.unwrap();
+        assert_eq!(
+            paint_order,
+            vec![
+                PaintOrderItem {
+                    batch_kind: BatchKind::Brush(BrushBatchKind::Solid),
+                    blend_mode: BlendMode::PremultipliedAlpha,
+                    pic_index: PictureIndex(1),
+                    prim_index: PrimitiveIndex(0),
+                },
+                PaintOrderItem {
+                    batch_kind: BatchKind::Brush(BrushBatchKind::Blend),
+                    blend_mode: BlendMode::PremultipliedAlpha,
+                    pic_index: PictureIndex(2),
+                    prim_index: PrimitiveIndex(1),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn grouping_batches_by_texture_saves_binds() {
+        let image = |id| BatchTextures::color(SourceTexture::TextureCache(CacheTextureId(id)));
+        let (a, b) = (image(0), image(1));
+        let no_texture = BatchTextures::no_texture();
+
+        // Each image texture is bound to the first two samplers.
+        assert_eq!(texture_bind_count(&[a, b, a, b]), 8);
+        assert_eq!(texture_bind_count(&[a, a, b, b]), 4);
+
+        // Batches without textures don't disturb the bindings.
+        assert_eq!(texture_bind_count(&[a, no_texture, a]), 2);
+    }
+
+    #[test]
+    fn overlapping_full_screen_batches_are_counted_on_every_tile() {
+        let screen_size = DeviceIntSize::new(800, 600);
+        let mut list = BatchList::new(screen_size);
+        list.record_batch_bounds();
+        let solid_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Solid),
+            BlendMode::None,
+            BatchTextures::no_texture(),
+        );
+        let image_key = BatchKey::new(
+            BatchKind::Brush(BrushBatchKind::Image(ImageBufferKind::Texture2D)),
+            BlendMode::None,
+            BatchTextures::color(SourceTexture::TextureCache(CacheTextureId(0))),
+        );
+        let screen_rect = DeviceIntRect::new(DeviceIntPoint::zero(), screen_size);
+        let instance = || SimplePrimitiveInstance::new(
+            GpuCacheAddress::invalid(),
+            RenderTaskAddress(0),
+            RenderTaskAddress(0),
+            ClipChainRectIndex(0),
+            ClipScrollNodeIndex(0),
+            ZBufferIdGenerator::new().next(),
+        ).build(0, 0, 0);
+        list.get_suitable_batch(solid_key, &screen_rect).push(instance());
+        list.get_suitable_batch(image_key, &screen_rect).push(instance());
+
+        let batches = &list.opaque_batch_list.batches;
+        assert_eq!(batches.len(), 2);
+        let grid = overdraw_grid(screen_size, 256, batches.iter().map(|b| b.drawn_rect()));
+
+        // The screen is covered by 4x3 tiles, the last ones only partially.
+        assert_eq!(grid, vec![2; 12]);
+
+        // A rect only counts on the tiles it touches.
+        let rect = DeviceIntRect::new(DeviceIntPoint::new(300, 0), DeviceIntSize::new(10, 10));
+        let grid = overdraw_grid(screen_size, 256, Some(rect));
+        assert_eq!(grid.iter().sum::<u32>(), 1);
+        assert_eq!(grid[1], 1);
+    }
+}
@@ -297,9 +297,10 @@ impl ClipSources {
         }
 
         // Depending on the complexity of the clip, we may either know the outer and/or inner
-        // rect, or neither or these.  In the case of a clip-out, we currently set the mask bounds
-        // to be unknown. This is conservative, but ensures correctness. In the future we can make
-        // this a lot more clever with some proper region handling.
+        // rect, or neither or these. A clip-out only removes a hole from the clipped region,
+        // so it never shrinks the outer rect, which comes from the other clips alone. Since
+        // the hole may lie anywhere inside, the inner rect is unknown. In the future we can
+        // make this a lot more clever with some proper region handling.
         let mut local_outer = Some(LayoutRect::max_rect());
         let mut local_inner = local_outer;
         let mut can_calculate_inner_rect = true;
@@ -314,11 +315,9 @@ impl ClipSources {
                     local_inner = None;
                 }
                 ClipSource::Rectangle(rect, mode) => {
-                    // Once we encounter a clip-out, we just assume the worst
-                    // case clip mask size, for now.
                     if mode == ClipMode::ClipOut {
                         can_calculate_inner_rect = false;
-                        break;
+                        continue;
                     }
 
                     can_calculate_outer_rect = true;
@@ -326,11 +325,9 @@ impl ClipSources {
                     local_inner = local_inner.and_then(|r| r.intersection(&rect));
                 }
                 ClipSource::RoundedRectangle(ref rect, ref radius, mode) => {
-                    // Once we encounter a clip-out, we just assume the worst
-                    // case clip mask size, for now.
                     if mode == ClipMode::ClipOut {
                         can_calculate_inner_rect = false;
-                        break;
+                        continue;
                     }
 
                     can_calculate_outer_rect = true;
@@ -639,3 +636,78 @@ pub struct ClipWorkItem {
     pub coordinate_system_id: CoordinateSystemId,
 }
 
+#[cfg(test)]
+mod tests {
+    use api::{BorderRadius, ClipMode, ComplexClipRegion, LayoutPoint, LayoutRect, LayoutSize};
+    use super::{ClipRegion, ClipSource, ClipSources};
+
+    fn rect(x: f32, y: f32, w: f32, h: f32) -> LayoutRect {
+        LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(w, h))
+    }
+
+    #[test]
+    fn clip_out_center_leaves_a_ring() {
+        let outer = rect(0.0, 0.0, 100.0, 100.0);
+        let hole = rect(20.0, 20.0, 60.0, 60.0);
+        let region = ClipRegion {
+            main: outer,
+            image_mask: None,
+            complex_clips: vec![
+                ComplexClipRegion::new(hole, BorderRadius::zero(), ClipMode::ClipOut),
+            ],
+        };
+        let sources = ClipSources::from(region);
+
+        // The mask covers the outer rect, with the hole drawn into it.
+        assert_eq!(sources.local_outer_rect, Some(outer));
+        assert_eq!(sources.local_inner_rect, LayoutRect::zero());
+        let hole_clips = sources
+            .clips()
+            .iter()
+            .filter(|&&(ref source, _)| match *source {
+                ClipSource::Rectangle(rect, ClipMode::ClipOut) => rect == hole,
+                _ => false,
+            })
+            .count();
+        assert_eq!(hole_clips, 1);
+    }
+
+    #[test]
+    fn clip_out_does_not_hide_later_clips_from_outer_rect() {
+        let sources = ClipSources::new(vec![
+            ClipSource::Rectangle(rect(20.0, 20.0, 60.0, 60.0), ClipMode::ClipOut),
+            ClipSource::Rectangle(rect(0.0, 0.0, 100.0, 100.0), ClipMode::Clip),
+            ClipSource::new_rounded_rect(
+                rect(10.0, 0.0, 100.0, 100.0),
+                BorderRadius::uniform(5.0),
+                ClipMode::Clip,
+            ),
+        ]);
+
+        assert_eq!(sources.local_outer_rect, Some(rect(10.0, 0.0, 90.0, 100.0)));
+        assert_eq!(sources.local_inner_rect, LayoutRect::zero());
+    }
+    #[test]
+    fn only_clips_with_a_corner_radius_need_rounded_masks() {
+        let clip = rect(0.0, 0.0, 100.0, 100.0);
+        match ClipSource::new_rounded_rect(clip, BorderRadius::zero(), ClipMode::Clip) {
+            ClipSource::Rectangle(rect, ClipMode::Clip) => assert_eq!(rect, clip),
+            _ => panic!("a clip without radius should stay a plain rectangle"),
+        }
+        match ClipSource::new_rounded_rect(clip, BorderRadius::uniform(10.0), ClipMode::Clip) {
+            ClipSource::RoundedRectangle(rect, radius, ClipMode::Clip) => {
+                assert_eq!(rect, clip);
+                assert_eq!(radius, BorderRadius::uniform(10.0));
+            }
+            _ => panic!("a clip with a radius should be rounded"),
+        }
+
+        // The corners are left out of the region known to be unclipped.
+        let sources = ClipSources::new(vec![
+            ClipSource::new_rounded_rect(clip, BorderRadius::uniform(10.0), ClipMode::Clip),
+        ]);
+        assert_eq!(sources.local_outer_rect, Some(clip));
+        assert_ne!(sources.local_inner_rect, clip);
+        assert!(clip.contains_rect(&sources.local_inner_rect));
+    }
+}
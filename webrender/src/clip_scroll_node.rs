@@ -2,9 +2,10 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use api::{DevicePixelScale, ExternalScrollId, LayoutPixel, LayoutPoint, LayoutRect, LayoutSize};
+use api::{DeviceIntPoint, DevicePixelScale, ExternalScrollId, LayoutPixel, LayoutPoint, LayoutRect, LayoutSize};
 use api::{LayoutVector2D, LayoutTransform, PipelineId, PropertyBinding};
-use api::{ScrollClamping, ScrollLocation, ScrollSensitivity, StickyOffsetBounds};
+use api::{ScrollClamping, ScrollLocation, ScrollMode, ScrollSensitivity, ScrollSnapType};
+use api::StickyOffsetBounds;
 use clip::{ClipChain, ClipChainNode, ClipSourcesHandle, ClipStore, ClipWorkItem};
 use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex, CoordinateSystemId};
 use clip_scroll_tree::TransformUpdateState;
@@ -13,8 +14,14 @@ use gpu_cache::GpuCache;
 use gpu_types::{ClipScrollNodeIndex as GPUClipScrollNodeIndex, ClipScrollNodeData};
 use resource_cache::ResourceCache;
 use scene::SceneProperties;
+use std::f32;
 use util::{LayoutToWorldFastTransform, LayoutFastTransform};
-use util::{TransformedRectKind};
+use util::{MatrixHelpers, TransformedRectKind};
+
+/// When settling a scroll gesture, movement along one axis that is at most this
+/// fraction of the movement along the other axis is treated as incidental, and
+/// only the dominant axis snaps.
+const MINOR_AXIS_GESTURE_RATIO: f32 = 0.25;
 
 #[derive(Debug)]
 pub struct StickyFrameInfo {
@@ -161,16 +168,37 @@ impl ClipScrollNode {
         parent_index: ClipScrollNodeIndex,
         external_id: Option<ExternalScrollId>,
         frame_rect: &LayoutRect,
-        content_size: &LayoutSize,
+        content_rect: &LayoutRect,
         scroll_sensitivity: ScrollSensitivity,
     ) -> Self {
+        // A scroll frame whose content has no area can't meaningfully scroll, and
+        // the offset math below would produce a bogus range for it. Treat it as a
+        // plain, non-scrollable frame so input events go to its ancestors instead.
+        let (content_rect, scroll_sensitivity) = if content_rect.size.width <= 0.0 ||
+            content_rect.size.height <= 0.0 {
+            warn!("Scroll frame {:?} has degenerate content {:?}", external_id, content_rect);
+            (frame_rect, ScrollSensitivity::Script)
+        } else {
+            (content_rect, scroll_sensitivity)
+        };
+
+        // Content that extends above or to the left of the frame can be revealed by
+        // scrolling in the positive direction, while content that extends below or to the
+        // right of the frame is revealed by scrolling in the negative direction.
+        let max_offset = LayoutVector2D::new(
+            (frame_rect.min_x() - content_rect.min_x()).max(0.0),
+            (frame_rect.min_y() - content_rect.min_y()).max(0.0),
+        );
+        let min_offset = LayoutVector2D::new(
+            (frame_rect.max_x() - content_rect.max_x()).min(0.0),
+            (frame_rect.max_y() - content_rect.max_y()).min(0.0),
+        );
+
         let node_type = NodeType::ScrollFrame(ScrollFrameInfo::new(
             *frame_rect,
             scroll_sensitivity,
-            LayoutSize::new(
-                (content_size.width - frame_rect.size.width).max(0.0),
-                (content_size.height - frame_rect.size.height).max(0.0)
-            ),
+            (max_offset - min_offset).to_size(),
+            max_offset,
             external_id,
         ));
 
@@ -193,6 +221,7 @@ impl ClipScrollNode {
             source_perspective,
             origin_in_parent_reference_frame,
             invertible: true,
+            is_flattened: false,
         };
         Self::new(pipeline_id, parent_index, NodeType::ReferenceFrame(info))
     }
@@ -211,14 +240,38 @@ impl ClipScrollNode {
         self.children.push(child);
     }
 
-    pub fn apply_old_scrolling_state(&mut self, old_scrolling_state: &ScrollFrameInfo) {
+    /// Carries the scroll state of a scroll frame over from the previous scene.
+    /// With a `max_size_ratio`, the old offset is clamped to the new scroll
+    /// range, and isn't restored at all if the content grew or shrank by more
+    /// than that ratio on either axis, since it's then likely new content.
+    pub fn apply_old_scrolling_state(
+        &mut self,
+        old_scrolling_state: &ScrollFrameInfo,
+        max_size_ratio: Option<f32>,
+    ) {
         match self.node_type {
             NodeType::ScrollFrame(ref mut scrolling) => {
+                if let Some(max_size_ratio) = max_size_ratio {
+                    if scrolling.content_size_ratio(old_scrolling_state) > max_size_ratio {
+                        return;
+                    }
+                }
+
                 let scroll_sensitivity = scrolling.scroll_sensitivity;
                 let scrollable_size = scrolling.scrollable_size;
+                let max_offset = scrolling.max_offset;
                 *scrolling = *old_scrolling_state;
                 scrolling.scroll_sensitivity = scroll_sensitivity;
                 scrolling.scrollable_size = scrollable_size;
+                scrolling.max_offset = max_offset;
+
+                if max_size_ratio.is_some() {
+                    let min_offset = scrolling.min_offset();
+                    scrolling.offset = LayoutVector2D::new(
+                        scrolling.offset.x.max(min_offset.x).min(max_offset.x),
+                        scrolling.offset.y.max(min_offset.y).min(max_offset.y),
+                    );
+                }
             }
             _ if old_scrolling_state.offset != LayoutVector2D::zero() => {
                 warn!("Tried to scroll a non-scroll node.")
@@ -246,10 +299,10 @@ impl ClipScrollNode {
                     return false;
                 }
 
-                let origin = LayoutPoint::new(origin.x.max(0.0), origin.y.max(0.0));
+                let min_offset = scrolling.min_offset();
                 LayoutVector2D::new(
-                    (-origin.x).max(-scrollable_width).min(0.0).round(),
-                    (-origin.y).max(-scrollable_height).min(0.0).round(),
+                    (-origin.x).max(min_offset.x).min(scrolling.max_offset.x).round(),
+                    (-origin.y).max(min_offset.y).min(scrolling.max_offset.y).round(),
                 )
             }
             ScrollClamping::NoClamping => LayoutPoint::zero() - *origin,
@@ -305,11 +358,33 @@ impl ClipScrollNode {
         scene_properties: &SceneProperties,
         clip_chains: &mut Vec<ClipChain>,
     ) {
+        if !self.update_world_transforms(state, next_coordinate_system_id, scene_properties) {
+            return;
+        }
+
+        self.update_clip_work_item(
+            state,
+            device_pixel_scale,
+            clip_store,
+            resource_cache,
+            gpu_cache,
+            clip_chains,
+        );
+    }
+
+    /// Update the transforms of this node without updating its clip. Returns
+    /// false if the node isn't rendered because of an uninvertible transform.
+    pub fn update_world_transforms(
+        &mut self,
+        state: &mut TransformUpdateState,
+        next_coordinate_system_id: &mut CoordinateSystemId,
+        scene_properties: &SceneProperties,
+    ) -> bool {
         // If any of our parents was not rendered, we are not rendered either and can just
         // quit here.
         if !state.invertible {
             self.mark_uninvertible();
-            return;
+            return false;
         }
 
         self.update_transform(state, next_coordinate_system_id, scene_properties);
@@ -326,19 +401,13 @@ impl ClipScrollNode {
         match self.node_type {
             NodeType::ReferenceFrame(info) if !info.invertible => {
                 self.mark_uninvertible();
-                return;
+                false
+            }
+            _ => {
+                self.invertible = true;
+                true
             }
-            _ => self.invertible = true,
         }
-
-        self.update_clip_work_item(
-            state,
-            device_pixel_scale,
-            clip_store,
-            resource_cache,
-            gpu_cache,
-            clip_chains,
-        );
     }
 
     pub fn update_clip_work_item(
@@ -435,7 +504,10 @@ impl ClipScrollNode {
 
         // The transformation for any content inside of us is the viewport transformation, plus
         // whatever scrolling offset we supply as well.
-        let scroll_offset = self.scroll_offset();
+        let scroll_offset = match self.node_type {
+            NodeType::ScrollFrame(ref scrolling) => scrolling.snapped_offset(state.device_pixel_scale),
+            _ => LayoutVector2D::zero(),
+        };
         self.world_content_transform = if scroll_offset != LayoutVector2D::zero() {
             self.world_viewport_transform.pre_translate(&scroll_offset)
         } else {
@@ -471,6 +543,9 @@ impl ClipScrollNode {
             LayoutFastTransform::with_vector(info.origin_in_parent_reference_frame)
             .pre_mul(&source_transform.into())
             .pre_mul(&info.source_perspective);
+        if info.is_flattened {
+            info.resolved_transform = info.resolved_transform.to_transform().project_to_2d().into();
+        }
 
         // The transformation for this viewport in world coordinates is the transformation for
         // our parent reference frame, plus any accumulated scrolling offsets from nodes
@@ -628,16 +703,22 @@ impl ClipScrollNode {
                 state.parent_accumulated_scroll_offset = LayoutVector2D::zero();
                 state.coordinate_system_relative_transform =
                     self.coordinate_system_relative_transform.clone();
-                let translation = -info.origin_in_parent_reference_frame;
-                state.nearest_scrolling_ancestor_viewport =
-                    state.nearest_scrolling_ancestor_viewport
-                       .translate(&translation);
+
+                // The viewport of the nearest scrolling ancestor is in the space of our
+                // parent, so map it back through our transform, rather than only undoing
+                // our origin, so that sticky content under a transformed reference frame
+                // is positioned against the correct region.
+                let viewport = state.nearest_scrolling_ancestor_viewport;
+                state.nearest_scrolling_ancestor_viewport = info.resolved_transform
+                    .unapply(&viewport)
+                    .unwrap_or_else(|| viewport.translate(&-info.origin_in_parent_reference_frame));
             }
             NodeType::Clip{ .. } => { }
             NodeType::ScrollFrame(ref scrolling) => {
+                let scroll_offset = scrolling.snapped_offset(state.device_pixel_scale);
                 state.parent_accumulated_scroll_offset =
-                    scrolling.offset + state.parent_accumulated_scroll_offset;
-                state.nearest_scrolling_ancestor_offset = scrolling.offset;
+                    scroll_offset + state.parent_accumulated_scroll_offset;
+                state.nearest_scrolling_ancestor_offset = scroll_offset;
                 state.nearest_scrolling_ancestor_viewport = scrolling.viewport_rect;
             }
             NodeType::StickyFrame(ref info) => {
@@ -668,16 +749,17 @@ impl ClipScrollNode {
         let delta = match scroll_location {
             ScrollLocation::Delta(delta) => delta,
             ScrollLocation::Start => {
-                if scrolling.offset.y.round() >= 0.0 {
+                let start_pos = scrolling.max_offset.y;
+                if scrolling.offset.y.round() >= start_pos {
                     // Nothing to do on this layer.
                     return false;
                 }
 
-                scrolling.offset.y = 0.0;
+                scrolling.offset.y = start_pos;
                 return true;
             }
             ScrollLocation::End => {
-                let end_pos = -scrolling.scrollable_size.height;
+                let end_pos = scrolling.min_offset().y;
                 if scrolling.offset.y.round() <= end_pos {
                     // Nothing to do on this layer.
                     return false;
@@ -690,25 +772,58 @@ impl ClipScrollNode {
 
         let scrollable_width = scrolling.scrollable_size.width;
         let scrollable_height = scrolling.scrollable_size.height;
+        let min_offset = scrolling.min_offset();
         let original_layer_scroll_offset = scrolling.offset;
+        scrolling.gesture_delta += delta;
 
         if scrollable_width > 0. {
-            scrolling.offset.x = (scrolling.offset.x + delta.x)
-                .min(0.0)
-                .max(-scrollable_width)
-                .round();
+            scrolling.offset.x = scroll_axis(
+                scrolling.offset.x,
+                delta.x,
+                min_offset.x,
+                scrolling.max_offset.x,
+                scrolling.scroll_mode,
+            );
         }
 
         if scrollable_height > 0. {
-            scrolling.offset.y = (scrolling.offset.y + delta.y)
-                .min(0.0)
-                .max(-scrollable_height)
-                .round();
+            scrolling.offset.y = scroll_axis(
+                scrolling.offset.y,
+                delta.y,
+                min_offset.y,
+                scrolling.max_offset.y,
+                scrolling.scroll_mode,
+            );
         }
 
         scrolling.offset != original_layer_scroll_offset
     }
 
+    /// The part of a scroll delta that moving from `old_offset` to the current
+    /// offset didn't use up. Frames that overscroll use up all of it on the
+    /// axes they can scroll.
+    pub fn unused_scroll_delta(
+        &self,
+        delta: LayoutVector2D,
+        old_offset: LayoutVector2D,
+    ) -> LayoutVector2D {
+        let scrolling = match self.node_type {
+            NodeType::ScrollFrame(ref scrolling) => scrolling,
+            _ => return delta,
+        };
+
+        let mut unused_delta = delta - (scrolling.offset - old_offset);
+        if let ScrollMode::Overscroll { .. } = scrolling.scroll_mode {
+            if scrolling.scrollable_size.width > 0.0 {
+                unused_delta.x = 0.0;
+            }
+            if scrolling.scrollable_size.height > 0.0 {
+                unused_delta.y = 0.0;
+            }
+        }
+        unused_delta
+    }
+
     pub fn scroll_offset(&self) -> LayoutVector2D {
         match self.node_type {
             NodeType::ScrollFrame(ref scrolling) => scrolling.offset,
@@ -716,6 +831,24 @@ impl ClipScrollNode {
         }
     }
 
+    pub fn max_scroll_offset(&self) -> LayoutVector2D {
+        match self.node_type {
+            NodeType::ScrollFrame(ref scrolling) => scrolling.max_offset,
+            _ => LayoutVector2D::zero(),
+        }
+    }
+
+    /// Settle the current scroll gesture by snapping the offset to the nearest
+    /// of the given snap offsets. See `ScrollFrameInfo::settle_to_snap_offsets`.
+    pub fn settle_scroll(&mut self, snap_offsets_x: &[f32], snap_offsets_y: &[f32]) -> bool {
+        match self.node_type {
+            NodeType::ScrollFrame(ref mut scrolling) => {
+                scrolling.settle_to_snap_offsets(snap_offsets_x, snap_offsets_y)
+            }
+            _ => false,
+        }
+    }
+
     pub fn matches_external_id(&self, external_id: ExternalScrollId) -> bool {
         match self.node_type {
             NodeType::ScrollFrame(info) if info.external_id == Some(external_id) => true,
@@ -736,11 +869,29 @@ pub struct ScrollFrameInfo {
     /// Amount that this ScrollFrame can scroll in both directions.
     pub scrollable_size: LayoutSize,
 
+    /// The largest offset this ScrollFrame can have. This is non-zero when the content
+    /// extends above or to the left of the frame origin, allowing the frame to scroll
+    /// in the positive direction to reveal that content.
+    pub max_offset: LayoutVector2D,
+
     /// An external id to identify this scroll frame to API clients. This
     /// allows setting scroll positions via the API without relying on ClipsIds
     /// which may change between frames.
     pub external_id: Option<ExternalScrollId>,
 
+    /// The scroll deltas received through input events since the last time
+    /// the gesture settled. Used to find the dominant axis of the gesture.
+    pub gesture_delta: LayoutVector2D,
+
+    /// The delta this frame keeps scrolling by on the next frame, after the
+    /// input that scrolled it stopped. See `ClipScrollTree::scroll_friction`.
+    pub glide_delta: LayoutVector2D,
+
+    /// Whether the offset can move past the edges of the content.
+    pub scroll_mode: ScrollMode,
+
+    /// How strictly the offset snaps when a scroll gesture settles.
+    pub snap_type: ScrollSnapType,
 }
 
 /// Manages scrolling offset.
@@ -749,6 +900,7 @@ impl ScrollFrameInfo {
         viewport_rect: LayoutRect,
         scroll_sensitivity: ScrollSensitivity,
         scrollable_size: LayoutSize,
+        max_offset: LayoutVector2D,
         external_id: Option<ExternalScrollId>,
     ) -> ScrollFrameInfo {
         ScrollFrameInfo {
@@ -756,10 +908,115 @@ impl ScrollFrameInfo {
             offset: LayoutVector2D::zero(),
             scroll_sensitivity,
             scrollable_size,
+            max_offset,
             external_id,
+            gesture_delta: LayoutVector2D::zero(),
+            glide_delta: LayoutVector2D::zero(),
+            scroll_mode: ScrollMode::Clamped,
+            snap_type: ScrollSnapType::Mandatory,
         }
     }
 
+    /// Move an offset that is past the edges of the content back toward them,
+    /// scaling the distance by `decay`. Once it is less than half a pixel,
+    /// the offset settles on the edge in whole pixels. Returns true if the
+    /// offset changed.
+    pub fn spring_back(&mut self, decay: f32) -> bool {
+        fn spring_back_axis(offset: f32, min: f32, max: f32, decay: f32) -> f32 {
+            let edge = offset.min(max).max(min);
+            let distance = (offset - edge) * decay;
+            if offset == edge {
+                offset
+            } else if distance.abs() < 0.5 {
+                edge.round()
+            } else {
+                edge + distance
+            }
+        }
+
+        let min_offset = self.min_offset();
+        let original_offset = self.offset;
+        self.offset = LayoutVector2D::new(
+            spring_back_axis(self.offset.x, min_offset.x, self.max_offset.x, decay),
+            spring_back_axis(self.offset.y, min_offset.y, self.max_offset.y, decay),
+        );
+        self.offset != original_offset
+    }
+
+    /// The smallest offset this ScrollFrame can have.
+    pub fn min_offset(&self) -> LayoutVector2D {
+        self.max_offset - self.scrollable_size.to_vector()
+    }
+
+    /// How many times larger the content of this frame is than that of
+    /// `other`, or the other way around, on the axis where they differ most.
+    fn content_size_ratio(&self, other: &ScrollFrameInfo) -> f32 {
+        fn ratio(a: f32, b: f32) -> f32 {
+            if a == b {
+                1.0
+            } else if a.min(b) <= 0.0 {
+                f32::INFINITY
+            } else {
+                a.max(b) / a.min(b)
+            }
+        }
+
+        let size = self.viewport_rect.size + self.scrollable_size;
+        let other_size = other.viewport_rect.size + other.scrollable_size;
+        ratio(size.width, other_size.width).max(ratio(size.height, other_size.height))
+    }
+
+    /// The scroll offset in whole device pixels. This is the offset that
+    /// is applied to the contents of the frame.
+    pub fn device_offset(&self, device_pixel_scale: DevicePixelScale) -> DeviceIntPoint {
+        DeviceIntPoint::new(
+            (self.offset.x * device_pixel_scale.0).round() as i32,
+            (self.offset.y * device_pixel_scale.0).round() as i32,
+        )
+    }
+
+    /// The scroll offset snapped to device pixels, so that scrolled content
+    /// stays aligned with the pixel grid.
+    pub fn snapped_offset(&self, device_pixel_scale: DevicePixelScale) -> LayoutVector2D {
+        let device_offset = self.device_offset(device_pixel_scale);
+        LayoutVector2D::new(
+            device_offset.x as f32 / device_pixel_scale.0,
+            device_offset.y as f32 / device_pixel_scale.0,
+        )
+    }
+
+    /// Snap the offset to the nearest snap offset on each axis, then start a
+    /// new gesture. If the gesture so far moved mostly along one axis, only
+    /// that axis snaps and the other is left where the user put it. With
+    /// proximity snapping, an axis only snaps if a snap offset is close
+    /// enough. Returns true if the offset changed.
+    pub fn settle_to_snap_offsets(
+        &mut self,
+        snap_offsets_x: &[f32],
+        snap_offsets_y: &[f32],
+    ) -> bool {
+        let (gesture_x, gesture_y) = (self.gesture_delta.x.abs(), self.gesture_delta.y.abs());
+        self.gesture_delta = LayoutVector2D::zero();
+
+        let snap_x = gesture_x > gesture_y * MINOR_AXIS_GESTURE_RATIO;
+        let snap_y = gesture_y > gesture_x * MINOR_AXIS_GESTURE_RATIO;
+
+        let min_offset = self.min_offset();
+        let original_offset = self.offset;
+        if snap_x {
+            if let Some(x) = nearest_snap_offset(self.offset.x, snap_offsets_x, self.snap_type) {
+                self.offset.x = x.min(self.max_offset.x).max(min_offset.x);
+            }
+        }
+        if snap_y {
+            if let Some(y) = nearest_snap_offset(self.offset.y, snap_offsets_y, self.snap_type) {
+                self.offset.y = y.min(self.max_offset.y).max(min_offset.y);
+            }
+        }
+
+        self.offset != original_offset
+    }
+
     pub fn sensitive_to_input_events(&self) -> bool {
         match self.scroll_sensitivity {
             ScrollSensitivity::ScriptAndInputEvents => true,
@@ -768,6 +1025,49 @@ impl ScrollFrameInfo {
     }
 }
 
+// Scroll one axis of an offset by `delta`, between `min` and `max`. Offsets
+// within those bounds are whole pixels. With overscroll, the part of the
+// movement past the bounds is slowed down, but moving back toward them isn't,
+// and the offset stops at the maximum distance past them.
+fn scroll_axis(offset: f32, delta: f32, min: f32, max: f32, scroll_mode: ScrollMode) -> f32 {
+    let target = offset + delta;
+    let edge = target.min(max).max(min);
+    let (resistance, max_distance) = match scroll_mode {
+        ScrollMode::Overscroll { resistance, max_distance } if target != edge => {
+            (resistance.max(0.0).min(1.0), max_distance.max(0.0))
+        }
+        _ => return edge.round(),
+    };
+
+    let past_same_edge = (target > max && offset > max) || (target < min && offset < min);
+    let new_offset = if past_same_edge && (target - edge).abs() <= (offset - edge).abs() {
+        target
+    } else {
+        let start = if past_same_edge { offset } else { edge };
+        start + (target - start) * (1.0 - resistance)
+    };
+    new_offset.min(max + max_distance).max(min - max_distance)
+}
+
+fn nearest_snap_offset(
+    offset: f32,
+    snap_offsets: &[f32],
+    snap_type: ScrollSnapType,
+) -> Option<f32> {
+    // Offsets that aren't finite can't be snapped to, and can't be ordered
+    // by their distance either.
+    let nearest = snap_offsets
+        .iter()
+        .cloned()
+        .filter(|snap_offset| snap_offset.is_finite())
+        .min_by(|a, b| (a - offset).abs().partial_cmp(&(b - offset).abs()).unwrap());
+    match (nearest, snap_type) {
+        (Some(snap_offset), ScrollSnapType::Proximity { threshold })
+            if (snap_offset - offset).abs() > threshold => None,
+        _ => nearest,
+    }
+}
+
 /// Contains information about reference frames.
 #[derive(Copy, Clone, Debug)]
 pub struct ReferenceFrameInfo {
@@ -789,4 +1089,294 @@ pub struct ReferenceFrameInfo {
 
     /// True if the resolved transform is invertible.
     pub invertible: bool,
+
+    /// True if this reference frame is inside a flat stacking context that
+    /// is part of a 3d rendering context. Its content is flattened into the
+    /// plane of that stacking context, so the z component of the transform
+    /// is dropped.
+    pub is_flattened: bool,
+}
+
+#[cfg(test)]
+mod test {
+    use api::{DevicePixelScale, LayoutPoint, LayoutRect, LayoutSize, LayoutTransform};
+    use api::{LayoutVector2D, PipelineId};
+    use api::{PropertyBinding, ScrollClamping, ScrollLocation, ScrollMode, ScrollSensitivity};
+    use api::ScrollSnapType;
+    use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex, CoordinateSystemId};
+    use clip_scroll_tree::TransformUpdateState;
+    use scene::SceneProperties;
+    use std::f32;
+    use super::{ClipScrollNode, NodeType};
+    use util::{LayoutFastTransform, LayoutToWorldFastTransform};
+
+    #[test]
+    fn scroll_to_content_left_of_frame_origin() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(
+            LayoutPoint::new(-100.0, 0.0),
+            LayoutSize::new(1000.0, 500.0),
+        );
+        let mut node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+
+        // Scrolling in the positive direction reveals the content on the left,
+        // but no further than where that content begins.
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(150.0, 0.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(100.0, 0.0));
+        assert!(!node.scroll(ScrollLocation::Delta(LayoutVector2D::new(10.0, 0.0))));
+
+        // The other end of the range is still bounded by the right edge of the content.
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(-1000.0, 0.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(-400.0, 0.0));
+
+        assert!(node.set_scroll_origin(
+            &LayoutPoint::new(-100.0, 0.0),
+            ScrollClamping::ToContentBounds,
+        ));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(100.0, 0.0));
+    }
+
+    #[test]
+    fn degenerate_scroll_frame_does_not_scroll() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::new(-100.0, 0.0), LayoutSize::zero());
+        let mut node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+
+        assert_eq!(node.scrollable_size(), LayoutSize::zero());
+        assert!(!node.scroll(ScrollLocation::Delta(LayoutVector2D::new(50.0, -50.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::zero());
+        match node.node_type {
+            NodeType::ScrollFrame(info) => assert!(!info.sensitive_to_input_events()),
+            _ => panic!("expected a scroll frame"),
+        }
+    }
+
+    #[test]
+    fn overscroll_springs_back_to_the_edge() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 1000.0));
+        let mut node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        let spring_back = |node: &mut ClipScrollNode| match node.node_type {
+            NodeType::ScrollFrame(ref mut info) => info.spring_back(0.5),
+            _ => unreachable!(),
+        };
+        if let NodeType::ScrollFrame(ref mut info) = node.node_type {
+            info.scroll_mode = ScrollMode::Overscroll { resistance: 0.5, max_distance: 100.0 };
+        }
+
+        // Past the top edge, only half of the delta applies, and the offset
+        // isn't rounded. Moving back toward the edge isn't slowed down.
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, 15.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, 7.5));
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -5.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, 2.5));
+
+        // The content fits horizontally, so there is nothing to overscroll.
+        assert!(!node.scroll(ScrollLocation::Delta(LayoutVector2D::new(30.0, 0.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, 2.5));
+
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, 20.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, 12.5));
+
+        // Springing back decays the distance from the edge, then settles
+        // exactly on it.
+        assert!(spring_back(&mut node));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, 6.25));
+        let mut ticks = 0;
+        while spring_back(&mut node) {
+            ticks += 1;
+            assert!(ticks < 10);
+        }
+        assert_eq!(node.scroll_offset(), LayoutVector2D::zero());
+
+        // Without overscroll, the offset stops at the edge.
+        if let NodeType::ScrollFrame(ref mut info) = node.node_type {
+            info.scroll_mode = ScrollMode::Clamped;
+        }
+        assert!(!node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, 15.0))));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::zero());
+    }
+
+    #[test]
+    fn overscroll_goes_at_most_its_max_distance_past_the_edge() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 1000.0));
+        let overscrolled_offset = |resistance, max_distance| {
+            let mut node = ClipScrollNode::new_scroll_frame(
+                PipelineId::dummy(),
+                ClipScrollNodeIndex(0),
+                None,
+                &frame_rect,
+                &content_rect,
+                ScrollSensitivity::ScriptAndInputEvents,
+            );
+            if let NodeType::ScrollFrame(ref mut info) = node.node_type {
+                info.scroll_mode = ScrollMode::Overscroll { resistance, max_distance };
+            }
+            for _ in 0 .. 3 {
+                node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -240.0)));
+            }
+            let offset = node.scroll_offset().y;
+            while let NodeType::ScrollFrame(ref mut info) = node.node_type {
+                if !info.spring_back(0.5) {
+                    break;
+                }
+            }
+            (offset, node.scroll_offset().y)
+        };
+
+        // A scroll frame with less resistance goes further past the bottom
+        // edge for the same deltas, and both spring back onto it.
+        assert_eq!(overscrolled_offset(0.5, 1000.0), (-610.0, -500.0));
+        assert_eq!(overscrolled_offset(0.75, 1000.0), (-555.0, -500.0));
+
+        // Neither goes further than its maximum distance.
+        assert_eq!(overscrolled_offset(0.5, 50.0), (-550.0, -500.0));
+        assert_eq!(overscrolled_offset(0.75, 50.0), (-550.0, -500.0));
+        assert_eq!(overscrolled_offset(0.5, 0.0), (-500.0, -500.0));
+    }
+
+    #[test]
+    fn mostly_vertical_gesture_snaps_only_vertically() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(1000.0, 1000.0));
+        let mut node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        let snap_offsets = [0.0, -200.0, -400.0];
+
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(-7.0, -90.0))));
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(-3.0, -80.0))));
+        assert!(node.settle_scroll(&snap_offsets, &snap_offsets));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(-10.0, -200.0));
+
+        // A diagonal gesture snaps both axes.
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(-140.0, -150.0))));
+        assert!(node.settle_scroll(&snap_offsets, &snap_offsets));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(-200.0, -400.0));
+    }
+
+    #[test]
+    fn proximity_snapping_only_settles_near_snap_offsets() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 1000.0));
+        let snap_offsets = [0.0, -200.0, -400.0];
+        let new_node = |snap_type| {
+            let mut node = ClipScrollNode::new_scroll_frame(
+                PipelineId::dummy(),
+                ClipScrollNodeIndex(0),
+                None,
+                &frame_rect,
+                &content_rect,
+                ScrollSensitivity::ScriptAndInputEvents,
+            );
+            if let NodeType::ScrollFrame(ref mut info) = node.node_type {
+                info.snap_type = snap_type;
+            }
+            node
+        };
+
+        // Mandatory snapping settles on a snap offset from any position.
+        let mut mandatory = new_node(ScrollSnapType::Mandatory);
+        assert!(mandatory.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -90.0))));
+        assert!(mandatory.settle_scroll(&[], &snap_offsets));
+        assert_eq!(mandatory.scroll_offset(), LayoutVector2D::new(0.0, 0.0));
+
+        // Proximity snapping keeps an offset far from any snap offset...
+        let mut proximity = new_node(ScrollSnapType::Proximity { threshold: 30.0 });
+        assert!(proximity.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -90.0))));
+        assert!(!proximity.settle_scroll(&[], &snap_offsets));
+        assert_eq!(proximity.scroll_offset(), LayoutVector2D::new(0.0, -90.0));
+
+        // ...and settles on one that is close enough.
+        assert!(proximity.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -85.0))));
+        assert!(proximity.settle_scroll(&[], &snap_offsets));
+        assert_eq!(proximity.scroll_offset(), LayoutVector2D::new(0.0, -200.0));
+    }
+
+    #[test]
+    fn snap_offsets_that_are_not_finite_are_ignored() {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 500.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(500.0, 1000.0));
+        let mut node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        let snap_offsets = [f32::NAN, -200.0, f32::NEG_INFINITY, -400.0];
+
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -150.0))));
+        assert!(node.settle_scroll(&[], &snap_offsets));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, -200.0));
+
+        // Without a finite offset to snap to, the offset stays put.
+        assert!(node.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -50.0))));
+        assert!(!node.settle_scroll(&[], &[f32::NAN]));
+        assert_eq!(node.scroll_offset(), LayoutVector2D::new(0.0, -250.0));
+    }
+
+    #[test]
+    fn scrolling_viewport_is_mapped_into_transformed_reference_frame() {
+        let mut node = ClipScrollNode::new_reference_frame(
+            Some(ClipScrollNodeIndex(0)),
+            Some(PropertyBinding::Value(LayoutTransform::create_scale(2.0, 2.0, 1.0))),
+            None,
+            LayoutVector2D::new(50.0, 50.0),
+            PipelineId::dummy(),
+        );
+
+        let mut state = TransformUpdateState {
+            parent_reference_frame_transform: LayoutToWorldFastTransform::identity(),
+            parent_accumulated_scroll_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_viewport: LayoutRect::new(
+                LayoutPoint::zero(),
+                LayoutSize::new(200.0, 200.0),
+            ),
+            parent_clip_chain_index: ClipChainIndex(0),
+            current_coordinate_system_id: CoordinateSystemId::root(),
+            coordinate_system_relative_transform: LayoutFastTransform::identity(),
+            invertible: true,
+            device_pixel_scale: DevicePixelScale::new(1.0),
+        };
+        let mut next_coordinate_system_id = CoordinateSystemId::root().next();
+        node.update_transform(&mut state, &mut next_coordinate_system_id, &SceneProperties::new());
+        node.prepare_state_for_children(&mut state);
+
+        // Content below the reference frame is scaled up and offset, so the viewport
+        // covers a smaller region, starting above and to the left of its origin.
+        assert_eq!(
+            state.nearest_scrolling_ancestor_viewport,
+            LayoutRect::new(LayoutPoint::new(-25.0, -25.0), LayoutSize::new(100.0, 100.0))
+        );
+    }
 }
@@ -2,21 +2,39 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use api::{DeviceIntRect, DevicePixelScale, ExternalScrollId, LayoutPoint, LayoutRect, LayoutVector2D};
-use api::{PipelineId, ScrollClamping, ScrollLocation, ScrollNodeState};
-use api::WorldPoint;
+use api::{DeviceIntPoint, DeviceIntRect, DevicePixelScale, ExternalScrollId, LayoutPoint, LayoutRect};
+use api::{PipelineId, ScrollAxes, ScrollClamping, ScrollLocation, ScrollMode, ScrollNodeState};
+use api::{ScrollFrameClipRect, ScrollSnapType, ScrollTreeNode};
+use api::{LayoutToWorldTransform, LayoutVector2D, WorldPoint};
 use clip::{ClipChain, ClipSourcesHandle, ClipStore};
 use clip_scroll_node::{ClipScrollNode, NodeType, ScrollFrameInfo, StickyFrameInfo};
+use euclid::vec3;
 use gpu_cache::GpuCache;
 use gpu_types::{ClipScrollNodeIndex as GPUClipScrollNodeIndex, ClipScrollNodeData};
 use internal_types::{FastHashMap, FastHashSet};
 use print_tree::{PrintTree, PrintTreePrinter};
 use resource_cache::ResourceCache;
 use scene::SceneProperties;
+use std::mem;
 use util::{LayoutFastTransform, LayoutToWorldFastTransform};
 
 pub type ScrollStates = FastHashMap<ExternalScrollId, ScrollFrameInfo>;
 
+/// The transform of the root reference frame into world space. This applies
+/// the pan, along with the stretch of a non-uniform device pixel ratio.
+pub fn root_reference_frame_transform(
+    pan: WorldPoint,
+    device_pixel_stretch: (f32, f32),
+) -> LayoutToWorldFastTransform {
+    if device_pixel_stretch == (1.0, 1.0) {
+        return LayoutVector2D::new(pan.x, pan.y).into();
+    }
+
+    LayoutToWorldTransform::create_scale(device_pixel_stretch.0, device_pixel_stretch.1, 1.0)
+        .post_translate(vec3(pan.x, pan.y, 0.0))
+        .into()
+}
+
 /// An id that identifies coordinate systems in the ClipScrollTree. Each
 /// coordinate system has an id and those ids will be shared when the coordinates
 /// system are the same or are in the same axis-aligned space. This allows
@@ -32,6 +50,19 @@ pub struct ClipScrollNodeIndex(pub usize);
 const ROOT_REFERENCE_FRAME_INDEX: ClipScrollNodeIndex = ClipScrollNodeIndex(0);
 const TOPMOST_SCROLL_NODE_INDEX: ClipScrollNodeIndex = ClipScrollNodeIndex(1);
 
+/// The largest scroll friction, which keeps a scroll frame gliding for a
+/// while but still brings it to a stop.
+const MAX_SCROLL_FRICTION: f32 = 0.95;
+
+/// The length of the frames that the glide of scroll frames is measured in,
+/// in seconds. Every frame of this length, a gliding scroll frame moves by
+/// its glide delta, and the delta shrinks by the scroll friction.
+pub const SCROLL_GLIDE_FRAME_DURATION: f32 = 1.0 / 60.0;
+
+/// How quickly scroll frames that are scrolled past their edges spring back.
+/// Their distance from the edge decays by a factor of e every 1/rate seconds.
+const OVERSCROLL_SPRING_RATE: f32 = 12.0;
+
 impl CoordinateSystemId {
     pub fn root() -> Self {
         CoordinateSystemId(0)
@@ -70,6 +101,10 @@ pub struct ClipScrollTree {
 
     pub pending_scroll_offsets: FastHashMap<ExternalScrollId, (LayoutPoint, ScrollClamping)>,
 
+    /// Pairs of scroll frames that follow each other's scrolling on the given
+    /// axes. These are keyed by external id, so they persist across scene builds.
+    pub scroll_links: Vec<(ExternalScrollId, ExternalScrollId, ScrollAxes)>,
+
     /// The current frame id, used for giving a unique id to all new dynamically
     /// added frames and clips. The ClipScrollTree increments this by one every
     /// time a new dynamic frame is created.
@@ -78,6 +113,11 @@ pub struct ClipScrollTree {
     /// A set of pipelines which should be discarded the next time this
     /// tree is drained.
     pub pipelines_to_discard: FastHashSet<PipelineId>,
+
+    /// The fraction of a scroll delta that a scroll frame keeps scrolling by
+    /// on the next frame, which decays by the same factor on every frame
+    /// after that. With zero, scrolling stops as soon as the input does.
+    scroll_friction: f32,
 }
 
 #[derive(Clone)]
@@ -103,6 +143,29 @@ pub struct TransformUpdateState {
     /// transformed by this node will not be displayed and display items not transformed by this
     /// node will not be clipped by clips that are transformed by this node.
     pub invertible: bool,
+
+    /// The scale from world to device pixels, which scroll offsets are snapped to.
+    pub device_pixel_scale: DevicePixelScale,
+}
+
+impl TransformUpdateState {
+    /// The state to start updating the tree from at the root reference frame.
+    fn new(
+        root_transform: LayoutToWorldFastTransform,
+        device_pixel_scale: DevicePixelScale,
+    ) -> Self {
+        TransformUpdateState {
+            parent_reference_frame_transform: root_transform,
+            parent_accumulated_scroll_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_viewport: LayoutRect::zero(),
+            parent_clip_chain_index: ClipChainIndex(0),
+            current_coordinate_system_id: CoordinateSystemId::root(),
+            coordinate_system_relative_transform: LayoutFastTransform::identity(),
+            invertible: true,
+            device_pixel_scale,
+        }
+    }
 }
 
 impl ClipScrollTree {
@@ -112,8 +175,10 @@ impl ClipScrollTree {
             clip_chains_descriptors: Vec::new(),
             clip_chains: vec![ClipChain::empty(&DeviceIntRect::zero())],
             pending_scroll_offsets: FastHashMap::default(),
+            scroll_links: Vec::new(),
             current_new_node_item: 1,
             pipelines_to_discard: FastHashSet::default(),
+            scroll_friction: 0.0,
         }
     }
 
@@ -145,6 +210,83 @@ impl ClipScrollTree {
         result
     }
 
+    /// The scroll frames with external ids and how they nest. This only
+    /// depends on the flattened scene, so it is valid before the first frame
+    /// is built from it.
+    pub fn scroll_tree(&self) -> Vec<ScrollTreeNode> {
+        let external_id = |index: ClipScrollNodeIndex| match self.nodes[index.0].node_type {
+            NodeType::ScrollFrame(info) => info.external_id,
+            _ => None,
+        };
+
+        self.nodes.iter().filter_map(|node| match node.node_type {
+            NodeType::ScrollFrame(info) => info.external_id.map(|id| {
+                let mut parent = node.parent;
+                while let Some(index) = parent {
+                    if external_id(index).is_some() {
+                        break;
+                    }
+                    parent = self.nodes[index.0].parent;
+                }
+                ScrollTreeNode {
+                    id,
+                    parent: parent.and_then(&external_id),
+                    viewport_size: info.viewport_rect.size,
+                    content_size: info.viewport_rect.size + info.scrollable_size,
+                }
+            }),
+            _ => None,
+        }).collect()
+    }
+
+    /// The clip rect that each scroll frame with an external id applies to
+    /// its contents. Useful for finding out why content is clipped. The
+    /// scroll offset is snapped to device pixels, as it is when drawing.
+    pub fn scroll_frame_clip_rects(
+        &self,
+        device_pixel_scale: DevicePixelScale,
+    ) -> Vec<ScrollFrameClipRect> {
+        self.nodes.iter().filter_map(|node| match node.node_type {
+            NodeType::ScrollFrame(info) => info.external_id.map(|id| {
+                let offset = info.snapped_offset(device_pixel_scale);
+                let local_rect = info.viewport_rect.translate(&-offset);
+                ScrollFrameClipRect {
+                    id,
+                    local_rect,
+                    world_rect: node.world_content_transform.transform_rect(&local_rect),
+                }
+            }),
+            _ => None,
+        }).collect()
+    }
+
+    /// The scroll offset of a scroll frame in whole device pixels, as it is
+    /// applied to the transform of the frame's contents.
+    pub fn device_scroll_offset(
+        &self,
+        id: ExternalScrollId,
+        device_pixel_scale: DevicePixelScale,
+    ) -> Option<DeviceIntPoint> {
+        self.nodes.iter().filter_map(|node| match node.node_type {
+            NodeType::ScrollFrame(info) if info.external_id == Some(id) => {
+                Some(info.device_offset(device_pixel_scale))
+            }
+            _ => None,
+        }).next()
+    }
+
+    /// The transform from the content of a scroll frame to world space, as of
+    /// the last update of the tree. This includes the scroll offset.
+    pub fn scroll_frame_world_transform(
+        &self,
+        id: ExternalScrollId,
+    ) -> Option<LayoutToWorldTransform> {
+        self.nodes
+            .iter()
+            .find(|node| node.matches_external_id(id))
+            .map(|node| node.world_content_transform.to_transform())
+    }
+
     pub fn drain(&mut self) -> ScrollStates {
         self.current_new_node_item = 1;
 
@@ -168,20 +310,108 @@ impl ClipScrollTree {
         scroll_states
     }
 
+    /// The index of the scroll frame with the given external id.
+    pub fn scroll_frame_index(&self, id: ExternalScrollId) -> Option<ClipScrollNodeIndex> {
+        self.nodes
+            .iter()
+            .position(|node| node.matches_external_id(id))
+            .map(ClipScrollNodeIndex)
+    }
+
+    /// Scroll the scroll frame with the given id to an absolute origin. Returns
+    /// true if the offset changed. Ids that aren't in the tree yet are kept and
+    /// applied once a frame with that id shows up.
     pub fn scroll_node(
         &mut self,
         origin: LayoutPoint,
         id: ExternalScrollId,
         clamp: ScrollClamping
     ) -> bool {
-        for node in &mut self.nodes {
-            if node.matches_external_id(id) {
-                return node.set_scroll_origin(&origin, clamp);
+        match self.nodes.iter().position(|node| node.matches_external_id(id)) {
+            Some(index) => {
+                let old_offset = self.nodes[index].scroll_offset();
+                let scrolled = self.nodes[index].set_scroll_origin(&origin, clamp);
+                if scrolled {
+                    let new_offset = self.nodes[index].scroll_offset();
+                    self.scroll_linked_frames(id, old_offset, new_offset);
+                }
+                scrolled
+            }
+            None => {
+                self.pending_scroll_offsets.insert(id, (origin, clamp));
+                false
             }
         }
+    }
 
-        self.pending_scroll_offsets.insert(id, (origin, clamp));
-        false
+    /// Scroll the scroll frame with the given id by a delta in its local space,
+    /// without hit testing. Returns true if the frame moved, and false for
+    /// unknown ids.
+    pub fn scroll_node_by(&mut self, id: ExternalScrollId, delta: LayoutVector2D) -> bool {
+        let index = match self.nodes.iter().position(|node| node.matches_external_id(id)) {
+            Some(index) => ClipScrollNodeIndex(index),
+            None => return false,
+        };
+
+        self.scroll_frame_and_linked_frames(index, ScrollLocation::Delta(delta))
+    }
+
+    /// Take on the scroll links and friction of the tree this one replaces.
+    /// The embedder sets these rather than the display list, so a tree built
+    /// for a new scene starts out without them.
+    pub fn inherit_scroll_settings(&mut self, old_tree: &ClipScrollTree) {
+        self.scroll_links = old_tree.scroll_links.clone();
+        self.scroll_friction = old_tree.scroll_friction;
+    }
+
+    /// Link two scroll frames so that they scroll together on the given axes.
+    /// Linking with empty axes removes any existing link between them.
+    pub fn link_scroll_frames(
+        &mut self,
+        first: ExternalScrollId,
+        second: ExternalScrollId,
+        axes: ScrollAxes,
+    ) {
+        self.scroll_links.retain(|&(a, b, _)| {
+            !((a == first && b == second) || (a == second && b == first))
+        });
+        if !axes.is_empty() && first != second {
+            self.scroll_links.push((first, second, axes));
+        }
+    }
+
+    /// Apply the change in offset of a scroll frame to the frames linked with
+    /// it. Each linked frame is clamped to its own scroll range, and links are
+    /// not followed transitively.
+    fn scroll_linked_frames(
+        &mut self,
+        id: ExternalScrollId,
+        old_offset: LayoutVector2D,
+        new_offset: LayoutVector2D,
+    ) {
+        let delta = new_offset - old_offset;
+        let linked: Vec<_> = self.scroll_links
+            .iter()
+            .filter_map(|&(first, second, axes)| {
+                if first == id {
+                    Some((second, axes))
+                } else if second == id {
+                    Some((first, axes))
+                } else {
+                    None
+                }
+            })
+            .collect();
+
+        for (linked_id, axes) in linked {
+            let linked_delta = LayoutVector2D::new(
+                if axes.contains(ScrollAxes::HORIZONTAL) { delta.x } else { 0.0 },
+                if axes.contains(ScrollAxes::VERTICAL) { delta.y } else { 0.0 },
+            );
+            if let Some(node) = self.nodes.iter_mut().find(|node| node.matches_external_id(linked_id)) {
+                node.scroll(ScrollLocation::Delta(linked_delta));
+            }
+        }
     }
 
     fn find_nearest_scrolling_ancestor(
@@ -200,6 +430,22 @@ impl ClipScrollTree {
         }
     }
 
+    /// The id of the scroll frame that scrolls the given node, if it has one.
+    pub fn nearest_scroll_frame_id(
+        &self,
+        index: Option<ClipScrollNodeIndex>,
+    ) -> Option<ExternalScrollId> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let index = self.find_nearest_scrolling_ancestor(index);
+        match self.nodes[index.0].node_type {
+            NodeType::ScrollFrame(ref info) => info.external_id,
+            _ => None,
+        }
+    }
+
     pub fn scroll_nearest_scrolling_ancestor(
         &mut self,
         scroll_location: ScrollLocation,
@@ -208,8 +454,182 @@ impl ClipScrollTree {
         if self.nodes.is_empty() {
             return false;
         }
-        let node_index = self.find_nearest_scrolling_ancestor(node_index);
-        self.nodes[node_index.0].scroll(scroll_location)
+
+        let mut node_index = self.find_nearest_scrolling_ancestor(node_index);
+        let mut scroll_location = scroll_location;
+        let mut scrolled = false;
+        loop {
+            let old_offset = self.nodes[node_index.0].scroll_offset();
+            let scrolled_node = self.scroll_frame_and_linked_frames(node_index, scroll_location);
+            scrolled |= scrolled_node;
+
+            // A scroll frame that can't use up all of a scroll delta hands the
+            // rest on to the nearest scroll frame that encloses it. Scrolling
+            // to the start or end only moves on if the frame can't move at all.
+            match scroll_location {
+                ScrollLocation::Delta(delta) => {
+                    if scrolled_node {
+                        self.set_glide_delta(node_index, delta * self.scroll_friction);
+                    }
+                    let unused_delta =
+                        self.nodes[node_index.0].unused_scroll_delta(delta, old_offset);
+                    if unused_delta.x.abs() < 0.5 && unused_delta.y.abs() < 0.5 {
+                        return scrolled;
+                    }
+                    scroll_location = ScrollLocation::Delta(unused_delta);
+                }
+                ScrollLocation::Start | ScrollLocation::End if scrolled_node => return true,
+                ScrollLocation::Start | ScrollLocation::End => {}
+            }
+
+            let parent_index = match self.nodes[node_index.0].parent {
+                Some(parent) => self.find_nearest_scrolling_ancestor(Some(parent)),
+                None => return scrolled,
+            };
+            if parent_index == node_index {
+                return scrolled;
+            }
+            node_index = parent_index;
+        }
+    }
+
+    /// Set how much of the motion of scroll input carries on after it, from
+    /// zero for none to just below one for a long glide.
+    pub fn set_scroll_friction(&mut self, scroll_friction: f32) {
+        self.scroll_friction = scroll_friction.max(0.0).min(MAX_SCROLL_FRICTION);
+    }
+
+    /// Advance the scroll frames that are still gliding after scroll input
+    /// by `dt` seconds. Returns true if any of them moved.
+    pub fn tick_scroll_glides(&mut self, dt: f32) -> bool {
+        if dt <= 0.0 {
+            return false;
+        }
+
+        // The distance covered by all the glide frames that fit in `dt`, as a
+        // multiple of the glide delta, and what the delta shrinks by.
+        let frames = dt / SCROLL_GLIDE_FRAME_DURATION;
+        let decay = self.scroll_friction.powf(frames);
+        let distance = (1.0 - decay) / (1.0 - self.scroll_friction);
+
+        let mut moved = false;
+
+        for index in 0 .. self.nodes.len() {
+            let node_index = ClipScrollNodeIndex(index);
+            let delta = match self.nodes[index].node_type {
+                NodeType::ScrollFrame(ref mut info) => {
+                    mem::replace(&mut info.glide_delta, LayoutVector2D::zero())
+                }
+                _ => continue,
+            };
+
+            // Offsets are whole pixels, so the glide ends once it moves less
+            // than half a pixel per frame, or hits the end of the frame.
+            if delta.x.abs() < 0.5 && delta.y.abs() < 0.5 {
+                continue;
+            }
+            let location = ScrollLocation::Delta(delta * distance);
+            if self.scroll_frame_and_linked_frames(node_index, location) {
+                self.set_glide_delta(node_index, delta * decay);
+                moved = true;
+            }
+        }
+
+        moved
+    }
+
+    /// Set whether a scroll frame can be scrolled past the edges of its
+    /// content. A frame that can't is moved back within them immediately.
+    /// Returns true if the scroll frame exists.
+    pub fn set_scroll_mode(&mut self, id: ExternalScrollId, scroll_mode: ScrollMode) -> bool {
+        for node in &mut self.nodes {
+            match node.node_type {
+                NodeType::ScrollFrame(ref mut info) if info.external_id == Some(id) => {
+                    info.scroll_mode = scroll_mode;
+                    if scroll_mode == ScrollMode::Clamped {
+                        info.spring_back(0.0);
+                    }
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Set how strictly a scroll frame snaps to its snap offsets when a
+    /// scroll gesture ends. Returns true if the scroll frame exists.
+    pub fn set_scroll_snap_type(
+        &mut self,
+        id: ExternalScrollId,
+        snap_type: ScrollSnapType,
+    ) -> bool {
+        for node in &mut self.nodes {
+            match node.node_type {
+                NodeType::ScrollFrame(ref mut info) if info.external_id == Some(id) => {
+                    info.snap_type = snap_type;
+                    return true;
+                }
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// End the scroll gesture on a scroll frame, settling it on its snap
+    /// offsets and starting a new gesture. Returns true if the frame moved.
+    pub fn end_scroll_gesture(
+        &mut self,
+        id: ExternalScrollId,
+        snap_offsets_x: &[f32],
+        snap_offsets_y: &[f32],
+    ) -> bool {
+        self.nodes
+            .iter_mut()
+            .find(|node| node.matches_external_id(id))
+            .map_or(false, |node| node.settle_scroll(snap_offsets_x, snap_offsets_y))
+    }
+
+    /// Move scroll frames that are scrolled past their edges back toward
+    /// them, for `dt` seconds of time passing. Returns true if any of them
+    /// moved.
+    pub fn tick_overscroll(&mut self, dt: f32) -> bool {
+        let decay = (-OVERSCROLL_SPRING_RATE * dt).exp();
+        let mut moved = false;
+        for node in &mut self.nodes {
+            if let NodeType::ScrollFrame(ref mut info) = node.node_type {
+                if info.scroll_mode != ScrollMode::Clamped {
+                    moved |= info.spring_back(decay);
+                }
+            }
+        }
+        moved
+    }
+
+    fn set_glide_delta(&mut self, node_index: ClipScrollNodeIndex, glide_delta: LayoutVector2D) {
+        if let NodeType::ScrollFrame(ref mut info) = self.nodes[node_index.0].node_type {
+            info.glide_delta = glide_delta;
+        }
+    }
+
+    fn scroll_frame_and_linked_frames(
+        &mut self,
+        node_index: ClipScrollNodeIndex,
+        scroll_location: ScrollLocation,
+    ) -> bool {
+        let old_offset = self.nodes[node_index.0].scroll_offset();
+        let scrolled = self.nodes[node_index.0].scroll(scroll_location);
+
+        let external_id = match self.nodes[node_index.0].node_type {
+            NodeType::ScrollFrame(info) => info.external_id,
+            _ => None,
+        };
+        if let (true, Some(id)) = (scrolled, external_id) {
+            let new_offset = self.nodes[node_index.0].scroll_offset();
+            self.scroll_linked_frames(id, old_offset, new_offset);
+        }
+
+        scrolled
     }
 
     pub fn update_tree(
@@ -219,7 +639,7 @@ impl ClipScrollTree {
         clip_store: &mut ClipStore,
         resource_cache: &mut ResourceCache,
         gpu_cache: &mut GpuCache,
-        pan: WorldPoint,
+        root_transform: LayoutToWorldFastTransform,
         node_data: &mut Vec<ClipScrollNodeData>,
         scene_properties: &SceneProperties,
     ) {
@@ -230,59 +650,26 @@ impl ClipScrollTree {
         self.clip_chains[0] = ClipChain::empty(screen_rect);
 
         let root_reference_frame_index = self.root_reference_frame_index();
-        let mut state = TransformUpdateState {
-            parent_reference_frame_transform: LayoutVector2D::new(pan.x, pan.y).into(),
-            parent_accumulated_scroll_offset: LayoutVector2D::zero(),
-            nearest_scrolling_ancestor_offset: LayoutVector2D::zero(),
-            nearest_scrolling_ancestor_viewport: LayoutRect::zero(),
-            parent_clip_chain_index: ClipChainIndex(0),
-            current_coordinate_system_id: CoordinateSystemId::root(),
-            coordinate_system_relative_transform: LayoutFastTransform::identity(),
-            invertible: true,
-        };
+        let state = TransformUpdateState::new(root_transform, device_pixel_scale);
         let mut next_coordinate_system_id = state.current_coordinate_system_id.next();
-        self.update_node(
-            root_reference_frame_index,
-            &mut state,
-            &mut next_coordinate_system_id,
-            device_pixel_scale,
-            clip_store,
-            resource_cache,
-            gpu_cache,
-            node_data,
-            scene_properties,
-        );
 
-        self.build_clip_chains(screen_rect);
-    }
-
-    fn update_node(
-        &mut self,
-        node_index: ClipScrollNodeIndex,
-        state: &mut TransformUpdateState,
-        next_coordinate_system_id: &mut CoordinateSystemId,
-        device_pixel_scale: DevicePixelScale,
-        clip_store: &mut ClipStore,
-        resource_cache: &mut ResourceCache,
-        gpu_cache: &mut GpuCache,
-        gpu_node_data: &mut Vec<ClipScrollNodeData>,
-        scene_properties: &SceneProperties,
-    ) {
-        // TODO(gw): This is an ugly borrow check workaround to clone these.
-        //           Restructure this to avoid the clones!
-        let mut state = state.clone();
-        let node_children = {
+        // Walk the tree depth first with an explicit stack, so that deeply nested
+        // trees can't overflow the call stack. Children are pushed in reverse so
+        // that nodes are visited in the same order as a recursive walk, which
+        // keeps GPU node data and coordinate system ids stable.
+        let mut stack = vec![(root_reference_frame_index, state)];
+        while let Some((node_index, mut state)) = stack.pop() {
             let node = match self.nodes.get_mut(node_index.0) {
                 Some(node) => node,
-                None => return,
+                None => continue,
             };
 
             // We set this early so that we can use it to populate the ClipChain.
-            node.node_data_index = GPUClipScrollNodeIndex(gpu_node_data.len() as u32);
+            node.node_data_index = GPUClipScrollNodeIndex(node_data.len() as u32);
 
             node.update(
                 &mut state,
-                next_coordinate_system_id,
+                &mut next_coordinate_system_id,
                 device_pixel_scale,
                 clip_store,
                 resource_cache,
@@ -291,28 +678,58 @@ impl ClipScrollTree {
                 &mut self.clip_chains,
             );
 
-            node.push_gpu_node_data(gpu_node_data);
+            node.push_gpu_node_data(node_data);
 
             if node.children.is_empty() {
-                return;
+                continue;
             }
 
             node.prepare_state_for_children(&mut state);
-            node.children.clone()
-        };
+            for child_index in node.children.iter().rev() {
+                stack.push((*child_index, state.clone()));
+            }
+        }
 
-        for child_node_index in node_children {
-            self.update_node(
-                child_node_index,
+        self.build_clip_chains(screen_rect);
+    }
+
+    /// Update the world transforms of all nodes, without updating clips. This
+    /// is enough to follow scrolling between frames.
+    pub fn update_transforms(
+        &mut self,
+        device_pixel_scale: DevicePixelScale,
+        root_transform: LayoutToWorldFastTransform,
+        scene_properties: &SceneProperties,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let root_reference_frame_index = self.root_reference_frame_index();
+        let state = TransformUpdateState::new(root_transform, device_pixel_scale);
+        let mut next_coordinate_system_id = state.current_coordinate_system_id.next();
+
+        // This follows the same traversal order as `update_tree`.
+        let mut stack = vec![(root_reference_frame_index, state)];
+        while let Some((node_index, mut state)) = stack.pop() {
+            let node = match self.nodes.get_mut(node_index.0) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            node.update_world_transforms(
                 &mut state,
-                next_coordinate_system_id,
-                device_pixel_scale,
-                clip_store,
-                resource_cache,
-                gpu_cache,
-                gpu_node_data,
+                &mut next_coordinate_system_id,
                 scene_properties,
             );
+            if node.children.is_empty() {
+                continue;
+            }
+
+            node.prepare_state_for_children(&mut state);
+            for child_index in node.children.iter().rev() {
+                stack.push((*child_index, state.clone()));
+            }
         }
     }
 
@@ -343,7 +760,11 @@ impl ClipScrollTree {
         }
     }
 
-    pub fn finalize_and_apply_pending_scroll_offsets(&mut self, old_states: ScrollStates) {
+    pub fn finalize_and_apply_pending_scroll_offsets(
+        &mut self,
+        old_states: ScrollStates,
+        max_size_ratio: Option<f32>,
+    ) {
         for node in &mut self.nodes {
             let external_id = match node.node_type {
                 NodeType::ScrollFrame(ScrollFrameInfo { external_id: Some(id), ..} ) => id,
@@ -351,7 +772,7 @@ impl ClipScrollTree {
             };
 
             if let Some(scrolling_state) = old_states.get(&external_id) {
-                node.apply_old_scrolling_state(scrolling_state);
+                node.apply_old_scrolling_state(scrolling_state, max_size_ratio);
             }
 
             if let Some((offset, clamping)) = self.pending_scroll_offsets.remove(&external_id) {
@@ -502,3 +923,593 @@ impl ClipScrollTree {
         &self.clip_chains[index.0]
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use api::{ExternalScrollId, LayoutPoint, LayoutRect, LayoutSize, LayoutTransform};
+    use api::{LayoutVector2D, DeviceIntPoint, DeviceIntRect, DeviceIntSize, DevicePixelRatio};
+    use api::{DevicePixelScale, PipelineId, PropertyBinding, ScrollAxes, ScrollClamping};
+    use api::{ScrollLocation, ScrollSensitivity, ScrollTreeNode, WorldPoint, WorldRect, WorldSize};
+    use clip::ClipStore;
+    use clip_scroll_node::ClipScrollNode;
+    use glyph_rasterizer::GlyphRasterizer;
+    use gpu_cache::GpuCache;
+    use rayon::ThreadPoolBuilder;
+    use resource_cache::ResourceCache;
+    use scene::SceneProperties;
+    use std::sync::Arc;
+    use super::{ClipChainIndex, ClipScrollNodeIndex, ClipScrollTree, CoordinateSystemId};
+    use super::{TransformUpdateState, root_reference_frame_transform};
+    use texture_cache::TextureCache;
+    use util::{LayoutFastTransform, LayoutToWorldFastTransform, calculate_screen_bounding_rect};
+
+    fn add_scroll_frame(tree: &mut ClipScrollTree, index: usize, id: u64, content_height: f32) {
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 100.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, content_height));
+        let node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            Some(ExternalScrollId(id, PipelineId::dummy())),
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(node, ClipScrollNodeIndex(index));
+    }
+
+    #[test]
+    fn scroll_friction_keeps_scrolling_briefly_after_input() {
+        let scroll_with_friction = |scroll_friction| {
+            let mut tree = ClipScrollTree::new();
+            let root = ClipScrollNode::new_reference_frame(
+                None,
+                None,
+                None,
+                LayoutVector2D::zero(),
+                PipelineId::dummy(),
+            );
+            tree.add_node(root, ClipScrollNodeIndex(0));
+            add_scroll_frame(&mut tree, 1, 1, 1000.0);
+            tree.set_scroll_friction(scroll_friction);
+
+            let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -20.0));
+            assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(1))));
+            assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -20.0));
+            tree
+        };
+
+        let frame = SCROLL_GLIDE_FRAME_DURATION;
+
+        // Without friction, scrolling stops with the input.
+        let mut tree = scroll_with_friction(0.0);
+        assert!(!tree.tick_scroll_glides(frame));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -20.0));
+
+        // With friction, it carries on by a decaying fraction of the delta.
+        let mut tree = scroll_with_friction(0.5);
+        assert!(tree.tick_scroll_glides(frame));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -30.0));
+        assert!(tree.tick_scroll_glides(frame));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -35.0));
+
+        // The glide comes to a stop after a few more frames.
+        let mut ticks = 0;
+        while tree.tick_scroll_glides(frame) {
+            ticks += 1;
+            assert!(ticks < 10);
+        }
+        let offset = tree.nodes[1].scroll_offset();
+        assert!(offset.y < -35.0 && offset.y > -45.0);
+        assert!(!tree.tick_scroll_glides(frame));
+        assert_eq!(tree.nodes[1].scroll_offset(), offset);
+
+        // The glide depends on the time that passes, not on how many frames
+        // it is split into.
+        let mut tree = scroll_with_friction(0.5);
+        assert!(tree.tick_scroll_glides(2.0 * frame));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -35.0));
+    }
+
+    #[test]
+    fn unused_scroll_delta_chains_to_enclosing_scroll_frames() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 100.0));
+        let content_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 150.0));
+        let inner = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(1),
+            Some(ExternalScrollId(2, PipelineId::dummy())),
+            &frame_rect,
+            &content_rect,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(inner, ClipScrollNodeIndex(2));
+
+        // The inner frame takes what it can and the outer frame gets the rest.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -80.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(2))));
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -30.0));
+
+        // Whatever is left once the outermost frame hits its edge is dropped.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -500.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(2))));
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -200.0));
+
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -10.0));
+        assert!(!tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(2))));
+    }
+
+    #[test]
+    fn linked_scroll_frames_scroll_together() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+        add_scroll_frame(&mut tree, 2, 2, 200.0);
+
+        tree.link_scroll_frames(
+            ExternalScrollId(1, PipelineId::dummy()),
+            ExternalScrollId(2, PipelineId::dummy()),
+            ScrollAxes::VERTICAL,
+        );
+
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -50.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(1))));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+
+        // The second frame stops at its own edge while the first keeps going.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -200.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(1))));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -200.0));
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -100.0));
+
+        // Scrolling back from the shorter frame moves both by the same amount.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, 60.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(2))));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -140.0));
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -40.0));
+    }
+
+    #[test]
+    fn non_uniform_device_pixel_ratio_stretches_content() {
+        let ratio = DevicePixelRatio::new(2.0, 1.0);
+        let transform = root_reference_frame_transform(WorldPoint::zero(), ratio.stretch());
+
+        // A square region in layout space covers twice as many device pixels
+        // horizontally as vertically, so any target allocated for it is too.
+        let rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(50.0, 50.0));
+        let device_rect = calculate_screen_bounding_rect(
+            &transform,
+            &rect,
+            DevicePixelScale::new(ratio.scale()),
+            None,
+        ).unwrap();
+        assert_eq!(device_rect.size.width, 2 * device_rect.size.height);
+        assert_eq!(device_rect.size.height, 50);
+    }
+
+    #[test]
+    fn device_scroll_offset_matches_content_transform() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+
+        let id = ExternalScrollId(1, PipelineId::dummy());
+        tree.scroll_node(LayoutPoint::new(0.0, 10.3), id, ScrollClamping::NoClamping);
+
+        let device_pixel_scale = DevicePixelScale::new(2.0);
+        let device_offset = tree.device_scroll_offset(id, device_pixel_scale);
+        assert_eq!(device_offset, Some(DeviceIntPoint::new(0, -21)));
+
+        let mut state = TransformUpdateState {
+            parent_reference_frame_transform: LayoutToWorldFastTransform::identity(),
+            parent_accumulated_scroll_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_viewport: LayoutRect::zero(),
+            parent_clip_chain_index: ClipChainIndex(0),
+            current_coordinate_system_id: CoordinateSystemId::root(),
+            coordinate_system_relative_transform: LayoutFastTransform::identity(),
+            invertible: true,
+            device_pixel_scale,
+        };
+        let mut next_coordinate_system_id = CoordinateSystemId::root().next();
+        tree.nodes[1].update_transform(
+            &mut state,
+            &mut next_coordinate_system_id,
+            &SceneProperties::new(),
+        );
+
+        // The contents are translated by exactly the reported number of device pixels.
+        let origin = tree.nodes[1]
+            .world_content_transform
+            .transform_point2d(&LayoutPoint::zero()) * device_pixel_scale;
+        assert_eq!(origin.x, 0.0);
+        assert_eq!(origin.y, -21.0);
+    }
+
+    #[test]
+    fn iframe_scrolls_independently_then_chains_to_parent() {
+        let parent_pipeline = PipelineId(0, 1);
+        let iframe_pipeline = PipelineId(0, 2);
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 100.0));
+
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            parent_pipeline,
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        let parent_scroll_frame = ClipScrollNode::new_scroll_frame(
+            parent_pipeline,
+            ClipScrollNodeIndex(0),
+            Some(ExternalScrollId(0, parent_pipeline)),
+            &frame_rect,
+            &LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 300.0)),
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(parent_scroll_frame, ClipScrollNodeIndex(1));
+        let iframe_root = ClipScrollNode::new_reference_frame(
+            Some(ClipScrollNodeIndex(1)),
+            None,
+            None,
+            LayoutVector2D::new(0.0, 20.0),
+            iframe_pipeline,
+        );
+        tree.add_node(iframe_root, ClipScrollNodeIndex(2));
+        let iframe_scroll_frame = ClipScrollNode::new_scroll_frame(
+            iframe_pipeline,
+            ClipScrollNodeIndex(2),
+            Some(ExternalScrollId(0, iframe_pipeline)),
+            &frame_rect,
+            &LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 200.0)),
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(iframe_scroll_frame, ClipScrollNodeIndex(3));
+
+        // Scrolling inside the iframe only moves the iframe's content.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -60.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(3))));
+        assert_eq!(tree.nodes[3].scroll_offset(), LayoutVector2D::new(0.0, -60.0));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::zero());
+
+        // The part of a delta that the iframe can't use moves the parent.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -90.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(3))));
+        assert_eq!(tree.nodes[3].scroll_offset(), LayoutVector2D::new(0.0, -100.0));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+
+        // Once the iframe is at its edge, the scroll chains to the parent document.
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -50.0));
+        assert!(tree.scroll_nearest_scrolling_ancestor(scroll, Some(ClipScrollNodeIndex(3))));
+        assert_eq!(tree.nodes[3].scroll_offset(), LayoutVector2D::new(0.0, -100.0));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -100.0));
+    }
+
+    #[test]
+    fn scroll_node_by_moves_known_frame_without_hit_testing() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+        add_scroll_frame(&mut tree, 2, 2, 300.0);
+
+        let id = ExternalScrollId(2, PipelineId::dummy());
+        assert!(tree.scroll_node_by(id, LayoutVector2D::new(0.0, -50.0)));
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::zero());
+
+        // The delta is clamped to the scrollable range, and the new offset
+        // is reported with the rest of the scroll state.
+        assert!(tree.scroll_node_by(id, LayoutVector2D::new(0.0, -500.0)));
+        let state = tree.get_scroll_node_state();
+        let state = state.iter().find(|state| state.id == id).unwrap();
+        assert_eq!(state.scroll_offset, LayoutVector2D::new(0.0, -200.0));
+        assert!(!tree.scroll_node_by(id, LayoutVector2D::new(0.0, -10.0)));
+
+        // Unknown ids are ignored.
+        let unknown = ExternalScrollId(3, PipelineId::dummy());
+        assert!(!tree.scroll_node_by(unknown, LayoutVector2D::new(0.0, -10.0)));
+    }
+
+    #[test]
+    fn scrolled_frame_reports_clip_rect_offset_by_scroll() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+
+        let id = ExternalScrollId(1, PipelineId::dummy());
+        tree.scroll_node(LayoutPoint::new(0.0, 40.0), id, ScrollClamping::ToContentBounds);
+
+        let mut state = TransformUpdateState {
+            parent_reference_frame_transform: LayoutToWorldFastTransform::identity(),
+            parent_accumulated_scroll_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_offset: LayoutVector2D::zero(),
+            nearest_scrolling_ancestor_viewport: LayoutRect::zero(),
+            parent_clip_chain_index: ClipChainIndex(0),
+            current_coordinate_system_id: CoordinateSystemId::root(),
+            coordinate_system_relative_transform: LayoutFastTransform::identity(),
+            invertible: true,
+            device_pixel_scale: DevicePixelScale::new(1.0),
+        };
+        let mut next_coordinate_system_id = CoordinateSystemId::root().next();
+        tree.nodes[1].update_transform(
+            &mut state,
+            &mut next_coordinate_system_id,
+            &SceneProperties::new(),
+        );
+
+        let clip_rects = tree.scroll_frame_clip_rects(DevicePixelScale::new(1.0));
+        assert_eq!(clip_rects.len(), 1);
+        assert_eq!(clip_rects[0].id, id);
+
+        // The contents are clipped to the part scrolled into view, which is
+        // still the frame's own viewport on screen.
+        let size = LayoutSize::new(100.0, 100.0);
+        assert_eq!(clip_rects[0].local_rect, LayoutRect::new(LayoutPoint::new(0.0, 40.0), size));
+        let world_size = WorldSize::new(100.0, 100.0);
+        assert_eq!(clip_rects[0].world_rect, WorldRect::new(WorldPoint::zero(), world_size));
+
+        // A fractional offset is snapped to device pixels the way the
+        // contents are, so the clip still lines up with the viewport.
+        tree.scroll_node(LayoutPoint::new(0.0, 40.3), id, ScrollClamping::ToContentBounds);
+        state.device_pixel_scale = DevicePixelScale::new(2.0);
+        tree.nodes[1].update_transform(
+            &mut state,
+            &mut next_coordinate_system_id,
+            &SceneProperties::new(),
+        );
+        let clip_rects = tree.scroll_frame_clip_rects(DevicePixelScale::new(2.0));
+        assert_eq!(clip_rects[0].local_rect, LayoutRect::new(LayoutPoint::new(0.0, 40.5), size));
+        assert_eq!(clip_rects[0].world_rect, WorldRect::new(WorldPoint::zero(), world_size));
+    }
+
+    #[test]
+    fn scroll_tree_links_nested_frames_before_frame_build() {
+        let pipeline_id = PipelineId::dummy();
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            pipeline_id,
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        add_scroll_frame(&mut tree, 1, 1, 300.0);
+
+        // A reference frame between two scroll frames doesn't break the link.
+        let inner_root = ClipScrollNode::new_reference_frame(
+            Some(ClipScrollNodeIndex(1)),
+            None,
+            None,
+            LayoutVector2D::new(0.0, 20.0),
+            pipeline_id,
+        );
+        tree.add_node(inner_root, ClipScrollNodeIndex(2));
+        let inner_scroll_frame = ClipScrollNode::new_scroll_frame(
+            pipeline_id,
+            ClipScrollNodeIndex(2),
+            Some(ExternalScrollId(2, pipeline_id)),
+            &LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(50.0, 50.0)),
+            &LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(200.0, 50.0)),
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(inner_scroll_frame, ClipScrollNodeIndex(3));
+
+        assert_eq!(tree.scroll_tree(), vec![
+            ScrollTreeNode {
+                id: ExternalScrollId(1, pipeline_id),
+                parent: None,
+                viewport_size: LayoutSize::new(100.0, 100.0),
+                content_size: LayoutSize::new(100.0, 300.0),
+            },
+            ScrollTreeNode {
+                id: ExternalScrollId(2, pipeline_id),
+                parent: Some(ExternalScrollId(1, pipeline_id)),
+                viewport_size: LayoutSize::new(50.0, 50.0),
+                content_size: LayoutSize::new(200.0, 50.0),
+            },
+        ]);
+    }
+
+    #[test]
+    fn restored_scroll_offset_resets_when_content_shrinks_dramatically() {
+        let id = ExternalScrollId(1, PipelineId::dummy());
+        let new_tree = |content_height| {
+            let mut tree = ClipScrollTree::new();
+            let root = ClipScrollNode::new_reference_frame(
+                None,
+                None,
+                None,
+                LayoutVector2D::zero(),
+                PipelineId::dummy(),
+            );
+            tree.add_node(root, ClipScrollNodeIndex(0));
+            add_scroll_frame(&mut tree, 1, 1, content_height);
+            tree
+        };
+        let restored_offset = |content_height| {
+            let mut old_tree = new_tree(1000.0);
+            old_tree.scroll_node(LayoutPoint::new(0.0, 800.0), id, ScrollClamping::ToContentBounds);
+
+            let mut tree = new_tree(content_height);
+            tree.finalize_and_apply_pending_scroll_offsets(old_tree.drain(), Some(2.0));
+            tree.nodes[1].scroll_offset()
+        };
+
+        // Similar content keeps its offset, or the nearest offset still in range.
+        assert_eq!(restored_offset(900.0), LayoutVector2D::new(0.0, -800.0));
+        assert_eq!(restored_offset(600.0), LayoutVector2D::new(0.0, -500.0));
+
+        // Content that shrank past the ratio starts from the top again.
+        assert_eq!(restored_offset(150.0), LayoutVector2D::zero());
+    }
+
+    #[test]
+    fn scroll_offsets_are_restored_by_external_id() {
+        let new_tree = || {
+            let mut tree = ClipScrollTree::new();
+            let root = ClipScrollNode::new_reference_frame(
+                None,
+                None,
+                None,
+                LayoutVector2D::zero(),
+                PipelineId::dummy(),
+            );
+            tree.add_node(root, ClipScrollNodeIndex(0));
+            tree
+        };
+        let id = ExternalScrollId(1, PipelineId::dummy());
+
+        let mut old_tree = new_tree();
+        add_scroll_frame(&mut old_tree, 1, 1, 300.0);
+        old_tree.scroll_node(LayoutPoint::new(0.0, 50.0), id, ScrollClamping::ToContentBounds);
+
+        // After a relayout the frame has a different node index, but the
+        // same external id, so it keeps its offset.
+        let mut tree = new_tree();
+        add_scroll_frame(&mut tree, 1, 2, 300.0);
+        add_scroll_frame(&mut tree, 2, 1, 300.0);
+        tree.finalize_and_apply_pending_scroll_offsets(old_tree.drain(), None);
+        assert_eq!(tree.nodes[1].scroll_offset(), LayoutVector2D::zero());
+        assert_eq!(tree.nodes[2].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+
+        // Offsets saved by the embedder can be restored before the frame
+        // they belong to exists.
+        let saved = tree.get_scroll_node_state();
+        let mut restored_tree = new_tree();
+        for state in &saved {
+            let origin = LayoutPoint::zero() - state.scroll_offset;
+            restored_tree.scroll_node(origin, state.id, ScrollClamping::ToContentBounds);
+        }
+        add_scroll_frame(&mut restored_tree, 1, 1, 300.0);
+        restored_tree.finalize_and_apply_pending_scroll_offsets(Default::default(), None);
+        assert_eq!(restored_tree.nodes[1].scroll_offset(), LayoutVector2D::new(0.0, -50.0));
+    }
+
+    #[test]
+    fn deeply_nested_reference_frames_accumulate_their_transforms() {
+        const DEPTH: usize = 1000;
+
+        // Every frame is offset from its parent, and every tenth one also
+        // mirrors its content horizontally.
+        let flips = |index: usize| index % 10 == 0;
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+        for index in 1 .. DEPTH {
+            let transform = if flips(index) {
+                Some(PropertyBinding::Value(LayoutTransform::create_scale(-1.0, 1.0, 1.0)))
+            } else {
+                None
+            };
+            let frame = ClipScrollNode::new_reference_frame(
+                Some(ClipScrollNodeIndex(index - 1)),
+                transform,
+                None,
+                LayoutVector2D::new(1.0, 2.0),
+                PipelineId::dummy(),
+            );
+            tree.add_node(frame, ClipScrollNodeIndex(index));
+        }
+
+        // Where a point in the frame at `index` ends up in the world, found
+        // by moving it out through each of its ancestors in turn.
+        let expected_world_point = |index: usize, mut point: LayoutPoint| {
+            for ancestor in (1 .. index + 1).rev() {
+                if flips(ancestor) {
+                    point.x = -point.x;
+                }
+                point = point + LayoutVector2D::new(1.0, 2.0);
+            }
+            point
+        };
+        let check_transforms = |tree: &ClipScrollTree| {
+            for (index, node) in tree.nodes.iter().enumerate() {
+                for &point in &[LayoutPoint::zero(), LayoutPoint::new(3.0, 5.0)] {
+                    let world = node.world_content_transform.transform_point2d(&point);
+                    let expected = expected_world_point(index, point);
+                    assert_eq!((world.x, world.y), (expected.x, expected.y));
+                }
+            }
+        };
+
+        // A chain this deep is walked without recursing once per level,
+        // both when the whole tree is updated...
+        let screen_rect = DeviceIntRect::new(DeviceIntPoint::zero(), DeviceIntSize::new(100, 100));
+        let workers = Arc::new(ThreadPoolBuilder::new().build().unwrap());
+        let mut resource_cache = ResourceCache::new(
+            TextureCache::new(2048),
+            GlyphRasterizer::new(workers).unwrap(),
+            None,
+        );
+        let mut node_data = Vec::new();
+        tree.update_tree(
+            &screen_rect,
+            DevicePixelScale::new(1.0),
+            &mut ClipStore::new(),
+            &mut resource_cache,
+            &mut GpuCache::new(),
+            LayoutToWorldFastTransform::identity(),
+            &mut node_data,
+            &SceneProperties::new(),
+        );
+        assert_eq!(node_data.len(), DEPTH);
+        check_transforms(&tree);
+
+        // ...and when only the transforms are.
+        tree.update_transforms(
+            DevicePixelScale::new(1.0),
+            LayoutToWorldFastTransform::identity(),
+            &SceneProperties::new(),
+        );
+        check_transforms(&tree);
+    }
+}
@@ -486,6 +486,30 @@ impl Texture {
         self.last_frame_used == frame_id
     }
 
+    /// A render target texture with no GL objects behind it, last used in
+    /// the given frame.
+    #[cfg(test)]
+    pub fn new_render_target_for_test(
+        size: DeviceUintSize,
+        layer_count: usize,
+        format: ImageFormat,
+        last_frame_used: FrameId,
+    ) -> Texture {
+        Texture {
+            id: 0,
+            target: gl::TEXTURE_2D_ARRAY,
+            layer_count: layer_count as i32,
+            format,
+            width: size.width,
+            height: size.height,
+            filter: TextureFilter::Linear,
+            render_target: Some(RenderTargetInfo { has_depth: false }),
+            fbo_ids: vec![FBOId(0); layer_count],
+            depth_rb: None,
+            last_frame_used,
+        }
+    }
+
     #[cfg(feature = "replay")]
     pub fn into_external(mut self) -> ExternalTexture {
         let ext = ExternalTexture {
@@ -666,6 +690,9 @@ pub struct Device {
     program_mode_id: UniformLocation,
     default_read_fbo: gl::GLuint,
     default_draw_fbo: gl::GLuint,
+    /// A framebuffer that stands in for the default one until the end of the
+    /// frame.
+    main_framebuffer_override: Option<FBOId>,
 
     device_pixel_ratio: f32,
     upload_method: UploadMethod,
@@ -683,6 +710,7 @@ pub struct Device {
     resource_override_path: Option<PathBuf>,
 
     max_texture_size: u32,
+    max_instances_per_draw: usize,
     renderer_name: String,
     cached_programs: Option<Rc<ProgramCache>>,
 
@@ -707,6 +735,17 @@ impl Device {
             gl.get_integer_v(gl::MAX_TEXTURE_SIZE, &mut max_texture_size);
         }
         let max_texture_size = max_texture_size[0] as u32;
+        // Drivers recommend not drawing from vertex buffers holding more
+        // than this many vertices, which instance buffers are subject to
+        // too. Zero means there is no recommendation.
+        let mut max_elements_vertices = [0];
+        unsafe {
+            gl.get_integer_v(gl::MAX_ELEMENTS_VERTICES, &mut max_elements_vertices);
+        }
+        let max_instances_per_draw = match max_elements_vertices[0] {
+            n if n > 0 => n as usize,
+            _ => usize::max_value(),
+        };
         let renderer_name = gl.get_string(gl::RENDERER);
 
         let mut extension_count = [0];
@@ -753,8 +792,10 @@ impl Device {
             program_mode_id: UniformLocation::INVALID,
             default_read_fbo: 0,
             default_draw_fbo: 0,
+            main_framebuffer_override: None,
 
             max_texture_size,
+            max_instances_per_draw,
             renderer_name,
             cached_programs,
             frame_id: FrameId(0),
@@ -782,6 +823,10 @@ impl Device {
         self.max_texture_size
     }
 
+    pub fn max_instances_per_draw(&self) -> usize {
+        self.max_instances_per_draw
+    }
+
     #[cfg(feature = "debug_renderer")]
     pub fn get_capabilities(&self) -> &Capabilities {
         &self.capabilities
@@ -922,7 +967,9 @@ impl Device {
     }
 
     pub fn bind_read_target(&mut self, texture_and_layer: Option<(&Texture, i32)>) {
-        let fbo_id = texture_and_layer.map_or(FBOId(self.default_read_fbo), |texture_and_layer| {
+        let default_fbo = self.main_framebuffer_override
+            .unwrap_or(FBOId(self.default_read_fbo));
+        let fbo_id = texture_and_layer.map_or(default_fbo, |texture_and_layer| {
             texture_and_layer.0.fbo_ids[texture_and_layer.1 as usize]
         });
 
@@ -943,7 +990,9 @@ impl Device {
         texture_and_layer: Option<(&Texture, i32)>,
         dimensions: Option<DeviceUintSize>,
     ) {
-        let fbo_id = texture_and_layer.map_or(FBOId(self.default_draw_fbo), |texture_and_layer| {
+        let default_fbo = self.main_framebuffer_override
+            .unwrap_or(FBOId(self.default_draw_fbo));
+        let fbo_id = texture_and_layer.map_or(default_fbo, |texture_and_layer| {
             texture_and_layer.0.fbo_ids[texture_and_layer.1 as usize]
         });
 
@@ -959,6 +1008,24 @@ impl Device {
         }
     }
 
+    /// The number of samples per pixel of the bound draw target, or zero if
+    /// it isn't multisampled.
+    pub fn draw_target_sample_count(&self) -> u32 {
+        let mut samples = [0];
+        unsafe {
+            self.gl.get_integer_v(gl::SAMPLES, &mut samples);
+        }
+        samples[0] as u32
+    }
+
+    /// Makes sure multisampled draw targets are rasterized with all of their
+    /// samples. OpenGL ES always does so.
+    pub fn enable_multisampling(&mut self) {
+        if self.gl.get_type() == gl::GlType::Gl {
+            self.gl.enable(gl::MULTISAMPLE);
+        }
+    }
+
     pub fn create_fbo_for_external_texture(&mut self, texture_id: u32) -> FBOId {
         let fbo = FBOId(self.gl.gen_framebuffers(1)[0]);
         fbo.bind(self.gl(), FBOTarget::Draw);
@@ -977,6 +1044,30 @@ impl Device {
         self.gl.delete_framebuffers(&[fbo.0]);
     }
 
+    /// Draw into the given framebuffer instead of the one that was bound when
+    /// the frame began, until the frame ends.
+    pub fn override_main_framebuffer(&mut self, fbo_id: FBOId) {
+        debug_assert!(self.inside_frame);
+
+        self.main_framebuffer_override = Some(fbo_id);
+        self.bind_draw_target_impl(fbo_id);
+    }
+
+    /// Draw into a layer of a render target texture instead of the main
+    /// framebuffer, until the frame ends.
+    pub fn override_main_framebuffer_with_texture(&mut self, texture: &Texture, layer: usize) {
+        let fbo_id = texture.fbo_ids[layer];
+        self.override_main_framebuffer(fbo_id);
+    }
+
+    /// Draw into the framebuffer that was bound when the frame began again.
+    pub fn reset_main_framebuffer_override(&mut self) {
+        debug_assert!(self.inside_frame);
+
+        self.main_framebuffer_override = None;
+        self.bind_draw_target(None, None);
+    }
+
     pub fn bind_external_draw_target(&mut self, fbo_id: FBOId) {
         debug_assert!(self.inside_frame);
 
@@ -1970,6 +2061,7 @@ impl Device {
     }
 
     pub fn end_frame(&mut self) {
+        self.main_framebuffer_override = None;
         self.bind_draw_target(None, None);
         self.bind_read_target(None);
 
@@ -6,13 +6,16 @@
 use api::{AlphaType, BorderDetails, BorderDisplayItem, BuiltDisplayListIter, ClipAndScrollInfo};
 use api::{ClipId, ColorF, ComplexClipRegion, DeviceIntPoint, DeviceIntRect, DeviceIntSize};
 use api::{DevicePixelScale, DeviceUintRect, DisplayItemRef, Epoch, ExtendMode, ExternalScrollId};
-use api::{FilterOp, FontInstanceKey, GlyphInstance, GlyphOptions, GlyphRasterSpace, GradientStop};
-use api::{IframeDisplayItem, ImageKey, ImageRendering, ItemRange, LayoutPoint};
+use api::{FilterOp, FontInstanceKey, FramebufferRead, GlyphInstance, GlyphOptions};
+use api::{GlyphRasterSpace, GradientStop};
+use api::{GradientRampKey, IframeDisplayItem, ImageColorSpace, ImageKey, ImageRendering};
+use api::{ItemRange, LayoutPoint};
 use api::{LayoutPrimitiveInfo, LayoutRect, LayoutVector2D, LayoutSize, LayoutTransform};
-use api::{LineOrientation, LineStyle, LocalClip, NinePatchBorderSource, PipelineId};
-use api::{PropertyBinding, RepeatMode, ScrollFrameDisplayItem, ScrollSensitivity, Shadow};
-use api::{SpecificDisplayItem, StackingContext, StickyFrameDisplayItem, TexelRect};
-use api::{TransformStyle, YuvColorSpace, YuvData};
+use api::{LineOrientation, LineStyle, LocalClip, MixBlendMode, NinePatchBorderSource};
+use api::{OutputTonemap, PipelineId, PropertyBinding, RepeatMode, ScrollFrameDisplayItem};
+use api::{ScrollSensitivity, Shadow, SpecificDisplayItem, StackingContext};
+use api::StackingContextHasher;
+use api::{StickyFrameDisplayItem, TexelRect, TransformStyle, YuvColorSpace, YuvData};
 use app_units::Au;
 use clip::{ClipRegion, ClipSource, ClipSources, ClipStore};
 use clip_scroll_node::{ClipScrollNode, NodeType, StickyFrameInfo};
@@ -24,14 +27,14 @@ use gpu_types::BrushFlags;
 use hit_test::{HitTestingItem, HitTestingRun};
 use image::simplify_repeated_primitive;
 use internal_types::{FastHashMap, FastHashSet};
-use picture::PictureCompositeMode;
+use picture::{PictureCompositeMode, PictureContentKey, PictureId};
 use prim_store::{BrushClipMaskKind, BrushKind, BrushPrimitive, BrushSegmentDescriptor, CachedGradient};
 use prim_store::{CachedGradientIndex, EdgeAaSegmentMask, ImageSource};
 use prim_store::{BrushSegment, PictureIndex, PrimitiveContainer, PrimitiveIndex, PrimitiveStore};
 use prim_store::{OpacityBinding, ScrollNodeAndClipChain, TextRunPrimitiveCpu};
 use render_backend::{DocumentView};
 use resource_cache::{FontInstanceMap, ImageRequest};
-use scene::{Scene, ScenePipeline, StackingContextHelpers};
+use scene::{FilterOpHelpers, Scene, ScenePipeline, StackingContextHelpers};
 use scene_builder::{BuiltScene, SceneRequest};
 use std::{f32, mem, usize};
 use tiling::{CompositeOps, ScrollbarPrimitive};
@@ -137,6 +140,15 @@ impl ClipIdToIndexMapper {
     }
 }
 
+/// Names a stacking context to render on its own, by its position among all
+/// the stacking contexts pushed in the display list of its pipeline, empty
+/// ones included, counting from zero in display list order.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StackingContextPreview {
+    pub pipeline_id: PipelineId,
+    pub index: usize,
+}
+
 /// A structure that converts a serialized display list into a form that WebRender
 /// can use to later build a frame. This structure produces a FrameBuilder. Public
 /// members are typically those that are destructured into the FrameBuilder.
@@ -144,6 +156,10 @@ pub struct DisplayListFlattener<'a> {
     /// The scene that we are currently flattening.
     scene: &'a Scene,
 
+    /// The pipeline flattened as the root of the frame. This is the root
+    /// pipeline of the scene, unless an overlay is flattened on its own.
+    root_pipeline_id: PipelineId,
+
     /// The ClipScrollTree that we are currently building during flattening.
     clip_scroll_tree: &'a mut ClipScrollTree,
 
@@ -157,6 +173,10 @@ pub struct DisplayListFlattener<'a> {
     /// output textures.
     output_pipelines: &'a FastHashSet<PipelineId>,
 
+    /// A set of pipelines that the caller has requested to read the
+    /// rendered pixels of.
+    readback_pipelines: &'a FastHashSet<PipelineId>,
+
     /// A list of replacements to make in order to properly handle fixed position
     /// content as well as stacking contexts that create reference frames.
     replacements: Vec<(ClipId, ClipId)>,
@@ -169,6 +189,11 @@ pub struct DisplayListFlattener<'a> {
     /// parent new scroll nodes.
     reference_frame_stack: Vec<(ClipId, ClipScrollNodeIndex)>,
 
+    /// The offset of each reference frame in `reference_frame_stack` from the
+    /// origin of the document, or None if it or one of its ancestors is
+    /// transformed.
+    reference_frame_offsets: Vec<Option<LayoutVector2D>>,
+
     /// A stack of stacking context properties.
     sc_stack: Vec<FlattenedStackingContext>,
 
@@ -196,6 +221,40 @@ pub struct DisplayListFlattener<'a> {
 
     /// The gradients collecting during display list flattening.
     pub cached_gradients: Vec<CachedGradient>,
+
+    /// The scale from layout to device pixels, used to snap stacking
+    /// context origins to the pixel grid.
+    device_pixel_scale: DevicePixelScale,
+
+    /// The picture ids given to the filter pictures of stacking contexts,
+    /// by pipeline, content hash and filter depth. Stacking contexts with
+    /// identical content reuse the ids, and so share any cached output.
+    shared_picture_ids: FastHashMap<PictureContentKey, PictureId>,
+
+    /// Hashes the content of the stacking contexts of the display list being
+    /// flattened.
+    content_hasher: StackingContextHasher,
+
+    /// The stacking contexts whose mix-blend-mode reads back the framebuffer.
+    pub framebuffer_reads: Vec<FramebufferRead>,
+
+    /// The stacking context to preview, if only that one is to be flattened.
+    preview: Option<StackingContextPreview>,
+
+    /// How many stacking contexts of the previewed pipeline were seen so far.
+    preview_search_index: usize,
+
+    /// Whether the items being flattened are inside the previewed stacking
+    /// context.
+    in_preview: bool,
+
+    /// How many clip and scroll nodes were defined before the previewed
+    /// stacking context.
+    preview_first_clip_node: usize,
+
+    /// The order the clip and scroll nodes of the display lists are defined
+    /// in, which tells whether a node is defined inside a stacking context.
+    clip_node_order: FastHashMap<ClipId, usize>,
 }
 
 impl<'a> DisplayListFlattener<'a> {
@@ -206,37 +265,135 @@ impl<'a> DisplayListFlattener<'a> {
         font_instances: FontInstanceMap,
         view: &DocumentView,
         output_pipelines: &FastHashSet<PipelineId>,
+        readback_pipelines: &FastHashSet<PipelineId>,
         frame_builder_config: &FrameBuilderConfig,
         new_scene: &mut Scene,
     ) -> FrameBuilder {
         // We checked that the root pipeline is available on the render backend.
         let root_pipeline_id = scene.root_pipeline_id.unwrap();
+
+        DisplayListFlattener::create(
+            old_builder,
+            scene,
+            clip_scroll_tree,
+            font_instances,
+            view,
+            output_pipelines,
+            readback_pipelines,
+            frame_builder_config,
+            new_scene,
+            root_pipeline_id,
+            None,
+        )
+    }
+
+    /// Creates a frame builder for just an overlay pipeline, whose frame is
+    /// drawn over that of the rest of the scene. The epochs of the pipelines
+    /// it draws are recorded in `new_scene`.
+    pub fn create_overlay_frame_builder(
+        scene: &Scene,
+        clip_scroll_tree: &mut ClipScrollTree,
+        font_instances: FontInstanceMap,
+        view: &DocumentView,
+        overlay_pipeline_id: PipelineId,
+        frame_builder_config: &FrameBuilderConfig,
+        new_scene: &mut Scene,
+    ) -> FrameBuilder {
+        DisplayListFlattener::create(
+            FrameBuilder::empty(),
+            scene,
+            clip_scroll_tree,
+            font_instances,
+            view,
+            &FastHashSet::default(),
+            &FastHashSet::default(),
+            frame_builder_config,
+            new_scene,
+            overlay_pipeline_id,
+            None,
+        )
+    }
+
+    /// Creates a frame builder for just one stacking context of the scene and
+    /// its contents, laid out from the origin of the view. Clips and scroll
+    /// frames from outside the stacking context don't apply to it.
+    pub fn create_preview_frame_builder(
+        scene: &Scene,
+        clip_scroll_tree: &mut ClipScrollTree,
+        font_instances: FontInstanceMap,
+        view: &DocumentView,
+        preview: StackingContextPreview,
+        frame_builder_config: &FrameBuilderConfig,
+    ) -> FrameBuilder {
+        let root_pipeline_id = scene.root_pipeline_id.unwrap();
+
+        DisplayListFlattener::create(
+            FrameBuilder::empty(),
+            scene,
+            clip_scroll_tree,
+            font_instances,
+            view,
+            &FastHashSet::default(),
+            &FastHashSet::default(),
+            frame_builder_config,
+            &mut Scene::new(),
+            root_pipeline_id,
+            Some(preview),
+        )
+    }
+
+    fn create(
+        old_builder: FrameBuilder,
+        scene: &Scene,
+        clip_scroll_tree: &mut ClipScrollTree,
+        font_instances: FontInstanceMap,
+        view: &DocumentView,
+        output_pipelines: &FastHashSet<PipelineId>,
+        readback_pipelines: &FastHashSet<PipelineId>,
+        frame_builder_config: &FrameBuilderConfig,
+        new_scene: &mut Scene,
+        root_pipeline_id: PipelineId,
+        preview: Option<StackingContextPreview>,
+    ) -> FrameBuilder {
         let root_pipeline = scene.pipelines.get(&root_pipeline_id).unwrap();
 
         let root_epoch = scene.pipeline_epochs[&root_pipeline_id];
 
         let background_color = root_pipeline
             .background_color
-            .and_then(|color| if color.a > 0.0 { Some(color) } else { None });
+            .and_then(|color| if color.a > 0.0 { Some(color) } else { None })
+            .map(|color| tonemap_color(color, &view.output_tonemap));
 
         let mut flattener = DisplayListFlattener {
             scene,
+            root_pipeline_id,
             clip_scroll_tree,
             font_instances,
             config: *frame_builder_config,
             pipeline_epochs: Vec::new(),
             replacements: Vec::new(),
             output_pipelines,
+            readback_pipelines,
             id_to_index_mapper: ClipIdToIndexMapper::default(),
             hit_testing_runs: recycle_vec(old_builder.hit_testing_runs),
             cached_gradients: recycle_vec(old_builder.cached_gradients),
             scrollbar_prims: recycle_vec(old_builder.scrollbar_prims),
             reference_frame_stack: Vec::new(),
+            reference_frame_offsets: Vec::new(),
             picture_stack: Vec::new(),
             shadow_stack: Vec::new(),
             sc_stack: Vec::new(),
             prim_store: old_builder.prim_store.recycle(),
             clip_store: old_builder.clip_store.recycle(),
+            device_pixel_scale: view.accumulated_scale_factor(),
+            shared_picture_ids: FastHashMap::default(),
+            content_hasher: StackingContextHasher::new(),
+            framebuffer_reads: Vec::new(),
+            preview,
+            preview_search_index: 0,
+            in_preview: false,
+            preview_first_clip_node: 0,
+            clip_node_order: FastHashMap::default(),
         };
 
         flattener.id_to_index_mapper.initialize_for_pipeline(root_pipeline);
@@ -245,8 +402,12 @@ impl<'a> DisplayListFlattener<'a> {
             &root_pipeline.viewport_size,
             &root_pipeline.content_size,
         );
-        flattener.setup_viewport_offset(view.inner_rect, view.accumulated_scale_factor());
-        flattener.flatten_root(root_pipeline, &root_pipeline.viewport_size);
+        flattener.setup_viewport_offset(view.inner_rect, flattener.device_pixel_scale);
+        flattener.flatten_root(
+            root_pipeline,
+            &root_pipeline.viewport_size,
+            output_tonemap_ops(&view.output_tonemap),
+        );
 
         debug_assert!(flattener.picture_stack.is_empty());
 
@@ -310,7 +471,12 @@ impl<'a> DisplayListFlattener<'a> {
             .collect()
     }
 
-    fn flatten_root(&mut self, pipeline: &'a ScenePipeline, frame_size: &LayoutSize) {
+    fn flatten_root(
+        &mut self,
+        pipeline: &'a ScenePipeline,
+        frame_size: &LayoutSize,
+        composite_ops: CompositeOps,
+    ) {
         let pipeline_id = pipeline.pipeline_id;
         let reference_frame_info = self.id_to_index_mapper.simple_scroll_and_clip_chain(
             &ClipId::root_reference_frame(pipeline_id)
@@ -323,18 +489,19 @@ impl<'a> DisplayListFlattener<'a> {
 
         self.push_stacking_context(
             pipeline_id,
-            CompositeOps::default(),
+            composite_ops,
             TransformStyle::Flat,
             true,
             true,
             root_scroll_node,
             None,
             GlyphRasterSpace::Screen,
+            0.0,
         );
 
         // For the root pipeline, there's no need to add a full screen rectangle
         // here, as it's handled by the framebuffer clear.
-        if self.scene.root_pipeline_id != Some(pipeline_id) {
+        if self.scene.root_pipeline_id != Some(pipeline_id) && !self.skips_content() {
             if let Some(pipeline) = self.scene.pipelines.get(&pipeline_id) {
                 if let Some(bg_color) = pipeline.background_color {
                     let root_bounds = LayoutRect::new(LayoutPoint::zero(), *frame_size);
@@ -350,9 +517,13 @@ impl<'a> DisplayListFlattener<'a> {
             }
         }
 
+        // The stacking contexts of this pipeline are hashed apart from those
+        // of the display list that embeds it.
+        let parent_hasher = mem::replace(&mut self.content_hasher, StackingContextHasher::new());
         self.flatten_items(&mut pipeline.display_list.iter(), pipeline_id, LayoutVector2D::zero());
+        self.content_hasher = parent_hasher;
 
-        if self.config.enable_scrollbars {
+        if self.config.enable_scrollbars && !self.skips_content() {
             let scrollbar_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(10.0, 70.0));
             let container_rect = LayoutRect::new(LayoutPoint::zero(), *frame_size);
             self.add_scroll_bar(
@@ -425,6 +596,17 @@ impl<'a> DisplayListFlattener<'a> {
         self.id_to_index_mapper.map_to_parent_clip_chain(info.id, parent_id);
     }
 
+    /// Inflate a clip rect by the clip margin of the enclosing stacking
+    /// context, so that overflow such as focus rings is not clipped away.
+    fn inflate_by_clip_margin(&self, clip_rect: &LayoutRect) -> LayoutRect {
+        match self.sc_stack.last() {
+            Some(sc) if sc.clip_margin > 0.0 => {
+                clip_rect.inflate(sc.clip_margin, sc.clip_margin)
+            }
+            _ => *clip_rect,
+        }
+    }
+
     fn flatten_scroll_frame(
         &mut self,
         item: &DisplayItemRef,
@@ -435,7 +617,7 @@ impl<'a> DisplayListFlattener<'a> {
     ) {
         let complex_clips = self.get_complex_clips(pipeline_id, item.complex_clip().0);
         let clip_region = ClipRegion::create_for_clip_node(
-            *item.clip_rect(),
+            self.inflate_by_clip_margin(item.clip_rect()),
             complex_clips,
             info.image_mask,
             reference_frame_relative_offset,
@@ -457,11 +639,24 @@ impl<'a> DisplayListFlattener<'a> {
             info.external_id,
             pipeline_id,
             &frame_rect,
-            &content_rect.size,
+            &content_rect,
             info.scroll_sensitivity,
         );
     }
 
+    /// Applies the configured blend quality to a stacking context's blend
+    /// mode, returning `None` if it should be drawn without blending.
+    fn lower_mix_blend_mode(&self, mode: MixBlendMode) -> Option<MixBlendMode> {
+        let lowered = self.config.blend_quality.lower(mode);
+        if lowered != mode {
+            debug!("{:?} blend drawn as {:?} at low blend quality", mode, lowered);
+        }
+        match lowered {
+            MixBlendMode::Normal => None,
+            _ => Some(lowered),
+        }
+    }
+
     fn flatten_stacking_context(
         &mut self,
         traversal: &mut BuiltDisplayListIter<'a>,
@@ -473,9 +668,15 @@ impl<'a> DisplayListFlattener<'a> {
         mut reference_frame_relative_offset: LayoutVector2D,
         is_backface_visible: bool,
     ) {
+        let is_preview_target = self.is_preview_target(pipeline_id);
+
+        // This has to happen before peeking into the stacking context below.
+        self.content_hasher.push(traversal);
+
         // Avoid doing unnecessary work for empty stacking contexts.
         if traversal.current_stacking_context_empty() {
             traversal.skip_current_stacking_context();
+            self.content_hasher.pop(traversal);
             return;
         }
 
@@ -489,12 +690,35 @@ impl<'a> DisplayListFlattener<'a> {
                 .display_list;
             CompositeOps::new(
                 stacking_context.filter_ops_for_compositing(display_list, item.filters()),
-                stacking_context.mix_blend_mode_for_compositing(),
+                stacking_context
+                    .mix_blend_mode_for_compositing()
+                    .and_then(|mode| self.lower_mix_blend_mode(mode)),
             )
         };
 
         let bounds = item.rect();
+        if composition_operations.requires_framebuffer_read() {
+            self.framebuffer_reads.push(FramebufferRead {
+                pipeline_id,
+                bounds,
+                mix_blend_mode: composition_operations.mix_blend_mode.unwrap(),
+            });
+        }
+
+        if is_preview_target {
+            // Lay the previewed content out from the origin of the view.
+            reference_frame_relative_offset = -bounds.origin.to_vector();
+            self.in_preview = true;
+            self.preview_first_clip_node = self.clip_node_order.len();
+        }
+        let scroll_node_id = self.scroll_node_in_preview(scroll_node_id);
+        let first_clip_node = self.clip_node_order.len();
+
         reference_frame_relative_offset += bounds.origin.to_vector();
+        if self.config.snap_stacking_context_origins {
+            reference_frame_relative_offset =
+                self.snap_origin_to_device_pixels(reference_frame_relative_offset);
+        }
 
         // If we have a transformation or a perspective, we should have been assigned a new
         // reference frame id. This means this stacking context establishes a new reference frame.
@@ -513,13 +737,17 @@ impl<'a> DisplayListFlattener<'a> {
                 stacking_context.perspective,
                 reference_frame_relative_offset,
             );
+            self.define_clip_node(reference_frame_id);
             self.replacements.push((unreplaced_scroll_id, reference_frame_id));
             reference_frame_relative_offset = LayoutVector2D::zero();
         }
 
         // We apply the replacements one more time in case we need to set it to a replacement
         // that we just pushed above.
-        let final_scroll_node = self.apply_scroll_frame_id_replacement(unreplaced_scroll_id);
+        let final_scroll_node = self.scroll_node_in_preview(
+            self.apply_scroll_frame_id_replacement(unreplaced_scroll_id)
+        );
+        let clip_node_id = self.clip_node_in_preview(stacking_context.clip_node_id);
         self.push_stacking_context(
             pipeline_id,
             composition_operations,
@@ -527,10 +755,19 @@ impl<'a> DisplayListFlattener<'a> {
             is_backface_visible,
             false,
             final_scroll_node,
-            stacking_context.clip_node_id,
+            clip_node_id,
             stacking_context.glyph_raster_space,
+            stacking_context.clip_margin,
         );
 
+        // The reference frame of the stacking context is defined inside it,
+        // while the nodes it's positioned and clipped by are outside of it.
+        self.sc_stack.last_mut().unwrap().first_clip_node = first_clip_node;
+        self.note_clip_node_use(scroll_node_id);
+        if let Some(clip_node_id) = clip_node_id {
+            self.note_clip_node_use(clip_node_id);
+        }
+
         self.flatten_items(
             traversal,
             pipeline_id,
@@ -542,7 +779,109 @@ impl<'a> DisplayListFlattener<'a> {
             self.pop_reference_frame();
         }
 
+        let content_hash = self.content_hasher.pop(traversal);
+        self.share_filter_pictures(content_hash);
         self.pop_stacking_context();
+
+        if is_preview_target {
+            self.in_preview = false;
+        }
+    }
+
+    /// Whether display items are left out because a stacking context is being
+    /// previewed and they aren't inside it.
+    fn skips_content(&self) -> bool {
+        self.preview.is_some() && !self.in_preview
+    }
+
+    /// Counts a stacking context of the previewed pipeline, returning whether
+    /// it is the one to preview.
+    fn is_preview_target(&mut self, pipeline_id: PipelineId) -> bool {
+        match self.preview {
+            Some(preview) if !self.in_preview && preview.pipeline_id == pipeline_id => {
+                let index = self.preview_search_index;
+                self.preview_search_index += 1;
+                index == preview.index
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether a clip or scroll node of the previewed pipeline is defined
+    /// outside of the previewed stacking context, while inside it.
+    fn is_outside_preview(&self, id: ClipId) -> bool {
+        match self.preview {
+            Some(preview) if self.in_preview => {
+                id.pipeline_id() == preview.pipeline_id &&
+                    !self.is_clip_node_defined_since(id, self.preview_first_clip_node)
+            }
+            _ => false,
+        }
+    }
+
+    /// Clip and scroll nodes from outside the previewed stacking context are
+    /// positioned apart from its content, so the content is positioned by the
+    /// root scroll node instead.
+    fn scroll_node_in_preview(&self, id: ClipId) -> ClipId {
+        if self.is_outside_preview(id) {
+            ClipId::root_scroll_node(self.root_pipeline_id)
+        } else {
+            id
+        }
+    }
+
+    /// Clips from outside the previewed stacking context are left out.
+    fn clip_node_in_preview(&self, id: Option<ClipId>) -> Option<ClipId> {
+        match id {
+            Some(id) if self.is_outside_preview(id) => None,
+            id => id,
+        }
+    }
+
+    fn define_clip_node(&mut self, id: ClipId) {
+        let order = self.clip_node_order.len();
+        self.clip_node_order.insert(id, order);
+    }
+
+    /// Whether a clip or scroll node was defined after the given number of
+    /// nodes were. The root nodes of pipelines aren't defined by items, so
+    /// they count as defined before any other.
+    fn is_clip_node_defined_since(&self, id: ClipId, node_count: usize) -> bool {
+        self.clip_node_order.get(&id).map_or(false, |&order| order >= node_count)
+    }
+
+    /// Notes that content uses a clip or scroll node, for the stacking
+    /// contexts it's inside of that the node is defined outside of.
+    fn note_clip_node_use(&mut self, id: ClipId) {
+        if id == ClipId::root_scroll_node(self.root_pipeline_id) ||
+           id == ClipId::root_reference_frame(self.root_pipeline_id) {
+            return;
+        }
+
+        let order = self.clip_node_order.get(&id).cloned();
+        for sc in self.sc_stack.iter_mut().rev() {
+            if order.map_or(false, |order| order >= sc.first_clip_node) {
+                break;
+            }
+            sc.is_clipped_from_outside = true;
+        }
+    }
+
+    /// Notes the clip and scroll nodes a display item defines.
+    fn define_clip_nodes_of_item(&mut self, item: &SpecificDisplayItem) {
+        match *item {
+            SpecificDisplayItem::Clip(ref info) => self.define_clip_node(info.id),
+            SpecificDisplayItem::ClipChain(ref info) => {
+                self.define_clip_node(ClipId::ClipChain(info.id))
+            }
+            SpecificDisplayItem::ScrollFrame(ref info) => {
+                self.define_clip_node(info.clip_id);
+                self.define_clip_node(info.scroll_frame_id);
+            }
+            SpecificDisplayItem::StickyFrame(ref info) => self.define_clip_node(info.id),
+            SpecificDisplayItem::Iframe(ref info) => self.define_clip_node(info.clip_id),
+            _ => {}
+        }
     }
 
     fn flatten_iframe(
@@ -594,11 +933,11 @@ impl<'a> DisplayListFlattener<'a> {
             Some(ExternalScrollId(0, iframe_pipeline_id)),
             iframe_pipeline_id,
             &iframe_rect,
-            &pipeline.content_size,
+            &LayoutRect::new(LayoutPoint::zero(), pipeline.content_size),
             ScrollSensitivity::ScriptAndInputEvents,
         );
 
-        self.flatten_root(pipeline, &iframe_rect.size);
+        self.flatten_root(pipeline, &iframe_rect.size, CompositeOps::default());
 
         self.pop_reference_frame();
     }
@@ -613,9 +952,55 @@ impl<'a> DisplayListFlattener<'a> {
         let unreplaced_scroll_id = clip_and_scroll_ids.scroll_node_id;
         clip_and_scroll_ids.scroll_node_id =
             self.apply_scroll_frame_id_replacement(clip_and_scroll_ids.scroll_node_id);
+        if self.in_preview {
+            clip_and_scroll_ids.scroll_node_id =
+                self.scroll_node_in_preview(clip_and_scroll_ids.scroll_node_id);
+            clip_and_scroll_ids.clip_node_id =
+                self.clip_node_in_preview(clip_and_scroll_ids.clip_node_id);
+        }
+        self.define_clip_nodes_of_item(item.item());
+        self.note_clip_node_use(clip_and_scroll_ids.scroll_node_id);
+        if let Some(clip_node_id) = clip_and_scroll_ids.clip_node_id {
+            self.note_clip_node_use(clip_node_id);
+        }
         let clip_and_scroll = self.id_to_index_mapper.map_clip_and_scroll(&clip_and_scroll_ids);
 
+        // Outside of a previewed stacking context only the items that give
+        // the scene its structure are flattened.
+        if self.skips_content() {
+            match *item.item() {
+                SpecificDisplayItem::PushStackingContext(..) |
+                SpecificDisplayItem::Iframe(..) |
+                SpecificDisplayItem::Clip(..) |
+                SpecificDisplayItem::ClipChain(..) |
+                SpecificDisplayItem::ScrollFrame(..) |
+                SpecificDisplayItem::StickyFrame(..) => {}
+                _ => return None,
+            }
+        }
+
         let prim_info = item.get_layout_primitive_info(&reference_frame_relative_offset);
+
+        // Content whose clip rect leaves none of its area visible, such as
+        // when the two only share an edge, would never be drawn.
+        match *item.item() {
+            SpecificDisplayItem::PushStackingContext(..) |
+            SpecificDisplayItem::Clip(..) |
+            SpecificDisplayItem::ClipChain(..) |
+            SpecificDisplayItem::ScrollFrame(..) |
+            SpecificDisplayItem::StickyFrame(..) |
+            SpecificDisplayItem::SetGradientStops |
+            SpecificDisplayItem::PushShadow(..) |
+            SpecificDisplayItem::PopAllShadows |
+            SpecificDisplayItem::PopStackingContext => {}
+            _ => {
+                let visible_rect = prim_info.rect.intersection(&prim_info.clip_rect);
+                if visible_rect.map_or(true, |rect| rect.is_empty()) {
+                    return None;
+                }
+            }
+        }
+
         match *item.item() {
             SpecificDisplayItem::Image(ref info) => {
                 self.add_image(
@@ -627,6 +1012,7 @@ impl<'a> DisplayListFlattener<'a> {
                     info.image_key,
                     info.image_rendering,
                     info.alpha_type,
+                    info.color_space,
                 );
             }
             SpecificDisplayItem::YuvImage(ref info) => {
@@ -682,6 +1068,7 @@ impl<'a> DisplayListFlattener<'a> {
                     info.gradient.end_point,
                     item.gradient_stops(),
                     item.display_list().get(item.gradient_stops()).count(),
+                    info.ramp,
                     info.gradient.extend_mode,
                     info.tile_size,
                     info.tile_spacing,
@@ -752,7 +1139,7 @@ impl<'a> DisplayListFlattener<'a> {
             SpecificDisplayItem::Clip(ref info) => {
                 let complex_clips = self.get_complex_clips(pipeline_id, item.complex_clip().0);
                 let clip_region = ClipRegion::create_for_clip_node(
-                    *item.clip_rect(),
+                    self.inflate_by_clip_margin(item.clip_rect()),
                     complex_clips,
                     info.image_mask,
                     &reference_frame_relative_offset,
@@ -760,13 +1147,17 @@ impl<'a> DisplayListFlattener<'a> {
                 self.add_clip_node(info.id, clip_and_scroll_ids.scroll_node_id, clip_region);
             }
             SpecificDisplayItem::ClipChain(ref info) => {
-                let items = self.get_clip_chain_items(pipeline_id, item.clip_chain_items())
+                let mut item_ids = self.get_clip_chain_items(pipeline_id, item.clip_chain_items());
+                item_ids.retain(|id| !self.is_outside_preview(*id));
+                let items = item_ids
                                 .iter()
                                 .map(|id| self.id_to_index_mapper.get_node_index(*id))
                                 .collect();
-                let parent = info.parent.map(|id|
-                     self.id_to_index_mapper.get_clip_chain_index(&ClipId::ClipChain(id))
-                );
+                let parent = match info.parent.map(ClipId::ClipChain) {
+                    Some(id) if !self.is_outside_preview(id) =>
+                        Some(self.id_to_index_mapper.get_clip_chain_index(&id)),
+                    _ => None,
+                };
                 let clip_chain_index =
                     self.clip_scroll_tree.add_clip_chain_descriptor(parent, items);
                 self.id_to_index_mapper.add_clip_chain(ClipId::ClipChain(info.id), clip_chain_index);
@@ -864,11 +1255,12 @@ impl<'a> DisplayListFlattener<'a> {
         &mut self,
         prim_index: PrimitiveIndex,
         clip_and_scroll: ScrollNodeAndClipChain,
+        paint_order: Option<i32>,
     ) {
         // Add primitive to the top-most Picture on the stack.
         let pic_index = self.picture_stack.last().unwrap();
         let pic = &mut self.prim_store.pictures[pic_index.0];
-        pic.add_primitive(prim_index, clip_and_scroll);
+        pic.add_primitive_with_paint_order(prim_index, clip_and_scroll, paint_order);
     }
 
     /// Convenience interface that creates a primitive entry and adds it
@@ -913,7 +1305,7 @@ impl<'a> DisplayListFlattener<'a> {
         if container.is_visible() {
             let prim_index = self.create_primitive(info, clip_sources, container);
             self.add_primitive_to_hit_testing_list(info, clip_and_scroll);
-            self.add_primitive_to_draw_list(prim_index, clip_and_scroll);
+            self.add_primitive_to_draw_list(prim_index, clip_and_scroll, info.paint_order);
         }
     }
 
@@ -927,6 +1319,7 @@ impl<'a> DisplayListFlattener<'a> {
         positioning_node: ClipId,
         clipping_node: Option<ClipId>,
         glyph_raster_space: GlyphRasterSpace,
+        clip_margin: f32,
     ) {
         let clip_chain_id = match clipping_node {
             Some(ref clipping_node) => self.id_to_index_mapper.get_clip_chain_index(clipping_node),
@@ -1002,6 +1395,18 @@ impl<'a> DisplayListFlattener<'a> {
             (parent_transform_style == TransformStyle::Preserve3D ||
              transform_style == TransformStyle::Preserve3D);
 
+        // A flat stacking context inside a 3d context flattens its content
+        // into its plane. This applies to the reference frames established
+        // directly inside it, or inside its descendants that don't have
+        // a reference frame of their own.
+        let flattens_reference_frames =
+            (transform_style == TransformStyle::Flat &&
+             parent_transform_style == TransformStyle::Preserve3D) ||
+            self.sc_stack.last().map_or(false, |sc| {
+                sc.flattens_reference_frames &&
+                sc.reference_frame_index == current_reference_frame_index
+            });
+
         // If this is participating in a 3d context *and* the
         // parent was not a 3d context, then this must be the
         // element that establishes a new 3d context.
@@ -1062,6 +1467,7 @@ impl<'a> DisplayListFlattener<'a> {
         };
 
         // For each filter, create a new image with that composite mode.
+        let mut filter_pic_indices = Vec::with_capacity(composite_ops.filters.len());
         for filter in composite_ops.filters.iter().rev() {
             let src_pic_index = self.prim_store.add_image_picture(
                 Some(PictureCompositeMode::Filter(*filter)),
@@ -1072,6 +1478,8 @@ impl<'a> DisplayListFlattener<'a> {
                 true,
             );
 
+            filter_pic_indices.push(src_pic_index);
+
             let src_prim = BrushPrimitive::new_picture(src_pic_index);
             let src_prim_index = self.prim_store.add_primitive(
                 &LayoutRect::zero(),
@@ -1119,10 +1527,48 @@ impl<'a> DisplayListFlattener<'a> {
             self.picture_stack.push(src_pic_index);
         }
 
+        // The content of a pipeline gets a picture of its own, which applies
+        // the layer effect of the pipeline when the frame is built. Pictures
+        // in a 3d rendering context are drawn as planes of its container, so
+        // there's nothing to apply an effect to.
+        let layer_effect_pic_index = if is_pipeline_root && !participating_in_3d_context {
+            let layer_pic_index = self.prim_store.add_image_picture(
+                None,
+                false,
+                pipeline_id,
+                current_reference_frame_index,
+                None,
+                true,
+            );
+            self.prim_store.pictures[layer_pic_index.0].layer_effect_pipeline_id =
+                Some(pipeline_id);
+
+            let layer_prim = BrushPrimitive::new_picture(layer_pic_index);
+            let layer_prim_index = self.prim_store.add_primitive(
+                &LayoutRect::zero(),
+                &max_clip,
+                is_backface_visible,
+                None,
+                None,
+                PrimitiveContainer::Brush(layer_prim),
+            );
+
+            let parent_pic = &mut self.prim_store.pictures[parent_pic_index.0];
+            parent_pic_index = layer_pic_index;
+            parent_pic.add_primitive(layer_prim_index, clip_and_scroll);
+
+            self.picture_stack.push(layer_pic_index);
+
+            Some(layer_pic_index)
+        } else {
+            None
+        };
+
         // By default, this picture will be collapsed into
         // the owning target.
         let mut composite_mode = None;
         let mut frame_output_pipeline_id = None;
+        let mut readback_pipeline_id = None;
 
         // If this stacking context if the root of a pipeline, and the caller
         // has requested it as an output frame, create a render task to isolate it.
@@ -1131,6 +1577,12 @@ impl<'a> DisplayListFlattener<'a> {
             frame_output_pipeline_id = Some(pipeline_id);
         }
 
+        // Similarly, isolate the pipeline if the caller wants to read its pixels.
+        if is_pipeline_root && self.readback_pipelines.contains(&pipeline_id) {
+            composite_mode = Some(PictureCompositeMode::Blit);
+            readback_pipeline_id = Some(pipeline_id);
+        }
+
         // Force an intermediate surface if the stacking context
         // has a clip node. In the future, we may decide during
         // prepare step to skip the intermediate surface if the
@@ -1155,6 +1607,7 @@ impl<'a> DisplayListFlattener<'a> {
             frame_output_pipeline_id,
             true,
         );
+        self.prim_store.pictures[pic_index.0].readback_pipeline_id = readback_pipeline_id;
 
         // Create a brush primitive that draws this picture.
         let sc_prim = BrushPrimitive::new_picture(pic_index);
@@ -1183,12 +1636,43 @@ impl<'a> DisplayListFlattener<'a> {
             pipeline_id,
             transform_style,
             rendering_context_3d_pic_index,
+            layer_effect_pic_index,
+            filter_pic_indices,
             glyph_raster_space,
+            clip_margin,
+            reference_frame_index: current_reference_frame_index,
+            flattens_reference_frames,
+            first_clip_node: self.clip_node_order.len(),
+            is_clipped_from_outside: false,
         };
 
         self.sc_stack.push(sc);
     }
 
+    /// Give the filter pictures of the current stacking context the ids of
+    /// those of any earlier stacking context with identical content, so that
+    /// they draw their filters from one shared cache entry, composited at
+    /// each of their positions. Content clipped from outside of the stacking
+    /// context isn't shared, as it would be clipped differently at each.
+    fn share_filter_pictures(&mut self, content_hash: u64) {
+        let sc = self.sc_stack.last().unwrap();
+        if sc.is_clipped_from_outside {
+            return;
+        }
+        for (filter_depth, pic_index) in sc.filter_pic_indices.iter().enumerate() {
+            let content_key = PictureContentKey {
+                pipeline_id: sc.pipeline_id,
+                content_hash,
+                filter_depth,
+            };
+            let pic = &mut self.prim_store.pictures[pic_index.0];
+            pic.id = *self.shared_picture_ids
+                .entry(content_key)
+                .or_insert(pic.id);
+            pic.content_key = Some(content_key);
+        }
+    }
+
     pub fn pop_stacking_context(&mut self) {
         let sc = self.sc_stack.pop().unwrap();
 
@@ -1203,18 +1687,25 @@ impl<'a> DisplayListFlattener<'a> {
             pop_count += 1;
         }
 
+        // Remove the layer effect picture of a pipeline if created
+        if sc.layer_effect_pic_index.is_some() {
+            pop_count += 1;
+        }
+
         for _ in 0 .. pop_count {
             let pic_index = self
                 .picture_stack
                 .pop()
                 .expect("bug: mismatched picture stack");
+            self.prim_store.pictures[pic_index.0].sort_runs_by_paint_order();
             self.prim_store.optimize_picture_if_possible(pic_index);
         }
 
         // By the time the stacking context stack is empty, we should
         // also have cleared the picture stack.
         if self.sc_stack.is_empty() {
-            self.picture_stack.pop().expect("bug: picture stack invalid");
+            let pic_index = self.picture_stack.pop().expect("bug: picture stack invalid");
+            self.prim_store.pictures[pic_index.0].sort_runs_by_paint_order();
             debug_assert!(self.picture_stack.is_empty());
         }
 
@@ -1233,16 +1724,29 @@ impl<'a> DisplayListFlattener<'a> {
         source_perspective: Option<LayoutTransform>,
         origin_in_parent_reference_frame: LayoutVector2D,
     ) -> ClipScrollNodeIndex {
+        let offset = match self.reference_frame_offsets.last() {
+            _ if source_transform.is_some() || source_perspective.is_some() => None,
+            Some(parent_offset) => {
+                parent_offset.map(|offset| offset + origin_in_parent_reference_frame)
+            }
+            None => Some(origin_in_parent_reference_frame),
+        };
+
         let index = self.id_to_index_mapper.get_node_index(reference_frame_id);
-        let node = ClipScrollNode::new_reference_frame(
+        let mut node = ClipScrollNode::new_reference_frame(
             parent_id.map(|id| self.id_to_index_mapper.get_node_index(id)),
             source_transform,
             source_perspective,
             origin_in_parent_reference_frame,
             pipeline_id,
         );
+        if let NodeType::ReferenceFrame(ref mut info) = node.node_type {
+            info.is_flattened =
+                self.sc_stack.last().map_or(false, |sc| sc.flattens_reference_frames);
+        }
         self.clip_scroll_tree.add_node(node, index);
         self.reference_frame_stack.push((reference_frame_id, index));
+        self.reference_frame_offsets.push(offset);
 
         match parent_id {
             Some(ref parent_id) =>
@@ -1291,7 +1795,7 @@ impl<'a> DisplayListFlattener<'a> {
             Some(ExternalScrollId(0, pipeline_id)),
             pipeline_id,
             &LayoutRect::new(LayoutPoint::zero(), *viewport_size),
-            content_size,
+            &LayoutRect::new(LayoutPoint::zero(), *content_size),
             ScrollSensitivity::ScriptAndInputEvents,
         );
     }
@@ -1323,7 +1827,7 @@ impl<'a> DisplayListFlattener<'a> {
         external_id: Option<ExternalScrollId>,
         pipeline_id: PipelineId,
         frame_rect: &LayoutRect,
-        content_size: &LayoutSize,
+        content_rect: &LayoutRect,
         scroll_sensitivity: ScrollSensitivity,
     ) -> ClipScrollNodeIndex {
         let node_index = self.id_to_index_mapper.get_node_index(new_node_id);
@@ -1332,7 +1836,7 @@ impl<'a> DisplayListFlattener<'a> {
             self.id_to_index_mapper.get_node_index(parent_id),
             external_id,
             frame_rect,
-            content_size,
+            content_rect,
             scroll_sensitivity,
         );
 
@@ -1343,6 +1847,25 @@ impl<'a> DisplayListFlattener<'a> {
 
     pub fn pop_reference_frame(&mut self) {
         self.reference_frame_stack.pop();
+        self.reference_frame_offsets.pop();
+    }
+
+    /// Snap an offset within the current reference frame so that it lands on
+    /// a whole device pixel of the document. The accumulated offset is snapped
+    /// rather than each origin, so that contexts meeting at the same layout
+    /// position keep meeting at the same device pixel.
+    ///
+    /// Transforms aren't resolved until frame building, so inside a
+    /// transformed reference frame the offset is snapped within that frame
+    /// instead, which only lines up with device pixels if the transform is a
+    /// translation by whole device pixels. Scroll and sticky offsets aren't
+    /// known either, and are left out.
+    fn snap_origin_to_device_pixels(&self, offset: LayoutVector2D) -> LayoutVector2D {
+        let frame_offset = self.reference_frame_offsets
+            .last()
+            .and_then(|frame_offset| *frame_offset)
+            .unwrap_or(LayoutVector2D::zero());
+        snap_to_device_pixels(frame_offset + offset, self.device_pixel_scale) - frame_offset
     }
 
     pub fn push_shadow(
@@ -1392,7 +1915,7 @@ impl<'a> DisplayListFlattener<'a> {
 
         // Add the shadow primitive. This must be done before pushing this
         // picture on to the shadow stack, to avoid infinite recursion!
-        self.add_primitive_to_draw_list(shadow_prim_index, clip_and_scroll);
+        self.add_primitive_to_draw_list(shadow_prim_index, clip_and_scroll, None);
         self.shadow_stack.push((shadow, shadow_pic_index));
     }
 
@@ -1472,6 +1995,7 @@ impl<'a> DisplayListFlattener<'a> {
         self.add_primitive_to_draw_list(
             prim_index,
             clip_and_scroll,
+            None,
         );
 
         self.scrollbar_prims.push(ScrollbarPrimitive {
@@ -1772,6 +2296,7 @@ impl<'a> DisplayListFlattener<'a> {
                     border.gradient.end_point - segment_rel,
                     gradient_stops,
                     gradient_stops_count,
+                    None,
                     border.gradient.extend_mode,
                     segment.size,
                     LayoutSize::zero(),
@@ -1808,12 +2333,13 @@ impl<'a> DisplayListFlattener<'a> {
         end_point: LayoutPoint,
         stops: ItemRange<GradientStop>,
         stops_count: usize,
+        ramp: Option<GradientRampKey>,
         extend_mode: ExtendMode,
         stretch_size: LayoutSize,
         mut tile_spacing: LayoutSize,
     ) {
         let gradient_index = CachedGradientIndex(self.cached_gradients.len());
-        self.cached_gradients.push(CachedGradient::new());
+        self.cached_gradients.push(CachedGradient::with_ramp(ramp));
 
         let mut prim_rect = info.rect;
         simplify_repeated_primitive(&stretch_size, &mut tile_spacing, &mut prim_rect);
@@ -2001,6 +2527,7 @@ impl<'a> DisplayListFlattener<'a> {
         image_key: ImageKey,
         image_rendering: ImageRendering,
         alpha_type: AlphaType,
+        color_space: ImageColorSpace,
     ) {
         let mut prim_rect = info.rect;
         simplify_repeated_primitive(&stretch_size, &mut tile_spacing, &mut prim_rect);
@@ -2037,6 +2564,7 @@ impl<'a> DisplayListFlattener<'a> {
                 sub_rect,
                 visible_tiles: Vec::new(),
                 opacity_binding: OpacityBinding::new(),
+                color_space,
             },
             None,
         );
@@ -2095,6 +2623,7 @@ pub fn build_scene(config: &FrameBuilderConfig, request: SceneRequest) -> BuiltS
         request.font_instances,
         &request.view,
         &request.output_pipelines,
+        &request.readback_pipelines,
         config,
         &mut new_scene
     );
@@ -2131,7 +2660,496 @@ struct FlattenedStackingContext {
     /// 3d rendering context, and the value is the picture
     // index of the 3d context container.
     rendering_context_3d_pic_index: Option<PictureIndex>,
+
+    /// If Some(..), this stacking context is the root of a pipeline, and
+    /// the value is the index of the picture that applies the layer effect
+    /// of the pipeline.
+    layer_effect_pic_index: Option<PictureIndex>,
+
+    /// The pictures that apply the filters of this stacking context, from
+    /// the innermost out.
+    filter_pic_indices: Vec<PictureIndex>,
+
+    /// Distance by which clips defined inside this stacking
+    /// context are inflated (CSS overflow-clip-margin).
+    clip_margin: f32,
+
+    /// The reference frame this stacking context is positioned in.
+    reference_frame_index: ClipScrollNodeIndex,
+
+    /// True if reference frames established inside this stacking context
+    /// are flattened into the plane of a flat stacking context that is
+    /// part of a 3d rendering context.
+    flattens_reference_frames: bool,
+
+    /// How many clip and scroll nodes were defined before this stacking
+    /// context.
+    first_clip_node: usize,
+
+    /// True if clip or scroll nodes defined outside of this stacking context,
+    /// other than the root nodes of the root pipeline, apply to its content.
+    is_clipped_from_outside: bool,
 }
 
 #[derive(Debug)]
 pub struct ScrollbarInfo(pub ClipScrollNodeIndex, pub LayoutRect);
+
+/// The filters applied to the root stacking context for an output tonemap.
+/// The gamma curve is applied before the brightness scale.
+fn output_tonemap_ops(tonemap: &OutputTonemap) -> CompositeOps {
+    let filters = [
+        FilterOp::Gamma(tonemap.gamma),
+        FilterOp::Brightness(tonemap.brightness),
+    ];
+    CompositeOps::new(
+        filters.iter().cloned().filter(|filter| !filter.is_noop()).collect(),
+        None,
+    )
+}
+
+/// The root background is drawn by the framebuffer clear rather than by the
+/// root picture, so the output tonemap has to be applied to it directly.
+fn tonemap_color(color: ColorF, tonemap: &OutputTonemap) -> ColorF {
+    if tonemap.is_identity() {
+        return color;
+    }
+    let adjust = |c: f32| (c.powf(1.0 / tonemap.gamma) * tonemap.brightness).min(1.0).max(0.0);
+    ColorF::new(adjust(color.r), adjust(color.g), adjust(color.b), color.a)
+}
+
+/// Round an offset to the nearest whole device pixel.
+fn snap_to_device_pixels(
+    offset: LayoutVector2D,
+    device_pixel_scale: DevicePixelScale,
+) -> LayoutVector2D {
+    (offset * device_pixel_scale).round() / device_pixel_scale
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{ColorF, DeviceIntPoint, DevicePixelRatio, DevicePixelScale};
+    use api::{DeviceUintPoint, DeviceUintRect, DeviceUintSize, DisplayListBuilder, Epoch};
+    use api::{FilterOp, FramebufferRead, GlyphRasterSpace};
+    use api::{LayoutPoint, LayoutPrimitiveInfo, LayoutRect, LayoutSize, LayoutVector2D};
+    use api::{MIN_OUTPUT_TONEMAP_GAMMA, MixBlendMode};
+    use api::{OutputTonemap, PipelineId, PresentationBlendMode, PropertyBinding, TransformStyle};
+    use clip_scroll_tree::ClipScrollTree;
+    use frame_builder::{BlendQuality, FrameBuilder, FrameBuilderConfig};
+    use internal_types::{FastHashMap, FastHashSet};
+    use picture::PictureCompositeMode;
+    use prim_store::{BrushKind, PictureIndex, PrimitiveKind, PrimitiveStore};
+    use render_backend::DocumentView;
+    use scene::Scene;
+    use std::f32;
+    use std::sync::{Arc, RwLock};
+    use super::{DisplayListFlattener, output_tonemap_ops};
+    use super::{snap_to_device_pixels, tonemap_color};
+
+    #[test]
+    fn stacking_context_origin_snaps_to_device_pixels() {
+        let device_pixel_scale = DevicePixelScale::new(2.0);
+        let bounds = LayoutRect::new(LayoutPoint::new(10.3, 5.6), LayoutSize::new(20.4, 8.0));
+
+        let offset = snap_to_device_pixels(bounds.origin.to_vector(), device_pixel_scale);
+        let snapped = LayoutRect::new(offset.to_point(), bounds.size);
+
+        let device_origin = snapped.origin * device_pixel_scale;
+        assert_eq!(device_origin.x, device_origin.x.round());
+        assert_eq!(device_origin.y, device_origin.y.round());
+        assert_eq!(snapped.origin, LayoutPoint::new(10.5, 5.5));
+        assert_eq!(snapped.size, bounds.size);
+
+        // Whole device pixels are left alone.
+        let aligned = LayoutVector2D::new(10.5, -3.0);
+        assert_eq!(snap_to_device_pixels(aligned, device_pixel_scale), aligned);
+    }
+
+    #[test]
+    fn output_tonemap_filters_the_root_stacking_context() {
+        let ops = output_tonemap_ops(&OutputTonemap::new(2.2, 0.8));
+        assert_eq!(ops.filters.len(), 2);
+        match (ops.filters[0], ops.filters[1]) {
+            (FilterOp::Gamma(gamma), FilterOp::Brightness(brightness)) => {
+                assert_eq!(gamma, 2.2);
+                assert_eq!(brightness, 0.8);
+            }
+            other => panic!("unexpected tonemap filters {:?}", other),
+        }
+
+        // Only the non-identity half of a tonemap is emitted.
+        let ops = output_tonemap_ops(&OutputTonemap::new(1.0, 0.5));
+        assert_eq!(ops.filters.len(), 1);
+
+        assert!(output_tonemap_ops(&OutputTonemap::identity()).filters.is_empty());
+    }
+
+    #[test]
+    fn output_tonemap_gamma_is_kept_above_zero() {
+        for &gamma in &[0.0, -2.2, f32::NAN] {
+            let tonemap = OutputTonemap::new(gamma, -1.0);
+            assert_eq!(tonemap.gamma, MIN_OUTPUT_TONEMAP_GAMMA);
+            assert_eq!(tonemap.brightness, 0.0);
+
+            // The gamma curve the shader applies stays finite.
+            let color = tonemap_color(ColorF::new(0.5, 0.5, 0.5, 1.0), &tonemap);
+            assert!(color.r.is_finite());
+            match output_tonemap_ops(&tonemap).filters[0] {
+                FilterOp::Gamma(gamma) => assert!((1.0 / gamma).is_finite()),
+                other => panic!("unexpected tonemap filter {:?}", other),
+            }
+        }
+    }
+
+    fn flatten(builder: DisplayListBuilder, config: &FrameBuilderConfig) -> FrameBuilder {
+        let (pipeline_id, content_size, display_list) = builder.finalize();
+
+        let mut scene = Scene::new();
+        scene.set_root_pipeline_id(pipeline_id);
+        scene.set_display_list(
+            pipeline_id,
+            Epoch(0),
+            display_list,
+            None,
+            content_size,
+            content_size,
+        );
+        flatten_scene(&scene, config)
+    }
+
+    /// The local rects of the primitives drawn into a picture, and into the
+    /// pictures it draws, in the order they are drawn.
+    fn drawn_rects(prim_store: &PrimitiveStore, pic_index: PictureIndex) -> Vec<LayoutRect> {
+        let mut rects = Vec::new();
+        for run in &prim_store.pictures[pic_index.0].runs {
+            for i in 0 .. run.count {
+                let metadata = &prim_store.cpu_metadata[run.base_prim_index.0 + i];
+                let brush_kind = match metadata.prim_kind {
+                    PrimitiveKind::Brush => {
+                        Some(&prim_store.cpu_brushes[metadata.cpu_prim_index.0].kind)
+                    }
+                    _ => None,
+                };
+                match brush_kind {
+                    Some(&BrushKind::Picture { pic_index }) => {
+                        rects.extend(drawn_rects(prim_store, pic_index));
+                    }
+                    _ => rects.push(metadata.local_rect),
+                }
+            }
+        }
+        rects
+    }
+
+    /// A view of a window of the given size, with nothing zoomed or panned.
+    fn view_of_size(window_size: DeviceUintSize) -> DocumentView {
+        DocumentView {
+            window_size,
+            inner_rect: DeviceUintRect::new(DeviceUintPoint::zero(), window_size),
+            layer: 0,
+            pan: DeviceIntPoint::zero(),
+            presentation_blend_mode: PresentationBlendMode::Replace,
+            device_pixel_ratio: DevicePixelRatio::uniform(1.0),
+            page_zoom_factor: 1.0,
+            pinch_zoom_factor: 1.0,
+            output_tonemap: OutputTonemap::identity(),
+        }
+    }
+
+    fn flatten_scene(scene: &Scene, config: &FrameBuilderConfig) -> FrameBuilder {
+        let root_pipeline_id = scene.root_pipeline_id.unwrap();
+        let viewport_size = scene.pipelines[&root_pipeline_id].viewport_size;
+        let window_size = DeviceUintSize::new(
+            viewport_size.width as u32,
+            viewport_size.height as u32,
+        );
+        let view = view_of_size(window_size);
+        DisplayListFlattener::create_frame_builder(
+            FrameBuilder::empty(),
+            scene,
+            &mut ClipScrollTree::new(),
+            Arc::new(RwLock::new(FastHashMap::default())),
+            &view,
+            &FastHashSet::default(),
+            &FastHashSet::default(),
+            config,
+            &mut Scene::new(),
+        )
+    }
+
+    #[test]
+    fn identical_blurred_cards_share_one_filter_picture() {
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(1100.0, 100.0);
+        let card_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(80.0, 80.0));
+
+        // Ten identical blurred cards, followed by one with different content.
+        let mut builder = DisplayListBuilder::new(pipeline_id, content_size);
+        for i in 0 .. 11 {
+            let origin = LayoutPoint::new(i as f32 * 100.0, 0.0);
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(LayoutRect::new(origin, card_rect.size)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(4.0)],
+                GlyphRasterSpace::Screen,
+            );
+            let color = if i < 10 {
+                ColorF::new(0.0, 0.0, 1.0, 1.0)
+            } else {
+                ColorF::new(1.0, 0.0, 0.0, 1.0)
+            };
+            builder.push_rect(&LayoutPrimitiveInfo::new(card_rect), color);
+            builder.pop_stacking_context();
+        }
+        let frame_builder = flatten(builder, &FrameBuilder::empty().config);
+
+        let blur_ids: Vec<_> = frame_builder.prim_store.pictures
+            .iter()
+            .filter(|pic| match pic.composite_mode {
+                Some(PictureCompositeMode::Filter(FilterOp::Blur(..))) => true,
+                _ => false,
+            })
+            .map(|pic| pic.id)
+            .collect();
+        assert_eq!(blur_ids.len(), 11);
+
+        // The picture id keys the cached blur output, so the ten identical
+        // cards render one cached target that is composited ten times.
+        assert!(blur_ids[.. 10].iter().all(|id| *id == blur_ids[0]));
+        assert_ne!(blur_ids[10], blur_ids[0]);
+    }
+
+    #[test]
+    fn cards_clipped_from_outside_dont_share_filter_pictures() {
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(200.0, 100.0);
+        let card_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(80.0, 80.0));
+
+        // Two identical blurred cards in one clip, which cuts the second one.
+        let mut builder = DisplayListBuilder::new(pipeline_id, content_size);
+        let clip_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(150.0, 100.0));
+        let clip = builder.define_clip(clip_rect, None, None);
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        builder.push_clip_id(clip);
+        for i in 0 .. 2 {
+            let origin = LayoutPoint::new(i as f32 * 100.0, 0.0);
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(LayoutRect::new(origin, card_rect.size)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(4.0)],
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_rect(&LayoutPrimitiveInfo::new(card_rect), color);
+            builder.pop_stacking_context();
+        }
+        builder.pop_clip_id();
+        let frame_builder = flatten(builder, &FrameBuilder::empty().config);
+
+        let blur_ids: Vec<_> = frame_builder.prim_store.pictures
+            .iter()
+            .filter(|pic| match pic.composite_mode {
+                Some(PictureCompositeMode::Filter(FilterOp::Blur(..))) => true,
+                _ => false,
+            })
+            .map(|pic| pic.id)
+            .collect();
+        assert_eq!(blur_ids.len(), 2);
+        assert_ne!(blur_ids[0], blur_ids[1]);
+    }
+
+    #[test]
+    fn low_blend_quality_lowers_hsl_blend_modes_to_normal() {
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(100.0, 100.0);
+        let rect = LayoutRect::new(LayoutPoint::zero(), content_size);
+
+        let blend_pictures = |blend_quality| {
+            // The blended context is nested, so it doesn't draw straight
+            // into the main framebuffer.
+            let mut builder = DisplayListBuilder::new(pipeline_id, content_size);
+            for &mix_blend_mode in &[MixBlendMode::Normal, MixBlendMode::Luminosity] {
+                builder.push_stacking_context(
+                    &LayoutPrimitiveInfo::new(rect),
+                    None,
+                    None,
+                    TransformStyle::Flat,
+                    None,
+                    mix_blend_mode,
+                    Vec::new(),
+                    GlyphRasterSpace::Screen,
+                );
+            }
+            builder.push_rect(&LayoutPrimitiveInfo::new(rect), ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_stacking_context();
+            builder.pop_stacking_context();
+
+            let mut config = FrameBuilder::empty().config;
+            config.blend_quality = blend_quality;
+            flatten(builder, &config)
+                .prim_store
+                .pictures
+                .iter()
+                .filter_map(|pic| match pic.composite_mode {
+                    Some(PictureCompositeMode::MixBlend(mode)) => Some(mode),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(blend_pictures(BlendQuality::High), vec![MixBlendMode::Luminosity]);
+        assert!(blend_pictures(BlendQuality::Low).is_empty());
+    }
+
+    #[test]
+    fn blended_stacking_contexts_report_framebuffer_reads() {
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(100.0, 100.0);
+        let outer_rect = LayoutRect::new(LayoutPoint::zero(), content_size);
+        let blend_rect = LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(50.0, 50.0));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        // Only a mix-blend-mode needs what is underneath. Every blend mode
+        // other than Normal reads the framebuffer back here, Multiply included,
+        // while filters only ever read their own content.
+        let mut builder = DisplayListBuilder::new(pipeline_id, content_size);
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(outer_rect),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Opacity(PropertyBinding::Value(0.5), 0.5)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(blend_rect),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Difference,
+            Vec::new(),
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&LayoutPrimitiveInfo::new(outer_rect), color);
+        builder.pop_stacking_context();
+        builder.pop_stacking_context();
+
+        let frame_builder = flatten(builder, &FrameBuilder::empty().config);
+        assert_eq!(
+            frame_builder.framebuffer_reads,
+            vec![FramebufferRead {
+                pipeline_id,
+                bounds: blend_rect,
+                mix_blend_mode: MixBlendMode::Difference,
+            }]
+        );
+    }
+
+    #[test]
+    fn content_clipped_to_zero_area_is_not_flattened() {
+        let root_pipeline_id = PipelineId(0, 0);
+        let iframe_pipeline_id = PipelineId(0, 1);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = |x, y, w, h| LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(w, h));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        let flatten_with_clipped_content = |clipped_content: bool| {
+            let mut root_builder = DisplayListBuilder::new(root_pipeline_id, size);
+            root_builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 50.0, 50.0)), color);
+            if clipped_content {
+                // Clips that only touch the content, or have no area.
+                root_builder.push_rect(
+                    &LayoutPrimitiveInfo::with_clip_rect(
+                        rect(50.0, 0.0, 50.0, 50.0),
+                        rect(0.0, 0.0, 50.0, 50.0),
+                    ),
+                    color,
+                );
+                root_builder.push_rect(
+                    &LayoutPrimitiveInfo::with_clip_rect(
+                        rect(50.0, 0.0, 50.0, 50.0),
+                        rect(60.0, 0.0, 0.0, 50.0),
+                    ),
+                    color,
+                );
+                root_builder.push_iframe(
+                    &LayoutPrimitiveInfo::with_clip_rect(
+                        rect(0.0, 50.0, 100.0, 50.0),
+                        rect(0.0, 50.0, 100.0, 0.0),
+                    ),
+                    iframe_pipeline_id,
+                );
+            }
+            let mut iframe_builder = DisplayListBuilder::new(iframe_pipeline_id, size);
+            iframe_builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 10.0, 10.0)), color);
+
+            let mut scene = Scene::new();
+            scene.set_root_pipeline_id(root_pipeline_id);
+            for builder in vec![root_builder, iframe_builder] {
+                let (pipeline_id, _, display_list) = builder.finalize();
+                scene.set_display_list(pipeline_id, Epoch(0), display_list, None, size, size);
+            }
+            flatten_scene(&scene, &FrameBuilder::empty().config)
+        };
+
+        let unclipped = flatten_with_clipped_content(false);
+        let clipped = flatten_with_clipped_content(true);
+        assert_eq!(clipped.prim_store.prim_count(), unclipped.prim_store.prim_count());
+        assert_eq!(clipped.prim_store.pictures.len(), unclipped.prim_store.pictures.len());
+    }
+
+    #[test]
+    fn overlay_is_flattened_apart_from_the_root_content() {
+        let root_pipeline_id = PipelineId(0, 0);
+        let overlay_pipeline_id = PipelineId(0, 1);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = |x, y, w, h| LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(w, h));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        let mut root_builder = DisplayListBuilder::new(root_pipeline_id, size);
+        root_builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 50.0, 50.0)), color);
+        root_builder.push_rect(&LayoutPrimitiveInfo::new(rect(50.0, 0.0, 50.0, 50.0)), color);
+        let mut overlay_builder = DisplayListBuilder::new(overlay_pipeline_id, size);
+        overlay_builder.push_rect(&LayoutPrimitiveInfo::new(rect(10.0, 10.0, 20.0, 20.0)), color);
+
+        let mut scene = Scene::new();
+        scene.set_root_pipeline_id(root_pipeline_id);
+        for (builder, epoch) in vec![(root_builder, Epoch(0)), (overlay_builder, Epoch(3))] {
+            let (pipeline_id, _, display_list) = builder.finalize();
+            scene.set_display_list(pipeline_id, epoch, display_list, None, size, size);
+        }
+
+        // The root content doesn't include the overlay.
+        let frame_builder = flatten_scene(&scene, &FrameBuilder::empty().config);
+        assert_eq!(
+            drawn_rects(&frame_builder.prim_store, PictureIndex(0)),
+            vec![rect(0.0, 0.0, 50.0, 50.0), rect(50.0, 0.0, 50.0, 50.0)],
+        );
+
+        let mut overlay_scene = Scene::new();
+        let overlay_frame_builder = DisplayListFlattener::create_overlay_frame_builder(
+            &scene,
+            &mut ClipScrollTree::new(),
+            Arc::new(RwLock::new(FastHashMap::default())),
+            &view_of_size(DeviceUintSize::new(100, 100)),
+            overlay_pipeline_id,
+            &FrameBuilder::empty().config,
+            &mut overlay_scene,
+        );
+        assert_eq!(
+            drawn_rects(&overlay_frame_builder.prim_store, PictureIndex(0)),
+            vec![rect(10.0, 10.0, 20.0, 20.0)],
+        );
+        assert_eq!(overlay_scene.pipeline_epochs[&overlay_pipeline_id], Epoch(3));
+        assert!(!overlay_scene.pipeline_epochs.contains_key(&root_pipeline_id));
+    }
+}
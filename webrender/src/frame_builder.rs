@@ -4,27 +4,31 @@
 
 use api::{BuiltDisplayList, ColorF, DeviceIntPoint, DeviceIntRect, DevicePixelScale};
 use api::{DeviceUintPoint, DeviceUintRect, DeviceUintSize, DocumentLayer, FontRenderMode};
-use api::{LayoutRect, LayoutSize, PipelineId, WorldPoint};
+use api::FramebufferRead;
+use api::{LayoutRect, LayoutSize, MixBlendMode, PipelineId, PremultipliedColorF};
+use api::PresentationBlendMode;
 use clip::{ClipChain, ClipStore};
 use clip_scroll_node::{ClipScrollNode};
 use clip_scroll_tree::{ClipScrollNodeIndex, ClipScrollTree};
 use display_list_flattener::{DisplayListFlattener};
-use gpu_cache::GpuCache;
+use gpu_cache::{GpuCache, GpuCacheHandle};
 use gpu_types::{ClipChainRectIndex, ClipScrollNodeData, UvRectKind};
 use hit_test::{HitTester, HitTestingRun};
-use internal_types::{FastHashMap};
-use picture::PictureSurface;
+use internal_types::{FastHashMap, FastHashSet};
+use picture::{PictureContentKey, PictureSurface};
 use prim_store::{CachedGradient, PrimitiveIndex, PrimitiveRun, PrimitiveStore};
 use profiler::{FrameProfileCounters, GpuCacheProfileCounters, TextureCacheProfileCounters};
 use render_backend::FrameId;
-use render_task::{RenderTask, RenderTaskId, RenderTaskLocation, RenderTaskTree};
+use render_task::{RenderTask, RenderTaskCacheEntryHandle, RenderTaskId, RenderTaskLocation};
+use render_task::RenderTaskTree;
 use resource_cache::{ResourceCache};
 use scene::{ScenePipeline, SceneProperties};
 use std::{mem, f32};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tiling::{Frame, RenderPass, RenderPassKind, RenderTargetContext};
-use tiling::{ScrollbarPrimitive, SpecialRenderPasses};
-use util::{self, MaxRect, WorldToLayoutFastTransform};
+use tiling::{DEFAULT_MIN_TARGET_SIZE, ScrollbarPrimitive, SpecialRenderPasses};
+use util::{self, LayoutToWorldFastTransform, MaxRect, WorldToLayoutFastTransform};
 
 #[derive(Clone, Copy)]
 #[cfg_attr(feature = "capture", derive(Serialize))]
@@ -34,6 +38,128 @@ pub struct FrameBuilderConfig {
     pub default_font_render_mode: FontRenderMode,
     pub dual_source_blending_is_supported: bool,
     pub dual_source_blending_is_enabled: bool,
+    pub frame_time_budget: Option<Duration>,
+    pub record_paint_order: bool,
+    pub cached_surface_fade_frames: u32,
+    pub snap_stacking_context_origins: bool,
+    pub blend_quality: BlendQuality,
+    pub retain_frame_snapshot: bool,
+    pub dither_seed: Option<u32>,
+    pub animate_dither: bool,
+    pub composite_clamp: CompositeClamp,
+    pub scroll_restore_max_size_ratio: Option<f32>,
+    pub scissor_clip_rects: bool,
+    pub clamp_pinch_zoom: bool,
+    pub resize_in_place: bool,
+    pub root_sample_count: u32,
+    pub min_render_target_size: u32,
+    pub missing_image_placeholders: bool,
+    pub overdraw_grid_tile_size: Option<u32>,
+}
+
+/// How faithfully mix-blend-modes are composited.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "capture", derive(Serialize))]
+#[cfg_attr(feature = "replay", derive(Deserialize))]
+pub enum BlendQuality {
+    /// Every blend mode is composited exactly.
+    High,
+    /// The non-separable blend modes (hue, saturation, color and
+    /// luminosity), which are costly to composite on low-end GPUs,
+    /// are drawn as normal content instead.
+    Low,
+}
+
+impl BlendQuality {
+    /// Returns the blend mode to composite in place of `mode`.
+    pub fn lower(&self, mode: MixBlendMode) -> MixBlendMode {
+        match (*self, mode) {
+            (BlendQuality::Low, MixBlendMode::Hue) |
+            (BlendQuality::Low, MixBlendMode::Saturation) |
+            (BlendQuality::Low, MixBlendMode::Color) |
+            (BlendQuality::Low, MixBlendMode::Luminosity) => MixBlendMode::Normal,
+            _ => mode,
+        }
+    }
+}
+
+/// How a mix-blend composite brings results that overflow the [0, 1] range,
+/// such as those of plus-lighter, back into range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "capture", derive(Serialize))]
+#[cfg_attr(feature = "replay", derive(Deserialize))]
+pub enum CompositeClamp {
+    /// Values are clamped, which can leave hard edges where they overflow.
+    Clamp = 0,
+    /// Values above a knee are compressed to approach 1.0 smoothly.
+    SoftKnee = 1,
+}
+
+bitflags! {
+    /// Work that can be left out of a frame that is over its time budget,
+    /// without affecting which content is visible.
+    pub struct DeferredWork: u8 {
+        /// Blurs are drawn at reduced resolution, which needs fewer samples.
+        const HIGH_QUALITY_FILTERS = 0x1;
+        /// Pictures are not stored in the texture cache for later frames,
+        /// though those stored already are still drawn from it.
+        const PICTURE_CACHE_WARMING = 0x2;
+    }
+}
+
+/// A limit on the time spent building a frame, measured from when the
+/// frame was requested.
+#[derive(Clone, Copy, Debug)]
+pub struct FrameTimeBudget {
+    pub time_budget: Duration,
+    pub start_instant: Instant,
+}
+
+impl FrameTimeBudget {
+    /// The work to leave out of the rest of the frame, if the budget has
+    /// been used up by `now`.
+    pub fn deferred_work(&self, now: Instant) -> DeferredWork {
+        if now.duration_since(self.start_instant) > self.time_budget {
+            DeferredWork::all()
+        } else {
+            DeferredWork::empty()
+        }
+    }
+}
+
+/// A picture surface that was stored in the texture cache on the last
+/// frame, and the device rect it was drawn at.
+#[derive(Debug)]
+pub struct CachedSurface {
+    pub handle: RenderTaskCacheEntryHandle,
+    pub device_rect: DeviceIntRect,
+    pub content_key: Option<PictureContentKey>,
+}
+
+/// A cached picture surface whose picture was removed by a new scene. It
+/// keeps being composited on top of the new content, at a decreasing
+/// opacity, until it has faded out.
+#[derive(Debug)]
+pub struct FadingSurface {
+    pub handle: RenderTaskCacheEntryHandle,
+    pub device_rect: DeviceIntRect,
+    pub gpu_location: GpuCacheHandle,
+    content_key: Option<PictureContentKey>,
+    frames_left: u32,
+}
+
+impl FadingSurface {
+    /// The opacity to composite the surface with on the next frame, or
+    /// `None` once it has faded out and can be released.
+    fn next_opacity(&mut self, fade_frames: u32) -> Option<f32> {
+        if self.frames_left == 0 {
+            return None;
+        }
+
+        let opacity = self.frames_left as f32 / (fade_frames + 1) as f32;
+        self.frames_left -= 1;
+        Some(opacity)
+    }
 }
 
 /// A builder structure for `tiling::Frame`
@@ -47,6 +173,11 @@ pub struct FrameBuilder {
     pub config: FrameBuilderConfig,
     pub cached_gradients: Vec<CachedGradient>,
     pub scrollbar_prims: Vec<ScrollbarPrimitive>,
+    pub cached_surfaces: Vec<CachedSurface>,
+    pub fading_surfaces: Vec<FadingSurface>,
+    pub framebuffer_reads: Vec<FramebufferRead>,
+    /// The pipelines none of whose primitives were visible in the last frame.
+    pub fully_clipped_pipelines: Vec<PipelineId>,
 }
 
 pub struct FrameBuildingContext<'a> {
@@ -67,6 +198,8 @@ pub struct FrameBuildingState<'a> {
     pub gpu_cache: &'a mut GpuCache,
     pub cached_gradients: &'a mut [CachedGradient],
     pub special_render_passes: &'a mut SpecialRenderPasses,
+    pub deferred_work: DeferredWork,
+    pub cached_surfaces: &'a mut Vec<CachedSurface>,
 }
 
 pub struct PictureContext<'a> {
@@ -120,6 +253,10 @@ impl FrameBuilder {
             hit_testing_runs: Vec::new(),
             cached_gradients: Vec::new(),
             scrollbar_prims: Vec::new(),
+            cached_surfaces: Vec::new(),
+            fading_surfaces: Vec::new(),
+            framebuffer_reads: Vec::new(),
+            fully_clipped_pipelines: Vec::new(),
             prim_store: PrimitiveStore::new(),
             clip_store: ClipStore::new(),
             screen_rect: DeviceUintRect::zero(),
@@ -130,6 +267,23 @@ impl FrameBuilder {
                 default_font_render_mode: FontRenderMode::Mono,
                 dual_source_blending_is_enabled: true,
                 dual_source_blending_is_supported: false,
+                frame_time_budget: None,
+                record_paint_order: false,
+                cached_surface_fade_frames: 0,
+                snap_stacking_context_origins: false,
+                blend_quality: BlendQuality::High,
+                retain_frame_snapshot: false,
+                dither_seed: None,
+                animate_dither: false,
+                composite_clamp: CompositeClamp::Clamp,
+                scroll_restore_max_size_ratio: None,
+                scissor_clip_rects: false,
+                clamp_pinch_zoom: false,
+                resize_in_place: false,
+                root_sample_count: 1,
+                min_render_target_size: DEFAULT_MIN_TARGET_SIZE,
+                missing_image_placeholders: false,
+                overdraw_grid_tile_size: None,
             },
         }
     }
@@ -144,6 +298,10 @@ impl FrameBuilder {
             hit_testing_runs: flattener.hit_testing_runs,
             cached_gradients: flattener.cached_gradients,
             scrollbar_prims: flattener.scrollbar_prims,
+            cached_surfaces: Vec::new(),
+            fading_surfaces: Vec::new(),
+            framebuffer_reads: flattener.framebuffer_reads,
+            fully_clipped_pipelines: Vec::new(),
             prim_store: flattener.prim_store,
             clip_store: flattener.clip_store,
             screen_rect,
@@ -153,6 +311,13 @@ impl FrameBuilder {
         }
     }
 
+    /// Renders the built scene into a window of a different size, keeping
+    /// the origin of the inner rect.
+    pub fn resize(&mut self, window_size: DeviceUintSize, inner_rect: DeviceUintRect) {
+        self.window_size = window_size;
+        self.screen_rect = inner_rect;
+    }
+
     /// Compute the contribution (bounding rectangles, and resources) of layers and their
     /// primitives in screen space.
     fn build_layer_screen_rects_and_cull_layers(
@@ -168,6 +333,7 @@ impl FrameBuilder {
         scene_properties: &SceneProperties,
         local_clip_rects: &mut Vec<LayoutRect>,
         node_data: &[ClipScrollNodeData],
+        deferred_work: DeferredWork,
     ) -> Option<RenderTaskId> {
         profile_scope!("cull");
 
@@ -202,6 +368,8 @@ impl FrameBuilder {
             gpu_cache,
             special_render_passes,
             cached_gradients: &mut self.cached_gradients,
+            deferred_work,
+            cached_surfaces: &mut self.cached_surfaces,
         };
 
         let pic_context = PictureContext {
@@ -241,6 +409,113 @@ impl FrameBuilder {
         Some(render_task_id)
     }
 
+    /// Make the pictures cached on the last frame that overlap the given
+    /// device rect draw their content again on the next one. Returns true
+    /// if there were any.
+    pub fn invalidate_rect(
+        &self,
+        rect: &DeviceIntRect,
+        resource_cache: &mut ResourceCache,
+    ) -> bool {
+        let mut invalidated = false;
+        for surface in &self.cached_surfaces {
+            if surface.device_rect.intersects(rect) {
+                resource_cache.invalidate_render_task(&surface.handle);
+                invalidated = true;
+            }
+        }
+        invalidated
+    }
+
+    /// Hand over the surfaces that this scene's pictures were cached in
+    /// on its last frame, along with any that are still fading out, so
+    /// that the scene replacing this one can fade out those it removed.
+    pub fn take_fading_surfaces(&mut self) -> Vec<FadingSurface> {
+        let fade_frames = self.config.cached_surface_fade_frames;
+        let mut fading_surfaces = mem::replace(&mut self.fading_surfaces, Vec::new());
+
+        if fade_frames > 0 {
+            fading_surfaces.extend(self.cached_surfaces.drain(..).map(|surface| {
+                FadingSurface {
+                    handle: surface.handle,
+                    device_rect: surface.device_rect,
+                    gpu_location: GpuCacheHandle::new(),
+                    content_key: surface.content_key,
+                    frames_left: fade_frames,
+                }
+            }));
+        }
+
+        fading_surfaces
+    }
+
+    /// Take over the surfaces the scene this one replaces was fading out,
+    /// leaving out those whose content is still drawn by this scene.
+    pub fn adopt_fading_surfaces(&mut self, surfaces: Vec<FadingSurface>) {
+        let content_keys: FastHashSet<PictureContentKey> = self.prim_store
+            .pictures
+            .iter()
+            .filter_map(|pic| pic.content_key)
+            .collect();
+
+        self.fading_surfaces = surfaces
+            .into_iter()
+            .filter(|surface| match surface.content_key {
+                Some(ref key) => !content_keys.contains(key),
+                None => true,
+            })
+            .collect();
+    }
+
+    /// Keep the surfaces that are still fading out alive in the texture
+    /// cache, and write the brush data they are composited with on this
+    /// frame. Surfaces that have faded out or were evicted are released.
+    fn update_fading_surfaces(
+        &mut self,
+        clip_scroll_tree: &ClipScrollTree,
+        resource_cache: &mut ResourceCache,
+        gpu_cache: &mut GpuCache,
+        device_pixel_scale: DevicePixelScale,
+    ) {
+        let fade_frames = self.config.cached_surface_fade_frames;
+        let root_node = &clip_scroll_tree.nodes[clip_scroll_tree.root_reference_frame_index().0];
+
+        self.fading_surfaces.retain(|surface| {
+            surface.frames_left > 0 &&
+                resource_cache.request_cached_render_task(&surface.handle, gpu_cache)
+        });
+
+        for surface in &mut self.fading_surfaces {
+            let opacity = match surface.next_opacity(fade_frames) {
+                Some(opacity) => opacity,
+                None => continue,
+            };
+
+            // The surface stays where it was last drawn on screen, so map
+            // its device rect back into the space of the root reference frame.
+            let world_rect = surface.device_rect.to_f32() / device_pixel_scale;
+            let local_rect = root_node
+                .world_content_transform
+                .unapply(&world_rect)
+                .unwrap_or_else(LayoutRect::zero);
+
+            // The opacity changes every frame, so always write new data.
+            gpu_cache.invalidate(&surface.gpu_location);
+            if let Some(mut request) = gpu_cache.request(&mut surface.gpu_location) {
+                // has to match VECS_PER_BRUSH_PRIM
+                request.push(local_rect);
+                request.push(local_rect);
+                // has to match VECS_PER_SPECIFIC_BRUSH of brush_image
+                request.push(ColorF::new(1.0, 1.0, 1.0, opacity).premultiplied());
+                request.push(PremultipliedColorF::WHITE);
+                request.push([local_rect.size.width, local_rect.size.height, 0.0, 0.0]);
+                // has to match VECS_PER_SEGMENT
+                request.push(local_rect);
+                request.push([0.0; 4]);
+            }
+        }
+    }
+
     fn update_scroll_bars(&mut self, clip_scroll_tree: &ClipScrollTree, gpu_cache: &mut GpuCache) {
         static SCROLLBAR_PADDING: f32 = 8.0;
 
@@ -256,7 +531,9 @@ impl FrameBuilder {
                 metadata.local_clip_rect.size = LayoutSize::zero();
                 continue;
             }
-            let amount_scrolled = -scroll_frame.scroll_offset().y / scrollable_distance;
+            let amount_scrolled =
+                (scroll_frame.max_scroll_offset().y - scroll_frame.scroll_offset().y) /
+                scrollable_distance;
 
             let frame_rect = scrollbar_prim.frame_rect;
             let min_y = frame_rect.origin.y + SCROLLBAR_PADDING;
@@ -279,10 +556,12 @@ impl FrameBuilder {
         pipelines: &FastHashMap<PipelineId, Arc<ScenePipeline>>,
         device_pixel_scale: DevicePixelScale,
         layer: DocumentLayer,
-        pan: WorldPoint,
+        presentation_blend_mode: PresentationBlendMode,
+        root_transform: LayoutToWorldFastTransform,
         texture_cache_profile: &mut TextureCacheProfileCounters,
         gpu_cache_profile: &mut GpuCacheProfileCounters,
         scene_properties: &SceneProperties,
+        time_budget: Option<FrameTimeBudget>,
     ) -> Frame {
         profile_scope!("build");
         debug_assert!(
@@ -310,17 +589,28 @@ impl FrameBuilder {
             &mut self.clip_store,
             resource_cache,
             gpu_cache,
-            pan,
+            root_transform,
             &mut node_data,
             scene_properties,
         );
 
         self.update_scroll_bars(clip_scroll_tree, gpu_cache);
+        self.update_fading_surfaces(clip_scroll_tree, resource_cache, gpu_cache, device_pixel_scale);
+        self.cached_surfaces.clear();
+
+        // Once the clip-scroll tree is up to date, decide whether there is
+        // still time left for the optional parts of preparing primitives.
+        let deferred_work = time_budget.map_or(DeferredWork::empty(), |budget| {
+            budget.deferred_work(Instant::now())
+        });
 
         let mut render_tasks = RenderTaskTree::new(frame_id);
 
         let screen_size = self.screen_rect.size.to_i32();
-        let mut special_render_passes = SpecialRenderPasses::new(&screen_size);
+        let mut special_render_passes = SpecialRenderPasses::new(
+            &screen_size,
+            self.config.min_render_target_size,
+        );
 
         let main_render_task_id = self.build_layer_screen_rects_and_cull_layers(
             clip_scroll_tree,
@@ -334,6 +624,7 @@ impl FrameBuilder {
             scene_properties,
             &mut clip_chain_local_clip_rects,
             &node_data,
+            deferred_work,
         );
 
         resource_cache.block_until_all_resources_added(gpu_cache,
@@ -353,7 +644,10 @@ impl FrameBuilder {
             // Do the allocations now, assigning each tile's tasks to a render
             // pass and target as required.
             for _ in 0 .. required_pass_count - 1 {
-                passes.push(RenderPass::new_off_screen(screen_size));
+                passes.push(RenderPass::new_off_screen(
+                    screen_size,
+                    self.config.min_render_target_size,
+                ));
             }
             passes.push(RenderPass::new_main_framebuffer(screen_size));
 
@@ -368,6 +662,14 @@ impl FrameBuilder {
         let mut has_texture_cache_tasks = false;
         let use_dual_source_blending = self.config.dual_source_blending_is_enabled &&
                                        self.config.dual_source_blending_is_supported;
+        // A seed fixes where the dither pattern starts. Without one, the
+        // pattern starts at the same place in every frame, unless it is
+        // animated to keep it from showing up as a static texture.
+        let dither_offset = match self.config.dither_seed {
+            Some(seed) => dither_offset(seed),
+            None if self.config.animate_dither => dither_offset(frame_id.0),
+            None => 0,
+        };
 
         for pass in &mut passes {
             let mut ctx = RenderTargetContext {
@@ -378,6 +680,13 @@ impl FrameBuilder {
                 use_dual_source_blending,
                 node_data: &node_data,
                 cached_gradients: &self.cached_gradients,
+                record_paint_order: self.config.record_paint_order,
+                fading_surfaces: &self.fading_surfaces,
+                dither_offset,
+                composite_clamp: self.config.composite_clamp,
+                scissor_clip_rects: self.config.scissor_clip_rects,
+                missing_image_placeholders: self.config.missing_image_placeholders,
+                overdraw_grid_tile_size: self.config.overdraw_grid_tile_size,
             };
 
             pass.build(
@@ -393,18 +702,21 @@ impl FrameBuilder {
             }
         }
 
+        self.fully_clipped_pipelines = self.prim_store.fully_clipped_pipelines();
+
         let gpu_cache_frame_id = gpu_cache.end_frame(gpu_cache_profile);
 
         render_tasks.write_task_data();
 
         resource_cache.end_frame();
 
-        Frame {
+        let mut frame = Frame {
             window_size: self.window_size,
             inner_rect: self.screen_rect,
             device_pixel_ratio: device_pixel_scale.0,
             background_color: self.background_color,
             layer,
+            presentation_blend_mode,
             profile_counters,
             passes,
             node_data,
@@ -414,7 +726,17 @@ impl FrameBuilder {
             gpu_cache_frame_id,
             has_been_rendered: false,
             has_texture_cache_tasks,
+            highlight: None,
+            sample_count: self.config.root_sample_count,
+            is_first_contentful: false,
+            overdraw_grid: None,
+        };
+
+        if let Some(tile_size) = self.config.overdraw_grid_tile_size {
+            frame.overdraw_grid = Some(frame.compute_overdraw_grid(tile_size));
         }
+
+        frame
     }
 
     pub fn create_hit_tester(&mut self, clip_scroll_tree: &ClipScrollTree) -> HitTester {
@@ -426,3 +748,81 @@ impl FrameBuilder {
     }
 }
 
+/// Picks the position in the 8x8 dither matrix that the pattern starts at,
+/// packed as `x | y << 3`, by hashing the seed.
+fn dither_offset(seed: u32) -> i32 {
+    (seed.wrapping_mul(0x9E37_79B1) >> 26) as i32
+}
+
+#[cfg(test)]
+mod test {
+    use api::{DeviceIntPoint, DeviceIntRect, DeviceIntSize, FilterOp, PipelineId};
+    use clip_scroll_tree::ClipScrollNodeIndex;
+    use freelist::FreeList;
+    use picture::{PictureCompositeMode, PictureContentKey};
+    use render_task::RenderTaskCacheMarker;
+    use std::time::{Duration, Instant};
+    use super::{CachedSurface, DeferredWork, FrameBuilder, FrameTimeBudget};
+
+    #[test]
+    fn exceeded_budget_defers_optional_work() {
+        let start_instant = Instant::now();
+        let budget = FrameTimeBudget {
+            time_budget: Duration::from_millis(4),
+            start_instant,
+        };
+
+        let within_budget = start_instant + Duration::from_millis(3);
+        assert_eq!(budget.deferred_work(within_budget), DeferredWork::empty());
+
+        let over_budget = start_instant + Duration::from_millis(5);
+        let deferred = budget.deferred_work(over_budget);
+        assert!(deferred.contains(DeferredWork::PICTURE_CACHE_WARMING));
+        assert!(deferred.contains(DeferredWork::HIGH_QUALITY_FILTERS));
+    }
+
+    #[test]
+    fn removed_cached_surface_fades_out_then_is_freed() {
+        let mut cache_entries = FreeList::<(), RenderTaskCacheMarker>::new();
+        let device_rect = DeviceIntRect::new(DeviceIntPoint::new(10, 20), DeviceIntSize::new(100, 50));
+        let other_rect = DeviceIntRect::new(DeviceIntPoint::new(10, 80), device_rect.size);
+        let content_key = |content_hash| PictureContentKey {
+            pipeline_id: PipelineId::dummy(),
+            content_hash,
+            filter_depth: 0,
+        };
+
+        let mut old_builder = FrameBuilder::empty();
+        old_builder.config.cached_surface_fade_frames = 3;
+        old_builder.cached_surfaces.push(CachedSurface {
+            handle: cache_entries.insert(()).weak(),
+            device_rect,
+            content_key: Some(content_key(1)),
+        });
+        old_builder.cached_surfaces.push(CachedSurface {
+            handle: cache_entries.insert(()).weak(),
+            device_rect: other_rect,
+            content_key: Some(content_key(2)),
+        });
+
+        // The scene replacing the old one still draws the second surface's
+        // content, so only the first one is faded out.
+        let mut new_builder = FrameBuilder::empty();
+        let pic_index = new_builder.prim_store.add_image_picture(
+            Some(PictureCompositeMode::Filter(FilterOp::Blur(3.0))),
+            false,
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            true,
+        );
+        new_builder.prim_store.pictures[pic_index.0].content_key = Some(content_key(2));
+        new_builder.adopt_fading_surfaces(old_builder.take_fading_surfaces());
+        assert_eq!(new_builder.fading_surfaces.len(), 1);
+        assert_eq!(new_builder.fading_surfaces[0].device_rect, device_rect);
+
+        let surface = &mut new_builder.fading_surfaces[0];
+        let opacities: Vec<_> = (0 .. 4).map(|_| surface.next_opacity(3)).collect();
+        assert_eq!(opacities, vec![Some(0.75), Some(0.5), Some(0.25), None]);
+    }
+}
@@ -234,6 +234,13 @@ pub struct BrushInstance {
     pub user_data: [i32; 3],
 }
 
+impl PrimitiveInstance {
+    #[cfg(test)]
+    pub fn brush_user_data(&self) -> [i32; 3] {
+        [self.data[5], self.data[6], self.data[7]]
+    }
+}
+
 impl From<BrushInstance> for PrimitiveInstance {
     fn from(instance: BrushInstance) -> Self {
         PrimitiveInstance {
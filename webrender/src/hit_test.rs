@@ -3,7 +3,7 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
 use api::{BorderRadius, ClipMode, HitTestFlags, HitTestItem, HitTestResult, ItemTag, LayoutPoint};
-use api::{LayoutPrimitiveInfo, LayoutRect, PipelineId, WorldPoint};
+use api::{ExternalScrollId, LayoutPrimitiveInfo, LayoutRect, PipelineId, WorldPoint, WorldRect};
 use clip::{ClipSource, ClipStore, rounded_rectangle_contains_point};
 use clip_scroll_node::{ClipScrollNode, NodeType};
 use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex, ClipScrollTree};
@@ -27,6 +27,10 @@ pub struct HitTestClipScrollNode {
 
     /// World viewport transform for content transformed by this node.
     world_viewport_transform: LayoutToWorldFastTransform,
+
+    /// The nearest scroll frame with an external id that scrolls content
+    /// positioned by this node.
+    scroll_id: Option<ExternalScrollId>,
 }
 
 /// A description of a clip chain in the HitTester. This is used to describe
@@ -114,6 +118,16 @@ impl HitTester {
         hit_tester
     }
 
+    /// Take the transforms of the tree the hit tester was built from again,
+    /// after it has been scrolled.
+    pub fn update_transforms(&mut self, clip_scroll_tree: &ClipScrollTree) {
+        debug_assert_eq!(self.nodes.len(), clip_scroll_tree.nodes.len());
+        for (node, tree_node) in self.nodes.iter_mut().zip(clip_scroll_tree.nodes.iter()) {
+            node.world_content_transform = tree_node.world_content_transform;
+            node.world_viewport_transform = tree_node.world_viewport_transform;
+        }
+    }
+
     fn read_clip_scroll_tree(
         &mut self,
         clip_scroll_tree: &ClipScrollTree,
@@ -138,6 +152,7 @@ impl HitTester {
                 regions: get_regions_for_clip_scroll_node(node, clip_store),
                 world_content_transform: node.world_content_transform,
                 world_viewport_transform: node.world_viewport_transform,
+                scroll_id: enclosing_scroll_id(clip_scroll_tree, index),
             });
 
             if let NodeType::Clip { clip_chain_index, .. } = node.node_type {
@@ -218,6 +233,10 @@ impl HitTester {
         true
     }
 
+    /// Find the scroll node of the topmost item under the point. Runs are stored in
+    /// paint order and never share a position in it, so walking them backwards and
+    /// stopping at the first hit picks the highest painted node, and always the same
+    /// node for the same point, even when several non-nested scroll nodes overlap there.
     pub fn find_node_under_point(&self, mut test: HitTest) -> Option<ClipScrollNodeIndex> {
         let point = test.get_absolute_point(self);
 
@@ -305,6 +324,7 @@ impl HitTester {
                 result.items.push(HitTestItem {
                     pipeline: pipeline_id,
                     tag: item.tag,
+                    scroll_id: scroll_node.scroll_id,
                     point_in_viewport,
                     point_relative_to_item: point_in_layer - item.rect.origin.to_vector(),
                 });
@@ -318,11 +338,94 @@ impl HitTester {
         result
     }
 
+    /// The part of `rect` that is inside the clip rects of a clip chain. The
+    /// clip-out regions are ignored, so this can be larger than the part of
+    /// `rect` that is actually drawn.
+    fn clip_rect_to_clip_chain(
+        &self,
+        rect: WorldRect,
+        clip_chain_index: ClipChainIndex,
+    ) -> Option<WorldRect> {
+        let mut rect = rect;
+        let mut clip_chain_index = Some(clip_chain_index);
+        while let Some(index) = clip_chain_index {
+            let descriptor = &self.clip_chains[index.0];
+            for clip_node_index in &descriptor.clips {
+                let node = &self.nodes[clip_node_index.0];
+                for region in &node.regions {
+                    let clip_rect = match *region {
+                        HitTestRegion::Rectangle(ref clip_rect, ClipMode::Clip) |
+                        HitTestRegion::RoundedRectangle(ref clip_rect, _, ClipMode::Clip) => {
+                            node.world_viewport_transform.transform_rect(clip_rect)
+                        }
+                        _ => continue,
+                    };
+                    rect = rect.intersection(&clip_rect)?;
+                }
+            }
+            clip_chain_index = descriptor.parent;
+        }
+        Some(rect)
+    }
+
+    /// The part of the items with the given tag that is visible within
+    /// `viewport`, in world space, after their clips and scroll offsets are
+    /// applied. Returns `None` if they are entirely clipped out or weren't
+    /// in the frame.
+    pub fn item_visible_rect(
+        &self,
+        pipeline_id: PipelineId,
+        tag: ItemTag,
+        viewport: &WorldRect,
+    ) -> Option<WorldRect> {
+        let mut visible_rect: Option<WorldRect> = None;
+        for &HitTestingRun(ref items, ref clip_and_scroll) in &self.runs {
+            let scroll_node = &self.nodes[clip_and_scroll.scroll_node_id.0];
+            if scroll_node.pipeline_id != pipeline_id {
+                continue;
+            }
+
+            for item in items.iter().filter(|item| item.tag == tag) {
+                let rect = item.rect
+                    .intersection(&item.clip_rect)
+                    .map(|rect| scroll_node.world_content_transform.transform_rect(&rect))
+                    .and_then(|rect| rect.intersection(viewport))
+                    .and_then(|rect| {
+                        self.clip_rect_to_clip_chain(rect, clip_and_scroll.clip_chain_index)
+                    });
+                if let Some(rect) = rect {
+                    visible_rect = Some(match visible_rect {
+                        Some(visible_rect) => visible_rect.union(&rect),
+                        None => rect,
+                    });
+                }
+            }
+        }
+        visible_rect
+    }
+
     pub fn get_pipeline_root(&self, pipeline_id: PipelineId) -> &HitTestClipScrollNode {
         &self.nodes[self.pipeline_root_nodes[&pipeline_id].0]
     }
 }
 
+fn enclosing_scroll_id(
+    clip_scroll_tree: &ClipScrollTree,
+    index: ClipScrollNodeIndex,
+) -> Option<ExternalScrollId> {
+    let mut node_index = Some(index);
+    while let Some(index) = node_index {
+        let node = &clip_scroll_tree.nodes[index.0];
+        if let NodeType::ScrollFrame(ref info) = node.node_type {
+            if info.external_id.is_some() {
+                return info.external_id;
+            }
+        }
+        node_index = node.parent;
+    }
+    None
+}
+
 fn get_regions_for_clip_scroll_node(
     node: &ClipScrollNode,
     clip_store: &ClipStore
@@ -402,3 +505,105 @@ impl HitTest {
         ).unwrap_or_else(|| WorldPoint::new(self.point.x, self.point.y))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use api::{ExternalScrollId, HitTestFlags, LayoutPoint, LayoutPrimitiveInfo, LayoutRect};
+    use api::LayoutSize;
+    use api::{LayoutVector2D, PipelineId, ScrollSensitivity, WorldPoint};
+    use clip::ClipStore;
+    use clip_scroll_node::ClipScrollNode;
+    use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex, ClipScrollTree};
+    use prim_store::ScrollNodeAndClipChain;
+    use super::{HitTest, HitTester, HitTestingItem, HitTestingRun};
+
+    fn add_scroll_frame(tree: &mut ClipScrollTree, index: usize, rect: &LayoutRect) {
+        let node = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            rect,
+            &rect.inflate(0.0, 100.0),
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(node, ClipScrollNodeIndex(index));
+    }
+
+    fn run_for_node(index: usize, rect: &LayoutRect) -> HitTestingRun {
+        let item = HitTestingItem::new((index as u64, 0), &LayoutPrimitiveInfo::new(*rect));
+        HitTestingRun(
+            vec![item],
+            ScrollNodeAndClipChain::new(ClipScrollNodeIndex(index), ClipChainIndex(0)),
+        )
+    }
+
+    #[test]
+    fn overlapping_scroll_frames_pick_highest_painted() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+
+        // Two sibling scroll frames at the same depth covering the same area.
+        let rect = LayoutRect::new(LayoutPoint::new(0.0, 0.0), LayoutSize::new(100.0, 100.0));
+        add_scroll_frame(&mut tree, 1, &rect);
+        add_scroll_frame(&mut tree, 2, &rect);
+
+        // The second frame's content is painted first, so the first frame is on top.
+        let runs = vec![run_for_node(2, &rect), run_for_node(1, &rect)];
+        let hit_tester = HitTester::new(&runs, &tree, &ClipStore::new());
+
+        for _ in 0 .. 3 {
+            let test = HitTest::new(None, WorldPoint::new(50.0, 50.0), HitTestFlags::empty());
+            assert_eq!(hit_tester.find_node_under_point(test), Some(ClipScrollNodeIndex(1)));
+        }
+    }
+
+    #[test]
+    fn hit_items_report_their_enclosing_scroll_frame() {
+        let mut tree = ClipScrollTree::new();
+        let root = ClipScrollNode::new_reference_frame(
+            None,
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(root, ClipScrollNodeIndex(0));
+
+        let rect = LayoutRect::new(LayoutPoint::new(0.0, 0.0), LayoutSize::new(100.0, 100.0));
+        let scroll_id = ExternalScrollId(1, PipelineId::dummy());
+        let scroll_frame = ClipScrollNode::new_scroll_frame(
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            Some(scroll_id),
+            &rect,
+            &rect.inflate(0.0, 100.0),
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        tree.add_node(scroll_frame, ClipScrollNodeIndex(1));
+
+        // Content of a reference frame inside the scroll frame is scrolled by it too.
+        let reference_frame = ClipScrollNode::new_reference_frame(
+            Some(ClipScrollNodeIndex(1)),
+            None,
+            None,
+            LayoutVector2D::zero(),
+            PipelineId::dummy(),
+        );
+        tree.add_node(reference_frame, ClipScrollNodeIndex(2));
+
+        let runs = vec![run_for_node(0, &rect), run_for_node(2, &rect)];
+        let hit_tester = HitTester::new(&runs, &tree, &ClipStore::new());
+
+        let test = HitTest::new(None, WorldPoint::new(50.0, 50.0), HitTestFlags::FIND_ALL);
+        let result = hit_tester.hit_test(test);
+        let hits: Vec<_> = result.items.iter().map(|item| (item.tag, item.scroll_id)).collect();
+        assert_eq!(hits, vec![((2, 0), Some(scroll_id)), ((0, 0), None)]);
+    }
+}
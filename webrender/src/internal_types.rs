@@ -66,6 +66,21 @@ pub enum SourceTexture {
     RenderTaskCache(SavedTargetIndex),
 }
 
+impl SourceTexture {
+    /// A key that orders textures the same way on every run, for iterating
+    /// over maps keyed by texture in a reproducible order.
+    pub fn sort_key(&self) -> (u8, u64, u8) {
+        match *self {
+            SourceTexture::Invalid => (0, 0, 0),
+            SourceTexture::TextureCache(id) => (1, id.0 as u64, 0),
+            SourceTexture::External(ref data) => (2, data.id.0, data.channel_index),
+            SourceTexture::CacheA8 => (3, 0, 0),
+            SourceTexture::CacheRGBA8 => (4, 0, 0),
+            SourceTexture::RenderTaskCache(index) => (5, index.0 as u64, 0),
+        }
+    }
+}
+
 pub const ORTHO_NEAR_PLANE: f32 = -1000000.0;
 pub const ORTHO_FAR_PLANE: f32 = 1000000.0;
 
@@ -139,18 +154,40 @@ pub struct RenderedDocument {
     pub pipeline_info: PipelineInfo,
 
     pub frame: tiling::Frame,
+
+    /// The frames of the overlays of the document, blended over `frame`
+    /// bottom-most first.
+    pub overlays: Vec<tiling::Frame>,
+
+    /// The structure of the last frame successfully built for the document,
+    /// if the renderer retains frame snapshots.
+    pub snapshot: Option<tiling::FrameSnapshot>,
+
+    /// The number of times a texture is bound for the primitive batches of
+    /// the frame, counted when it was built.
+    pub texture_bind_count: usize,
 }
 
 impl RenderedDocument {
     pub fn new(
         pipeline_info: PipelineInfo,
         frame: tiling::Frame,
+        snapshot: Option<tiling::FrameSnapshot>,
     ) -> Self {
         RenderedDocument {
             pipeline_info,
+            texture_bind_count: frame.texture_bind_count(),
             frame,
+            overlays: Vec::new(),
+            snapshot,
         }
     }
+
+    /// Whether any frame of the document writes to the texture cache and
+    /// hasn't been drawn yet.
+    pub fn must_be_drawn(&self) -> bool {
+        self.frame.must_be_drawn() || self.overlays.iter().any(|frame| frame.must_be_drawn())
+    }
 }
 
 pub enum DebugOutput {
@@ -177,6 +214,11 @@ pub enum ResultMsg {
         TextureUpdateList,
         BackendProfileCounters,
     ),
+    PublishStackingContextPreview(
+        DocumentId,
+        tiling::Frame,
+        TextureUpdateList,
+    ),
 }
 
 #[derive(Clone, Debug)]
@@ -8,12 +8,14 @@ use api::{DevicePixelScale, PictureIntPoint, PictureIntRect, PictureIntSize};
 use box_shadow::{BLUR_SAMPLE_SCALE};
 use clip_scroll_node::ClipScrollNode;
 use clip_scroll_tree::ClipScrollNodeIndex;
-use frame_builder::{FrameBuildingContext, FrameBuildingState, PictureState, PrimitiveRunContext};
+use frame_builder::{CachedSurface, DeferredWork, FrameBuildingContext, FrameBuildingState};
+use frame_builder::PictureState;
+use frame_builder::PrimitiveRunContext;
 use gpu_cache::{GpuCacheHandle};
 use gpu_types::UvRectKind;
 use prim_store::{PrimitiveIndex, PrimitiveRun, PrimitiveRunLocalRect};
 use prim_store::{PrimitiveMetadata, ScrollNodeAndClipChain};
-use render_task::{ClearMode, RenderTask, RenderTaskCacheEntryHandle};
+use render_task::{ClearMode, MIN_DOWNSCALING_RT_SIZE, RenderTask, RenderTaskCacheEntryHandle};
 use render_task::{RenderTaskCacheKey, RenderTaskCacheKeyKind, RenderTaskId, RenderTaskLocation};
 use scene::{FilterOpHelpers, SceneProperties};
 use std::mem;
@@ -62,6 +64,16 @@ pub enum PictureSurface {
 #[cfg_attr(feature = "replay", derive(Deserialize))]
 pub struct PictureId(pub u64);
 
+/// Identifies the content of a filter picture in a way that stays the same
+/// across scenes: by the hash of the content of its stacking context, and
+/// how far down the filter chain of that stacking context it is.
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+pub struct PictureContentKey {
+    pub pipeline_id: PipelineId,
+    pub content_hash: u64,
+    pub filter_depth: usize,
+}
+
 // Cache key that determines whether a pre-existing
 // picture in the texture cache matches the content
 // of the current picture.
@@ -143,6 +155,10 @@ pub struct PicturePrimitive {
     // pages to a texture), this is the pipeline this
     // picture is the root of.
     pub frame_output_pipeline_id: Option<PipelineId>,
+    // If the caller asked to read back the pixels of a
+    // pipeline, this is the pipeline this picture is the
+    // root of.
+    pub readback_pipeline_id: Option<PipelineId>,
     // The original reference frame ID for this picture.
     // It is only different if this is part of a 3D
     // rendering context.
@@ -155,10 +171,24 @@ pub struct PicturePrimitive {
 
     // Unique identifier for this picture.
     pub id: PictureId,
+    // For the filter pictures of stacking contexts, what the
+    // picture draws, which is the same for the same content
+    // in later scenes.
+    pub content_key: Option<PictureContentKey>,
+    // For the picture that applies the layer effect of a
+    // pipeline, the pipeline. Its composite mode is set from
+    // the effect when the frame is built.
+    pub layer_effect_pipeline_id: Option<PipelineId>,
 }
 
 impl PicturePrimitive {
     pub fn resolve_scene_properties(&mut self, properties: &SceneProperties) -> bool {
+        if let Some(pipeline_id) = self.layer_effect_pipeline_id {
+            self.composite_mode = properties
+                .resolve_layer_effect(pipeline_id)
+                .map(PictureCompositeMode::Filter);
+        }
+
         match self.composite_mode {
             Some(PictureCompositeMode::Filter(ref mut filter)) => {
                 match *filter {
@@ -190,12 +220,15 @@ impl PicturePrimitive {
             composite_mode,
             is_in_3d_context,
             frame_output_pipeline_id,
+            readback_pipeline_id: None,
             reference_frame_index,
             real_local_rect: LayoutRect::zero(),
             extra_gpu_data_handle: GpuCacheHandle::new(),
             apply_local_clip_rect,
             pipeline_id,
             id,
+            content_key: None,
+            layer_effect_pipeline_id: None,
         }
     }
 
@@ -204,8 +237,20 @@ impl PicturePrimitive {
         prim_index: PrimitiveIndex,
         clip_and_scroll: ScrollNodeAndClipChain
     ) {
+        self.add_primitive_with_paint_order(prim_index, clip_and_scroll, None);
+    }
+
+    pub fn add_primitive_with_paint_order(
+        &mut self,
+        prim_index: PrimitiveIndex,
+        clip_and_scroll: ScrollNodeAndClipChain,
+        paint_order: Option<i32>,
+    ) {
+        let paint_order = paint_order.unwrap_or(0);
+
         if let Some(ref mut run) = self.runs.last_mut() {
             if run.clip_and_scroll == clip_and_scroll &&
+               run.paint_order == paint_order &&
                run.base_prim_index.0 + run.count == prim_index.0 {
                 run.count += 1;
                 return;
@@ -216,9 +261,18 @@ impl PicturePrimitive {
             base_prim_index: prim_index,
             count: 1,
             clip_and_scroll,
+            paint_order,
         });
     }
 
+    /// Move any runs with an explicit paint order into place. The sort is
+    /// stable, so runs sharing a paint order keep their display list order.
+    pub fn sort_runs_by_paint_order(&mut self) {
+        if self.runs.iter().any(|run| run.paint_order != 0) {
+            self.runs.sort_by_key(|run| run.paint_order);
+        }
+    }
+
     pub fn update_local_rect(
         &mut self,
         prim_run_rect: PrimitiveRunLocalRect,
@@ -324,61 +378,51 @@ impl PicturePrimitive {
                     frame_context.device_pixel_scale,
                 );
 
+                // Get the relative clipped rect within the overall prim rect, that
+                // forms part of the cache key.
+                let pic_relative_render_rect = PictureIntRect::new(
+                    PictureIntPoint::new(
+                        device_rect.origin.x - prim_screen_rect.unclipped.origin.x,
+                        device_rect.origin.y - prim_screen_rect.unclipped.origin.y,
+                    ),
+                    PictureIntSize::new(
+                        device_rect.size.width,
+                        device_rect.size.height,
+                    ),
+                );
+                let cache_key = RenderTaskCacheKey {
+                    size: device_rect.size,
+                    kind: RenderTaskCacheKeyKind::Picture(PictureCacheKey {
+                        picture_id: self.id,
+                        unclipped_size: prim_screen_rect.unclipped.size,
+                        pic_relative_render_rect,
+                    }),
+                };
+
                 // If we are drawing a blur that has primitives or clips that contain
                 // a complex coordinate system, don't bother caching them (for now).
                 // It's likely that they are animating and caching may not help here
                 // anyway. In the future we should relax this a bit, so that we can
                 // cache tasks with complex coordinate systems if we detect the
                 // relevant transforms haven't changed from frame to frame.
-                let surface = if pic_state_for_children.has_non_root_coord_system {
-                    let picture_task = RenderTask::new_picture(
-                        RenderTaskLocation::Dynamic(None, Some(device_rect.size)),
-                        prim_index,
-                        device_rect.origin,
-                        pic_state_for_children.tasks,
-                        uv_rect_kind,
-                    );
-
-                    let picture_task_id = frame_state.render_tasks.add(picture_task);
-
-                    let blur_render_task = RenderTask::new_blur(
-                        blur_std_deviation,
-                        picture_task_id,
-                        frame_state.render_tasks,
-                        RenderTargetKind::Color,
-                        ClearMode::Transparent,
-                    );
-
-                    let render_task_id = frame_state.render_tasks.add(blur_render_task);
-
-                    pic_state.tasks.push(render_task_id);
-
-                    PictureSurface::RenderTask(render_task_id)
+                // If the frame is over its time budget, don't spend time storing
+                // the picture in the texture cache for the benefit of later frames,
+                // but still draw it from there if an earlier frame stored it.
+                let defer_caching = frame_state.deferred_work.contains(DeferredWork::PICTURE_CACHE_WARMING);
+                let cache_item = if pic_state_for_children.has_non_root_coord_system {
+                    None
+                } else if defer_caching {
+                    let cache_item = frame_state.resource_cache
+                        .request_cached_render_task(&cache_key, frame_state.gpu_cache);
+                    if cache_item.is_none() {
+                        frame_state.profile_counters.deferred_picture_caches.inc();
+                    }
+                    cache_item
                 } else {
-                    // Get the relative clipped rect within the overall prim rect, that
-                    // forms part of the cache key.
-                    let pic_relative_render_rect = PictureIntRect::new(
-                        PictureIntPoint::new(
-                            device_rect.origin.x - prim_screen_rect.unclipped.origin.x,
-                            device_rect.origin.y - prim_screen_rect.unclipped.origin.y,
-                        ),
-                        PictureIntSize::new(
-                            device_rect.size.width,
-                            device_rect.size.height,
-                        ),
-                    );
-
                     // Request a render task that will cache the output in the
                     // texture cache.
-                    let cache_item = frame_state.resource_cache.request_render_task(
-                        RenderTaskCacheKey {
-                            size: device_rect.size,
-                            kind: RenderTaskCacheKeyKind::Picture(PictureCacheKey {
-                                picture_id: self.id,
-                                unclipped_size: prim_screen_rect.unclipped.size,
-                                pic_relative_render_rect,
-                            }),
-                        },
+                    Some(frame_state.resource_cache.request_render_task(
+                        cache_key,
                         frame_state.gpu_cache,
                         frame_state.render_tasks,
                         None,
@@ -404,15 +448,57 @@ impl PicturePrimitive {
                                 ClearMode::Transparent,
                             );
 
-                            let render_task_id = render_tasks.add(blur_render_task);
+                            render_tasks.add(blur_render_task)
+                        }
+                    ))
+                };
 
+                let surface = match cache_item {
+                    Some(cache_item) => {
+                        // Pictures sharing this entry are all drawn after it is
+                        // rendered, not just the one that requested the task.
+                        let pending_task_id = frame_state.resource_cache
+                            .get_cached_render_task(&cache_item)
+                            .pending_render_task_id();
+                        if let Some(render_task_id) = pending_task_id {
                             pic_state.tasks.push(render_task_id);
-
-                            render_task_id
                         }
-                    );
 
-                    PictureSurface::TextureCache(cache_item)
+                        frame_state.cached_surfaces.push(CachedSurface {
+                            handle: cache_item.clone(),
+                            device_rect,
+                            content_key: self.content_key,
+                        });
+
+                        PictureSurface::TextureCache(cache_item)
+                    }
+                    None => {
+                        let picture_task = RenderTask::new_picture(
+                            RenderTaskLocation::Dynamic(None, Some(device_rect.size)),
+                            prim_index,
+                            device_rect.origin,
+                            pic_state_for_children.tasks,
+                            uv_rect_kind,
+                        );
+
+                        let picture_task_id = frame_state.render_tasks.add(picture_task);
+                        let (blur_src_task_id, blur_std_deviation) =
+                            reduce_blur_quality(picture_task_id, blur_std_deviation, frame_state);
+
+                        let blur_render_task = RenderTask::new_blur(
+                            blur_std_deviation,
+                            blur_src_task_id,
+                            frame_state.render_tasks,
+                            RenderTargetKind::Color,
+                            ClearMode::Transparent,
+                        );
+
+                        let render_task_id = frame_state.render_tasks.add(blur_render_task);
+
+                        pic_state.tasks.push(render_task_id);
+
+                        PictureSurface::RenderTask(render_task_id)
+                    }
                 };
 
                 self.surface = Some(surface);
@@ -452,10 +538,12 @@ impl PicturePrimitive {
                 picture_task.mark_for_saving();
 
                 let picture_task_id = frame_state.render_tasks.add(picture_task);
+                let (blur_src_task_id, blur_std_deviation) =
+                    reduce_blur_quality(picture_task_id, blur_std_deviation, frame_state);
 
                 let blur_render_task = RenderTask::new_blur(
                     blur_std_deviation.round(),
-                    picture_task_id,
+                    blur_src_task_id,
                     frame_state.render_tasks,
                     RenderTargetKind::Color,
                     ClearMode::Transparent,
@@ -529,6 +617,39 @@ impl PicturePrimitive {
                 pic_state.tasks.push(render_task_id);
                 self.surface = Some(PictureSurface::RenderTask(render_task_id));
             }
+            Some(PictureCompositeMode::Filter(FilterOp::Downscale(factor))) => {
+                let uv_rect_kind = calculate_uv_rect_kind(
+                    &prim_metadata.local_rect,
+                    &prim_run_context.scroll_node,
+                    &prim_screen_rect.clipped,
+                    frame_context.device_pixel_scale,
+                );
+
+                let picture_task = RenderTask::new_picture(
+                    RenderTaskLocation::Dynamic(None, Some(prim_screen_rect.clipped.size)),
+                    prim_index,
+                    prim_screen_rect.clipped.origin,
+                    pic_state_for_children.tasks,
+                    uv_rect_kind,
+                );
+
+                // Shrink the picture in steps of at most a factor of two, so
+                // that the bilinear sample taken by each scaling pass covers
+                // all of the source pixels that map to a destination pixel.
+                let mut render_task_id = frame_state.render_tasks.add(picture_task);
+                for size in downscale_steps(prim_screen_rect.clipped.size, factor) {
+                    let scaling_task = RenderTask::new_scaling(
+                        RenderTargetKind::Color,
+                        render_task_id,
+                        frame_state.render_tasks,
+                        size,
+                    );
+                    render_task_id = frame_state.render_tasks.add(scaling_task);
+                }
+
+                pic_state.tasks.push(render_task_id);
+                self.surface = Some(PictureSurface::RenderTask(render_task_id));
+            }
             Some(PictureCompositeMode::Filter(filter)) => {
                 if let FilterOp::ColorMatrix(m) = filter {
                     if let Some(mut request) = frame_state.gpu_cache.request(&mut self.extra_gpu_data_handle) {
@@ -602,6 +723,63 @@ impl PicturePrimitive {
     }
 }
 
+// When high quality filters are deferred for this frame, blur a half
+// resolution copy of the picture instead, which needs a quarter of the
+// samples. Returns the task to blur, and the std deviation to blur it by.
+fn reduce_blur_quality(
+    src_task_id: RenderTaskId,
+    blur_std_deviation: f32,
+    frame_state: &mut FrameBuildingState,
+) -> (RenderTaskId, f32) {
+    if !frame_state.deferred_work.contains(DeferredWork::HIGH_QUALITY_FILTERS) {
+        return (src_task_id, blur_std_deviation);
+    }
+
+    let size = frame_state.render_tasks[src_task_id].get_dynamic_size();
+    if size.width < MIN_DOWNSCALING_RT_SIZE || size.height < MIN_DOWNSCALING_RT_SIZE {
+        return (src_task_id, blur_std_deviation);
+    }
+
+    frame_state.profile_counters.degraded_filters.inc();
+
+    let scaling_task = RenderTask::new_scaling(
+        RenderTargetKind::Color,
+        src_task_id,
+        frame_state.render_tasks,
+        DeviceIntSize::new(size.width / 2, size.height / 2),
+    );
+
+    (frame_state.render_tasks.add(scaling_task), blur_std_deviation * 0.5)
+}
+
+// The size of the target a picture with a downscale filter is
+// rendered into, rounding up so that no content is dropped.
+fn downscaled_size(size: DeviceIntSize, factor: u32) -> DeviceIntSize {
+    let factor = factor.max(1) as i32;
+    DeviceIntSize::new(
+        ((size.width + factor - 1) / factor).max(1),
+        ((size.height + factor - 1) / factor).max(1),
+    )
+}
+
+// The sizes of the scaling passes needed to go from a full size
+// picture to the downscaled target, halving the size each pass.
+fn downscale_steps(size: DeviceIntSize, factor: u32) -> Vec<DeviceIntSize> {
+    let target_size = downscaled_size(size, factor);
+    let mut steps = Vec::new();
+    let mut current_size = size;
+
+    while current_size != target_size {
+        current_size = DeviceIntSize::new(
+            ((current_size.width + 1) / 2).max(target_size.width),
+            ((current_size.height + 1) / 2).max(target_size.height),
+        );
+        steps.push(current_size);
+    }
+
+    steps
+}
+
 // Calculate a single screen-space UV for a picture.
 fn calculate_screen_uv(
     local_pos: &LayoutPoint,
@@ -672,3 +850,119 @@ fn calculate_uv_rect_kind(
         bottom_right,
     }
 }
+
+#[cfg(test)]
+mod test {
+    use api::{DeviceIntSize, FilterOp, LayoutPoint, LayoutRect, LayoutSize, PipelineId};
+    use api::PropertyBinding;
+    use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex};
+    use prim_store::{PrimitiveIndex, PrimitiveRunLocalRect, ScrollNodeAndClipChain};
+    use scene::FilterOpHelpers;
+    use super::{PictureCompositeMode, PictureId, PicturePrimitive, downscale_steps};
+
+    #[test]
+    fn downscale_allocates_reduced_target() {
+        let size = DeviceIntSize::new(200, 100);
+        assert_eq!(downscale_steps(size, 1), vec![]);
+        assert_eq!(downscale_steps(size, 2), vec![DeviceIntSize::new(100, 50)]);
+        assert_eq!(
+            downscale_steps(size, 3),
+            vec![DeviceIntSize::new(100, 50), DeviceIntSize::new(67, 34)]
+        );
+        assert_eq!(
+            downscale_steps(size, 4),
+            vec![DeviceIntSize::new(100, 50), DeviceIntSize::new(50, 25)]
+        );
+    }
+
+    // Build an off-screen picture for a filter, and return it along with
+    // the local rect it is composited over for some fixed content.
+    fn filtered_picture_rect(filter: FilterOp) -> (PicturePrimitive, LayoutRect, LayoutRect) {
+        let mut picture = PicturePrimitive::new_image(
+            PictureId(0),
+            Some(PictureCompositeMode::Filter(filter)),
+            false,
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            true,
+        );
+        let content_rect = LayoutRect::new(LayoutPoint::new(10.0, 20.0), LayoutSize::new(200.0, 100.0));
+        let local_rect = picture.update_local_rect(PrimitiveRunLocalRect {
+            local_rect_in_actual_parent_space: content_rect,
+            local_rect_in_original_parent_space: content_rect,
+        });
+        (picture, content_rect, local_rect)
+    }
+
+    #[test]
+    fn only_pictures_drawn_into_their_parent_allow_subpixel_aa() {
+        let picture = |composite_mode| {
+            PicturePrimitive::new_image(
+                PictureId(0),
+                composite_mode,
+                false,
+                PipelineId::dummy(),
+                ClipScrollNodeIndex(0),
+                None,
+                true,
+            )
+        };
+        let opacity = |amount| {
+            let filter = FilterOp::Opacity(PropertyBinding::Value(amount), amount);
+            Some(PictureCompositeMode::Filter(filter))
+        };
+
+        assert!(picture(None).allow_subpixel_aa());
+        assert!(picture(opacity(1.0)).allow_subpixel_aa());
+
+        // Text drawn into an intermediate surface has no known background.
+        assert!(!picture(opacity(0.5)).allow_subpixel_aa());
+        assert!(!picture(Some(PictureCompositeMode::Blit)).allow_subpixel_aa());
+    }
+
+    #[test]
+    fn downscale_composites_at_full_size() {
+        let (picture, content_rect, local_rect) = filtered_picture_rect(FilterOp::Downscale(2));
+        assert!(!picture.can_draw_directly_to_parent_surface());
+        assert_eq!(local_rect, content_rect);
+    }
+
+    #[test]
+    fn posterize_composites_in_a_single_pass() {
+        assert!(FilterOp::Posterize(256).is_noop());
+        assert!(!FilterOp::Posterize(4).is_noop());
+        assert!(!FilterOp::Threshold(1.0).is_noop());
+
+        // Unlike blurs, pointwise filters never sample outside the content.
+        let (picture, content_rect, local_rect) = filtered_picture_rect(FilterOp::Posterize(4));
+        assert!(!picture.can_draw_directly_to_parent_surface());
+        assert_eq!(local_rect, content_rect);
+    }
+
+    #[test]
+    fn explicit_paint_order_draws_after_later_siblings() {
+        let mut picture = PicturePrimitive::new_image(
+            PictureId(0),
+            None,
+            false,
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            true,
+        );
+        let clip_and_scroll =
+            ScrollNodeAndClipChain::new(ClipScrollNodeIndex(0), ClipChainIndex(0));
+
+        picture.add_primitive_with_paint_order(PrimitiveIndex(0), clip_and_scroll, Some(1));
+        picture.add_primitive(PrimitiveIndex(1), clip_and_scroll);
+        picture.add_primitive(PrimitiveIndex(2), clip_and_scroll);
+        picture.sort_runs_by_paint_order();
+
+        let order: Vec<_> = picture.runs
+            .iter()
+            .map(|run| (run.base_prim_index.0, run.count))
+            .collect();
+        assert_eq!(order, vec![(1, 2), (0, 1)]);
+    }
+}
@@ -4,7 +4,8 @@
 
 use api::{AlphaType, BorderRadius, BoxShadowClipMode, BuiltDisplayList, ClipMode, ColorF, ComplexClipRegion};
 use api::{DeviceIntRect, DeviceIntSize, DeviceUintSize, DevicePixelScale, Epoch, ExtendMode, FontRenderMode};
-use api::{FilterOp, GlyphInstance, GlyphKey, GradientStop, ImageKey, ImageRendering, ItemRange, ItemTag, TileOffset};
+use api::{FilterOp, GlyphInstance, GlyphKey, GradientRampKey, GradientStop, ImageKey, ImageRendering, ItemRange};
+use api::{ImageColorSpace, ItemTag, TileOffset};
 use api::{GlyphRasterSpace, LayoutPoint, LayoutRect, LayoutSize, LayoutToWorldTransform, LayoutVector2D};
 use api::{PipelineId, PremultipliedColorF, PropertyBinding, Shadow, YuvColorSpace, YuvFormat, DeviceIntSideOffsets};
 use border::{BorderCornerInstance, BorderEdgeKind};
@@ -37,6 +38,11 @@ use util::{pack_as_float, recycle_vec};
 
 const MIN_BRUSH_SPLIT_AREA: f32 = 256.0 * 256.0;
 
+/// The most primitives an opacity filter is collapsed into. Checking
+/// them for overlap is quadratic, and large groups are better served
+/// by a single intermediate surface anyway.
+const MAX_OPACITY_COLLAPSE_PRIMS: usize = 8;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct ScrollNodeAndClipChain {
     pub scroll_node_id: ClipScrollNodeIndex,
@@ -57,6 +63,8 @@ pub struct PrimitiveRun {
     pub base_prim_index: PrimitiveIndex,
     pub count: usize,
     pub clip_and_scroll: ScrollNodeAndClipChain,
+    /// Runs are drawn in ascending paint order within their picture.
+    pub paint_order: i32,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -85,12 +93,31 @@ pub struct CachedGradientIndex(pub usize);
 
 pub struct CachedGradient {
     pub handle: GpuCacheHandle,
+    /// The ramp whose stops are drawn in place of the display list stops.
+    pub ramp: Option<GradientRampKey>,
+    /// The epoch of the ramp stops currently written to the GPU cache.
+    ramp_epoch: Option<Epoch>,
 }
 
 impl CachedGradient {
     pub fn new() -> CachedGradient {
+        CachedGradient::with_ramp(None)
+    }
+
+    pub fn with_ramp(ramp: Option<GradientRampKey>) -> CachedGradient {
         CachedGradient {
             handle: GpuCacheHandle::new(),
+            ramp,
+            ramp_epoch: None,
+        }
+    }
+
+    /// Invalidate the gradient data if the ramp stops changed since it was
+    /// written. Only the data is rewritten; the primitive is left as is.
+    pub fn update_ramp_epoch(&mut self, ramp_epoch: Option<Epoch>, gpu_cache: &mut GpuCache) {
+        if self.ramp_epoch != ramp_epoch {
+            gpu_cache.invalidate(&self.handle);
+            self.ramp_epoch = ramp_epoch;
         }
     }
 }
@@ -274,6 +301,7 @@ pub enum BrushKind {
         sub_rect: Option<DeviceIntRect>,
         opacity_binding: OpacityBinding,
         visible_tiles: Vec<VisibleImageTile>,
+        color_space: ImageColorSpace,
     },
     YuvImage {
         yuv_key: [ImageKey; 3],
@@ -577,18 +605,14 @@ impl ToGpuBlocks for BorderPrimitiveCpu {
 
 // The gradient entry index for the first color stop
 pub const GRADIENT_DATA_FIRST_STOP: usize = 0;
-// The gradient entry index for the last color stop
-pub const GRADIENT_DATA_LAST_STOP: usize = GRADIENT_DATA_SIZE - 1;
 
 // The start of the gradient data table
 pub const GRADIENT_DATA_TABLE_BEGIN: usize = GRADIENT_DATA_FIRST_STOP + 1;
-// The exclusive bound of the gradient data table
-pub const GRADIENT_DATA_TABLE_END: usize = GRADIENT_DATA_LAST_STOP;
-// The number of entries in the gradient data table.
-pub const GRADIENT_DATA_TABLE_SIZE: usize = 128;
-
-// The number of entries in a gradient data: GRADIENT_DATA_TABLE_SIZE + first stop entry + last stop entry
-pub const GRADIENT_DATA_SIZE: usize = GRADIENT_DATA_TABLE_SIZE + 2;
+// The smallest number of entries in the gradient data table.
+pub const GRADIENT_DATA_TABLE_MIN_SIZE: usize = 128;
+// The largest number of entries in the gradient data table. The whole table, plus the
+// header block and the first/last stop entries, needs to fit in a single GPU cache row.
+pub const GRADIENT_DATA_TABLE_MAX_SIZE: usize = 256;
 
 #[derive(Debug)]
 #[repr(C)]
@@ -614,162 +638,191 @@ impl<'a> GradientGpuBlockBuilder<'a> {
         }
     }
 
-    /// Generate a color ramp filling the indices in [start_idx, end_idx) and interpolating
-    /// from start_color to end_color.
-    fn fill_colors(
-        &self,
-        start_idx: usize,
-        end_idx: usize,
-        start_color: &PremultipliedColorF,
-        end_color: &PremultipliedColorF,
-        entries: &mut [GradientDataEntry; GRADIENT_DATA_SIZE],
-    ) {
-        // Calculate the color difference for individual steps in the ramp.
-        let inv_steps = 1.0 / (end_idx - start_idx) as f32;
-        let step_r = (end_color.r - start_color.r) * inv_steps;
-        let step_g = (end_color.g - start_color.g) * inv_steps;
-        let step_b = (end_color.b - start_color.b) * inv_steps;
-        let step_a = (end_color.a - start_color.a) * inv_steps;
-
-        let mut cur_color = *start_color;
-
-        // Walk the ramp writing start and end colors for each entry.
-        for index in start_idx .. end_idx {
-            let entry = &mut entries[index];
-            entry.start_color = cur_color;
-            cur_color.r += step_r;
-            cur_color.g += step_g;
-            cur_color.b += step_b;
-            cur_color.a += step_a;
-            entry.end_color = cur_color;
-        }
-    }
-
-    /// Compute an index into the gradient entry table based on a gradient stop offset. This
-    /// function maps offsets from [0, 1] to indices in [GRADIENT_DATA_TABLE_BEGIN, GRADIENT_DATA_TABLE_END].
-    #[inline]
-    fn get_index(offset: f32) -> usize {
-        (offset.max(0.0).min(1.0) * GRADIENT_DATA_TABLE_SIZE as f32 +
-            GRADIENT_DATA_TABLE_BEGIN as f32)
-            .round() as usize
-    }
-
     // Build the gradient data from the supplied stops, reversing them if necessary.
     fn build(&self, reverse_stops: bool, request: &mut GpuDataRequest) {
-        let src_stops = self.display_list.get(self.stops_range);
-
-        // Preconditions (should be ensured by DisplayListBuilder):
-        // * we have at least two stops
-        // * first stop has offset 0.0
-        // * last stop has offset 1.0
-
-        let mut src_stops = src_stops.into_iter();
-        let mut cur_color = match src_stops.next() {
-            Some(stop) => {
-                debug_assert_eq!(stop.offset, 0.0);
-                stop.color.premultiplied()
-            }
-            None => {
-                error!("Zero gradient stops found!");
-                PremultipliedColorF::BLACK
-            }
-        };
+        let src_stops: Vec<GradientStop> = self.display_list
+            .get(self.stops_range)
+            .collect();
+        write_gradient_data(&src_stops, reverse_stops, request);
+    }
+}
 
-        // A table of gradient entries, with two colors per entry, that specify the start and end color
-        // within the segment of the gradient space represented by that entry. To lookup a gradient result,
-        // first the entry index is calculated to determine which two colors to interpolate between, then
-        // the offset within that entry bucket is used to interpolate between the two colors in that entry.
-        // This layout preserves hard stops, as the end color for a given entry can differ from the start
-        // color for the following entry, despite them being adjacent. Colors are stored within in BGRA8
-        // format for texture upload. This table requires the gradient color stops to be normalized to the
-        // range [0, 1]. The first and last entries hold the first and last color stop colors respectively,
-        // while the entries in between hold the interpolated color stop values for the range [0, 1].
-        let mut entries: [GradientDataEntry; GRADIENT_DATA_SIZE] = unsafe { mem::uninitialized() };
-
-        if reverse_stops {
-            // Fill in the first entry (for reversed stops) with the first color stop
-            self.fill_colors(
-                GRADIENT_DATA_LAST_STOP,
-                GRADIENT_DATA_LAST_STOP + 1,
-                &cur_color,
-                &cur_color,
-                &mut entries,
-            );
+fn write_gradient_data(
+    src_stops: &[GradientStop],
+    reverse_stops: bool,
+    request: &mut GpuDataRequest,
+) {
+    let entries = build_gradient_data(src_stops, reverse_stops);
 
-            // Fill in the center of the gradient table, generating a color ramp between each consecutive pair
-            // of gradient stops. Each iteration of a loop will fill the indices in [next_idx, cur_idx). The
-            // loop will then fill indices in [GRADIENT_DATA_TABLE_BEGIN, GRADIENT_DATA_TABLE_END).
-            let mut cur_idx = GRADIENT_DATA_TABLE_END;
-            for next in src_stops {
-                let next_color = next.color.premultiplied();
-                let next_idx = Self::get_index(1.0 - next.offset);
-
-                if next_idx < cur_idx {
-                    self.fill_colors(next_idx, cur_idx, &next_color, &cur_color, &mut entries);
-                    cur_idx = next_idx;
-                }
+    // The first block holds the number of entries in the table, so that the
+    // shader knows how to map gradient offsets to table entries.
+    let table_size = entries.len() - 2;
+    request.push([table_size as f32, 0.0, 0.0, 0.0]);
 
-                cur_color = next_color;
-            }
-            if cur_idx != GRADIENT_DATA_TABLE_BEGIN {
-                error!("Gradient stops abruptly at {}, auto-completing to white", cur_idx);
-                self.fill_colors(GRADIENT_DATA_TABLE_BEGIN, cur_idx, &PremultipliedColorF::WHITE, &cur_color, &mut entries);
-            }
+    for entry in entries.iter() {
+        request.push(entry.start_color);
+        request.push(entry.end_color);
+    }
+}
 
-            // Fill in the last entry (for reversed stops) with the last color stop
-            self.fill_colors(
-                GRADIENT_DATA_FIRST_STOP,
-                GRADIENT_DATA_FIRST_STOP + 1,
-                &cur_color,
-                &cur_color,
-                &mut entries,
-            );
-        } else {
-            // Fill in the first entry with the first color stop
-            self.fill_colors(
-                GRADIENT_DATA_FIRST_STOP,
-                GRADIENT_DATA_FIRST_STOP + 1,
-                &cur_color,
-                &cur_color,
-                &mut entries,
-            );
+/// Generate a color ramp filling the indices in [start_idx, end_idx) and interpolating
+/// from start_color to end_color.
+fn fill_gradient_colors(
+    start_idx: usize,
+    end_idx: usize,
+    start_color: &PremultipliedColorF,
+    end_color: &PremultipliedColorF,
+    entries: &mut [GradientDataEntry],
+) {
+    // Calculate the color difference for individual steps in the ramp.
+    let inv_steps = 1.0 / (end_idx - start_idx) as f32;
+    let step_r = (end_color.r - start_color.r) * inv_steps;
+    let step_g = (end_color.g - start_color.g) * inv_steps;
+    let step_b = (end_color.b - start_color.b) * inv_steps;
+    let step_a = (end_color.a - start_color.a) * inv_steps;
+
+    let mut cur_color = *start_color;
+
+    // Walk the ramp writing start and end colors for each entry.
+    for index in start_idx .. end_idx {
+        let entry = &mut entries[index];
+        entry.start_color = cur_color;
+        cur_color.r += step_r;
+        cur_color.g += step_g;
+        cur_color.b += step_b;
+        cur_color.a += step_a;
+        entry.end_color = cur_color;
+    }
+}
 
-            // Fill in the center of the gradient table, generating a color ramp between each consecutive pair
-            // of gradient stops. Each iteration of a loop will fill the indices in [cur_idx, next_idx). The
-            // loop will then fill indices in [GRADIENT_DATA_TABLE_BEGIN, GRADIENT_DATA_TABLE_END).
-            let mut cur_idx = GRADIENT_DATA_TABLE_BEGIN;
-            for next in src_stops {
-                let next_color = next.color.premultiplied();
-                let next_idx = Self::get_index(next.offset);
-
-                if next_idx > cur_idx {
-                    self.fill_colors(cur_idx, next_idx, &cur_color, &next_color, &mut entries);
-                    cur_idx = next_idx;
-                }
+/// Compute an index into the gradient entry table based on a gradient stop offset. This
+/// function maps offsets from [0, 1] to indices in [GRADIENT_DATA_TABLE_BEGIN, table_size + 1].
+#[inline]
+fn gradient_data_index(offset: f32, table_size: usize) -> usize {
+    (offset.max(0.0).min(1.0) * table_size as f32 + GRADIENT_DATA_TABLE_BEGIN as f32)
+        .round() as usize
+}
 
-                cur_color = next_color;
-            }
-            if cur_idx != GRADIENT_DATA_TABLE_END {
-                error!("Gradient stops abruptly at {}, auto-completing to white", cur_idx);
-                self.fill_colors(cur_idx, GRADIENT_DATA_TABLE_END, &PremultipliedColorF::WHITE, &cur_color, &mut entries);
-            }
+/// Pick the number of entries of the gradient data table so that every pair of
+/// stops that are not at the same offset is at least one entry apart, otherwise
+/// closely spaced stops would be quantized into a single entry and blended away.
+/// Stops at the same offset (hard stops) don't need any extra precision since
+/// each entry already has its own start and end color.
+fn gradient_data_table_size(offsets: &[f32]) -> usize {
+    let min_spacing = offsets
+        .windows(2)
+        .map(|pair| pair[1] - pair[0])
+        .filter(|spacing| *spacing > 0.0)
+        .fold(1.0, f32::min);
+
+    let mut table_size = GRADIENT_DATA_TABLE_MIN_SIZE;
+    while (table_size as f32) * min_spacing < 1.0 &&
+          table_size < GRADIENT_DATA_TABLE_MAX_SIZE {
+        table_size *= 2;
+    }
+    table_size
+}
 
+/// Build the table of gradient entries for the given stops. Stop offsets are
+/// clamped to [0, 1] and forced to be non-decreasing, as specified by CSS.
+///
+/// This is a table of gradient entries, with two colors per entry, that specify the start and
+/// end color within the segment of the gradient space represented by that entry. To lookup a
+/// gradient result, first the entry index is calculated to determine which two colors to
+/// interpolate between, then the offset within that entry bucket is used to interpolate
+/// between the two colors in that entry. This layout preserves hard stops, as the end color
+/// for a given entry can differ from the start color for the following entry, despite them
+/// being adjacent. The first and last entries hold the first and last color stop colors
+/// respectively, while the entries in between hold the interpolated color stop values for
+/// the range [0, 1].
+fn build_gradient_data(
+    src_stops: &[GradientStop],
+    reverse_stops: bool,
+) -> Vec<GradientDataEntry> {
+    // Preconditions (should be ensured by DisplayListBuilder):
+    // * we have at least two stops
+    // * first stop has offset 0.0
+    // * last stop has offset 1.0
+    let mut prev_offset = 0.0;
+    let mut stops: Vec<(f32, PremultipliedColorF)> = src_stops
+        .iter()
+        .map(|stop| {
+            let offset = stop.offset.max(prev_offset).min(1.0);
+            prev_offset = offset;
+            (offset, stop.color.premultiplied())
+        })
+        .collect();
 
-            // Fill in the last entry with the last color stop
-            self.fill_colors(
-                GRADIENT_DATA_LAST_STOP,
-                GRADIENT_DATA_LAST_STOP + 1,
-                &cur_color,
-                &cur_color,
-                &mut entries,
-            );
+    if reverse_stops {
+        stops.reverse();
+        for stop in &mut stops {
+            stop.0 = 1.0 - stop.0;
+        }
+    }
+
+    let offsets: Vec<f32> = stops.iter().map(|stop| stop.0).collect();
+    let table_size = gradient_data_table_size(&offsets);
+    let table_end = GRADIENT_DATA_TABLE_BEGIN + table_size;
+    let last_stop = table_end;
+
+    let empty_entry = || GradientDataEntry {
+        start_color: PremultipliedColorF::WHITE,
+        end_color: PremultipliedColorF::WHITE,
+    };
+    let mut entries: Vec<GradientDataEntry> = (0 .. table_size + 2)
+        .map(|_| empty_entry())
+        .collect();
+
+    let mut stops = stops.into_iter();
+    let mut cur_color = match stops.next() {
+        Some((offset, color)) => {
+            debug_assert_eq!(offset, 0.0);
+            color
+        }
+        None => {
+            error!("Zero gradient stops found!");
+            PremultipliedColorF::BLACK
         }
+    };
+
+    // Fill in the first entry with the first color stop
+    fill_gradient_colors(
+        GRADIENT_DATA_FIRST_STOP,
+        GRADIENT_DATA_FIRST_STOP + 1,
+        &cur_color,
+        &cur_color,
+        &mut entries,
+    );
+
+    // Fill in the center of the gradient table, generating a color ramp between each consecutive pair
+    // of gradient stops. Each iteration of a loop will fill the indices in [cur_idx, next_idx). The
+    // loop will then fill indices in [GRADIENT_DATA_TABLE_BEGIN, table_end).
+    let mut cur_idx = GRADIENT_DATA_TABLE_BEGIN;
+    for (offset, next_color) in stops {
+        let next_idx = gradient_data_index(offset, table_size);
 
-        for entry in entries.iter() {
-            request.push(entry.start_color);
-            request.push(entry.end_color);
+        if next_idx > cur_idx {
+            fill_gradient_colors(cur_idx, next_idx, &cur_color, &next_color, &mut entries);
+            cur_idx = next_idx;
         }
+
+        cur_color = next_color;
+    }
+    if cur_idx != table_end {
+        error!("Gradient stops abruptly at {}, auto-completing to white", cur_idx);
+        fill_gradient_colors(cur_idx, table_end, &PremultipliedColorF::WHITE, &cur_color, &mut entries);
     }
+
+    // Fill in the last entry with the last color stop
+    fill_gradient_colors(
+        last_stop,
+        last_stop + 1,
+        &cur_color,
+        &cur_color,
+        &mut entries,
+    );
+
+    entries
 }
 
 #[derive(Debug, Clone)]
@@ -1299,67 +1352,73 @@ impl PrimitiveStore {
         PrimitiveIndex(prim_index)
     }
 
-    // Internal method that retrieves the primitive index of a primitive
-    // that can be the target for collapsing parent opacity filters into.
-    fn get_opacity_collapse_prim(
+    // Internal method that collects the primitives that can be the target
+    // for collapsing parent opacity filters into. Returns false if any
+    // primitive in the picture (or its pass-through children) can't
+    // take the opacity itself.
+    fn get_opacity_collapse_prims(
         &self,
         pic_index: PictureIndex,
-    ) -> Option<PrimitiveIndex> {
+        prims: &mut Vec<(PrimitiveIndex, ClipScrollNodeIndex)>,
+    ) -> bool {
         let pic = &self.pictures[pic_index.0];
 
-        // We can only collapse opacity if there is a single primitive, otherwise
-        // the opacity needs to be applied to the primitives as a group.
-        if pic.runs.len() != 1 {
-            return None;
-        }
-
-        let run = &pic.runs[0];
-        if run.count != 1 {
-            return None;
-        }
-
-        let prim_metadata = &self.cpu_metadata[run.base_prim_index.0];
-
-        // For now, we only support opacity collapse on solid rects and images.
-        // This covers the most common types of opacity filters that can be
-        // handled by this optimization. In the future, we can easily extend
-        // this to other primitives, such as text runs and gradients.
-        match prim_metadata.prim_kind {
-            PrimitiveKind::Brush => {
-                let brush = &self.cpu_brushes[prim_metadata.cpu_prim_index.0];
-                match brush.kind {
-                    BrushKind::Picture { pic_index, .. } => {
-                        let pic = &self.pictures[pic_index.0];
-                        // If we encounter a picture that is a pass-through
-                        // (i.e. no composite mode), then we can recurse into
-                        // that to try and find a primitive to collapse to.
-                        if pic.composite_mode.is_none() {
-                            return self.get_opacity_collapse_prim(pic_index);
+        for run in &pic.runs {
+            for i in 0 .. run.count {
+                let prim_index = PrimitiveIndex(run.base_prim_index.0 + i);
+                let prim_metadata = &self.cpu_metadata[prim_index.0];
+
+                // For now, we only support opacity collapse on solid rects and images.
+                // This covers the most common types of opacity filters that can be
+                // handled by this optimization. In the future, we can easily extend
+                // this to other primitives, such as text runs and gradients.
+                let collapsible = match prim_metadata.prim_kind {
+                    PrimitiveKind::Brush => {
+                        let brush = &self.cpu_brushes[prim_metadata.cpu_prim_index.0];
+                        match brush.kind {
+                            BrushKind::Picture { pic_index, .. } => {
+                                // If we encounter a picture that is a pass-through
+                                // (i.e. no composite mode), such as a lone child
+                                // stacking context, then we can recurse into that
+                                // to try and find primitives to collapse to. Layer
+                                // effects only get a composite mode when the frame
+                                // is built, so they can't be looked into.
+                                let pic = &self.pictures[pic_index.0];
+                                pic.composite_mode.is_none() &&
+                                    pic.layer_effect_pipeline_id.is_none() &&
+                                    self.get_opacity_collapse_prims(pic_index, prims)
+                            }
+                            // If we find a rect or image, we can use that as a
+                            // primitive to collapse the opacity into.
+                            BrushKind::Solid { .. } | BrushKind::Image { .. } => {
+                                prims.push((prim_index, run.clip_and_scroll.scroll_node_id));
+                                true
+                            }
+                            BrushKind::Border { .. } |
+                            BrushKind::YuvImage { .. } |
+                            BrushKind::LinearGradient { .. } |
+                            BrushKind::RadialGradient { .. } |
+                            BrushKind::Clear => false,
                         }
                     }
-                    // If we find a single rect or image, we can use that
-                    // as the primitive to collapse the opacity into.
-                    BrushKind::Solid { .. } | BrushKind::Image { .. } => {
-                        return Some(run.base_prim_index)
-                    }
-                    BrushKind::Border { .. } |
-                    BrushKind::YuvImage { .. } |
-                    BrushKind::LinearGradient { .. } |
-                    BrushKind::RadialGradient { .. } |
-                    BrushKind::Clear => {}
+                    PrimitiveKind::TextRun |
+                    PrimitiveKind::Border => false,
+                };
+
+                if !collapsible || prims.len() > MAX_OPACITY_COLLAPSE_PRIMS {
+                    return false;
                 }
             }
-            PrimitiveKind::TextRun |
-            PrimitiveKind::Border => {}
         }
 
-        None
+        true
     }
 
     // Apply any optimizations to drawing this picture. Currently,
     // we just support collapsing pictures with an opacity filter
-    // by pushing that opacity value into the color of a primitive
-    // if that picture contains one compatible primitive.
+    // by pushing that opacity value into the color of the primitives
+    // if that picture only contains compatible primitives that don't
+    // overlap each other.
     pub fn optimize_picture_if_possible(
         &mut self,
         pic_index: PictureIndex,
@@ -1374,16 +1433,34 @@ impl PrimitiveStore {
             }
         };
 
-        // See if this picture contains a single primitive that supports
+        // See if this picture only contains primitives that support
         // opacity collapse.
-        if let Some(prim_index) = self.get_opacity_collapse_prim(pic_index) {
+        let mut prims = Vec::new();
+        if !self.get_opacity_collapse_prims(pic_index, &mut prims) || prims.is_empty() {
+            return;
+        }
+
+        // Applying the opacity to each primitive separately is only the same
+        // as applying it to the group if none of them blend with each other.
+        // Local rects are only comparable within the same spatial node.
+        for (i, &(prim_index, scroll_node_id)) in prims.iter().enumerate() {
+            let local_rect = &self.cpu_metadata[prim_index.0].local_rect;
+            for &(other_index, other_scroll_node_id) in &prims[i + 1 ..] {
+                if other_scroll_node_id != scroll_node_id ||
+                   local_rect.intersects(&self.cpu_metadata[other_index.0].local_rect) {
+                    return;
+                }
+            }
+        }
+
+        for (prim_index, _) in prims {
             let prim_metadata = &self.cpu_metadata[prim_index.0];
             match prim_metadata.prim_kind {
                 PrimitiveKind::Brush => {
                     let brush = &mut self.cpu_brushes[prim_metadata.cpu_prim_index.0];
 
-                    // By this point, we know we should only have found a primitive
-                    // that supports opacity collapse.
+                    // By this point, we know we should only have found primitives
+                    // that support opacity collapse.
                     match brush.kind {
                         BrushKind::Solid { ref mut opacity_binding, .. } |
                         BrushKind::Image { ref mut opacity_binding, .. } => {
@@ -1404,14 +1481,14 @@ impl PrimitiveStore {
                     unreachable!("bug: invalid prim type for opacity collapse");
                 }
             }
-
-            // The opacity filter has been collapsed, so mark this picture
-            // as a pass though. This means it will no longer allocate an
-            // intermediate surface or incur an extra blend / blit. Instead,
-            // the collapsed primitive will be drawn directly into the
-            // parent picture.
-            self.pictures[pic_index.0].composite_mode = None;
         }
+
+        // The opacity filter has been collapsed, so mark this picture
+        // as a pass though. This means it will no longer allocate an
+        // intermediate surface or incur an extra blend / blit. Instead,
+        // the collapsed primitives will be drawn directly into the
+        // parent picture.
+        self.pictures[pic_index.0].composite_mode = None;
     }
 
     pub fn get_metadata(&self, index: PrimitiveIndex) -> &PrimitiveMetadata {
@@ -1422,6 +1499,39 @@ impl PrimitiveStore {
         self.cpu_metadata.len()
     }
 
+    /// The pipelines that have primitives in the scene, none of which were
+    /// visible in the last frame. Pictures don't count as primitives here,
+    /// since they are only visible through their content.
+    pub fn fully_clipped_pipelines(&self) -> Vec<PipelineId> {
+        let mut pipelines: Vec<(PipelineId, bool)> = Vec::new();
+
+        for pic in &self.pictures {
+            for run in &pic.runs {
+                for prim_index in run.base_prim_index.0 .. run.base_prim_index.0 + run.count {
+                    let metadata = &self.cpu_metadata[prim_index];
+                    if let PrimitiveKind::Brush = metadata.prim_kind {
+                        if let BrushKind::Picture { .. } =
+                            self.cpu_brushes[metadata.cpu_prim_index.0].kind {
+                            continue;
+                        }
+                    }
+
+                    let is_visible = metadata.screen_rect.is_some();
+                    match pipelines.iter_mut().find(|entry| entry.0 == pic.pipeline_id) {
+                        Some(entry) => entry.1 |= is_visible,
+                        None => pipelines.push((pic.pipeline_id, is_visible)),
+                    }
+                }
+            }
+        }
+
+        pipelines
+            .into_iter()
+            .filter(|&(_, is_visible)| !is_visible)
+            .map(|(pipeline_id, _)| pipeline_id)
+            .collect()
+    }
+
     fn prepare_prim_for_render_inner(
         &mut self,
         prim_index: PrimitiveIndex,
@@ -2574,16 +2684,24 @@ fn build_gradient_stops_request(
     frame_state: &mut FrameBuildingState,
     pic_context: &PictureContext
 ) {
-    let stops_handle = &mut frame_state.cached_gradients[gradient_index.0].handle;
-    if let Some(mut request) = frame_state.gpu_cache.request(stops_handle) {
-        let gradient_builder = GradientGpuBlockBuilder::new(
-            stops_range,
-            pic_context.display_list,
-        );
-        gradient_builder.build(
-            reverse_stops,
-            &mut request,
-        );
+    let cached_gradient = &mut frame_state.cached_gradients[gradient_index.0];
+    let ramp = cached_gradient.ramp.and_then(|key| frame_state.resource_cache.get_gradient_ramp(key));
+    cached_gradient.update_ramp_epoch(ramp.map(|ramp| ramp.epoch), frame_state.gpu_cache);
+
+    if let Some(mut request) = frame_state.gpu_cache.request(&mut cached_gradient.handle) {
+        match ramp {
+            Some(ramp) => write_gradient_data(&ramp.stops, reverse_stops, &mut request),
+            None => {
+                let gradient_builder = GradientGpuBlockBuilder::new(
+                    stops_range,
+                    pic_context.display_list,
+                );
+                gradient_builder.build(
+                    reverse_stops,
+                    &mut request,
+                );
+            }
+        }
     }
 }
 
@@ -2768,3 +2886,203 @@ impl<'a> GpuDataRequest<'a> {
         self.push(extra_data);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use api::{ColorF, Epoch, FilterOp, GradientRampKey, GradientStop, IdNamespace, LayoutPoint};
+    use api::{LayoutRect, LayoutSize, PipelineId, PropertyBinding};
+    use clip_scroll_tree::{ClipChainIndex, ClipScrollNodeIndex};
+    use gpu_cache::GpuCache;
+    use picture::PictureCompositeMode;
+    use scene::SceneProperties;
+    use super::{BrushKind, BrushPrimitive, CachedGradient, PictureIndex, PrimitiveContainer};
+    use super::{PrimitiveIndex, write_gradient_data};
+    use super::{PrimitiveStore, ScrollNodeAndClipChain};
+    use super::{GRADIENT_DATA_TABLE_MIN_SIZE, build_gradient_data, gradient_data_table_size};
+
+    fn stop(offset: f32, color: ColorF) -> GradientStop {
+        GradientStop { offset, color }
+    }
+
+    #[test]
+    fn hard_stop_is_not_blended() {
+        let red = ColorF::new(1.0, 0.0, 0.0, 1.0);
+        let blue = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        let stops = [
+            stop(0.0, red),
+            stop(0.5, red),
+            stop(0.5, blue),
+            stop(1.0, blue),
+        ];
+
+        let entries = build_gradient_data(&stops, false);
+        assert_eq!(entries.len(), GRADIENT_DATA_TABLE_MIN_SIZE + 2);
+
+        // The hard stop lands between two adjacent entries: the one before it
+        // ends with the first color and the one after it starts with the second.
+        let hard_stop = 1 + GRADIENT_DATA_TABLE_MIN_SIZE / 2;
+        assert_eq!(entries[hard_stop - 1].start_color, red.premultiplied());
+        assert_eq!(entries[hard_stop - 1].end_color, red.premultiplied());
+        assert_eq!(entries[hard_stop].start_color, blue.premultiplied());
+        assert_eq!(entries[hard_stop].end_color, blue.premultiplied());
+
+        // Reversing the stops mirrors the transition.
+        let entries = build_gradient_data(&stops, true);
+        assert_eq!(entries[hard_stop - 1].end_color, blue.premultiplied());
+        assert_eq!(entries[hard_stop].start_color, red.premultiplied());
+    }
+
+    // Requests the gradient data, returning true if it had to be written.
+    fn request_gradient_data(
+        gpu_cache: &mut GpuCache,
+        gradient: &mut CachedGradient,
+        stops: &[GradientStop],
+    ) -> bool {
+        let written = match gpu_cache.request(&mut gradient.handle) {
+            Some(mut request) => {
+                write_gradient_data(stops, false, &mut request);
+                true
+            }
+            None => false,
+        };
+        gpu_cache.extract_updates();
+        written
+    }
+
+    #[test]
+    fn animating_ramp_only_rewrites_gradient_data() {
+        let red = ColorF::new(1.0, 0.0, 0.0, 1.0);
+        let blue = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        let stops = [stop(0.0, red), stop(1.0, blue)];
+
+        let mut gpu_cache = GpuCache::new();
+        let mut gradient = CachedGradient::with_ramp(Some(GradientRampKey(IdNamespace(0), 1)));
+
+        gpu_cache.begin_frame();
+        gradient.update_ramp_epoch(Some(Epoch(0)), &mut gpu_cache);
+        assert!(request_gradient_data(&mut gpu_cache, &mut gradient, &stops));
+
+        // Frames without a ramp update reuse the gradient data.
+        gpu_cache.begin_frame();
+        gradient.update_ramp_epoch(Some(Epoch(0)), &mut gpu_cache);
+        assert!(!request_gradient_data(&mut gpu_cache, &mut gradient, &stops));
+
+        // Updating the ramp rewrites the data of the same cached gradient.
+        let stops = [stop(0.0, blue), stop(1.0, red)];
+        gpu_cache.begin_frame();
+        gradient.update_ramp_epoch(Some(Epoch(1)), &mut gpu_cache);
+        assert!(request_gradient_data(&mut gpu_cache, &mut gradient, &stops));
+    }
+
+    #[test]
+    fn table_size_follows_stop_spacing() {
+        assert_eq!(gradient_data_table_size(&[0.0, 0.5, 0.5, 1.0]), GRADIENT_DATA_TABLE_MIN_SIZE);
+        assert_eq!(gradient_data_table_size(&[0.0, 0.004, 1.0]), 2 * GRADIENT_DATA_TABLE_MIN_SIZE);
+    }
+
+    fn add_rect(
+        store: &mut PrimitiveStore,
+        pic_index: PictureIndex,
+        rect: LayoutRect,
+    ) -> PrimitiveIndex {
+        let prim_index = store.add_primitive(
+            &rect,
+            &LayoutRect::max_rect(),
+            true,
+            None,
+            None,
+            PrimitiveContainer::Brush(BrushPrimitive::new(
+                BrushKind::new_solid(ColorF::new(1.0, 0.0, 0.0, 1.0)),
+                None,
+            )),
+        );
+        let clip_and_scroll =
+            ScrollNodeAndClipChain::new(ClipScrollNodeIndex(0), ClipChainIndex(0));
+        store.pictures[pic_index.0].add_primitive(prim_index, clip_and_scroll);
+        prim_index
+    }
+
+    // Builds an opacity group holding a single pass-through child stacking
+    // context with two rects, returning the group and the rects.
+    fn opacity_group(
+        store: &mut PrimitiveStore,
+        second_rect: LayoutRect,
+    ) -> (PictureIndex, [PrimitiveIndex; 2]) {
+        let opacity = FilterOp::Opacity(PropertyBinding::Value(0.5), 0.5);
+        let group_index = store.add_image_picture(
+            Some(PictureCompositeMode::Filter(opacity)),
+            false,
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            true,
+        );
+        let child_index = store.add_image_picture(
+            None,
+            false,
+            PipelineId::dummy(),
+            ClipScrollNodeIndex(0),
+            None,
+            true,
+        );
+        let child_prim = store.add_primitive(
+            &LayoutRect::zero(),
+            &LayoutRect::max_rect(),
+            true,
+            None,
+            None,
+            PrimitiveContainer::Brush(BrushPrimitive::new_picture(child_index)),
+        );
+        let clip_and_scroll =
+            ScrollNodeAndClipChain::new(ClipScrollNodeIndex(0), ClipChainIndex(0));
+        store.pictures[group_index.0].add_primitive(child_prim, clip_and_scroll);
+
+        let first_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 100.0));
+        let rects = [
+            add_rect(store, child_index, first_rect),
+            add_rect(store, child_index, second_rect),
+        ];
+        (group_index, rects)
+    }
+
+    fn collapsed_opacity(store: &mut PrimitiveStore, prim_index: PrimitiveIndex) -> f32 {
+        let cpu_prim_index = store.cpu_metadata[prim_index.0].cpu_prim_index;
+        match store.cpu_brushes[cpu_prim_index.0].kind {
+            BrushKind::Solid { ref mut opacity_binding, .. } => {
+                opacity_binding.update(&SceneProperties::new());
+                opacity_binding.current
+            }
+            _ => panic!("expected a solid rect"),
+        }
+    }
+
+    #[test]
+    fn opacity_collapses_into_non_overlapping_child_stacking_context() {
+        let mut store = PrimitiveStore::new();
+        let (group_index, rects) = opacity_group(
+            &mut store,
+            LayoutRect::new(LayoutPoint::new(100.0, 0.0), LayoutSize::new(100.0, 100.0)),
+        );
+        store.optimize_picture_if_possible(group_index);
+
+        // The group no longer needs a render target, and each of the child's
+        // draw items carries the opacity instead.
+        assert!(store.pictures[group_index.0].composite_mode.is_none());
+        for &prim_index in &rects {
+            assert_eq!(collapsed_opacity(&mut store, prim_index), 0.5);
+        }
+
+        // Overlapping content has to be blended as a group.
+        let mut store = PrimitiveStore::new();
+        let (group_index, rects) = opacity_group(
+            &mut store,
+            LayoutRect::new(LayoutPoint::new(50.0, 50.0), LayoutSize::new(100.0, 100.0)),
+        );
+        store.optimize_picture_if_possible(group_index);
+
+        assert!(store.pictures[group_index.0].composite_mode.is_some());
+        for &prim_index in &rects {
+            assert_eq!(collapsed_opacity(&mut store, prim_index), 1.0);
+        }
+    }
+}
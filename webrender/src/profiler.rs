@@ -317,6 +317,8 @@ pub struct FrameProfileCounters {
     pub targets_used: IntProfileCounter,
     pub targets_changed: IntProfileCounter,
     pub targets_created: IntProfileCounter,
+    pub degraded_filters: IntProfileCounter,
+    pub deferred_picture_caches: IntProfileCounter,
 }
 
 impl FrameProfileCounters {
@@ -327,6 +329,8 @@ impl FrameProfileCounters {
             targets_used: IntProfileCounter::new("Used targets"),
             targets_changed: IntProfileCounter::new("Changed targets"),
             targets_created: IntProfileCounter::new("Created targets"),
+            degraded_filters: IntProfileCounter::new("Degraded filters"),
+            deferred_picture_caches: IntProfileCounter::new("Deferred picture caches"),
         }
     }
     pub fn reset_targets(&mut self) {
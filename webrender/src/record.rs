@@ -82,7 +82,16 @@ pub fn should_record_msg(msg: &ApiMsg) -> bool {
             for msg in &msgs.frame_ops {
                 match *msg {
                     FrameMsg::GetScrollNodeState(..) |
-                    FrameMsg::HitTest(..) => {}
+                    FrameMsg::GetScrollTree(..) |
+                    FrameMsg::GetFramebufferReads(..) |
+                    FrameMsg::GetFullyClippedPipelines(..) |
+                    FrameMsg::GetDeviceScrollOffset(..) |
+                    FrameMsg::GetScrollFrameWorldTransform(..) |
+                    FrameMsg::GetScrollFrameClipRects(..) |
+                    FrameMsg::IsFrameDirty(..) |
+                    FrameMsg::HitTest(..) |
+                    FrameMsg::ScrollNodeTo(..) |
+                    FrameMsg::GetItemVisibleRect(..) => {}
                     _ => return true,
                 }
             }
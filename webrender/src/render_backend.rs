@@ -2,23 +2,28 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
 
-use api::{ApiMsg, BuiltDisplayList, ClearCache, DebugCommand};
+use api::{ApiMsg, BuiltDisplayList, ClearCache, ColorF, DebugCommand};
 #[cfg(feature = "debugger")]
 use api::{BuiltDisplayListIter, SpecificDisplayItem};
-use api::{DeviceIntPoint, DevicePixelScale, DeviceUintPoint, DeviceUintRect, DeviceUintSize};
+use api::{DeviceIntPoint, DevicePixelRatio, DevicePixelScale, DeviceUintPoint, DeviceUintRect};
+use api::DeviceUintSize;
 use api::{DocumentId, DocumentLayer, ExternalScrollId, FrameMsg, HitTestFlags, HitTestResult};
-use api::{IdNamespace, LayoutPoint, PipelineId, RenderNotifier, SceneMsg, ScrollClamping};
-use api::{ScrollLocation, ScrollNodeState, TransactionMsg};
+use api::{IdNamespace, ItemTag, LayoutPoint, PipelineId, PresentationBlendMode, RenderNotifier};
+use api::{LayerEffect, LayoutSize, LayoutVector2D, OutputTonemap, SceneMsg, ScrollClamping};
+use api::{ResourceUpdates, ScrollLocation, ScrollNodeState, TransactionMsg, WorldPoint};
+use api::{LayoutRect, WorldRect};
 use api::channel::{MsgReceiver, Payload};
 #[cfg(feature = "capture")]
 use api::CaptureBits;
 #[cfg(feature = "replay")]
 use api::CapturedDocument;
-use clip_scroll_tree::{ClipScrollNodeIndex, ClipScrollTree};
+use clip_scroll_tree::{ClipScrollNodeIndex, ClipScrollTree, ScrollStates};
+use clip_scroll_tree::SCROLL_GLIDE_FRAME_DURATION;
+use clip_scroll_tree::root_reference_frame_transform;
 #[cfg(feature = "debugger")]
 use debug_server;
-use display_list_flattener::DisplayListFlattener;
-use frame_builder::{FrameBuilder, FrameBuilderConfig};
+use display_list_flattener::{DisplayListFlattener, StackingContextPreview};
+use frame_builder::{FrameBuilder, FrameBuilderConfig, FrameTimeBudget};
 use gpu_cache::GpuCache;
 use hit_test::{HitTest, HitTester};
 use internal_types::{DebugOutput, FastHashMap, FastHashSet, RenderedDocument, ResultMsg};
@@ -42,7 +47,8 @@ use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
 use std::mem::replace;
 use std::sync::mpsc::{channel, Sender, Receiver};
 use std::u32;
-use tiling::Frame;
+use std::time::Instant;
+use tiling::{Frame, FrameHighlight, FrameSnapshot};
 use time::precise_time_ns;
 
 #[cfg_attr(feature = "capture", derive(Serialize))]
@@ -53,19 +59,46 @@ pub struct DocumentView {
     pub inner_rect: DeviceUintRect,
     pub layer: DocumentLayer,
     pub pan: DeviceIntPoint,
-    pub device_pixel_ratio: f32,
+    pub presentation_blend_mode: PresentationBlendMode,
+    pub device_pixel_ratio: DevicePixelRatio,
     pub page_zoom_factor: f32,
     pub pinch_zoom_factor: f32,
+    pub output_tonemap: OutputTonemap,
 }
 
 impl DocumentView {
     pub fn accumulated_scale_factor(&self) -> DevicePixelScale {
         DevicePixelScale::new(
-            self.device_pixel_ratio *
+            self.device_pixel_ratio.scale() *
             self.page_zoom_factor *
             self.pinch_zoom_factor
         )
     }
+
+    /// Keeps content of the given size on screen. The pinch zoom can't go
+    /// below the scale at which the content fits the inner rect, and the pan
+    /// can't move an edge of the content past the center of the inner rect.
+    /// Each axis of the content is scaled by its own device pixel ratio.
+    pub fn clamp_pinch_zoom(&mut self, content_size: LayoutSize) {
+        let ratio = self.device_pixel_ratio;
+        let unzoomed_width = content_size.width * ratio.x * self.page_zoom_factor;
+        let unzoomed_height = content_size.height * ratio.y * self.page_zoom_factor;
+        let viewport_size = self.inner_rect.size.to_f32();
+        let fit_scale = (viewport_size.width / unzoomed_width)
+            .min(viewport_size.height / unzoomed_height);
+        if fit_scale.is_finite() {
+            self.pinch_zoom_factor = self.pinch_zoom_factor.max(fit_scale.min(1.0));
+        }
+
+        let zoomed_width = unzoomed_width * self.pinch_zoom_factor;
+        let zoomed_height = unzoomed_height * self.pinch_zoom_factor;
+        let center_x = viewport_size.width / 2.0;
+        let center_y = viewport_size.height / 2.0;
+        self.pan = DeviceIntPoint::new(
+            (self.pan.x as f32).max(center_x - zoomed_width).min(center_x).round() as i32,
+            (self.pan.y as f32).max(center_y - zoomed_height).min(center_y).round() as i32,
+        );
+    }
 }
 
 struct SceneData {
@@ -73,6 +106,22 @@ struct SceneData {
     removed_pipelines: Vec<PipelineId>,
 }
 
+/// A pipeline drawn over the content of a document, in a frame of its own.
+struct Overlay {
+    pipeline_id: PipelineId,
+    /// The overlay flattened on its own. It's dropped when the scene or the
+    /// window changes, and flattened again when the overlay is next rendered.
+    built: Option<BuiltOverlay>,
+}
+
+struct BuiltOverlay {
+    clip_scroll_tree: ClipScrollTree,
+    frame_builder: FrameBuilder,
+    /// The pipelines the overlay was flattened from, and the epochs of those
+    /// it draws.
+    scene: Scene,
+}
+
 #[derive(Copy, Clone, Hash, PartialEq, PartialOrd, Debug, Eq, Ord)]
 #[cfg_attr(feature = "capture", derive(Serialize))]
 #[cfg_attr(feature = "replay", derive(Deserialize))]
@@ -104,6 +153,9 @@ struct Document {
     // A set of pipelines that the caller has requested be
     // made available as output textures.
     output_pipelines: FastHashSet<PipelineId>,
+    // A set of pipelines that the caller has requested to
+    // read the rendered pixels of.
+    readback_pipelines: FastHashSet<PipelineId>,
     // A helper switch to prevent any frames rendering triggered by scrolling
     // messages between `SetDisplayList` and `GenerateFrame`.
     // If we allow them, then a reftest that scrolls a few layers before generating
@@ -123,6 +175,35 @@ struct Document {
     /// Properties that are resolved during frame building and can be changed at any time
     /// without requiring the scene to be re-built.
     dynamic_properties: SceneProperties,
+
+    /// True if anything that affects the output of `render` (the scene, scroll offsets,
+    /// zoom, dynamic properties or resources) changed since the last frame was built.
+    frame_is_dirty: bool,
+
+    /// The structure of the last frame that was built, if the frame builder
+    /// config asks for it to be retained.
+    last_frame_snapshot: Option<FrameSnapshot>,
+
+    /// The scroll frame that receives scrolls regardless of where the
+    /// cursor is, e.g. for the duration of a drag.
+    scroll_capture: Option<ExternalScrollId>,
+
+    /// The scroll frame whose viewport is highlighted in rendered frames,
+    /// and the color it's highlighted with.
+    highlighted_scroll_frame: Option<(ExternalScrollId, ColorF)>,
+
+    /// Whether a frame with any content was rendered yet.
+    has_rendered_content: bool,
+
+    /// When scrolling was last advanced for a new frame.
+    last_scroll_tick: Option<Instant>,
+
+    /// The stacking context to render a preview of once the transaction is
+    /// processed, and the size of the target to render it into.
+    pending_preview: Option<(StackingContextPreview, DeviceUintSize)>,
+
+    /// Pipelines drawn over the content of the document, bottom-most first.
+    overlays: Vec<Overlay>,
 }
 
 impl Document {
@@ -152,24 +233,82 @@ impl Document {
                 inner_rect: DeviceUintRect::new(DeviceUintPoint::zero(), window_size),
                 layer,
                 pan: DeviceIntPoint::zero(),
+                presentation_blend_mode: PresentationBlendMode::default(),
                 page_zoom_factor: 1.0,
                 pinch_zoom_factor: 1.0,
-                device_pixel_ratio: default_device_pixel_ratio,
+                output_tonemap: OutputTonemap::identity(),
+                device_pixel_ratio: DevicePixelRatio::uniform(default_device_pixel_ratio),
             },
             clip_scroll_tree: ClipScrollTree::new(),
             frame_id: FrameId(0),
             frame_builder_config,
             frame_builder: None,
             output_pipelines: FastHashSet::default(),
+            readback_pipelines: FastHashSet::default(),
             render_on_scroll,
             render_on_hittest: false,
             hit_tester: None,
             dynamic_properties: SceneProperties::new(),
+            frame_is_dirty: true,
+            last_frame_snapshot: None,
+            scroll_capture: None,
+            highlighted_scroll_frame: None,
+            has_rendered_content: false,
+            last_scroll_tick: None,
+            pending_preview: None,
+            overlays: Vec::new(),
         }
     }
 
     fn can_render(&self) -> bool { self.frame_builder.is_some() }
 
+    /// Keeps the root pipeline on screen under pinch zoom and pan, if the
+    /// frame builder config asks for it. Called again whenever the view or
+    /// the size of the root content changes.
+    fn clamp_pinch_zoom(&mut self) {
+        if !self.frame_builder_config.clamp_pinch_zoom {
+            return;
+        }
+        let scene = &self.pending.scene;
+        let root_pipeline = scene.root_pipeline_id.and_then(|id| scene.pipelines.get(&id));
+        if let Some(root_pipeline) = root_pipeline {
+            let (pinch_zoom_factor, pan) = (self.view.pinch_zoom_factor, self.view.pan);
+            self.view.clamp_pinch_zoom(root_pipeline.content_size);
+            if self.view.pinch_zoom_factor != pinch_zoom_factor || self.view.pan != pan {
+                self.frame_is_dirty = true;
+            }
+        }
+    }
+
+    /// Enables or disables reading back the pixels of a pipeline. Returns
+    /// true if that changed, in which case the scene has to be built again
+    /// to isolate the pipeline in its own picture, or to stop doing so.
+    fn set_frame_readback(&mut self, pipeline_id: PipelineId, enable: bool) -> bool {
+        let changed = if enable {
+            self.readback_pipelines.insert(pipeline_id)
+        } else {
+            self.readback_pipelines.remove(&pipeline_id)
+        };
+        if changed {
+            self.frame_is_dirty = true;
+        }
+        changed
+    }
+
+    /// Applies the resource updates of a transaction. Any of them, like an
+    /// image becoming ready, can change what the frame draws.
+    fn update_resources(
+        &mut self,
+        resource_cache: &mut ResourceCache,
+        updates: ResourceUpdates,
+        resource_profile: &mut ResourceProfileCounters,
+    ) {
+        if !updates.updates.is_empty() {
+            self.frame_is_dirty = true;
+        }
+        resource_cache.update_resources(updates, resource_profile);
+    }
+
     // TODO: We will probably get rid of this soon and always forward to the scene building thread.
     fn build_scene(&mut self, resource_cache: &mut ResourceCache) {
         let max_texture_size = resource_cache.max_texture_size();
@@ -186,7 +325,7 @@ impl Document {
             return;
         }
 
-        let old_builder = self.frame_builder.take().unwrap_or_else(FrameBuilder::empty);
+        let mut old_builder = self.frame_builder.take().unwrap_or_else(FrameBuilder::empty);
         let root_pipeline_id = match self.pending.scene.root_pipeline_id {
             Some(root_pipeline_id) => root_pipeline_id,
             None => return,
@@ -196,30 +335,39 @@ impl Document {
             return;
         }
 
+        let fading_surfaces = old_builder.take_fading_surfaces();
+
         // The DisplayListFlattener will re-create the up-to-date current scene's pipeline epoch
         // map and clip scroll tree from the information in the pending scene.
         self.current.scene.pipeline_epochs.clear();
         let old_scrolling_states = self.clip_scroll_tree.drain();
 
-        let frame_builder = DisplayListFlattener::create_frame_builder(
+        let mut frame_builder = DisplayListFlattener::create_frame_builder(
             old_builder,
             &self.pending.scene,
             &mut self.clip_scroll_tree,
             resource_cache.get_font_instances(),
             &self.view,
             &self.output_pipelines,
+            &self.readback_pipelines,
             &self.frame_builder_config,
             &mut self.current.scene,
         );
 
-        self.clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(old_scrolling_states);
+        self.clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(
+            old_scrolling_states,
+            self.frame_builder_config.scroll_restore_max_size_ratio,
+        );
 
         if !self.current.removed_pipelines.is_empty() {
             warn!("Built the scene several times without rendering it.");
         }
 
         self.current.removed_pipelines.extend(self.pending.removed_pipelines.drain(..));
+        frame_builder.adopt_fading_surfaces(fading_surfaces);
         self.frame_builder = Some(frame_builder);
+        self.discard_built_overlays();
+        self.frame_is_dirty = true;
 
         // Advance to the next frame.
         self.frame_id.0 += 1;
@@ -251,6 +399,7 @@ impl Document {
                 view: self.view.clone(),
                 font_instances: resource_cache.get_font_instances(),
                 output_pipelines: self.output_pipelines.clone(),
+                readback_pipelines: self.readback_pipelines.clone(),
             })
         } else {
             None
@@ -265,18 +414,32 @@ impl Document {
         }).unwrap();
     }
 
+    /// Builds a frame of the current scene. Any frame time budget is
+    /// measured from `requested_at`, when the transaction asking for the
+    /// frame arrived, so that it includes scene building and resource work.
     fn render(
         &mut self,
         resource_cache: &mut ResourceCache,
         gpu_cache: &mut GpuCache,
         resource_profile: &mut ResourceProfileCounters,
+        requested_at: Instant,
     ) -> RenderedDocument {
         let accumulated_scale_factor = self.view.accumulated_scale_factor();
         let pan = self.view.pan.to_f32() / accumulated_scale_factor;
+        let root_transform = root_reference_frame_transform(
+            pan,
+            self.view.device_pixel_ratio.stretch(),
+        );
         let removed_pipelines = replace(&mut self.current.removed_pipelines, Vec::new());
 
-        let frame = {
+        let mut frame = {
             let frame_builder = self.frame_builder.as_mut().unwrap();
+            let time_budget = frame_builder.config.frame_time_budget.map(|time_budget| {
+                FrameTimeBudget {
+                    time_budget,
+                    start_instant: requested_at,
+                }
+            });
             let frame = frame_builder.build(
                 resource_cache,
                 gpu_cache,
@@ -285,14 +448,42 @@ impl Document {
                 &self.current.scene.pipelines,
                 accumulated_scale_factor,
                 self.view.layer,
-                pan,
+                self.view.presentation_blend_mode,
+                root_transform,
                 &mut resource_profile.texture_cache,
                 &mut resource_profile.gpu_cache,
                 &self.dynamic_properties,
+                time_budget,
             );
             self.hit_tester = Some(frame_builder.create_hit_tester(&self.clip_scroll_tree));
             frame
         };
+        self.frame_is_dirty = false;
+
+        if !self.has_rendered_content && frame.has_content() {
+            frame.is_first_contentful = true;
+            self.has_rendered_content = true;
+        }
+
+        frame.highlight = self.highlighted_scroll_frame.and_then(|(id, color)| {
+            self.clip_scroll_tree
+                .scroll_frame_clip_rects(accumulated_scale_factor)
+                .into_iter()
+                .find(|clip_rect| clip_rect.id == id)
+                .map(|clip_rect| {
+                    let device_rect = clip_rect.world_rect * accumulated_scale_factor;
+                    FrameHighlight { rect: device_rect.round_out().to_i32(), color }
+                })
+        });
+
+        if self.frame_builder_config.retain_frame_snapshot {
+            self.last_frame_snapshot = Some(FrameSnapshot {
+                render_targets: frame.render_targets(),
+                draw_command_count: frame.draw_command_count(),
+                scroll_offsets: self.get_scroll_node_state(),
+                pipeline_epochs: self.current.scene.pipeline_epochs.clone(),
+            });
+        }
 
         self.make_rendered_document(frame, removed_pipelines)
     }
@@ -303,7 +494,8 @@ impl Document {
                 epochs: self.current.scene.pipeline_epochs.clone(),
                 removed_pipelines,
             },
-            frame
+            frame,
+            self.last_frame_snapshot.clone(),
         )
     }
 
@@ -318,7 +510,118 @@ impl Document {
         scroll_location: ScrollLocation,
         scroll_node_index: Option<ClipScrollNodeIndex>,
     ) -> bool {
-        self.clip_scroll_tree.scroll_nearest_scrolling_ancestor(scroll_location, scroll_node_index)
+        let scrolled = self.clip_scroll_tree
+            .scroll_nearest_scrolling_ancestor(scroll_location, scroll_node_index);
+        self.scrolled(scrolled)
+    }
+
+    /// Moves the hit tester along with the scroll frames, so that content is
+    /// hit where it will be drawn even before the next frame is rendered.
+    fn scrolled(&mut self, scrolled: bool) -> bool {
+        self.frame_is_dirty |= scrolled;
+
+        // The tree of a new scene doesn't match the hit tester until it's rendered.
+        if !scrolled || self.render_on_hittest {
+            return scrolled;
+        }
+
+        if let Some(ref mut hit_tester) = self.hit_tester {
+            let accumulated_scale_factor = self.view.accumulated_scale_factor();
+            let pan = self.view.pan.to_f32() / accumulated_scale_factor;
+            let root_transform = root_reference_frame_transform(
+                pan,
+                self.view.device_pixel_ratio.stretch(),
+            );
+            self.clip_scroll_tree.update_transforms(
+                accumulated_scale_factor,
+                root_transform,
+                &self.dynamic_properties,
+            );
+            hit_tester.update_transforms(&self.clip_scroll_tree);
+        }
+        scrolled
+    }
+
+    /// Scrolls the scroll frame that captured scrolling, or else the nearest
+    /// scrolling ancestor of the content under the cursor. Returns true if
+    /// any nodes actually changed position.
+    pub fn scroll(&mut self, scroll_location: ScrollLocation, cursor: WorldPoint) -> bool {
+        let captured_index = self.scroll_capture
+            .and_then(|id| self.clip_scroll_tree.scroll_frame_index(id));
+        let node_index = match (captured_index, &self.hit_tester) {
+            (Some(index), _) => Some(index),
+            (None, &Some(ref hit_tester)) => {
+                let test = HitTest::new(None, cursor, HitTestFlags::empty());
+                hit_tester.find_node_under_point(test)
+            }
+            (None, &None) => return false,
+        };
+        self.scroll_nearest_scrolling_ancestor(scroll_location, node_index)
+    }
+
+    /// Captures scrolling for the scroll frame of the content under the
+    /// cursor, so that the rest of the gesture doesn't hit test again.
+    /// Returns the id of that scroll frame.
+    pub fn begin_scroll(&mut self, cursor: WorldPoint) -> Option<ExternalScrollId> {
+        self.scroll_capture = match self.hit_tester {
+            Some(ref hit_tester) => {
+                let test = HitTest::new(None, cursor, HitTestFlags::empty());
+                let node_index = hit_tester.find_node_under_point(test);
+                self.clip_scroll_tree.nearest_scroll_frame_id(node_index)
+            }
+            None => None,
+        };
+        self.scroll_capture
+    }
+
+    /// Advance the scroll frames that keep moving without input to the time
+    /// `now`. Returns true if any of them moved.
+    fn tick_scrolling(&mut self, now: Instant) -> bool {
+        // Scroll frames that start moving after a pause move by one frame,
+        // rather than by all the time that passed while nothing moved.
+        let dt = self.last_scroll_tick.map_or(SCROLL_GLIDE_FRAME_DURATION, |last_tick| {
+            let elapsed = now - last_tick;
+            elapsed.as_secs() as f32 + elapsed.subsec_nanos() as f32 / 1_000_000_000.0
+        });
+
+        let glided = self.clip_scroll_tree.tick_scroll_glides(dt);
+        let sprung_back = self.clip_scroll_tree.tick_overscroll(dt);
+        let moved = glided || sprung_back;
+        self.last_scroll_tick = if moved { Some(now) } else { None };
+        self.frame_is_dirty |= moved;
+        moved
+    }
+
+    /// The part of the items with the given tag that is visible in the last
+    /// rendered frame, in world space.
+    pub fn item_visible_rect(&self, pipeline_id: PipelineId, tag: ItemTag) -> Option<WorldRect> {
+        let viewport = self.view.inner_rect.to_f32() / self.view.accumulated_scale_factor();
+        self.hit_tester
+            .as_ref()
+            .and_then(|hit_tester| hit_tester.item_visible_rect(pipeline_id, tag, &viewport))
+    }
+
+    /// Makes the cached pictures that overlap a rect of a scroll frame's
+    /// content draw it again on the next frame, while the others stay
+    /// cached. Returns true if any of them did.
+    pub fn invalidate_rect(
+        &mut self,
+        id: ExternalScrollId,
+        rect: LayoutRect,
+        resource_cache: &mut ResourceCache,
+    ) -> bool {
+        let world_rect = match self.clip_scroll_tree.scroll_frame_world_transform(id) {
+            Some(transform) => transform.transform_rect(&rect),
+            None => return false,
+        };
+        let device_rect = (world_rect * self.view.accumulated_scale_factor()).round_out().to_i32();
+
+        let invalidated = match self.frame_builder {
+            Some(ref frame_builder) => frame_builder.invalidate_rect(&device_rect, resource_cache),
+            None => false,
+        };
+        self.frame_is_dirty |= invalidated;
+        invalidated
     }
 
     /// Returns true if the node actually changed position or false otherwise.
@@ -328,7 +631,37 @@ impl Document {
         id: ExternalScrollId,
         clamp: ScrollClamping
     ) -> bool {
-        self.clip_scroll_tree.scroll_node(origin, id, clamp)
+        let scrolled = self.clip_scroll_tree.scroll_node(origin, id, clamp);
+        self.scrolled(scrolled)
+    }
+
+    /// Returns true if the node actually changed position or false otherwise.
+    pub fn scroll_node_by(&mut self, id: ExternalScrollId, delta: LayoutVector2D) -> bool {
+        let scrolled = self.clip_scroll_tree.scroll_node_by(id, delta);
+        self.scrolled(scrolled)
+    }
+
+    pub fn end_scroll_gesture(
+        &mut self,
+        id: ExternalScrollId,
+        snap_offsets_x: &[f32],
+        snap_offsets_y: &[f32],
+    ) -> bool {
+        let settled = self.clip_scroll_tree.end_scroll_gesture(id, snap_offsets_x, snap_offsets_y);
+        self.scrolled(settled)
+    }
+
+    /// Set or clear the effect applied to the content of a pipeline when it
+    /// is composited. It is applied when the next frame is built, without
+    /// rebuilding the scene. Returns true if it changed.
+    pub fn set_layer_effect(
+        &mut self,
+        pipeline_id: PipelineId,
+        effect: Option<LayerEffect>,
+    ) -> bool {
+        let changed = self.dynamic_properties.set_layer_effect(pipeline_id, effect);
+        self.frame_is_dirty |= changed;
+        changed
     }
 
     pub fn get_scroll_node_state(&self) -> Vec<ScrollNodeState> {
@@ -338,16 +671,230 @@ impl Document {
     pub fn new_async_scene_ready(&mut self, mut built_scene: BuiltScene) {
         self.current.scene = built_scene.scene;
 
-        self.frame_builder = Some(built_scene.frame_builder);
+        let mut frame_builder = built_scene.frame_builder;
+        if let Some(mut old_builder) = self.frame_builder.take() {
+            frame_builder.adopt_fading_surfaces(old_builder.take_fading_surfaces());
+        }
+        self.frame_builder = Some(frame_builder);
         self.current.removed_pipelines.extend(built_scene.removed_pipelines.drain(..));
 
+        built_scene.clip_scroll_tree.inherit_scroll_settings(&self.clip_scroll_tree);
         let old_scrolling_states = self.clip_scroll_tree.drain();
         self.clip_scroll_tree = built_scene.clip_scroll_tree;
-        self.clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(old_scrolling_states);
+        self.clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(
+            old_scrolling_states,
+            self.frame_builder_config.scroll_restore_max_size_ratio,
+        );
+        self.discard_built_overlays();
+        self.frame_is_dirty = true;
 
         // Advance to the next frame.
         self.frame_id.0 += 1;
     }
+
+    fn set_window_parameters(
+        &mut self,
+        window_size: DeviceUintSize,
+        inner_rect: DeviceUintRect,
+        device_pixel_ratio: DevicePixelRatio,
+    ) -> DocumentOps {
+        let is_pure_resize = device_pixel_ratio == self.view.device_pixel_ratio &&
+            inner_rect.origin == self.view.inner_rect.origin;
+        self.view.window_size = window_size;
+        self.view.inner_rect = inner_rect;
+        self.view.device_pixel_ratio = device_pixel_ratio;
+        self.discard_built_overlays();
+        self.clamp_pinch_zoom();
+        self.frame_is_dirty = true;
+
+        if !self.frame_builder_config.resize_in_place {
+            return DocumentOps::nop();
+        }
+
+        // Nothing in the built scene depends on the size of the window, so
+        // a pure resize only has to tell the frame builder about it.
+        if is_pure_resize {
+            if let Some(ref mut frame_builder) = self.frame_builder {
+                frame_builder.resize(window_size, inner_rect);
+                return DocumentOps::nop();
+            }
+        }
+
+        if self.pending.scene.root_pipeline_id.is_some() {
+            DocumentOps::build()
+        } else {
+            DocumentOps::nop()
+        }
+    }
+
+    /// Renders only one stacking context of the current scene, into a target
+    /// of the given size, apart from the frames of the document.
+    fn render_stacking_context_preview(
+        &mut self,
+        preview: StackingContextPreview,
+        size: DeviceUintSize,
+    ) {
+        self.pending_preview = Some((preview, size));
+    }
+
+    /// Builds a frame of the given size showing only one stacking context of
+    /// the current scene, e.g. for a thumbnail of a single component. Scroll
+    /// frames inside it start out unscrolled.
+    fn build_stacking_context_preview(
+        &mut self,
+        preview: StackingContextPreview,
+        size: DeviceUintSize,
+        resource_cache: &mut ResourceCache,
+        gpu_cache: &mut GpuCache,
+        resource_profile: &mut ResourceProfileCounters,
+    ) -> Option<Frame> {
+        if self.current.scene.root_pipeline_id.is_none() {
+            return None;
+        }
+
+        let view = DocumentView {
+            window_size: size,
+            inner_rect: DeviceUintRect::new(DeviceUintPoint::zero(), size),
+            pan: DeviceIntPoint::zero(),
+            ..self.view.clone()
+        };
+        let mut clip_scroll_tree = ClipScrollTree::new();
+        let mut frame_builder = DisplayListFlattener::create_preview_frame_builder(
+            &self.current.scene,
+            &mut clip_scroll_tree,
+            resource_cache.get_font_instances(),
+            &view,
+            preview,
+            &self.frame_builder_config,
+        );
+        clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(ScrollStates::default(), None);
+
+        let root_transform = root_reference_frame_transform(
+            WorldPoint::zero(),
+            view.device_pixel_ratio.stretch(),
+        );
+
+        Some(frame_builder.build(
+            resource_cache,
+            gpu_cache,
+            self.frame_id,
+            &mut clip_scroll_tree,
+            &self.current.scene.pipelines,
+            view.accumulated_scale_factor(),
+            view.layer,
+            view.presentation_blend_mode,
+            root_transform,
+            &mut resource_profile.texture_cache,
+            &mut resource_profile.gpu_cache,
+            &self.dynamic_properties,
+            None,
+        ))
+    }
+
+    /// Draw a pipeline over the content of the document and any earlier
+    /// overlays. Returns false if it was already an overlay.
+    fn add_overlay(&mut self, pipeline_id: PipelineId) -> bool {
+        if self.overlays.iter().any(|overlay| overlay.pipeline_id == pipeline_id) {
+            return false;
+        }
+        self.overlays.push(Overlay { pipeline_id, built: None });
+        self.frame_is_dirty = true;
+        true
+    }
+
+    /// Stop drawing an overlay. Returns false if it wasn't one.
+    fn remove_overlay(&mut self, pipeline_id: PipelineId) -> bool {
+        let count = self.overlays.len();
+        self.overlays.retain(|overlay| overlay.pipeline_id != pipeline_id);
+        if self.overlays.len() == count {
+            return false;
+        }
+        self.frame_is_dirty = true;
+        true
+    }
+
+    fn discard_built_overlays(&mut self) {
+        for overlay in &mut self.overlays {
+            overlay.built = None;
+        }
+    }
+
+    /// Renders an overlay into a frame of its own, which is drawn over the
+    /// frame of the document. Returns false if the overlay isn't drawn, either
+    /// because its display list didn't arrive yet or because the scene draws
+    /// the pipeline already, as the root pipeline or an iframe.
+    fn render_overlay(
+        &mut self,
+        index: usize,
+        rendered_document: &mut RenderedDocument,
+        resource_cache: &mut ResourceCache,
+        gpu_cache: &mut GpuCache,
+        resource_profile: &mut ResourceProfileCounters,
+    ) -> bool {
+        let pipeline_id = self.overlays[index].pipeline_id;
+        if self.current.scene.pipeline_epochs.contains_key(&pipeline_id) ||
+           !self.pending.scene.pipelines.contains_key(&pipeline_id) {
+            return false;
+        }
+
+        if self.overlays[index].built.is_none() {
+            let mut clip_scroll_tree = ClipScrollTree::new();
+            let mut scene = Scene::new();
+            let frame_builder = DisplayListFlattener::create_overlay_frame_builder(
+                &self.pending.scene,
+                &mut clip_scroll_tree,
+                resource_cache.get_font_instances(),
+                &self.view,
+                pipeline_id,
+                &self.frame_builder_config,
+                &mut scene,
+            );
+            clip_scroll_tree.finalize_and_apply_pending_scroll_offsets(
+                ScrollStates::default(),
+                None,
+            );
+            self.overlays[index].built = Some(BuiltOverlay {
+                clip_scroll_tree,
+                frame_builder,
+                scene,
+            });
+        }
+
+        let accumulated_scale_factor = self.view.accumulated_scale_factor();
+        let pan = self.view.pan.to_f32() / accumulated_scale_factor;
+        let root_transform = root_reference_frame_transform(
+            pan,
+            self.view.device_pixel_ratio.stretch(),
+        );
+
+        let overlay = self.overlays[index].built.as_mut().unwrap();
+        let frame = overlay.frame_builder.build(
+            resource_cache,
+            gpu_cache,
+            self.frame_id,
+            &mut overlay.clip_scroll_tree,
+            &overlay.scene.pipelines,
+            accumulated_scale_factor,
+            self.view.layer,
+            PresentationBlendMode::SourceOver,
+            root_transform,
+            &mut resource_profile.texture_cache,
+            &mut resource_profile.gpu_cache,
+            &self.dynamic_properties,
+            None,
+        );
+        rendered_document.overlays.push(frame);
+        rendered_document.pipeline_info.epochs.extend(
+            overlay.scene.pipeline_epochs.iter().map(|(id, epoch)| (*id, *epoch))
+        );
+        true
+    }
+
+    /// Returns true if rendering this document now could produce a different
+    /// frame than the last one that was built.
+    pub fn is_dirty(&self) -> bool {
+        self.frame_is_dirty
+    }
 }
 
 struct DocumentOps {
@@ -485,10 +1032,36 @@ impl RenderBackend {
             }
             SceneMsg::SetPageZoom(factor) => {
                 doc.view.page_zoom_factor = factor.get();
+                doc.clamp_pinch_zoom();
+                doc.frame_is_dirty = true;
+                DocumentOps::nop()
+            }
+            SceneMsg::SetOutputTonemap(tonemap) => {
+                // The message may not have been built with `OutputTonemap::new`,
+                // so make sure the values are valid.
+                let tonemap = OutputTonemap::new(tonemap.gamma, tonemap.brightness);
+                if doc.view.output_tonemap == tonemap {
+                    return DocumentOps::nop();
+                }
+                doc.view.output_tonemap = tonemap;
+                if doc.pending.scene.root_pipeline_id.is_some() {
+                    DocumentOps::build()
+                } else {
+                    DocumentOps::nop()
+                }
+            }
+            SceneMsg::AddOverlay(pipeline_id) => {
+                doc.add_overlay(pipeline_id);
+                DocumentOps::nop()
+            }
+            SceneMsg::RemoveOverlay(pipeline_id) => {
+                doc.remove_overlay(pipeline_id);
                 DocumentOps::nop()
             }
             SceneMsg::SetPinchZoom(factor) => {
                 doc.view.pinch_zoom_factor = factor.get();
+                doc.clamp_pinch_zoom();
+                doc.frame_is_dirty = true;
                 DocumentOps::nop()
             }
             SceneMsg::SetWindowParameters {
@@ -496,10 +1069,7 @@ impl RenderBackend {
                 inner_rect,
                 device_pixel_ratio,
             } => {
-                doc.view.window_size = window_size;
-                doc.view.inner_rect = inner_rect;
-                doc.view.device_pixel_ratio = device_pixel_ratio;
-                DocumentOps::nop()
+                doc.set_window_parameters(window_size, inner_rect, device_pixel_ratio)
             }
             SceneMsg::SetDisplayList {
                 epoch,
@@ -553,6 +1123,7 @@ impl RenderBackend {
                         content_size,
                     );
                 }
+                doc.clamp_pinch_zoom();
 
                 if let Some(ref mut ros) = doc.render_on_scroll {
                     *ros = false; //wait for `GenerateFrame`
@@ -578,6 +1149,7 @@ impl RenderBackend {
                 profile_scope!("SetRootPipeline");
 
                 doc.pending.scene.set_root_pipeline_id(pipeline_id);
+                doc.clamp_pinch_zoom();
                 if doc.pending.scene.pipelines.get(&pipeline_id).is_some() {
                     DocumentOps::build()
                 } else {
@@ -589,6 +1161,8 @@ impl RenderBackend {
 
                 doc.pending.scene.remove_pipeline(pipeline_id);
                 doc.pending.removed_pipelines.push(pipeline_id);
+                doc.dynamic_properties.set_layer_effect(pipeline_id, None);
+                doc.remove_overlay(pipeline_id);
                 DocumentOps::nop()
             }
         }
@@ -615,26 +1189,18 @@ impl RenderBackend {
                 }
                 DocumentOps::nop()
             }
+            FrameMsg::EnableFrameReadback(pipeline_id, enable) => {
+                if doc.set_frame_readback(pipeline_id, enable) {
+                    DocumentOps::build()
+                } else {
+                    DocumentOps::nop()
+                }
+            }
             FrameMsg::Scroll(delta, cursor) => {
                 profile_scope!("Scroll");
 
-                let mut should_render = true;
-                let node_index = match doc.hit_tester {
-                    Some(ref hit_tester) => {
-                        // Ideally we would call doc.scroll_nearest_scrolling_ancestor here, but
-                        // we need have to avoid a double-borrow.
-                        let test = HitTest::new(None, cursor, HitTestFlags::empty());
-                        hit_tester.find_node_under_point(test)
-                    }
-                    None => {
-                        should_render = false;
-                        None
-                    }
-                };
-
                 let should_render =
-                    should_render &&
-                    doc.scroll_nearest_scrolling_ancestor(delta, node_index) &&
+                    doc.scroll(delta, cursor) &&
                     doc.render_on_scroll == Some(true);
                 DocumentOps {
                     scroll: true,
@@ -643,6 +1209,24 @@ impl RenderBackend {
                     ..DocumentOps::nop()
                 }
             }
+            FrameMsg::SetScrollCapture(id) => {
+                doc.scroll_capture = id;
+                doc.frame_is_dirty = true;
+                DocumentOps::nop()
+            }
+            FrameMsg::BeginScroll(cursor) => {
+                doc.begin_scroll(cursor);
+                DocumentOps::nop()
+            }
+            FrameMsg::SetHighlightedScrollFrame(highlight) => {
+                doc.highlighted_scroll_frame = highlight;
+                doc.frame_is_dirty = true;
+                DocumentOps::nop()
+            }
+            FrameMsg::GetItemVisibleRect(pipeline_id, tag, tx) => {
+                tx.send(doc.item_visible_rect(pipeline_id, tag)).unwrap();
+                DocumentOps::nop()
+            }
             FrameMsg::HitTest(pipeline_id, point, flags, tx) => {
 
                 let result = match doc.hit_tester {
@@ -657,6 +1241,13 @@ impl RenderBackend {
             }
             FrameMsg::SetPan(pan) => {
                 doc.view.pan = pan;
+                doc.clamp_pinch_zoom();
+                doc.frame_is_dirty = true;
+                DocumentOps::nop()
+            }
+            FrameMsg::SetPresentationBlendMode(mode) => {
+                doc.view.presentation_blend_mode = mode;
+                doc.frame_is_dirty = true;
                 DocumentOps::nop()
             }
             FrameMsg::ScrollNodeWithId(origin, id, clamp) => {
@@ -672,19 +1263,144 @@ impl RenderBackend {
                     ..DocumentOps::nop()
                 }
             }
+            FrameMsg::ScrollNodeTo(origin, id, clamp, tx) => {
+                profile_scope!("ScrollNodeTo");
+
+                let scrolled = doc.scroll_node(origin, id, clamp);
+                tx.send(scrolled).unwrap();
+                if !scrolled {
+                    return DocumentOps::nop();
+                }
+                let should_render = doc.render_on_scroll == Some(true);
+
+                DocumentOps {
+                    scroll: true,
+                    render: should_render,
+                    composite: should_render,
+                    ..DocumentOps::nop()
+                }
+            }
+            FrameMsg::ScrollNodeBy(id, delta) => {
+                profile_scope!("ScrollNodeBy");
+
+                let should_render = doc.scroll_node_by(id, delta)
+                    && doc.render_on_scroll == Some(true);
+
+                DocumentOps {
+                    scroll: true,
+                    render: should_render,
+                    composite: should_render,
+                    ..DocumentOps::nop()
+                }
+            }
+            FrameMsg::InvalidateRect(id, rect) => {
+                if doc.invalidate_rect(id, rect, &mut self.resource_cache) {
+                    DocumentOps::render()
+                } else {
+                    DocumentOps::nop()
+                }
+            }
+            FrameMsg::LinkScrollFrames(first, second, axes) => {
+                doc.clip_scroll_tree.link_scroll_frames(first, second, axes);
+                doc.frame_is_dirty = true;
+                DocumentOps::nop()
+            }
+            FrameMsg::SetScrollFriction(scroll_friction) => {
+                doc.clip_scroll_tree.set_scroll_friction(scroll_friction);
+                DocumentOps::nop()
+            }
+            FrameMsg::SetScrollMode(id, scroll_mode) => {
+                doc.frame_is_dirty |= doc.clip_scroll_tree.set_scroll_mode(id, scroll_mode);
+                DocumentOps::nop()
+            }
+            FrameMsg::SetScrollSnapType(id, snap_type) => {
+                doc.clip_scroll_tree.set_scroll_snap_type(id, snap_type);
+                DocumentOps::nop()
+            }
+            FrameMsg::EndScrollGesture(id, snap_offsets_x, snap_offsets_y) => {
+                profile_scope!("EndScrollGesture");
+
+                let should_render = doc.end_scroll_gesture(id, &snap_offsets_x, &snap_offsets_y)
+                    && doc.render_on_scroll == Some(true);
+
+                DocumentOps {
+                    scroll: true,
+                    render: should_render,
+                    composite: should_render,
+                    ..DocumentOps::nop()
+                }
+            }
             FrameMsg::GetScrollNodeState(tx) => {
                 profile_scope!("GetScrollNodeState");
                 tx.send(doc.get_scroll_node_state()).unwrap();
                 DocumentOps::nop()
             }
+            FrameMsg::GetScrollTree(tx) => {
+                profile_scope!("GetScrollTree");
+                tx.send(doc.clip_scroll_tree.scroll_tree()).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::GetFramebufferReads(tx) => {
+                profile_scope!("GetFramebufferReads");
+                let reads = doc.frame_builder
+                    .as_ref()
+                    .map_or(Vec::new(), |builder| builder.framebuffer_reads.clone());
+                tx.send(reads).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::GetFullyClippedPipelines(tx) => {
+                profile_scope!("GetFullyClippedPipelines");
+                let pipelines = doc.frame_builder
+                    .as_ref()
+                    .map_or(Vec::new(), |builder| builder.fully_clipped_pipelines.clone());
+                tx.send(pipelines).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::GetDeviceScrollOffset(id, tx) => {
+                profile_scope!("GetDeviceScrollOffset");
+                let device_pixel_scale = doc.view.accumulated_scale_factor();
+                tx.send(doc.clip_scroll_tree.device_scroll_offset(id, device_pixel_scale)).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::GetScrollFrameWorldTransform(id, tx) => {
+                profile_scope!("GetScrollFrameWorldTransform");
+                tx.send(doc.clip_scroll_tree.scroll_frame_world_transform(id)).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::GetScrollFrameClipRects(tx) => {
+                profile_scope!("GetScrollFrameClipRects");
+                let device_pixel_scale = doc.view.accumulated_scale_factor();
+                tx.send(doc.clip_scroll_tree.scroll_frame_clip_rects(device_pixel_scale)).unwrap();
+                DocumentOps::nop()
+            }
+            FrameMsg::IsFrameDirty(tx) => {
+                tx.send(doc.is_dirty()).unwrap();
+                DocumentOps::nop()
+            }
             FrameMsg::UpdateDynamicProperties(property_bindings) => {
                 doc.dynamic_properties.set_properties(property_bindings);
+                doc.frame_is_dirty = true;
                 DocumentOps::render()
             }
             FrameMsg::AppendDynamicProperties(property_bindings) => {
                 doc.dynamic_properties.add_properties(property_bindings);
+                doc.frame_is_dirty = true;
                 DocumentOps::render()
             }
+            FrameMsg::RenderStackingContextPreview(pipeline_id, index, size) => {
+                profile_scope!("RenderStackingContextPreview");
+
+                let preview = StackingContextPreview { pipeline_id, index };
+                doc.render_stacking_context_preview(preview, size);
+                DocumentOps::nop()
+            }
+            FrameMsg::SetLayerEffect(pipeline_id, effect) => {
+                if doc.set_layer_effect(pipeline_id, effect) {
+                    DocumentOps::render()
+                } else {
+                    DocumentOps::nop()
+                }
+            }
         }
     }
 
@@ -816,6 +1532,11 @@ impl RenderBackend {
             ApiMsg::UpdateResources(updates) => {
                 self.resource_cache
                     .update_resources(updates, &mut profile_counters.resources);
+                // Resources are shared between documents, so any of them may
+                // be affected by the update.
+                for (_, doc) in &mut self.documents {
+                    doc.frame_is_dirty = true;
+                }
             }
             ApiMsg::GetGlyphDimensions(instance_key, glyph_keys, tx) => {
                 let mut glyph_dimensions = Vec::with_capacity(glyph_keys.len());
@@ -964,6 +1685,7 @@ impl RenderBackend {
         frame_counter: &mut u32,
         profile_counters: &mut BackendProfileCounters,
     ) {
+        let requested_at = Instant::now();
         let mut op = DocumentOps::nop();
 
         for scene_msg in transaction_msg.scene_ops.drain(..) {
@@ -991,7 +1713,8 @@ impl RenderBackend {
             return;
         }
 
-        self.resource_cache.update_resources(
+        self.documents.get_mut(&document_id).unwrap().update_resources(
+            &mut self.resource_cache,
             transaction_msg.resource_updates,
             &mut profile_counters.resources,
         );
@@ -1014,15 +1737,35 @@ impl RenderBackend {
             if let Some(ref sampler) = self.sampler {
                 transaction_msg.frame_ops.append(&mut sampler.sample());
             }
+
+            // Scroll frames that glide after scroll input move on by a step
+            // with every frame, and those past their edges spring back.
+            let doc = self.documents.get_mut(&document_id).unwrap();
+            if doc.tick_scrolling(Instant::now()) {
+                op.scroll = true;
+            }
         }
 
+        let mut rebuild_scene = false;
         for frame_msg in transaction_msg.frame_ops {
             let _timer = profile_counters.total_time.timer();
-            op.combine(self.process_frame_msg(document_id, frame_msg));
+            let frame_op = self.process_frame_msg(document_id, frame_msg);
+            rebuild_scene = rebuild_scene || frame_op.build;
+            op.combine(frame_op);
         }
 
         let doc = self.documents.get_mut(&document_id).unwrap();
 
+        // Some frame messages, like enabling frame readback, change how the
+        // scene is flattened, so it has to be built again with them applied.
+        if rebuild_scene {
+            let _timer = profile_counters.total_time.timer();
+            profile_scope!("rebuild scene");
+
+            doc.build_scene(&mut self.resource_cache);
+            doc.render_on_hittest = true;
+        }
+
         if transaction_msg.generate_frame {
             if let Some(ref mut ros) = doc.render_on_scroll {
                 *ros = true;
@@ -1053,18 +1796,39 @@ impl RenderBackend {
             let (pending_update, rendered_document) = {
                 let _timer = profile_counters.total_time.timer();
 
-                let rendered_document = doc.render(
+                let mut rendered_document = doc.render(
                     &mut self.resource_cache,
                     &mut self.gpu_cache,
                     &mut profile_counters.resources,
+                    requested_at,
                 );
 
                 debug!("generated frame for document {:?} with {} passes",
                     document_id, rendered_document.frame.passes.len());
 
+                if rendered_document.frame.is_first_contentful {
+                    self.notifier.first_contentful_frame(document_id);
+                }
+
                 let msg = ResultMsg::UpdateGpuCache(self.gpu_cache.extract_updates());
                 self.result_tx.send(msg).unwrap();
 
+                // The GPU cache updates of each frame go out before the
+                // frame of the next overlay is built.
+                for index in 0 .. doc.overlays.len() {
+                    let rendered = doc.render_overlay(
+                        index,
+                        &mut rendered_document,
+                        &mut self.resource_cache,
+                        &mut self.gpu_cache,
+                        &mut profile_counters.resources,
+                    );
+                    if rendered {
+                        let msg = ResultMsg::UpdateGpuCache(self.gpu_cache.extract_updates());
+                        self.result_tx.send(msg).unwrap();
+                    }
+                }
+
                 let pending_update = self.resource_cache.pending_updates();
                 (pending_update, rendered_document)
             };
@@ -1081,8 +1845,40 @@ impl RenderBackend {
             doc.render_on_hittest = false;
         }
 
-        if transaction_msg.generate_frame {
-            self.notifier.new_frame_ready(document_id, op.scroll, op.composite);
+        // A stacking context preview is published on its own, after the
+        // frame of the document if there was one, and leaves that frame be.
+        let mut preview_published = false;
+        if let Some((preview, size)) = doc.pending_preview.take() {
+            let _timer = profile_counters.total_time.timer();
+            profile_scope!("generate stacking context preview");
+
+            let frame = doc.build_stacking_context_preview(
+                preview,
+                size,
+                &mut self.resource_cache,
+                &mut self.gpu_cache,
+                &mut profile_counters.resources,
+            );
+            if let Some(frame) = frame {
+                let msg = ResultMsg::UpdateGpuCache(self.gpu_cache.extract_updates());
+                self.result_tx.send(msg).unwrap();
+
+                let msg = ResultMsg::PublishStackingContextPreview(
+                    document_id,
+                    frame,
+                    self.resource_cache.pending_updates(),
+                );
+                self.result_tx.send(msg).unwrap();
+                preview_published = true;
+            }
+        }
+
+        if transaction_msg.generate_frame || preview_published {
+            self.notifier.new_frame_ready(
+                document_id,
+                op.scroll,
+                op.composite || preview_published,
+            );
         }
     }
 
@@ -1239,6 +2035,7 @@ impl RenderBackend {
                     &mut self.resource_cache,
                     &mut self.gpu_cache,
                     &mut profile_counters.resources,
+                    Instant::now(),
                 );
                 //TODO: write down full `RenderedDocument`?
                 // it has `pipeline_epoch_map`,
@@ -1341,10 +2138,16 @@ impl RenderBackend {
                 frame_builder_config: self.frame_config.clone(),
                 frame_builder: Some(FrameBuilder::empty()),
                 output_pipelines: FastHashSet::default(),
+                readback_pipelines: FastHashSet::default(),
                 render_on_scroll: None,
                 render_on_hittest: false,
                 dynamic_properties: SceneProperties::new(),
                 hit_tester: None,
+                frame_is_dirty: true,
+                last_frame_snapshot: None,
+                scroll_capture: None,
+                highlighted_scroll_frame: None,
+                overlays: Vec::new(),
             };
 
             let frame_name = format!("frame-{}-{}", (id.0).0, id.1);
@@ -1359,6 +2162,7 @@ impl RenderBackend {
                         &mut self.resource_cache,
                         &mut self.gpu_cache,
                         &mut profile_counters.resources,
+                        Instant::now(),
                     )
                 }
             };
@@ -1381,3 +2185,2548 @@ impl RenderBackend {
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use api::{ColorF, DeviceIntPoint, DeviceIntRect, DeviceIntSize, DevicePixelRatio};
+    use api::{DeviceUintPoint, DeviceUintRect, DeviceUintSize};
+    use api::{DisplayListBuilder, Epoch, ExtendMode, ExternalScrollId, FilterOp, GradientStop};
+    use api::{GlyphRasterSpace, LayoutPoint, LayoutTransform, MixBlendMode, PropertyBinding};
+    use api::{ClearCache, ClipId};
+    use api::{AlphaType, IdNamespace, ImageColorSpace, ImageData, ImageDescriptor, ImageFormat};
+    use api::{ExternalImageData, ExternalImageId, ExternalImageType, TextureTarget};
+    use api::{HitTestFlags, ImageKey, ImageRendering, LayerEffect, ResourceUpdates};
+    use api::{PresentationBlendMode, TransformStyle};
+    use api::{LayoutPrimitiveInfo, LayoutRect, LayoutSize, LayoutVector2D, PipelineId};
+    use api::{ScrollAxes, ScrollLocation, ScrollSensitivity, ScrollSnapType, WorldPoint, WorldRect};
+    use api::{ScrollClamping, WorldSize};
+    use batch::{BatchKind, BrushBatchKind, PrimitiveBatch};
+    use clip::ClipChainNodeIter;
+    use clip_scroll_node::NodeType;
+    use display_list_flattener::{StackingContextPreview, build_scene};
+    use euclid::Angle;
+    use frame_builder::{CompositeClamp, FrameBuilder, FrameBuilderConfig};
+    use glyph_rasterizer::GlyphRasterizer;
+    use gpu_types::BlurDirection;
+    use hit_test::HitTest;
+    use internal_types::{RenderedDocument, SourceTexture, TextureUpdateOp};
+    use gpu_cache::GpuCache;
+    use picture::PictureCompositeMode;
+    use profiler::BackendProfileCounters;
+    use rayon::ThreadPoolBuilder;
+    use render_task::RenderTaskKind;
+    use resource_cache::ResourceCache;
+    use scene::MIN_FILTER_GAMMA;
+    use scene_builder::SceneRequest;
+    use std::ops::{Deref, DerefMut};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use super::Document;
+    use texture_cache::TextureCache;
+    use tiling::{ColorRenderTarget, DrawCommand, FlatCommand, Frame, FrameHighlight};
+    use tiling::{FrameTarget, FrameVisitor, RenderPassKind, RenderTargetIndex};
+
+    fn new_resource_cache() -> ResourceCache {
+        let workers = Arc::new(ThreadPoolBuilder::new().build().unwrap());
+        ResourceCache::new(
+            TextureCache::new(2048),
+            GlyphRasterizer::new(workers).unwrap(),
+            None,
+        )
+    }
+
+    /// A document along with the caches that building and rendering its
+    /// frames needs.
+    struct TestDocument {
+        doc: Document,
+        resource_cache: ResourceCache,
+        gpu_cache: GpuCache,
+        profile_counters: BackendProfileCounters,
+    }
+
+    impl TestDocument {
+        /// A document with a 100x100 window.
+        fn new(config: FrameBuilderConfig) -> Self {
+            TestDocument::with_window(config, DeviceUintSize::new(100, 100), 1.0)
+        }
+
+        fn with_window(
+            config: FrameBuilderConfig,
+            window_size: DeviceUintSize,
+            device_pixel_ratio: f32,
+        ) -> Self {
+            TestDocument {
+                doc: Document::new(config, window_size, 0, false, device_pixel_ratio),
+                resource_cache: new_resource_cache(),
+                gpu_cache: GpuCache::new(),
+                profile_counters: BackendProfileCounters::new(),
+            }
+        }
+
+        /// Sets the display list of a pipeline, with a viewport the size of
+        /// its content. Returns the id of the pipeline.
+        fn set_display_list(&mut self, builder: DisplayListBuilder, epoch: Epoch) -> PipelineId {
+            let (pipeline_id, content_size, display_list) = builder.finalize();
+            self.doc.pending.scene.set_display_list(
+                pipeline_id,
+                epoch,
+                display_list,
+                None,
+                content_size,
+                content_size,
+            );
+            pipeline_id
+        }
+
+        /// Builds a scene of the display list, as that of the root pipeline.
+        fn build_scene(&mut self, builder: DisplayListBuilder) {
+            self.build_scene_at_epoch(builder, Epoch(0));
+        }
+
+        fn build_scene_at_epoch(&mut self, builder: DisplayListBuilder, epoch: Epoch) {
+            let pipeline_id = self.set_display_list(builder, epoch);
+            self.doc.pending.scene.set_root_pipeline_id(pipeline_id);
+            self.doc.build_scene(&mut self.resource_cache);
+        }
+
+        /// Builds a scene of the display list the way the scene builder
+        /// thread does, with a new clip-scroll tree replacing the current one.
+        fn build_scene_async(&mut self, builder: DisplayListBuilder) {
+            let pipeline_id = self.set_display_list(builder, Epoch(0));
+            self.doc.pending.scene.set_root_pipeline_id(pipeline_id);
+            let request = SceneRequest {
+                scene: self.doc.pending.scene.clone(),
+                view: self.doc.view.clone(),
+                font_instances: self.resource_cache.get_font_instances(),
+                output_pipelines: self.doc.output_pipelines.clone(),
+                readback_pipelines: self.doc.readback_pipelines.clone(),
+                removed_pipelines: Vec::new(),
+            };
+            let built_scene = build_scene(&self.doc.frame_builder_config, request);
+            self.doc.new_async_scene_ready(built_scene);
+        }
+
+        /// Applies resource updates the way a transaction does.
+        fn apply_resource_updates(&mut self, updates: ResourceUpdates) {
+            self.doc.update_resources(
+                &mut self.resource_cache,
+                updates,
+                &mut self.profile_counters.resources,
+            );
+        }
+
+        fn render(&mut self) -> RenderedDocument {
+            self.render_requested_at(Instant::now())
+        }
+
+        fn render_requested_at(&mut self, requested_at: Instant) -> RenderedDocument {
+            let mut rendered = self.doc.render(
+                &mut self.resource_cache,
+                &mut self.gpu_cache,
+                &mut self.profile_counters.resources,
+                requested_at,
+            );
+            self.gpu_cache.extract_updates();
+            for index in 0 .. self.doc.overlays.len() {
+                self.doc.render_overlay(
+                    index,
+                    &mut rendered,
+                    &mut self.resource_cache,
+                    &mut self.gpu_cache,
+                    &mut self.profile_counters.resources,
+                );
+                self.gpu_cache.extract_updates();
+            }
+            rendered
+        }
+
+        fn build_preview(
+            &mut self,
+            preview: StackingContextPreview,
+            size: DeviceUintSize,
+        ) -> Option<Frame> {
+            let frame = self.doc.build_stacking_context_preview(
+                preview,
+                size,
+                &mut self.resource_cache,
+                &mut self.gpu_cache,
+                &mut self.profile_counters.resources,
+            );
+            self.gpu_cache.extract_updates();
+            frame
+        }
+    }
+
+    impl Deref for TestDocument {
+        type Target = Document;
+        fn deref(&self) -> &Document {
+            &self.doc
+        }
+    }
+
+    impl DerefMut for TestDocument {
+        fn deref_mut(&mut self) -> &mut Document {
+            &mut self.doc
+        }
+    }
+
+    /// Renders a frame of a 100x100 document that shows the display list.
+    fn render_display_list(
+        config: FrameBuilderConfig,
+        builder: DisplayListBuilder,
+    ) -> RenderedDocument {
+        let mut doc = TestDocument::new(config);
+        doc.build_scene(builder);
+        doc.render()
+    }
+
+    /// Calls `f` with each draw command of the frame, in the order they are
+    /// executed, along with the target it draws into.
+    fn visit_draw_commands<F: FnMut(FrameTarget, DrawCommand)>(frame: &Frame, f: F) {
+        struct Visitor<F> {
+            target: Option<FrameTarget>,
+            f: F,
+        }
+
+        impl<F: FnMut(FrameTarget, DrawCommand)> FrameVisitor for Visitor<F> {
+            fn begin_target(&mut self, _: usize, target: FrameTarget) {
+                self.target = Some(target);
+            }
+            fn draw_command(&mut self, command: DrawCommand) {
+                (self.f)(self.target.expect("draw outside of a target"), command);
+            }
+            fn end_target(&mut self) {
+                self.target = None;
+            }
+        }
+
+        frame.visit(&mut Visitor { target: None, f });
+    }
+
+    /// Calls `f` with each primitive batch of the frame.
+    fn visit_batches<F: FnMut(&PrimitiveBatch)>(frame: &Frame, mut f: F) {
+        visit_draw_commands(frame, |_, command| match command {
+            DrawCommand::OpaqueBatch(batch) | DrawCommand::AlphaBatch(batch) => f(batch),
+            _ => {}
+        });
+    }
+
+    fn solid_instance_count(frame: &Frame) -> usize {
+        let mut count = 0;
+        visit_batches(frame, |batch| {
+            if batch.key.kind == BatchKind::Brush(BrushBatchKind::Solid) {
+                count += batch.instances.len();
+            }
+        });
+        count
+    }
+
+    #[test]
+    fn clamped_pinch_zoom_keeps_content_on_screen() {
+        let mut config = FrameBuilder::empty().config;
+        config.clamp_pinch_zoom = true;
+        let mut doc = Document::new(config, DeviceUintSize::new(100, 100), 0, false, 1.0);
+
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(400.0, 200.0);
+        let (_, _, display_list) = DisplayListBuilder::new(pipeline_id, content_size).finalize();
+        doc.pending.scene.set_root_pipeline_id(pipeline_id);
+        doc.pending.scene.set_display_list(
+            pipeline_id,
+            Epoch(0),
+            display_list,
+            None,
+            content_size,
+            content_size,
+        );
+
+        // The content fits the 100x100 window when scaled by a quarter.
+        doc.view.pinch_zoom_factor = 0.1;
+        doc.clamp_pinch_zoom();
+        assert_eq!(doc.view.pinch_zoom_factor, 0.25);
+
+        // Unzoomed the content is 400x200 device pixels, so its edges can
+        // move as far as the center of the window at (50, 50).
+        doc.view.pinch_zoom_factor = 1.0;
+        doc.view.pan = DeviceIntPoint::new(-1000, 80);
+        doc.clamp_pinch_zoom();
+        assert_eq!(doc.view.pan, DeviceIntPoint::new(-350, 50));
+
+        doc.view.pan = DeviceIntPoint::new(-20, -30);
+        doc.clamp_pinch_zoom();
+        assert_eq!(doc.view.pan, DeviceIntPoint::new(-20, -30));
+    }
+
+    #[test]
+    fn pinch_zoom_is_clamped_again_when_the_window_changes() {
+        let mut config = FrameBuilder::empty().config;
+        config.clamp_pinch_zoom = true;
+        let mut doc = Document::new(config, DeviceUintSize::new(100, 100), 0, false, 1.0);
+
+        let pipeline_id = PipelineId(0, 0);
+        let content_size = LayoutSize::new(400.0, 200.0);
+        let (_, _, display_list) = DisplayListBuilder::new(pipeline_id, content_size).finalize();
+        doc.pending.scene.set_root_pipeline_id(pipeline_id);
+        doc.pending.scene.set_display_list(
+            pipeline_id,
+            Epoch(0),
+            display_list,
+            None,
+            content_size,
+            content_size,
+        );
+        doc.view.pinch_zoom_factor = 0.25;
+        doc.view.pan = DeviceIntPoint::new(-20, -30);
+
+        // In a 200x200 window the content no longer fits below half scale.
+        let size = DeviceUintSize::new(200, 200);
+        let inner_rect = DeviceUintRect::new(DeviceUintPoint::zero(), size);
+        doc.set_window_parameters(size, inner_rect, DevicePixelRatio::uniform(1.0));
+        assert_eq!(doc.view.pinch_zoom_factor, 0.5);
+        assert_eq!(doc.view.pan, DeviceIntPoint::new(-20, 0));
+
+        // Each axis fits by its own ratio: 400x200 content stretched by
+        // (0.5, 2) is 200x400 device pixels, which fits at half scale.
+        doc.view.pinch_zoom_factor = 0.25;
+        doc.set_window_parameters(size, inner_rect, DevicePixelRatio::new(0.5, 2.0));
+        assert_eq!(doc.view.pinch_zoom_factor, 0.5);
+
+        // At that scale the content is 100x200, so its right edge can't go
+        // past the center of the window.
+        assert_eq!(doc.view.pan, DeviceIntPoint::new(0, 0));
+    }
+
+    #[test]
+    fn frame_is_dirty_until_it_is_rendered() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        assert!(doc.is_dirty());
+
+        let scroll_id = ExternalScrollId(1, PipelineId(0, 0));
+        doc.build_scene(scroll_frame_with_tagged_rects(scroll_id));
+        assert!(doc.is_dirty());
+        doc.render();
+        assert!(!doc.is_dirty());
+
+        // Scrolling dirties the frame, unless nothing moved.
+        assert!(!doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, 50.0)));
+        assert!(!doc.is_dirty());
+        assert!(doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, -50.0)));
+        assert!(doc.is_dirty());
+        doc.render();
+        assert!(!doc.is_dirty());
+
+        // So does a new scene, even without any change to its content.
+        doc.build_scene(scroll_frame_with_tagged_rects(scroll_id));
+        assert!(doc.is_dirty());
+    }
+
+    #[test]
+    fn enabling_frame_readback_rebuilds_the_scene() {
+        let readbacks = |frame: &Frame| -> Vec<PipelineId> {
+            let mut pipelines = Vec::new();
+            for pass in &frame.passes {
+                let targets: Vec<&ColorRenderTarget> = match pass.kind {
+                    RenderPassKind::MainFramebuffer(ref target) => vec![target],
+                    RenderPassKind::OffScreen { ref color, .. } => color.targets.iter().collect(),
+                };
+                for target in targets {
+                    pipelines.extend(target.pixel_readbacks.iter().map(|r| r.pipeline_id));
+                }
+            }
+            pipelines
+        };
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        doc.build_scene(builder);
+        assert!(readbacks(&doc.render().frame).is_empty());
+
+        // Only a change to the set of pipelines asks for a new scene.
+        assert!(doc.set_frame_readback(PipelineId(0, 0), true));
+        assert!(!doc.set_frame_readback(PipelineId(0, 0), true));
+        assert!(doc.is_dirty());
+
+        {
+            let TestDocument { ref mut doc, ref mut resource_cache, .. } = doc;
+            doc.build_scene(resource_cache);
+        }
+        assert_eq!(readbacks(&doc.render().frame), vec![PipelineId(0, 0)]);
+
+        assert!(doc.set_frame_readback(PipelineId(0, 0), false));
+        {
+            let TestDocument { ref mut doc, ref mut resource_cache, .. } = doc;
+            doc.build_scene(resource_cache);
+        }
+        assert!(readbacks(&doc.render().frame).is_empty());
+    }
+
+    #[test]
+    fn paint_order_is_reported_per_render_target() {
+        let render_paint_order = |record_paint_order: bool| {
+            let size = LayoutSize::new(100.0, 100.0);
+            let rect = |x| LayoutPrimitiveInfo::new(
+                LayoutRect::new(LayoutPoint::new(x, 0.0), LayoutSize::new(20.0, 20.0)),
+            );
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            builder.push_rect(&rect(0.0), ColorF::new(1.0, 0.0, 0.0, 1.0));
+            builder.push_stacking_context(
+                &rect(30.0),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Opacity(PropertyBinding::Value(0.5), 0.5)],
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_rect(&rect(30.0), ColorF::new(0.0, 1.0, 0.0, 1.0));
+            builder.pop_stacking_context();
+            builder.push_rect(&rect(60.0), ColorF::new(0.0, 0.0, 1.0, 1.0));
+
+            let mut config = FrameBuilder::empty().config;
+            config.record_paint_order = record_paint_order;
+            render_display_list(config, builder).frame.render_targets()
+        };
+
+        for summary in render_paint_order(false) {
+            assert!(summary.paint_order.is_empty());
+        }
+
+        // The main framebuffer draws the two outer rects around the faded
+        // picture, whose own rect is drawn into an off-screen target.
+        let summaries = render_paint_order(true);
+        let main = summaries
+            .iter()
+            .find(|summary| match summary.target {
+                FrameTarget::MainFramebuffer => true,
+                _ => false,
+            })
+            .unwrap();
+        let faded: Vec<_> = summaries
+            .iter()
+            .filter(|summary| match summary.target {
+                FrameTarget::Color(..) => true,
+                _ => false,
+            })
+            .flat_map(|summary| summary.paint_order.iter())
+            .collect();
+
+        let root_pic_index = main.paint_order[0].pic_index;
+        assert_eq!(main.paint_order.len(), 3);
+        assert!(main.paint_order.iter().all(|item| item.pic_index == root_pic_index));
+        assert!(main.paint_order[0].prim_index.0 < main.paint_order[1].prim_index.0);
+        assert!(main.paint_order[1].prim_index.0 < main.paint_order[2].prim_index.0);
+        assert_eq!(faded.len(), 1);
+        assert!(faded[0].pic_index != root_pic_index);
+        assert_eq!(faded[0].batch_kind, BatchKind::Brush(BrushBatchKind::Solid));
+    }
+
+    #[test]
+    fn pure_resize_keeps_the_built_scene() {
+        let mut config = FrameBuilder::empty().config;
+        config.resize_in_place = true;
+        let mut doc = TestDocument::new(config);
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_rect(
+            &LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size)),
+            ColorF::new(0.0, 0.0, 1.0, 1.0),
+        );
+        doc.build_scene(builder);
+        doc.render();
+
+        let window_size = DeviceUintSize::new(200, 150);
+        let inner_rect = DeviceUintRect::new(DeviceUintPoint::zero(), window_size);
+        let ops = doc.set_window_parameters(
+            window_size,
+            inner_rect,
+            DevicePixelRatio::uniform(1.0),
+        );
+        assert!(!ops.build);
+
+        let rendered = doc.render();
+        assert_eq!(rendered.frame.window_size, window_size);
+        assert_eq!(rendered.frame.inner_rect, inner_rect);
+
+        // A new device pixel ratio changes the scene itself.
+        let ops = doc.set_window_parameters(
+            window_size,
+            inner_rect,
+            DevicePixelRatio::uniform(2.0),
+        );
+        assert!(ops.build);
+    }
+
+    #[test]
+    fn stacking_context_preview_draws_only_its_content() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = |x, y, w, h| LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(w, h));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        let push_stacking_context = |builder: &mut DisplayListBuilder, bounds| {
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(bounds),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                Vec::new(),
+                GlyphRasterSpace::Screen,
+            );
+        };
+
+        // The second stacking context, nested in the first and clipped to its
+        // bounds from outside, holds two of the four rects.
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        push_stacking_context(&mut builder, rect(0.0, 0.0, 100.0, 100.0));
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 10.0, 10.0)), color);
+        let clip = builder.define_clip(rect(50.0, 50.0, 50.0, 50.0), None, None);
+        builder.push_clip_id(clip);
+        push_stacking_context(&mut builder, rect(50.0, 50.0, 50.0, 50.0));
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 20.0, 20.0)), color);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(20.0, 20.0, 20.0, 20.0)), color);
+        builder.pop_stacking_context();
+        builder.pop_clip_id();
+        builder.pop_stacking_context();
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(80.0, 0.0, 10.0, 10.0)), color);
+        doc.build_scene(builder);
+
+        let rendered = doc.render();
+        assert_eq!(solid_instance_count(&rendered.frame), 4);
+
+        // The clip from outside the stacking context doesn't apply to the
+        // preview, which is laid out from the origin.
+        let frame_id = doc.frame_id;
+        let preview = StackingContextPreview { pipeline_id, index: 1 };
+        let preview = doc.build_preview(preview, DeviceUintSize::new(50, 50)).unwrap();
+        assert_eq!(solid_instance_count(&preview), 2);
+        assert_eq!(preview.window_size, DeviceUintSize::new(50, 50));
+
+        // The frames of the document are left as they are.
+        assert_eq!(doc.frame_id.0, frame_id.0);
+        let rendered = doc.render();
+        assert_eq!(solid_instance_count(&rendered.frame), 4);
+        assert_eq!(rendered.frame.window_size, DeviceUintSize::new(100, 100));
+    }
+
+    #[test]
+    fn only_the_main_framebuffer_is_multisampled() {
+        let mut config = FrameBuilder::empty().config;
+        config.root_sample_count = 4;
+
+        // The blur is drawn through intermediate targets.
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Blur(4.0)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(config, builder);
+
+        let targets = rendered.frame.render_targets();
+        assert!(targets.len() > 1);
+        for summary in targets {
+            let expected = match summary.target {
+                FrameTarget::MainFramebuffer => 4,
+                _ => 1,
+            };
+            assert_eq!(summary.sample_count, expected);
+        }
+    }
+
+    #[test]
+    fn drop_shadow_draws_a_blurred_copy_behind_the_content() {
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(
+            LayoutRect::new(LayoutPoint::new(20.0, 20.0), LayoutSize::new(40.0, 40.0)),
+        );
+        let shadow_color = ColorF::new(0.0, 0.0, 0.0, 0.5);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::DropShadow(LayoutVector2D::new(5.0, 5.0), 3.0, shadow_color)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(FrameBuilder::empty().config, builder);
+
+        // The shadow is blurred along both axes, then composited along with
+        // the content it was made from.
+        let mut vertical_blurs = 0;
+        let mut horizontal_blurs = 0;
+        let mut composited_images = 0;
+        visit_draw_commands(&rendered.frame, |target, command| match (target, command) {
+            (_, DrawCommand::Blur(BlurDirection::Vertical, _)) => vertical_blurs += 1,
+            (_, DrawCommand::Blur(BlurDirection::Horizontal, _)) => horizontal_blurs += 1,
+            (FrameTarget::MainFramebuffer, DrawCommand::AlphaBatch(batch)) => {
+                if let BatchKind::Brush(BrushBatchKind::Image(..)) = batch.key.kind {
+                    composited_images += batch.instances.len();
+                }
+            }
+            _ => {}
+        });
+        assert!(vertical_blurs > 0);
+        assert!(horizontal_blurs > 0);
+        assert_eq!(composited_images, 2);
+    }
+
+    #[test]
+    fn identical_blurred_cards_render_one_target_composited_at_each_position() {
+        let card_rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(80.0, 80.0));
+        let push_card = |builder: &mut DisplayListBuilder, origin: LayoutPoint| {
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(LayoutRect::new(origin, card_rect.size)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(4.0)],
+                GlyphRasterSpace::Screen,
+            );
+            let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+            builder.push_rect(&LayoutPrimitiveInfo::new(card_rect), color);
+            builder.pop_stacking_context();
+        };
+
+        // Ten identical cards drawn directly, and one more drawn inside a
+        // translucent stacking context.
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), LayoutSize::new(1200.0, 120.0));
+        for i in 0 .. 10 {
+            push_card(&mut builder, LayoutPoint::new(20.0 + 110.0 * i as f32, 20.0));
+        }
+        let last_card_origin = LayoutPoint::new(1100.0, 20.0);
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(LayoutRect::new(last_card_origin, card_rect.size)),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Opacity(PropertyBinding::Value(0.5), 0.5)],
+            GlyphRasterSpace::Screen,
+        );
+        push_card(&mut builder, LayoutPoint::zero());
+        builder.pop_stacking_context();
+
+        let config = FrameBuilder::empty().config;
+        let mut doc = TestDocument::with_window(config, DeviceUintSize::new(1200, 120), 1.0);
+        doc.build_scene(builder);
+        let rendered = doc.render();
+
+        // The blur is rendered once, and composited at each card drawn
+        // directly into the framebuffer.
+        let mut vertical_blurs = 0;
+        let mut composited_images = 0;
+        visit_draw_commands(&rendered.frame, |target, command| match (target, command) {
+            (_, DrawCommand::Blur(BlurDirection::Vertical, instances)) => {
+                vertical_blurs += instances.len();
+            }
+            (FrameTarget::MainFramebuffer, DrawCommand::AlphaBatch(batch)) => {
+                if let BatchKind::Brush(BrushBatchKind::Image(..)) = batch.key.kind {
+                    composited_images += batch.instances.len();
+                }
+            }
+            _ => {}
+        });
+        assert_eq!(vertical_blurs, 1);
+        assert_eq!(composited_images, 10);
+
+        // Both the framebuffer and the translucent picture that draws the
+        // last card wait for the shared blur to be rendered.
+        let tasks = &rendered.frame.render_tasks.tasks;
+        let blur_indices: Vec<_> = tasks
+            .iter()
+            .enumerate()
+            .filter(|&(_, task)| match task.kind {
+                RenderTaskKind::HorizontalBlur(..) => true,
+                _ => false,
+            })
+            .map(|(index, _)| index as u32)
+            .collect();
+        assert_eq!(blur_indices.len(), 1);
+        let dependents = tasks
+            .iter()
+            .filter(|task| task.children.iter().any(|child| child.0 == blur_indices[0]))
+            .count();
+        assert_eq!(dependents, 2);
+    }
+
+    #[test]
+    fn invalidating_a_rect_re_renders_only_the_cached_pictures_it_overlaps() {
+        let pipeline_id = PipelineId(0, 0);
+        let card_size = LayoutSize::new(80.0, 80.0);
+        let mut builder = DisplayListBuilder::new(pipeline_id, LayoutSize::new(300.0, 120.0));
+        let cards = [
+            (LayoutPoint::new(20.0, 20.0), ColorF::new(0.0, 0.0, 1.0, 1.0)),
+            (LayoutPoint::new(180.0, 20.0), ColorF::new(1.0, 0.0, 0.0, 1.0)),
+        ];
+        for &(origin, color) in &cards {
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(LayoutRect::new(origin, card_size)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(4.0)],
+                GlyphRasterSpace::Screen,
+            );
+            let card_rect = LayoutRect::new(LayoutPoint::zero(), card_size);
+            builder.push_rect(&LayoutPrimitiveInfo::new(card_rect), color);
+            builder.pop_stacking_context();
+        }
+
+        let config = FrameBuilder::empty().config;
+        let mut doc = TestDocument::with_window(config, DeviceUintSize::new(300, 120), 1.0);
+        doc.build_scene(builder);
+
+        let vertical_blurs = |frame: &Frame| {
+            let mut count = 0;
+            visit_draw_commands(frame, |_, command| {
+                if let DrawCommand::Blur(BlurDirection::Vertical, instances) = command {
+                    count += instances.len();
+                }
+            });
+            count
+        };
+
+        // Both blurs are drawn once, then stay in the texture cache.
+        assert_eq!(vertical_blurs(&doc.render().frame), 2);
+        assert_eq!(vertical_blurs(&doc.render().frame), 0);
+
+        // Nothing is cached between the cards.
+        let root_id = ExternalScrollId(0, pipeline_id);
+        let gap = LayoutRect::new(LayoutPoint::new(140.0, 0.0), LayoutSize::new(10.0, 10.0));
+        assert!(!doc.doc.invalidate_rect(root_id, gap, &mut doc.resource_cache));
+        assert_eq!(vertical_blurs(&doc.render().frame), 0);
+
+        // A small rect over the first card draws only its blur again.
+        let caret = LayoutRect::new(LayoutPoint::new(50.0, 50.0), LayoutSize::new(2.0, 12.0));
+        assert!(doc.doc.invalidate_rect(root_id, caret, &mut doc.resource_cache));
+        assert_eq!(vertical_blurs(&doc.render().frame), 1);
+        assert_eq!(vertical_blurs(&doc.render().frame), 0);
+    }
+
+    #[test]
+    fn filter_with_only_culled_content_allocates_no_off_screen_target() {
+        let off_screen_targets = |origin: LayoutPoint| {
+            let size = LayoutSize::new(100.0, 100.0);
+            let rect = LayoutRect::new(origin, LayoutSize::new(40.0, 40.0));
+            let info = LayoutPrimitiveInfo::new(rect);
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            builder.push_stacking_context(
+                &info,
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(3.0)],
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_stacking_context();
+            let rendered = render_display_list(FrameBuilder::empty().config, builder);
+
+            rendered.frame
+                .render_targets()
+                .iter()
+                .filter(|summary| match summary.target {
+                    FrameTarget::MainFramebuffer => false,
+                    _ => true,
+                })
+                .count()
+        };
+
+        // The blur is drawn through off-screen targets while its content is
+        // visible, but once it is all culled there is nothing to allocate.
+        assert!(off_screen_targets(LayoutPoint::new(20.0, 20.0)) > 0);
+        assert_eq!(off_screen_targets(LayoutPoint::new(1000.0, 1000.0)), 0);
+    }
+
+    #[test]
+    fn layer_effect_composites_a_pipeline_without_rebuilding_the_scene() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let root_pipeline_id = PipelineId(0, 0);
+        let iframe_pipeline_id = PipelineId(0, 1);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(50.0, 50.0));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        let mut builder = DisplayListBuilder::new(iframe_pipeline_id, rect.size);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+        doc.set_display_list(builder, Epoch(0));
+        let mut builder = DisplayListBuilder::new(root_pipeline_id, size);
+        builder.push_iframe(&LayoutPrimitiveInfo::new(rect), iframe_pipeline_id);
+        doc.build_scene(builder);
+        assert_eq!(doc.render().frame.passes.len(), 1);
+
+        // The layer effect of a pipeline, if any, as it was applied to the
+        // last frame, and the id of the root picture of the scene.
+        let applied_effect = |doc: &TestDocument| {
+            let prim_store = &doc.frame_builder.as_ref().unwrap().prim_store;
+            let layer_pic = prim_store.pictures
+                .iter()
+                .find(|pic| pic.layer_effect_pipeline_id == Some(iframe_pipeline_id))
+                .unwrap();
+            (layer_pic.composite_mode, prim_store.pictures[0].id)
+        };
+        let (_, root_pic_id) = applied_effect(&doc);
+
+        // The content of the pipeline is drawn off screen, and composited at
+        // the given opacity.
+        assert!(doc.set_layer_effect(iframe_pipeline_id, Some(LayerEffect::Opacity(0.5))));
+        assert!(doc.is_dirty());
+        assert_eq!(doc.render().frame.passes.len(), 2);
+        match applied_effect(&doc) {
+            (Some(PictureCompositeMode::Filter(FilterOp::Opacity(_, amount))), pic_id) => {
+                assert_eq!(amount, 0.5);
+                assert_eq!(pic_id, root_pic_id);
+            }
+            other => panic!("unexpected layer effect {:?}", other),
+        }
+
+        // Clearing the effect draws the content directly again.
+        assert!(doc.set_layer_effect(iframe_pipeline_id, None));
+        assert!(!doc.set_layer_effect(iframe_pipeline_id, None));
+        assert_eq!(doc.render().frame.passes.len(), 1);
+        assert_eq!(applied_effect(&doc), (None, root_pic_id));
+    }
+
+    #[test]
+    fn posterize_draws_its_content_in_a_single_off_screen_pass() {
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(
+            LayoutRect::new(LayoutPoint::new(20.0, 20.0), LayoutSize::new(40.0, 40.0)),
+        );
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Posterize(4)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(FrameBuilder::empty().config, builder);
+
+        // The content is drawn into one off-screen target with no blur or
+        // scaling tasks in between, and is posterized as it is composited.
+        let summaries = rendered.frame.render_targets();
+        assert_eq!(rendered.frame.passes.len(), 2);
+        let targets: Vec<_> = summaries.iter().map(|summary| summary.target).collect();
+        assert_eq!(targets.len(), 2);
+        match (targets[0], targets[1]) {
+            (FrameTarget::Color(..), FrameTarget::MainFramebuffer) => {}
+            _ => panic!("unexpected targets {:?}", targets),
+        }
+
+        let mut off_screen_solids = 0;
+        let mut blends = 0;
+        visit_draw_commands(&rendered.frame, |target, command| match (target, command) {
+            (_, DrawCommand::Blur(..)) | (_, DrawCommand::Scaling(..)) => {
+                panic!("posterize should not need intermediate tasks");
+            }
+            (FrameTarget::Color(..), DrawCommand::OpaqueBatch(batch)) |
+            (FrameTarget::Color(..), DrawCommand::AlphaBatch(batch)) => {
+                if let BatchKind::Brush(BrushBatchKind::Solid) = batch.key.kind {
+                    off_screen_solids += batch.instances.len();
+                }
+            }
+            (FrameTarget::MainFramebuffer, DrawCommand::AlphaBatch(batch)) => {
+                if let BatchKind::Brush(BrushBatchKind::Blend) = batch.key.kind {
+                    blends += batch.instances.len();
+                }
+            }
+            _ => {}
+        });
+        assert_eq!(off_screen_solids, 1);
+        assert_eq!(blends, 1);
+    }
+
+    #[test]
+    fn gamma_of_zero_is_drawn_with_the_minimum_gamma() {
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(
+            LayoutRect::new(LayoutPoint::new(20.0, 20.0), LayoutSize::new(40.0, 40.0)),
+        );
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Gamma(0.0)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(FrameBuilder::empty().config, builder);
+
+        // The shader raises colors to the power of 1 / gamma, so the gamma
+        // it reads must stay above zero.
+        let mut gammas = Vec::new();
+        visit_batches(&rendered.frame, |batch| {
+            if let BatchKind::Brush(BrushBatchKind::Blend) = batch.key.kind {
+                gammas.extend(batch.instances.iter().map(|i| i.brush_user_data()[2]));
+            }
+        });
+        assert_eq!(gammas, vec![(MIN_FILTER_GAMMA * 65536.0) as i32]);
+        assert!((65536.0 / gammas[0] as f32).is_finite());
+    }
+
+    #[test]
+    fn frame_over_its_time_budget_blurs_at_reduced_resolution() {
+        let scalings = |requested_before: Duration| {
+            let mut config = FrameBuilder::empty().config;
+            config.frame_time_budget = Some(Duration::from_secs(1));
+            let mut doc = TestDocument::with_window(config, DeviceUintSize::new(300, 300), 1.0);
+
+            let size = LayoutSize::new(300.0, 300.0);
+            let info = LayoutPrimitiveInfo::new(
+                LayoutRect::new(LayoutPoint::new(50.0, 50.0), LayoutSize::new(200.0, 200.0)),
+            );
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            builder.push_stacking_context(
+                &info,
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                vec![FilterOp::Blur(3.0)],
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_stacking_context();
+            doc.build_scene(builder);
+
+            // The budget counts from when the frame was requested, so a
+            // request that waited behind other work starts out over it.
+            let rendered = doc.render_requested_at(Instant::now() - requested_before);
+            let mut scalings = 0;
+            visit_draw_commands(&rendered.frame, |_, command| {
+                if let DrawCommand::Scaling(..) = command {
+                    scalings += 1;
+                }
+            });
+            scalings
+        };
+
+        assert_eq!(scalings(Duration::from_secs(0)), 0);
+        assert!(scalings(Duration::from_secs(2)) > 0);
+    }
+
+    #[test]
+    fn frame_over_its_time_budget_draws_pictures_cached_before() {
+        let mut config = FrameBuilder::empty().config;
+        config.frame_time_budget = Some(Duration::from_secs(1));
+        let mut doc = TestDocument::new(config);
+
+        let info = LayoutPrimitiveInfo::new(
+            LayoutRect::new(LayoutPoint::new(20.0, 20.0), LayoutSize::new(60.0, 60.0)),
+        );
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), LayoutSize::new(100.0, 100.0));
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Blur(3.0)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        doc.build_scene(builder);
+
+        // The first frame stores the blurred picture in the texture cache,
+        // which a frame over its budget draws from rather than blurring the
+        // picture again.
+        let rendered = doc.render();
+        assert!(rendered.frame.render_targets().len() > 1);
+        let rendered = doc.render_requested_at(Instant::now() - Duration::from_secs(2));
+        let targets = rendered.frame.render_targets();
+        assert_eq!(targets.len(), 1);
+        match targets[0].target {
+            FrameTarget::MainFramebuffer => {}
+            other => panic!("unexpected target {:?}", other),
+        }
+    }
+
+    #[test]
+    fn content_transformed_far_off_the_viewport_is_culled() {
+        let solid_instances = |offset: f32| {
+            let size = LayoutSize::new(100.0, 100.0);
+            let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            let transform = LayoutTransform::create_translation(offset, 0.0, 0.0);
+            builder.push_stacking_context(
+                &info,
+                None,
+                Some(PropertyBinding::Value(transform)),
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                Vec::new(),
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_stacking_context();
+            let rendered = render_display_list(FrameBuilder::empty().config, builder);
+            solid_instance_count(&rendered.frame)
+        };
+
+        assert_eq!(solid_instances(0.0), 1);
+        // A transform that puts the rect far outside the viewport leaves
+        // nothing to draw.
+        assert_eq!(solid_instances(1.0e9), 0);
+        assert_eq!(solid_instances(-1.0e9), 0);
+    }
+
+    #[test]
+    fn image_that_is_not_ready_is_drawn_once_it_is_ready() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let image_key = ImageKey::new(IdNamespace(0), 0);
+        let mut updates = ResourceUpdates::new();
+        updates.add_image(
+            image_key,
+            ImageDescriptor::new(4, 4, ImageFormat::BGRA8, true, false),
+            ImageData::new(vec![0xff; 4 * 4 * 4]),
+            None,
+        );
+        updates.set_image_not_ready(image_key);
+        doc.apply_resource_updates(updates);
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let rect = LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(40.0, 40.0));
+        builder.push_image(
+            &LayoutPrimitiveInfo::new(rect),
+            rect.size,
+            LayoutSize::zero(),
+            ImageRendering::Auto,
+            AlphaType::PremultipliedAlpha,
+            image_key,
+        );
+        doc.build_scene(builder);
+
+        let image_instances = |frame: &Frame| {
+            let mut instances = 0;
+            visit_batches(frame, |batch| {
+                if let BatchKind::Brush(BrushBatchKind::Image(..)) = batch.key.kind {
+                    instances += batch.instances.len();
+                }
+            });
+            instances
+        };
+
+        // The image is left out while it decodes.
+        assert_eq!(image_instances(&doc.render().frame), 0);
+        assert!(!doc.is_dirty());
+
+        // Being told it is ready asks for a new frame, which draws it.
+        let mut updates = ResourceUpdates::new();
+        updates.notify_image_ready(image_key);
+        doc.apply_resource_updates(updates);
+        assert!(doc.is_dirty());
+        assert_eq!(image_instances(&doc.render().frame), 1);
+    }
+
+    #[test]
+    fn evicted_image_is_uploaded_again_before_it_is_drawn() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let image_key = ImageKey::new(IdNamespace(0), 0);
+        doc.resource_cache.add_image_template(
+            image_key,
+            ImageDescriptor::new(4, 4, ImageFormat::BGRA8, true, false),
+            ImageData::new(vec![0xff; 4 * 4 * 4]),
+            None,
+        );
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let rect = LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(40.0, 40.0));
+        builder.push_image(
+            &LayoutPrimitiveInfo::new(rect),
+            rect.size,
+            LayoutSize::zero(),
+            ImageRendering::Auto,
+            AlphaType::PremultipliedAlpha,
+            image_key,
+        );
+        doc.build_scene(builder);
+
+        // Renders a frame, returning the texture the image is drawn from and
+        // what happened to that texture since the last frame.
+        let render = |doc: &mut TestDocument| {
+            let rendered = doc.render();
+            let mut textures = Vec::new();
+            visit_batches(&rendered.frame, |batch| {
+                if let BatchKind::Brush(BrushBatchKind::Image(..)) = batch.key.kind {
+                    textures.push(batch.key.textures.colors[0]);
+                }
+            });
+            assert_eq!(textures.len(), 1);
+            let texture_id = match textures[0] {
+                SourceTexture::TextureCache(id) => id,
+                texture => panic!("image drawn from {:?}", texture),
+            };
+            let ops: Vec<_> = doc.resource_cache.pending_updates().updates
+                .iter()
+                .filter(|update| update.id == texture_id)
+                .map(|update| match update.op {
+                    TextureUpdateOp::Create { .. } => "create",
+                    TextureUpdateOp::Update { .. } => "upload",
+                    TextureUpdateOp::Free => "free",
+                })
+                .collect();
+            (texture_id, ops)
+        };
+
+        let (texture_id, ops) = render(&mut doc);
+        assert_eq!(ops, vec!["create", "upload"]);
+        let (_, ops) = render(&mut doc);
+        assert!(ops.is_empty());
+
+        // Once evicted, the image is uploaded again before the frame draws
+        // it, even if the freed texture's id is handed out again.
+        doc.resource_cache.clear(ClearCache::IMAGES | ClearCache::TEXTURE_CACHE);
+        let (new_texture_id, ops) = render(&mut doc);
+        if new_texture_id == texture_id {
+            assert_eq!(ops, vec!["free", "create", "upload"]);
+        } else {
+            assert_eq!(ops, vec!["create", "upload"]);
+        }
+    }
+
+    #[test]
+    fn image_without_a_texture_is_drawn_as_a_placeholder_when_enabled() {
+        let rendered_batches = |missing_image_placeholders| {
+            let mut config = FrameBuilder::empty().config;
+            config.missing_image_placeholders = missing_image_placeholders;
+            let mut doc = TestDocument::new(config);
+
+            // Tiles larger than the texture cache allows are never uploaded.
+            let image_key = ImageKey::new(IdNamespace(0), 0);
+            let mut updates = ResourceUpdates::new();
+            updates.add_image(
+                image_key,
+                ImageDescriptor::new(4, 4, ImageFormat::BGRA8, true, false),
+                ImageData::new(vec![0xff; 4 * 4 * 4]),
+                Some(4096),
+            );
+            doc.apply_resource_updates(updates);
+
+            let size = LayoutSize::new(100.0, 100.0);
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            let rect = LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(40.0, 40.0));
+            builder.push_image(
+                &LayoutPrimitiveInfo::new(rect),
+                rect.size,
+                LayoutSize::zero(),
+                ImageRendering::Auto,
+                AlphaType::PremultipliedAlpha,
+                image_key,
+            );
+            doc.build_scene(builder);
+            let frame = doc.render().frame;
+
+            let mut kinds = Vec::new();
+            visit_batches(&frame, |batch| {
+                assert!(batch.key.textures.colors.iter().all(|&t| t == SourceTexture::Invalid));
+                kinds.push((batch.key.kind, batch.instances.len()));
+            });
+            kinds
+        };
+
+        assert!(rendered_batches(false).is_empty());
+        let solid = BatchKind::Brush(BrushBatchKind::Solid);
+        assert_eq!(rendered_batches(true), vec![(solid, 1)]);
+    }
+
+    #[test]
+    fn texture_binds_are_counted_when_the_frame_is_built() {
+        let texture_binds = |origins: [LayoutPoint; 4]| {
+            let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+            // Two images in separate textures.
+            let image_keys = [ImageKey::new(IdNamespace(0), 0), ImageKey::new(IdNamespace(0), 1)];
+            let mut updates = ResourceUpdates::new();
+            for (i, &image_key) in image_keys.iter().enumerate() {
+                let external = ExternalImageData {
+                    id: ExternalImageId(i as u64),
+                    channel_index: 0,
+                    image_type: ExternalImageType::TextureHandle(TextureTarget::Default),
+                };
+                updates.add_image(
+                    image_key,
+                    ImageDescriptor::new(4, 4, ImageFormat::BGRA8, false, false),
+                    ImageData::External(external),
+                    None,
+                );
+            }
+            doc.apply_resource_updates(updates);
+
+            // The images alternate between the two textures.
+            let size = LayoutSize::new(100.0, 100.0);
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            for (i, &origin) in origins.iter().enumerate() {
+                let rect = LayoutRect::new(origin, LayoutSize::new(40.0, 40.0));
+                builder.push_image(
+                    &LayoutPrimitiveInfo::new(rect),
+                    rect.size,
+                    LayoutSize::zero(),
+                    ImageRendering::Auto,
+                    AlphaType::PremultipliedAlpha,
+                    image_keys[i % 2],
+                );
+            }
+            doc.build_scene(builder);
+            let rendered = doc.render();
+            assert_eq!(rendered.texture_bind_count, rendered.frame.texture_bind_count());
+            rendered.texture_bind_count
+        };
+
+        // Overlapping images are drawn in order, one batch each, and each
+        // batch binds its texture to the first two samplers.
+        let staggered = [
+            LayoutPoint::new(10.0, 10.0),
+            LayoutPoint::new(20.0, 20.0),
+            LayoutPoint::new(30.0, 30.0),
+            LayoutPoint::new(40.0, 40.0),
+        ];
+        assert_eq!(texture_binds(staggered), 8);
+
+        // Apart, the images of each texture are grouped into one batch.
+        let apart = [
+            LayoutPoint::new(0.0, 0.0),
+            LayoutPoint::new(50.0, 0.0),
+            LayoutPoint::new(0.0, 50.0),
+            LayoutPoint::new(50.0, 50.0),
+        ];
+        assert_eq!(texture_binds(apart), 4);
+    }
+
+    #[test]
+    fn overdraw_grid_is_built_only_when_configured() {
+        let overdraw_grid = |overdraw_grid_tile_size| {
+            let mut config = FrameBuilder::empty().config;
+            config.overdraw_grid_tile_size = overdraw_grid_tile_size;
+            let mut doc = TestDocument::new(config);
+
+            let image_key = ImageKey::new(IdNamespace(0), 0);
+            let mut updates = ResourceUpdates::new();
+            let external = ExternalImageData {
+                id: ExternalImageId(0),
+                channel_index: 0,
+                image_type: ExternalImageType::TextureHandle(TextureTarget::Default),
+            };
+            updates.add_image(
+                image_key,
+                ImageDescriptor::new(4, 4, ImageFormat::BGRA8, true, false),
+                ImageData::External(external),
+                None,
+            );
+            doc.apply_resource_updates(updates);
+
+            // An opaque image and a solid rect, both covering the window, are
+            // drawn in separate batches.
+            let size = LayoutSize::new(100.0, 100.0);
+            let rect = LayoutRect::new(LayoutPoint::zero(), size);
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            builder.push_image(
+                &LayoutPrimitiveInfo::new(rect),
+                rect.size,
+                LayoutSize::zero(),
+                ImageRendering::Auto,
+                AlphaType::PremultipliedAlpha,
+                image_key,
+            );
+            builder.push_rect(&LayoutPrimitiveInfo::new(rect), ColorF::new(0.0, 1.0, 0.0, 1.0));
+            doc.build_scene(builder);
+            doc.render().frame.overdraw_grid
+        };
+
+        assert_eq!(overdraw_grid(None), None);
+        // The 100x100 window is covered by 3x3 tiles, the last ones partially.
+        assert_eq!(overdraw_grid(Some(40)), Some(vec![2; 9]));
+    }
+
+    #[test]
+    fn first_contentful_frame_is_flagged_once() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let render = |doc: &mut TestDocument, with_content: bool| {
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            if with_content {
+                builder.push_rect(
+                    &LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size)),
+                    ColorF::new(0.0, 0.0, 1.0, 1.0),
+                );
+            }
+            doc.build_scene(builder);
+            doc.render().frame.is_first_contentful
+        };
+
+        assert!(!render(&mut doc, false));
+        assert!(!render(&mut doc, false));
+        assert!(render(&mut doc, true));
+        assert!(!render(&mut doc, true));
+        assert!(!render(&mut doc, false));
+    }
+
+    #[test]
+    fn filtered_scene_flattens_in_dependency_order() {
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_stacking_context(
+            &info,
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Grayscale(1.0)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(FrameBuilder::empty().config, builder);
+
+        let commands = rendered.frame.flatten_commands();
+        let mut current_pass = 0;
+        let mut current_target = None;
+        let mut offscreen_draws = 0;
+        let mut main_passes = Vec::new();
+        let mut composites = 0;
+        for command in &commands {
+            match *command {
+                FlatCommand::BeginTarget { pass_index, target } => {
+                    assert!(current_target.is_none());
+                    assert_eq!(pass_index, current_pass);
+                    if let FrameTarget::MainFramebuffer = target {
+                        main_passes.push(pass_index);
+                    }
+                    current_target = Some(target);
+                }
+                FlatCommand::Draw(ref draw) => match (current_target, draw) {
+                    (Some(FrameTarget::MainFramebuffer), &DrawCommand::AlphaBatch(..)) |
+                    (Some(FrameTarget::MainFramebuffer), &DrawCommand::OpaqueBatch(..)) => {
+                        composites += 1;
+                    }
+                    (Some(_), _) => offscreen_draws += 1,
+                    (None, _) => panic!("draw outside of a target"),
+                },
+                FlatCommand::EndTarget => {
+                    assert!(current_target.take().is_some());
+                }
+                FlatCommand::EndPass(pass_index) => {
+                    assert!(current_target.is_none());
+                    assert_eq!(pass_index, current_pass);
+                    current_pass += 1;
+                }
+            }
+        }
+
+        // The filter is drawn offscreen in earlier passes, and the last pass
+        // composites it into the main framebuffer.
+        assert_eq!(current_pass, rendered.frame.passes.len());
+        assert!(current_pass > 1);
+        assert_eq!(main_passes, vec![current_pass - 1]);
+        assert!(offscreen_draws > 0);
+        assert!(composites > 0);
+    }
+
+    #[test]
+    fn visitor_sees_targets_and_batches_in_render_order() {
+        #[derive(Debug, PartialEq)]
+        enum Event {
+            Begin(usize, String),
+            Batch(*const PrimitiveBatch),
+            End,
+        }
+
+        struct Events(Vec<Event>);
+
+        impl FrameVisitor for Events {
+            fn begin_target(&mut self, pass_index: usize, target: FrameTarget) {
+                self.0.push(Event::Begin(pass_index, format!("{:?}", target)));
+            }
+            fn draw_command(&mut self, command: DrawCommand) {
+                match command {
+                    DrawCommand::OpaqueBatch(batch) | DrawCommand::AlphaBatch(batch) => {
+                        self.0.push(Event::Batch(batch));
+                    }
+                    _ => {}
+                }
+            }
+            fn end_target(&mut self) {
+                self.0.push(Event::End);
+            }
+        }
+
+        // A blurred rect next to an opaque one, so that there are batches
+        // both in an off-screen pass and in the main framebuffer.
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = |x| LayoutRect::new(LayoutPoint::new(x, 0.0), LayoutSize::new(40.0, 40.0));
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0)), ColorF::new(1.0, 0.0, 0.0, 1.0));
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(rect(50.0)),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Blur(2.0)],
+            GlyphRasterSpace::Screen,
+        );
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(50.0)), ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        let rendered = render_display_list(FrameBuilder::empty().config, builder);
+        let frame = &rendered.frame;
+
+        let mut events = Events(Vec::new());
+        frame.visit(&mut events);
+
+        // Walk the passes by hand, with the opaque batches of each color
+        // target drawn front to back before its alpha batches.
+        let mut expected = Vec::new();
+        for (pass_index, pass) in frame.passes.iter().enumerate() {
+            let color_targets: Vec<(FrameTarget, &ColorRenderTarget)> = match pass.kind {
+                RenderPassKind::MainFramebuffer(ref target) => {
+                    vec![(FrameTarget::MainFramebuffer, target)]
+                }
+                RenderPassKind::OffScreen { ref alpha, ref color, ref texture_cache } => {
+                    assert!(texture_cache.is_empty());
+                    for index in 0 .. alpha.targets.len() {
+                        let target = FrameTarget::Alpha(RenderTargetIndex(index));
+                        expected.push(Event::Begin(pass_index, format!("{:?}", target)));
+                        expected.push(Event::End);
+                    }
+                    color.targets
+                        .iter()
+                        .enumerate()
+                        .map(|(index, target)| {
+                            (FrameTarget::Color(RenderTargetIndex(index)), target)
+                        })
+                        .collect()
+                }
+            };
+            for (target, color_target) in color_targets {
+                expected.push(Event::Begin(pass_index, format!("{:?}", target)));
+                for container in &color_target.alpha_batch_containers {
+                    for batch in container.opaque_batches.iter().rev() {
+                        expected.push(Event::Batch(batch));
+                    }
+                }
+                for container in &color_target.alpha_batch_containers {
+                    for batch in &container.alpha_batches {
+                        expected.push(Event::Batch(batch));
+                    }
+                }
+                expected.push(Event::End);
+            }
+        }
+
+        assert!(frame.passes.len() > 1);
+        let batch_count = expected
+            .iter()
+            .filter(|event| match **event {
+                Event::Batch(..) => true,
+                _ => false,
+            })
+            .count();
+        assert!(batch_count > 1);
+        assert_eq!(events.0, expected);
+    }
+
+    #[test]
+    fn flat_stacking_context_flattens_3d_content_into_its_plane() {
+        // Where the origin of content moved along z ends up, when it is inside
+        // a stacking context of the given style in a rotated 3d context.
+        let content_origin = |transform_style: TransformStyle| {
+            let size = LayoutSize::new(100.0, 100.0);
+            let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            let push_stacking_context = |
+                builder: &mut DisplayListBuilder,
+                transform: Option<LayoutTransform>,
+                transform_style,
+            | {
+                builder.push_stacking_context(
+                    &info,
+                    None,
+                    transform.map(PropertyBinding::Value),
+                    transform_style,
+                    None,
+                    MixBlendMode::Normal,
+                    Vec::new(),
+                    GlyphRasterSpace::Screen,
+                );
+            };
+            let rotation = LayoutTransform::create_rotation(0.0, 1.0, 0.0, Angle::degrees(60.0));
+            push_stacking_context(&mut builder, Some(rotation), TransformStyle::Preserve3D);
+            push_stacking_context(&mut builder, None, transform_style);
+            let depth = LayoutTransform::create_translation(0.0, 0.0, 50.0);
+            push_stacking_context(&mut builder, Some(depth), TransformStyle::Flat);
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            for _ in 0 .. 3 {
+                builder.pop_stacking_context();
+            }
+
+            let mut doc = TestDocument::new(FrameBuilder::empty().config);
+            doc.build_scene(builder);
+            doc.render();
+
+            let depth_frame = doc.clip_scroll_tree.nodes
+                .iter()
+                .rev()
+                .find(|node| match node.node_type {
+                    NodeType::ReferenceFrame(..) => true,
+                    _ => false,
+                })
+                .unwrap();
+            depth_frame.world_content_transform.transform_point2d(&LayoutPoint::zero())
+        };
+
+        // Preserving 3d, the offset along z is rotated into x.
+        let origin = content_origin(TransformStyle::Preserve3D);
+        assert!((origin.x.abs() - 50.0 * (60.0f32).to_radians().sin()).abs() < 0.01);
+
+        // A flat stacking context keeps its content in its own plane, which
+        // the rotation leaves in place at the origin.
+        let origin = content_origin(TransformStyle::Flat);
+        assert!(origin.x.abs() < 0.01);
+        assert!(origin.y.abs() < 0.01);
+    }
+
+    #[test]
+    fn pipeline_outside_the_viewport_is_reported_as_fully_clipped() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let root_pipeline_id = PipelineId(0, 0);
+        let iframe_pipeline_id = PipelineId(0, 1);
+        let size = LayoutSize::new(100.0, 100.0);
+        let iframe_size = LayoutSize::new(50.0, 50.0);
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        // The root pipeline draws on screen, and embeds the other one past
+        // its right edge.
+        let rect = LayoutRect::new(LayoutPoint::zero(), iframe_size);
+        let mut builder = DisplayListBuilder::new(iframe_pipeline_id, iframe_size);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+        doc.set_display_list(builder, Epoch(0));
+
+        let mut builder = DisplayListBuilder::new(root_pipeline_id, size);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+        let iframe_rect = LayoutRect::new(LayoutPoint::new(200.0, 0.0), iframe_size);
+        builder.push_iframe(&LayoutPrimitiveInfo::new(iframe_rect), iframe_pipeline_id);
+        doc.build_scene(builder);
+        doc.render();
+
+        let fully_clipped = &doc.frame_builder.as_ref().unwrap().fully_clipped_pipelines;
+        assert_eq!(*fully_clipped, vec![iframe_pipeline_id]);
+    }
+
+    #[test]
+    fn overlay_draws_over_the_document_frame_until_removed() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let root_pipeline_id = PipelineId(0, 0);
+        let overlay_pipeline_id = PipelineId(0, 1);
+        let iframe_pipeline_id = PipelineId(0, 2);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(20.0, 20.0));
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        for &pipeline_id in &[overlay_pipeline_id, iframe_pipeline_id] {
+            let mut builder = DisplayListBuilder::new(pipeline_id, size);
+            builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+            doc.set_display_list(builder, Epoch(2));
+        }
+        let mut builder = DisplayListBuilder::new(root_pipeline_id, size);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect), color);
+        builder.push_iframe(&LayoutPrimitiveInfo::new(rect), iframe_pipeline_id);
+        doc.build_scene(builder);
+        let original = doc.render();
+        assert!(original.overlays.is_empty());
+        let solids = solid_instance_count(&original.frame);
+        let frame_id = doc.frame_id;
+
+        // The overlay is drawn in a frame of its own, without building the
+        // scene again, and leaves the frame of the document as it was.
+        assert!(doc.add_overlay(overlay_pipeline_id));
+        assert!(!doc.add_overlay(overlay_pipeline_id));
+        let rendered = doc.render();
+        assert_eq!(doc.frame_id, frame_id);
+        assert_eq!(rendered.frame.draw_command_count(), original.frame.draw_command_count());
+        assert_eq!(solid_instance_count(&rendered.frame), solids);
+        assert_eq!(rendered.overlays.len(), 1);
+        assert_eq!(solid_instance_count(&rendered.overlays[0]), 1);
+        assert_eq!(
+            rendered.overlays[0].presentation_blend_mode,
+            PresentationBlendMode::SourceOver,
+        );
+        assert_eq!(rendered.pipeline_info.epochs[&overlay_pipeline_id], Epoch(2));
+
+        // The root pipeline and iframes are drawn by the scene already.
+        assert!(doc.add_overlay(root_pipeline_id));
+        assert!(doc.add_overlay(iframe_pipeline_id));
+        assert_eq!(doc.render().overlays.len(), 1);
+
+        assert!(doc.remove_overlay(overlay_pipeline_id));
+        assert!(!doc.remove_overlay(overlay_pipeline_id));
+        let rendered = doc.render();
+        assert!(rendered.overlays.is_empty());
+        assert_eq!(solid_instance_count(&rendered.frame), solids);
+    }
+
+    #[test]
+    fn nested_stacking_context_origin_snaps_to_document_device_pixels() {
+        let mut config = FrameBuilder::empty().config;
+        config.snap_stacking_context_origins = true;
+        let mut doc = TestDocument::with_window(config, DeviceUintSize::new(100, 100), 2.0);
+        let root_pipeline_id = PipelineId(0, 0);
+        let iframe_pipeline_id = PipelineId(0, 1);
+        let iframe_size = LayoutSize::new(40.0, 40.0);
+        let rect = LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(20.0, 20.0));
+
+        // A stacking context at a fractional origin, inside an iframe that
+        // is at a fractional origin too.
+        let mut builder = DisplayListBuilder::new(iframe_pipeline_id, iframe_size);
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(rect.translate(&LayoutVector2D::new(0.3, 0.3))),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            Vec::new(),
+            GlyphRasterSpace::Screen,
+        );
+        let info = LayoutPrimitiveInfo {
+            tag: Some((1, 0)),
+            ..LayoutPrimitiveInfo::new(rect)
+        };
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_stacking_context();
+        doc.set_display_list(builder, Epoch(0));
+
+        let mut builder = DisplayListBuilder::new(root_pipeline_id, LayoutSize::new(50.0, 50.0));
+        let iframe_rect = LayoutRect::new(LayoutPoint::new(10.3, 10.3), iframe_size);
+        builder.push_iframe(&LayoutPrimitiveInfo::new(iframe_rect), iframe_pipeline_id);
+        doc.build_scene(builder);
+        doc.render();
+
+        // The content lands on a whole device pixel of the document, rather
+        // than a whole device pixel of the iframe, and keeps its size.
+        let visible_rect = doc.item_visible_rect(iframe_pipeline_id, (1, 0)).unwrap();
+        for &coordinate in &[visible_rect.origin.x, visible_rect.origin.y] {
+            assert!((coordinate - 10.5).abs() < 0.001, "{} is not snapped", coordinate);
+        }
+        assert!((visible_rect.size.width - 20.0).abs() < 0.001);
+        assert!((visible_rect.size.height - 20.0).abs() < 0.001);
+    }
+
+    #[test]
+    fn scroll_frame_world_transform_includes_parent_transform_and_offset() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        // A scroll frame inside a stacking context that is translated.
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), size);
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        let translation = LayoutTransform::create_translation(20.0, 10.0, 0.0);
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(frame_rect),
+            None,
+            Some(PropertyBinding::Value(translation)),
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            Vec::new(),
+            GlyphRasterSpace::Screen,
+        );
+        let scroll_id = ExternalScrollId(1, pipeline_id);
+        let scroll_frame = builder.define_scroll_frame(
+            Some(scroll_id),
+            LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 300.0)),
+            frame_rect,
+            vec![],
+            None,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        builder.push_clip_id(scroll_frame);
+        builder.push_rect(&LayoutPrimitiveInfo::new(frame_rect), ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_clip_id();
+        builder.pop_stacking_context();
+        doc.build_scene(builder);
+
+        doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, -50.0));
+        doc.render();
+
+        let transform = doc.clip_scroll_tree.scroll_frame_world_transform(scroll_id).unwrap();
+        let origin = transform.transform_point2d(&LayoutPoint::zero());
+        assert_eq!(origin, WorldPoint::new(20.0, -40.0));
+
+        let unknown_id = ExternalScrollId(2, pipeline_id);
+        assert!(doc.clip_scroll_tree.scroll_frame_world_transform(unknown_id).is_none());
+    }
+
+    #[test]
+    fn image_color_space_is_recorded_on_its_draw_call() {
+        let image_color_spaces = |color_space| {
+            let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+            let image_key = ImageKey::new(IdNamespace(0), 0);
+            doc.resource_cache.add_image_template(
+                image_key,
+                ImageDescriptor::new(4, 4, ImageFormat::BGRA8, true, false),
+                ImageData::new(vec![0xff; 4 * 4 * 4]),
+                None,
+            );
+
+            let size = LayoutSize::new(100.0, 100.0);
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            let rect = LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(40.0, 40.0));
+            let info = LayoutPrimitiveInfo::new(rect);
+            match color_space {
+                Some(color_space) => builder.push_image_with_color_space(
+                    &info,
+                    rect.size,
+                    LayoutSize::zero(),
+                    ImageRendering::Auto,
+                    AlphaType::PremultipliedAlpha,
+                    image_key,
+                    color_space,
+                ),
+                None => builder.push_image(
+                    &info,
+                    rect.size,
+                    LayoutSize::zero(),
+                    ImageRendering::Auto,
+                    AlphaType::PremultipliedAlpha,
+                    image_key,
+                ),
+            }
+            doc.build_scene(builder);
+            let rendered = doc.render();
+
+            let mut color_spaces = Vec::new();
+            visit_batches(&rendered.frame, |batch| {
+                if let BatchKind::Brush(BrushBatchKind::Image(..)) = batch.key.kind {
+                    color_spaces.extend(batch.instances.iter().map(|i| i.brush_user_data()[2]));
+                }
+            });
+            color_spaces
+        };
+
+        // The draw call of a P3 image asks the shader to convert it to sRGB,
+        // while untagged images are taken to be sRGB already.
+        let display_p3 = ImageColorSpace::DisplayP3 as i32;
+        let srgb = ImageColorSpace::Srgb as i32;
+        assert_eq!(image_color_spaces(Some(ImageColorSpace::DisplayP3)), vec![display_p3]);
+        assert_eq!(image_color_spaces(None), vec![srgb]);
+    }
+
+    #[test]
+    fn frame_snapshot_survives_failed_scene_build() {
+        let mut config = FrameBuilder::empty().config;
+        config.retain_frame_snapshot = true;
+        let mut doc = TestDocument::new(config);
+
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        builder.push_rect(
+            &LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size)),
+            ColorF::new(0.0, 0.0, 1.0, 1.0),
+        );
+        doc.build_scene_at_epoch(builder, Epoch(1));
+        let rendered = doc.render();
+
+        let snapshot = rendered.snapshot.expect("no snapshot of a built frame");
+        assert!(snapshot.draw_command_count > 0);
+        assert!(!snapshot.render_targets.is_empty());
+        assert_eq!(snapshot.pipeline_epochs.get(&pipeline_id), Some(&Epoch(1)));
+
+        // Without its root pipeline the next scene can't be built, and the
+        // document has nothing to render.
+        doc.pending.scene.remove_pipeline(pipeline_id);
+        let TestDocument { ref mut doc, ref mut resource_cache, .. } = doc;
+        doc.build_scene(resource_cache);
+        assert!(!doc.can_render());
+
+        let retained = doc.last_frame_snapshot.as_ref().expect("snapshot was dropped");
+        assert_eq!(retained.draw_command_count, snapshot.draw_command_count);
+        assert_eq!(retained.render_targets.len(), snapshot.render_targets.len());
+        assert_eq!(retained.pipeline_epochs.get(&pipeline_id), Some(&Epoch(1)));
+    }
+
+    #[test]
+    fn nested_clips_keep_their_own_transforms() {
+        let size = LayoutSize::new(100.0, 100.0);
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let outer_clip = builder.define_clip(
+            LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(50.0, 100.0)),
+            None,
+            None,
+        );
+        builder.push_clip_id(outer_clip);
+        let rotation = LayoutTransform::create_rotation(0.0, 0.0, 1.0, Angle::degrees(45.0));
+        builder.push_stacking_context(
+            &info,
+            None,
+            Some(PropertyBinding::Value(rotation)),
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            Vec::new(),
+            GlyphRasterSpace::Screen,
+        );
+        let inner_clip = builder.define_clip(info.rect, None, None);
+        builder.push_clip_id(inner_clip);
+        builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_clip_id();
+        builder.pop_stacking_context();
+        builder.pop_clip_id();
+
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        doc.build_scene(builder);
+        doc.render();
+
+        // The rotated clip isn't folded into the outer one. Each is applied
+        // in its own coordinate system.
+        let chain = doc.clip_scroll_tree.clip_chains
+            .iter()
+            .find(|chain| chain.has_non_root_coord_system)
+            .expect("no clip chain for the rotated clip");
+        let coordinate_systems: Vec<_> = ClipChainNodeIter { current: chain.nodes.clone() }
+            .map(|node| node.work_item.coordinate_system_id)
+            .collect();
+        assert_eq!(coordinate_systems.len(), 2);
+        assert_ne!(coordinate_systems[0], coordinate_systems[1]);
+    }
+
+    #[test]
+    fn rendered_epochs_are_acknowledged_per_pipeline() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        builder.push_rect(
+            &LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size)),
+            ColorF::new(0.0, 0.0, 1.0, 1.0),
+        );
+        doc.build_scene_at_epoch(builder, Epoch(3));
+        let rendered = doc.render();
+
+        let info = &rendered.pipeline_info;
+        assert!(info.is_epoch_rendered(pipeline_id, Epoch(2)));
+        assert!(info.is_epoch_rendered(pipeline_id, Epoch(3)));
+        assert!(!info.is_epoch_rendered(pipeline_id, Epoch(4)));
+        assert!(!info.is_epoch_rendered(PipelineId(0, 1), Epoch(0)));
+    }
+
+    /// Two 50x100 scroll frames side by side over 300-tall content, with
+    /// ids 1 and 2, each with hit-testable content.
+    fn side_by_side_scroll_frames(pipeline_id: PipelineId) -> DisplayListBuilder {
+        let size = LayoutSize::new(100.0, 100.0);
+        let frame_size = LayoutSize::new(50.0, 100.0);
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        for (i, x) in [0.0, 50.0].iter().enumerate() {
+            let frame_rect = LayoutRect::new(LayoutPoint::new(*x, 0.0), frame_size);
+            let content_rect = LayoutRect::new(frame_rect.origin, LayoutSize::new(50.0, 300.0));
+            let scroll_frame = builder.define_scroll_frame(
+                Some(ExternalScrollId(i as u64 + 1, pipeline_id)),
+                content_rect,
+                frame_rect,
+                vec![],
+                None,
+                ScrollSensitivity::ScriptAndInputEvents,
+            );
+            builder.push_clip_id(scroll_frame);
+            let info = LayoutPrimitiveInfo {
+                tag: Some((i as u64, 0)),
+                ..LayoutPrimitiveInfo::new(content_rect)
+            };
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_clip_id();
+        }
+        builder
+    }
+
+    fn scroll_offset(doc: &TestDocument, id: ExternalScrollId) -> LayoutVector2D {
+        doc.get_scroll_node_state()
+            .into_iter()
+            .find(|state| state.id == id)
+            .unwrap()
+            .scroll_offset
+    }
+
+    #[test]
+    fn scroll_links_and_friction_survive_a_scene_rebuild() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        let left = ExternalScrollId(1, pipeline_id);
+        let right = ExternalScrollId(2, pipeline_id);
+        doc.build_scene(side_by_side_scroll_frames(pipeline_id));
+        doc.clip_scroll_tree.link_scroll_frames(left, right, ScrollAxes::VERTICAL);
+        doc.clip_scroll_tree.set_scroll_friction(0.5);
+
+        // A scene from the scene builder thread comes with a tree of its own,
+        // which has to keep the settings the embedder gave the old one.
+        doc.build_scene_async(side_by_side_scroll_frames(pipeline_id));
+        doc.render();
+
+        let over_left = WorldPoint::new(25.0, 50.0);
+        assert!(doc.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -20.0)), over_left));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -20.0));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::new(0.0, -20.0));
+
+        assert!(doc.tick_scrolling(Instant::now()));
+        assert!(scroll_offset(&doc, left).y < -20.0);
+        assert_eq!(scroll_offset(&doc, right), scroll_offset(&doc, left));
+    }
+
+    #[test]
+    fn scrolling_a_frame_to_an_origin_reports_whether_it_moved() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        let left = ExternalScrollId(1, pipeline_id);
+        doc.build_scene(side_by_side_scroll_frames(pipeline_id));
+        doc.render();
+
+        let scroll_to = |doc: &mut TestDocument, y| {
+            doc.scroll_node(LayoutPoint::new(0.0, y), left, ScrollClamping::ToContentBounds)
+        };
+        assert!(scroll_to(&mut doc, 40.0));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -40.0));
+        assert!(!scroll_to(&mut doc, 40.0));
+
+        // Origins past the end of the content are clamped to it.
+        assert!(scroll_to(&mut doc, 1000.0));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -200.0));
+        assert!(!scroll_to(&mut doc, 500.0));
+    }
+
+    #[test]
+    fn a_glide_after_a_pause_starts_with_a_single_frame_of_movement() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        let left = ExternalScrollId(1, pipeline_id);
+        doc.build_scene(side_by_side_scroll_frames(pipeline_id));
+        doc.render();
+        doc.clip_scroll_tree.set_scroll_friction(0.5);
+
+        let over_left = WorldPoint::new(25.0, 50.0);
+        let scroll = ScrollLocation::Delta(LayoutVector2D::new(0.0, -20.0));
+
+        // Let a first glide come to a stop.
+        let mut now = Instant::now();
+        assert!(doc.scroll(scroll, over_left));
+        let mut ticks = 0;
+        while doc.tick_scrolling(now) {
+            now += Duration::from_millis(16);
+            ticks += 1;
+            assert!(ticks < 20);
+        }
+
+        // The time that passed while nothing moved doesn't count towards
+        // the next glide.
+        now += Duration::from_secs(10);
+        assert!(doc.scroll(scroll, over_left));
+        let offset = scroll_offset(&doc, left);
+        assert!(doc.tick_scrolling(now));
+        assert_eq!(scroll_offset(&doc, left), offset + LayoutVector2D::new(0.0, -10.0));
+    }
+
+    #[test]
+    fn ending_a_scroll_gesture_settles_on_snap_offsets() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        let left = ExternalScrollId(1, pipeline_id);
+        let right = ExternalScrollId(2, pipeline_id);
+        doc.build_scene(side_by_side_scroll_frames(pipeline_id));
+        doc.render();
+        let snap_offsets = [0.0, -100.0, -200.0];
+        let proximity = ScrollSnapType::Proximity { threshold: 20.0 };
+        assert!(doc.clip_scroll_tree.set_scroll_snap_type(right, proximity));
+
+        // A mandatory frame settles on the nearest snap offset.
+        assert!(doc.scroll_node_by(left, LayoutVector2D::new(0.0, -60.0)));
+        assert!(doc.end_scroll_gesture(left, &[], &snap_offsets));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -100.0));
+
+        // A proximity frame keeps an offset far from the snap offsets...
+        assert!(doc.scroll_node_by(right, LayoutVector2D::new(0.0, -60.0)));
+        assert!(!doc.end_scroll_gesture(right, &[], &snap_offsets));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::new(0.0, -60.0));
+
+        // ...and settles on one it is released near.
+        assert!(doc.scroll_node_by(right, LayoutVector2D::new(0.0, -25.0)));
+        assert!(doc.end_scroll_gesture(right, &[], &snap_offsets));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::new(0.0, -100.0));
+    }
+
+    #[test]
+    fn captured_scroll_frame_scrolls_wherever_the_cursor_is() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        doc.build_scene(side_by_side_scroll_frames(pipeline_id));
+        doc.render();
+
+        let left = ExternalScrollId(1, pipeline_id);
+        let right = ExternalScrollId(2, pipeline_id);
+
+        // Drag from the left frame and over the right one.
+        doc.scroll_capture = Some(left);
+        let over_right = WorldPoint::new(75.0, 50.0);
+        assert!(doc.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -30.0)), over_right));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -30.0));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::zero());
+
+        // Without the capture, the frame under the cursor scrolls.
+        doc.scroll_capture = None;
+        assert!(doc.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -30.0)), over_right));
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -30.0));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::new(0.0, -30.0));
+
+        // Beginning a scroll captures the frame under the cursor, and the
+        // rest of the gesture scrolls it like scrolling with the cursor
+        // held still over it.
+        let over_left = WorldPoint::new(25.0, 50.0);
+        for _ in 0 .. 2 {
+            assert!(doc.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -10.0)), over_left));
+        }
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -50.0));
+        assert_eq!(doc.begin_scroll(over_left), Some(left));
+        for cursor in &[over_left, over_right] {
+            assert!(doc.scroll(ScrollLocation::Delta(LayoutVector2D::new(0.0, -10.0)), *cursor));
+        }
+        assert_eq!(scroll_offset(&doc, left), LayoutVector2D::new(0.0, -70.0));
+        assert_eq!(scroll_offset(&doc, right), LayoutVector2D::new(0.0, -30.0));
+    }
+
+    #[test]
+    fn panning_by_the_window_height_renders_successive_pages() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+
+        // A document three windows tall, with one tagged rect per page.
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 300.0);
+        let page_rect = |page| {
+            let origin = LayoutPoint::new(0.0, page as f32 * 100.0);
+            LayoutRect::new(origin, LayoutSize::new(100.0, 100.0))
+        };
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        for page in 0 .. 3 {
+            let info = LayoutPrimitiveInfo {
+                tag: Some((page, 0)),
+                ..LayoutPrimitiveInfo::new(page_rect(page))
+            };
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        }
+        doc.build_scene(builder);
+
+        let window_rect = WorldRect::new(WorldPoint::zero(), WorldSize::new(100.0, 100.0));
+        for page in 0 .. 3 {
+            doc.view.pan = DeviceIntPoint::new(0, -100 * page as i32);
+            doc.render();
+
+            // Only the current page shows, filling the window.
+            for tag in 0 .. 3 {
+                let expected = if tag == page { Some(window_rect) } else { None };
+                assert_eq!(doc.item_visible_rect(pipeline_id, (tag, 0)), expected);
+            }
+        }
+    }
+
+    /// A display list with a scroll frame the size of the window, whose
+    /// content is two windows taller. It holds a rect tagged 1 at the top
+    /// of the content, and one tagged 2 at the bottom.
+    fn scroll_frame_with_tagged_rects(scroll_id: ExternalScrollId) -> DisplayListBuilder {
+        let size = LayoutSize::new(100.0, 100.0);
+        let frame_rect = LayoutRect::new(LayoutPoint::zero(), size);
+        let mut builder = DisplayListBuilder::new(scroll_id.1, size);
+        let scroll_frame = builder.define_scroll_frame(
+            Some(scroll_id),
+            LayoutRect::new(LayoutPoint::zero(), LayoutSize::new(100.0, 300.0)),
+            frame_rect,
+            vec![],
+            None,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        builder.push_clip_id(scroll_frame);
+        for &(tag, y) in &[(1, 0.0), (2, 200.0)] {
+            let info = LayoutPrimitiveInfo {
+                tag: Some((tag, 0)),
+                ..LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::new(0.0, y), size))
+            };
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+        }
+        builder.pop_clip_id();
+        builder
+    }
+
+    #[test]
+    fn half_scrolled_out_item_reports_visible_half() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let pipeline_id = PipelineId(0, 0);
+        let scroll_id = ExternalScrollId(1, pipeline_id);
+        doc.build_scene(scroll_frame_with_tagged_rects(scroll_id));
+        doc.render();
+
+        let world_rect = |y, height| {
+            WorldRect::new(WorldPoint::new(0.0, y), WorldSize::new(100.0, height))
+        };
+        assert_eq!(doc.item_visible_rect(pipeline_id, (1, 0)), Some(world_rect(0.0, 100.0)));
+        assert_eq!(doc.item_visible_rect(pipeline_id, (2, 0)), None);
+
+        // Scroll the first item halfway out of the frame.
+        doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, -50.0));
+        doc.render();
+
+        assert_eq!(doc.item_visible_rect(pipeline_id, (1, 0)), Some(world_rect(0.0, 50.0)));
+        assert_eq!(doc.item_visible_rect(pipeline_id, (2, 0)), None);
+    }
+
+    #[test]
+    fn hit_testing_follows_scrolling_before_the_next_frame() {
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        let scroll_id = ExternalScrollId(1, PipelineId(0, 0));
+        doc.build_scene(scroll_frame_with_tagged_rects(scroll_id));
+        doc.render();
+
+        let hit_tags = |doc: &TestDocument| {
+            let test = HitTest::new(None, WorldPoint::new(50.0, 50.0), HitTestFlags::empty());
+            let result = doc.hit_tester.as_ref().unwrap().hit_test(test);
+            result.items.iter().map(|item| item.tag).collect::<Vec<_>>()
+        };
+        assert_eq!(hit_tags(&doc), vec![(1, 0)]);
+
+        // Clicks land on what the scroll brought under them, even though no
+        // frame has been rendered since.
+        assert!(doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, -200.0)));
+        assert_eq!(hit_tags(&doc), vec![(2, 0)]);
+    }
+
+    #[test]
+    fn clip_margin_keeps_outlines_at_scroll_frame_edges_visible() {
+        let outline_and_scroll_offset = |clip_margin| {
+            let window_size = DeviceUintSize::new(200, 200);
+            let mut doc = TestDocument::with_window(FrameBuilder::empty().config, window_size, 1.0);
+
+            let pipeline_id = PipelineId(0, 0);
+            let size = LayoutSize::new(200.0, 200.0);
+            let frame_rect =
+                LayoutRect::new(LayoutPoint::new(10.0, 10.0), LayoutSize::new(100.0, 100.0));
+            let mut builder = DisplayListBuilder::new(pipeline_id, size);
+            builder.push_stacking_context_with_clip_margin(
+                &LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                Vec::new(),
+                GlyphRasterSpace::Screen,
+                clip_margin,
+            );
+            let scroll_id = ExternalScrollId(1, pipeline_id);
+            let scroll_frame = builder.define_scroll_frame(
+                Some(scroll_id),
+                LayoutRect::new(frame_rect.origin, LayoutSize::new(100.0, 300.0)),
+                frame_rect,
+                vec![],
+                None,
+                ScrollSensitivity::ScriptAndInputEvents,
+            );
+            builder.push_clip_id(scroll_frame);
+            // A focus outline that reaches a pixel past the edges of the frame.
+            let info = LayoutPrimitiveInfo {
+                tag: Some((1, 0)),
+                ..LayoutPrimitiveInfo::new(frame_rect.inflate(1.0, 1.0))
+            };
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_clip_id();
+            builder.pop_stacking_context();
+            doc.build_scene(builder);
+            doc.render();
+            let outline = doc.item_visible_rect(pipeline_id, (1, 0));
+
+            doc.scroll_node_by(scroll_id, LayoutVector2D::new(0.0, -1000.0));
+            let scroll_offset = doc.get_scroll_node_state()
+                .iter()
+                .find(|state| state.id == scroll_id)
+                .map(|state| state.scroll_offset);
+            (outline, scroll_offset)
+        };
+
+        let world_rect = |origin, size| {
+            WorldRect::new(WorldPoint::new(origin, origin), WorldSize::new(size, size))
+        };
+        let max_scroll_offset = Some(LayoutVector2D::new(0.0, -200.0));
+
+        // Without a margin, the scroll frame's clip cuts the outline off.
+        let (outline, scroll_offset) = outline_and_scroll_offset(0.0);
+        assert_eq!(outline, Some(world_rect(10.0, 100.0)));
+        assert_eq!(scroll_offset, max_scroll_offset);
+
+        // With a margin the whole outline shows, and the frame scrolls as far
+        // as before.
+        let (outline, scroll_offset) = outline_and_scroll_offset(2.0);
+        assert_eq!(outline, Some(world_rect(9.0, 102.0)));
+        assert_eq!(scroll_offset, max_scroll_offset);
+    }
+
+    #[test]
+    fn stacking_contexts_share_a_clip_chain() {
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let rect = |x, y, w, h| LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(w, h));
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        let clip = builder.define_clip(rect(0.0, 0.0, 100.0, 50.0), None, None);
+        let clip_chain = builder.define_clip_chain(None, vec![clip]);
+        let halves = vec![(1, rect(0.0, 0.0, 50.0, 100.0)), (2, rect(50.0, 0.0, 50.0, 100.0))];
+        for (tag, half) in halves {
+            builder.push_stacking_context(
+                &LayoutPrimitiveInfo::new(rect(0.0, 0.0, 100.0, 100.0)),
+                None,
+                None,
+                TransformStyle::Flat,
+                None,
+                MixBlendMode::Normal,
+                Vec::new(),
+                GlyphRasterSpace::Screen,
+            );
+            builder.push_clip_id(ClipId::ClipChain(clip_chain));
+            let info = LayoutPrimitiveInfo {
+                tag: Some((tag, 0)),
+                ..LayoutPrimitiveInfo::new(half)
+            };
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_clip_id();
+            builder.pop_stacking_context();
+        }
+        let mut doc = TestDocument::new(FrameBuilder::empty().config);
+        doc.build_scene(builder);
+        doc.render();
+
+        // The clip and the chain referencing it are stored once.
+        let clip_nodes = doc.clip_scroll_tree.nodes
+            .iter()
+            .filter(|node| match node.node_type {
+                NodeType::Clip { .. } => true,
+                _ => false,
+            })
+            .count();
+        assert_eq!(clip_nodes, 1);
+        assert_eq!(doc.clip_scroll_tree.clip_chains_descriptors.len(), 1);
+
+        // Both stacking contexts are clipped by it.
+        let world_rect = |x, y, w, h| WorldRect::new(WorldPoint::new(x, y), WorldSize::new(w, h));
+        assert_eq!(
+            doc.item_visible_rect(pipeline_id, (1, 0)),
+            Some(world_rect(0.0, 0.0, 50.0, 50.0))
+        );
+        assert_eq!(
+            doc.item_visible_rect(pipeline_id, (2, 0)),
+            Some(world_rect(50.0, 0.0, 50.0, 50.0))
+        );
+    }
+
+    #[test]
+    fn highlighted_scroll_frame_covers_its_device_rect() {
+        let window_size = DeviceUintSize::new(200, 200);
+        let mut doc = TestDocument::with_window(FrameBuilder::empty().config, window_size, 2.0);
+
+        let pipeline_id = PipelineId(0, 0);
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(pipeline_id, size);
+        let scroll_id = ExternalScrollId(1, pipeline_id);
+        let frame_rect = LayoutRect::new(LayoutPoint::new(20.0, 10.0), LayoutSize::new(50.0, 40.0));
+        let scroll_frame = builder.define_scroll_frame(
+            Some(scroll_id),
+            LayoutRect::new(frame_rect.origin, LayoutSize::new(50.0, 200.0)),
+            frame_rect,
+            vec![],
+            None,
+            ScrollSensitivity::ScriptAndInputEvents,
+        );
+        builder.push_clip_id(scroll_frame);
+        builder.push_rect(&LayoutPrimitiveInfo::new(frame_rect), ColorF::new(0.0, 0.0, 1.0, 1.0));
+        builder.pop_clip_id();
+        doc.build_scene(builder);
+
+        let color = ColorF::new(1.0, 0.0, 1.0, 0.5);
+        doc.highlighted_scroll_frame = Some((scroll_id, color));
+        let rendered = doc.render();
+
+        // The highlight follows the viewport, in device pixels.
+        let rect = DeviceIntRect::new(DeviceIntPoint::new(40, 20), DeviceIntSize::new(100, 80));
+        assert_eq!(rendered.frame.highlight, Some(FrameHighlight { rect, color }));
+
+        doc.highlighted_scroll_frame = None;
+        let rendered = doc.render();
+        assert_eq!(rendered.frame.highlight, None);
+    }
+
+    /// A display list of a 100x100 document filled with a gradient.
+    fn gradient_display_list() -> DisplayListBuilder {
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let stops = vec![
+            GradientStop { offset: 0.0, color: ColorF::new(0.0, 0.0, 0.0, 1.0) },
+            GradientStop { offset: 1.0, color: ColorF::new(1.0, 1.0, 1.0, 1.0) },
+        ];
+        let gradient = builder.create_gradient(
+            LayoutPoint::zero(),
+            LayoutPoint::new(100.0, 0.0),
+            stops,
+            ExtendMode::Clamp,
+        );
+        let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+        builder.push_gradient(&info, gradient, size, LayoutSize::zero());
+        builder
+    }
+
+    fn gradient_dither_offsets(frame: &Frame) -> Vec<i32> {
+        let mut offsets = Vec::new();
+        visit_batches(frame, |batch| {
+            if batch.key.kind == BatchKind::Brush(BrushBatchKind::LinearGradient) {
+                offsets.extend(batch.instances.iter().map(|i| i.brush_user_data()[1]));
+            }
+        });
+        offsets
+    }
+
+    #[test]
+    fn dither_seed_fixes_the_dither_offset_of_gradients() {
+        let dither_offsets = |seed| {
+            let mut config = FrameBuilder::empty().config;
+            config.dither_seed = Some(seed);
+            let rendered = render_display_list(config, gradient_display_list());
+            gradient_dither_offsets(&rendered.frame)
+        };
+
+        let offsets = dither_offsets(1);
+        assert_eq!(offsets.len(), 1);
+        assert_eq!(dither_offsets(1), offsets);
+        assert_ne!(dither_offsets(2), offsets);
+    }
+
+    #[test]
+    fn dither_pattern_only_moves_between_frames_when_animated() {
+        let frame_offsets = |animate_dither| {
+            let mut config = FrameBuilder::empty().config;
+            config.animate_dither = animate_dither;
+            let mut doc = TestDocument::new(config);
+            doc.build_scene(gradient_display_list());
+            let first = gradient_dither_offsets(&doc.render().frame);
+            doc.build_scene(gradient_display_list());
+            let second = gradient_dither_offsets(&doc.render().frame);
+            (first, second)
+        };
+
+        let (first, second) = frame_offsets(false);
+        assert_eq!(first, vec![0]);
+        assert_eq!(second, first);
+
+        let (first, second) = frame_offsets(true);
+        assert_eq!(first.len(), 1);
+        assert_ne!(second, first);
+    }
+
+    #[test]
+    fn plus_lighter_composite_records_its_clamp() {
+        let mix_blend_data = |composite_clamp| {
+            let mut config = FrameBuilder::empty().config;
+            config.composite_clamp = composite_clamp;
+
+            // The blended context is nested, so it has a backdrop to blend with.
+            let size = LayoutSize::new(100.0, 100.0);
+            let info = LayoutPrimitiveInfo::new(LayoutRect::new(LayoutPoint::zero(), size));
+            let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+            for &mix_blend_mode in &[MixBlendMode::Normal, MixBlendMode::PlusLighter] {
+                builder.push_stacking_context(
+                    &info,
+                    None,
+                    None,
+                    TransformStyle::Flat,
+                    None,
+                    mix_blend_mode,
+                    Vec::new(),
+                    GlyphRasterSpace::Screen,
+                );
+            }
+            builder.push_rect(&info, ColorF::new(0.0, 0.0, 1.0, 1.0));
+            builder.pop_stacking_context();
+            builder.pop_stacking_context();
+            let rendered = render_display_list(config, builder);
+
+            let mut data = Vec::new();
+            visit_draw_commands(&rendered.frame, |_, command| {
+                if let DrawCommand::AlphaBatch(batch) = command {
+                    if let BatchKind::Brush(BrushBatchKind::MixBlend { .. }) = batch.key.kind {
+                        data.extend(batch.instances.iter().map(|i| i.brush_user_data()[0]));
+                    }
+                }
+            });
+            data
+        };
+
+        // The mode is in the low bits, and the clamp above them.
+        let mode = MixBlendMode::PlusLighter as i32;
+        assert_eq!(mix_blend_data(CompositeClamp::Clamp), vec![mode]);
+        assert_eq!(mix_blend_data(CompositeClamp::SoftKnee), vec![mode | 1 << 16]);
+    }
+
+    #[test]
+    fn scissor_mode_groups_batches_by_clip_rect() {
+        #[derive(Debug, PartialEq)]
+        enum Command {
+            Scissor(Option<DeviceIntRect>),
+            Batch(usize),
+        }
+
+        let mut config = FrameBuilder::empty().config;
+        config.scissor_clip_rects = true;
+
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let rect = |x, y, size| {
+            LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(size, size))
+        };
+        let color = ColorF::new(0.0, 0.0, 1.0, 1.0);
+
+        // Two rects share the first clip, and one is in the second.
+        let first_clip = builder.define_clip(rect(0.0, 0.0, 50.0), vec![], None);
+        builder.push_clip_id(first_clip);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 20.0)), color);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(25.0, 25.0, 20.0)), color);
+        builder.pop_clip_id();
+        let second_clip = builder.define_clip(rect(50.0, 50.0, 50.0), vec![], None);
+        builder.push_clip_id(second_clip);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(60.0, 60.0, 20.0)), color);
+        builder.pop_clip_id();
+        let rendered = render_display_list(config, builder);
+
+        let mut commands = Vec::new();
+        visit_draw_commands(&rendered.frame, |_, command| match command {
+            DrawCommand::Scissor(rect) => commands.push(Command::Scissor(rect)),
+            DrawCommand::OpaqueBatch(batch) | DrawCommand::AlphaBatch(batch) => {
+                commands.push(Command::Batch(batch.instances.len()))
+            }
+            _ => {}
+        });
+
+        // Opaque batches are drawn front-to-back.
+        let device_rect = |x, y| {
+            DeviceIntRect::new(DeviceIntPoint::new(x, y), DeviceIntSize::new(50, 50))
+        };
+        assert_eq!(
+            commands,
+            vec![
+                Command::Scissor(Some(device_rect(50, 50))),
+                Command::Batch(1),
+                Command::Scissor(Some(device_rect(0, 0))),
+                Command::Batch(2),
+                Command::Scissor(None),
+            ],
+        );
+    }
+
+    #[test]
+    fn scissor_rects_stay_within_the_task_they_draw() {
+        let mut config = FrameBuilder::empty().config;
+        config.scissor_clip_rects = true;
+
+        // A clip covering the whole document, inside a blurred stacking
+        // context that is drawn into a much smaller off-screen task.
+        let size = LayoutSize::new(100.0, 100.0);
+        let mut builder = DisplayListBuilder::new(PipelineId(0, 0), size);
+        let rect = |x, y, size| {
+            LayoutRect::new(LayoutPoint::new(x, y), LayoutSize::new(size, size))
+        };
+        builder.push_stacking_context(
+            &LayoutPrimitiveInfo::new(rect(40.0, 40.0, 20.0)),
+            None,
+            None,
+            TransformStyle::Flat,
+            None,
+            MixBlendMode::Normal,
+            vec![FilterOp::Blur(2.0)],
+            GlyphRasterSpace::Screen,
+        );
+        let clip = builder.define_clip(rect(-40.0, -40.0, 100.0), vec![], None);
+        builder.push_clip_id(clip);
+        let blue = ColorF::new(0.0, 0.0, 1.0, 1.0);
+        let red = ColorF::new(1.0, 0.0, 0.0, 1.0);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(0.0, 0.0, 10.0)), blue);
+        builder.push_rect(&LayoutPrimitiveInfo::new(rect(10.0, 10.0, 10.0)), red);
+        builder.pop_clip_id();
+        builder.pop_stacking_context();
+        let rendered = render_display_list(config, builder);
+
+        let picture_rects: Vec<_> = rendered.frame.render_tasks.tasks
+            .iter()
+            .filter(|task| match task.kind {
+                RenderTaskKind::Picture(..) => true,
+                _ => false,
+            })
+            .map(|task| task.get_target_rect())
+            .collect();
+
+        let mut off_screen_scissors = 0;
+        visit_draw_commands(&rendered.frame, |target, command| match (target, command) {
+            (FrameTarget::Color(index), DrawCommand::Scissor(Some(rect))) => {
+                off_screen_scissors += 1;
+                assert!(picture_rects.iter().any(|&(task_rect, task_index)| {
+                    task_index.0 == index.0 && task_rect.contains_rect(&rect)
+                }));
+            }
+            _ => {}
+        });
+        assert!(off_screen_scissors > 0);
+    }
+}
@@ -209,6 +209,15 @@ pub struct BlurTask {
     uv_rect_kind: UvRectKind,
 }
 
+#[derive(Debug)]
+#[cfg_attr(feature = "capture", derive(Serialize))]
+#[cfg_attr(feature = "replay", derive(Deserialize))]
+pub struct ScalingTask {
+    pub target_kind: RenderTargetKind,
+    pub uv_rect_handle: GpuCacheHandle,
+    uv_rect_kind: UvRectKind,
+}
+
 impl BlurTask {
     #[cfg(feature = "debugger")]
     fn print_with<T: PrintTreePrinter>(&self, pt: &mut T) {
@@ -276,7 +285,7 @@ pub enum RenderTaskKind {
     #[allow(dead_code)]
     Glyph(GlyphTask),
     Readback(DeviceIntRect),
-    Scaling(RenderTargetKind),
+    Scaling(ScalingTask),
     Blit(BlitTask),
 }
 
@@ -523,6 +532,7 @@ impl RenderTask {
             let downscaling_task = RenderTask::new_scaling(
                 target_kind,
                 downscaling_src_task_id,
+                render_tasks,
                 adjusted_blur_target_size,
             );
             downscaling_src_task_id = render_tasks.add(downscaling_task);
@@ -560,12 +570,19 @@ impl RenderTask {
     pub fn new_scaling(
         target_kind: RenderTargetKind,
         src_task_id: RenderTaskId,
+        render_tasks: &RenderTaskTree,
         target_size: DeviceIntSize,
     ) -> Self {
+        let uv_rect_kind = render_tasks[src_task_id].uv_rect_kind();
+
         RenderTask {
             children: vec![src_task_id],
             location: RenderTaskLocation::Dynamic(None, Some(target_size)),
-            kind: RenderTaskKind::Scaling(target_kind),
+            kind: RenderTaskKind::Scaling(ScalingTask {
+                target_kind,
+                uv_rect_handle: GpuCacheHandle::new(),
+                uv_rect_kind,
+            }),
             clear_mode: match target_kind {
                 RenderTargetKind::Color => ClearMode::Transparent,
                 RenderTargetKind::Alpha => ClearMode::One,
@@ -600,8 +617,7 @@ impl RenderTask {
     fn uv_rect_kind(&self) -> UvRectKind {
         match self.kind {
             RenderTaskKind::CacheMask(..) |
-            RenderTaskKind::Readback(..) |
-            RenderTaskKind::Scaling(..) => {
+            RenderTaskKind::Readback(..) => {
                 unreachable!("bug: unexpected render task");
             }
 
@@ -609,6 +625,10 @@ impl RenderTask {
                 task.uv_rect_kind
             }
 
+            RenderTaskKind::Scaling(ref task) => {
+                task.uv_rect_kind
+            }
+
             RenderTaskKind::VerticalBlur(ref task) |
             RenderTaskKind::HorizontalBlur(ref task) => {
                 task.uv_rect_kind
@@ -700,9 +720,11 @@ impl RenderTask {
             RenderTaskKind::HorizontalBlur(ref info) => {
                 gpu_cache.get_address(&info.uv_rect_handle)
             }
+            RenderTaskKind::Scaling(ref info) => {
+                gpu_cache.get_address(&info.uv_rect_handle)
+            }
             RenderTaskKind::ClipRegion(..) |
             RenderTaskKind::Readback(..) |
-            RenderTaskKind::Scaling(..) |
             RenderTaskKind::Blit(..) |
             RenderTaskKind::CacheMask(..) |
             RenderTaskKind::Glyph(..) => {
@@ -772,8 +794,8 @@ impl RenderTask {
                 RenderTargetKind::Color
             }
 
-            RenderTaskKind::Scaling(target_kind) => {
-                target_kind
+            RenderTaskKind::Scaling(ref task_info) => {
+                task_info.target_kind
             }
 
             RenderTaskKind::Picture(..) => {
@@ -833,8 +855,10 @@ impl RenderTask {
             RenderTaskKind::Picture(ref mut info) => {
                 (&mut info.uv_rect_handle, info.uv_rect_kind)
             }
+            RenderTaskKind::Scaling(ref mut info) => {
+                (&mut info.uv_rect_handle, info.uv_rect_kind)
+            }
             RenderTaskKind::Readback(..) |
-            RenderTaskKind::Scaling(..) |
             RenderTaskKind::Blit(..) |
             RenderTaskKind::ClipRegion(..) |
             RenderTaskKind::CacheMask(..) |
@@ -883,9 +907,9 @@ impl RenderTask {
                 pt.new_level("Readback".to_owned());
                 pt.add_item(format!("rect: {:?}", rect));
             }
-            RenderTaskKind::Scaling(ref kind) => {
+            RenderTaskKind::Scaling(ref task) => {
                 pt.new_level("Scaling".to_owned());
-                pt.add_item(format!("kind: {:?}", kind));
+                pt.add_item(format!("kind: {:?}", task.target_kind));
             }
             RenderTaskKind::Blit(ref task) => {
                 pt.new_level("Blit".to_owned());
@@ -948,9 +972,21 @@ pub struct RenderTaskCacheEntry {
     pending_render_task_id: Option<RenderTaskId>,
     user_data: Option<[f32; 3]>,
     is_opaque: bool,
+    /// Set when the cached content is out of date, so that it is drawn
+    /// again the next time it is requested even though it is still in
+    /// the texture cache.
+    is_invalid: bool,
     pub handle: TextureCacheHandle,
 }
 
+impl RenderTaskCacheEntry {
+    /// The task that renders this entry in the current frame, if it has to
+    /// be rendered.
+    pub fn pending_render_task_id(&self) -> Option<RenderTaskId> {
+        self.pending_render_task_id
+    }
+}
+
 #[derive(Debug)]
 pub enum RenderTaskCacheMarker {}
 
@@ -1105,6 +1141,7 @@ impl RenderTaskCache {
                                         pending_render_task_id: None,
                                         user_data,
                                         is_opaque,
+                                        is_invalid: false,
                                     };
                                     cache_entries.insert(entry)
                                 });
@@ -1112,7 +1149,8 @@ impl RenderTaskCache {
 
         if cache_entry.pending_render_task_id.is_none() {
             // Check if this texture cache handle is valid.
-            if texture_cache.request(&cache_entry.handle, gpu_cache) {
+            let needs_render = texture_cache.request(&cache_entry.handle, gpu_cache);
+            if needs_render || cache_entry.is_invalid {
                 // Invoke user closure to get render task chain
                 // to draw this into the texture cache.
                 let render_task_id = try!(f(render_tasks));
@@ -1120,12 +1158,59 @@ impl RenderTaskCache {
                 cache_entry.pending_render_task_id = Some(render_task_id);
                 cache_entry.user_data = user_data;
                 cache_entry.is_opaque = is_opaque;
+                cache_entry.is_invalid = false;
             }
         }
 
         Ok(entry_handle.weak())
     }
 
+    // Request the entry for a key only if it is already cached and up
+    // to date, or is being drawn in this frame, without creating it or
+    // drawing it again.
+    pub fn request_cached(
+        &mut self,
+        key: &RenderTaskCacheKey,
+        texture_cache: &mut TextureCache,
+        gpu_cache: &mut GpuCache,
+    ) -> Option<RenderTaskCacheEntryHandle> {
+        let entry_handle = match self.map.get(key) {
+            Some(entry_handle) => entry_handle,
+            None => return None,
+        };
+        let cache_entry = self.cache_entries.get(entry_handle);
+
+        if cache_entry.pending_render_task_id.is_some() {
+            return Some(entry_handle.weak());
+        }
+        if cache_entry.is_invalid || texture_cache.request(&cache_entry.handle, gpu_cache) {
+            return None;
+        }
+        Some(entry_handle.weak())
+    }
+
+    // Make the next request for an entry draw it again, even if it
+    // is still in the texture cache.
+    pub fn invalidate(&mut self, handle: &RenderTaskCacheEntryHandle) {
+        if let Some(entry) = self.cache_entries.get_opt_mut(handle) {
+            entry.is_invalid = true;
+        }
+    }
+
+    // Mark an existing entry as used on this frame, without drawing
+    // it again. Returns false if it has been evicted.
+    pub fn request_existing(
+        &self,
+        handle: &RenderTaskCacheEntryHandle,
+        texture_cache: &mut TextureCache,
+        gpu_cache: &mut GpuCache,
+    ) -> bool {
+        match self.cache_entries.get_opt(handle) {
+            Some(entry) => !texture_cache.request(&entry.handle, gpu_cache),
+            None => false,
+        }
+    }
+
     pub fn get_cache_entry(
         &self,
         handle: &RenderTaskCacheEntryHandle,
@@ -26,7 +26,7 @@ use device::{FileWatcherHandler, ShaderError, TextureFilter,
              VertexUsageHint, VAO, VBO, CustomVAO};
 use device::{ProgramCache, ReadPixelsFormat};
 use euclid::{rect, Transform3D};
-use frame_builder::FrameBuilderConfig;
+use frame_builder::{BlendQuality, CompositeClamp, FrameBuilderConfig};
 use gleam::gl;
 use glyph_rasterizer::{GlyphFormat, GlyphRasterizer};
 use gpu_cache::{GpuBlockData, GpuCacheUpdate, GpuCacheUpdateList};
@@ -59,11 +59,14 @@ use std::rc::Rc;
 use std::sync::Arc;
 use std::sync::mpsc::{channel, Receiver, Sender};
 use std::thread;
+use std::time::Duration;
 use texture_cache::TextureCache;
 use thread_profiler::{register_thread_with_profiler, write_profile};
 use tiling::{AlphaRenderTarget, ColorRenderTarget};
 use tiling::{BlitJob, BlitJobSource, RenderPass, RenderPassKind, RenderTargetList};
-use tiling::{Frame, RenderTarget, RenderTargetKind, ScalingInfo, TextureCacheRenderTarget};
+use tiling::{Frame, FrameSnapshot, FrameVisitor, RenderTarget, RenderTargetKind, ScalingInfo};
+use tiling::{DEFAULT_MIN_TARGET_SIZE, RenderTargetSummary, TextureCacheRenderTarget};
+use tiling::{FramebufferClear, framebuffer_clear, ordered_texture_cache_targets};
 #[cfg(not(feature = "pathfinder"))]
 use tiling::GlyphJob;
 use time::precise_time_ns;
@@ -756,19 +759,24 @@ impl SourceTextureResolver {
         assert!(self.saved_textures.is_empty());
     }
 
-    fn end_frame(&mut self) {
+    fn end_frame(&mut self, stats: &mut RendererStats) {
         // return the cached targets to the pool
-        self.end_pass(None, None);
+        self.end_pass(None, None, stats);
         // return the global alpha texture
-        self.render_target_pool.extend(self.shared_alpha_texture.take());
+        if let Some(texture) = self.shared_alpha_texture.take() {
+            free_render_target(&mut self.render_target_pool, texture, stats);
+        }
         // return the saved targets as well
-        self.render_target_pool.extend(self.saved_textures.drain(..));
+        for texture in self.saved_textures.drain(..) {
+            free_render_target(&mut self.render_target_pool, texture, stats);
+        }
     }
 
     fn end_pass(
         &mut self,
         a8_texture: Option<ActiveTexture>,
         rgba8_texture: Option<ActiveTexture>,
+        stats: &mut RendererStats,
     ) {
         // If we have cache textures from previous pass, return them to the pool.
         // Also assign the pool index of those cache textures to last pass's index because this is
@@ -780,7 +788,7 @@ impl SourceTextureResolver {
                 assert_eq!(self.saved_textures.len(), index.0);
                 self.saved_textures.push(at.texture);
             } else {
-                self.render_target_pool.push(at.texture);
+                free_render_target(&mut self.render_target_pool, at.texture, stats);
             }
         }
         if let Some(at) = self.cache_a8_texture.take() {
@@ -792,7 +800,7 @@ impl SourceTextureResolver {
                 assert!(self.shared_alpha_texture.is_none());
                 self.shared_alpha_texture = Some(at.texture);
             } else {
-                self.render_target_pool.push(at.texture);
+                free_render_target(&mut self.render_target_pool, at.texture, stats);
             }
         }
 
@@ -1257,13 +1265,71 @@ struct FrameOutput {
     fbo_id: FBOId,
 }
 
-#[derive(PartialEq)]
+/// A texture owned by the caller that frames are drawn into instead of the
+/// bound framebuffer.
+struct RootOutput {
+    texture_id: u32,
+    fbo_id: FBOId,
+}
+
+#[derive(Debug, PartialEq)]
 struct TargetSelector {
     size: DeviceUintSize,
     num_layers: usize,
     format: ImageFormat,
 }
 
+/// Takes a target out of the pool for the given selector, preferring an
+/// exact match, then any target of the same format not yet used in this
+/// frame. Only if neither exists is a new one created. Only exact matches
+/// count as reused, since any other target has its storage allocated
+/// again for the new size.
+fn allocate_render_target<F: FnOnce() -> Texture>(
+    pool: &mut Vec<Texture>,
+    selector: TargetSelector,
+    frame_id: FrameId,
+    counters: &mut FrameProfileCounters,
+    stats: &mut RendererStats,
+    create: F,
+) -> Texture {
+    counters.targets_used.inc();
+
+    // First, try finding a perfect match
+    let index = pool.iter().position(|texture| {
+        //TODO: re-use a part of a larger target, if available
+        selector == TargetSelector {
+            size: texture.get_dimensions(),
+            num_layers: texture.get_render_target_layer_count(),
+            format: texture.get_format(),
+        }
+    });
+    if let Some(pos) = index {
+        stats.render_targets_reused += 1;
+        return pool.swap_remove(pos);
+    }
+
+    // Next, try at least finding a matching format
+    counters.targets_changed.inc();
+    stats.render_targets_allocated += 1;
+    let index = pool.iter().position(|texture| {
+        texture.get_format() == selector.format && !texture.used_in_frame(frame_id)
+    });
+    match index {
+        Some(pos) => pool.swap_remove(pos),
+        None => {
+            counters.targets_created.inc();
+            // finally, give up and create a new one
+            create()
+        }
+    }
+}
+
+/// Returns a target to the pool once the frame is done with it.
+fn free_render_target(pool: &mut Vec<Texture>, target: Texture, stats: &mut RendererStats) {
+    stats.render_targets_freed += 1;
+    pool.push(target);
+}
+
 #[cfg(feature = "debug_renderer")]
 struct LazyInitializedDebugRenderer {
     debug_renderer: Option<DebugRenderer>,
@@ -1311,8 +1377,12 @@ pub struct Renderer {
     pub gpu_glyph_renderer: GpuGlyphRenderer,
 
     max_texture_size: u32,
+    max_instances_per_draw: usize,
     max_recorded_profiles: usize,
 
+    /// The stats of the last few frames, if they are averaged.
+    stats_history: Option<RendererStatsHistory>,
+
     clear_color: Option<ColorF>,
     enable_clear_scissor: bool,
     #[cfg(feature = "debug_renderer")]
@@ -1357,6 +1427,20 @@ pub struct Renderer {
     // Currently allocated FBOs for output frames.
     output_targets: FastHashMap<u32, FrameOutput>,
 
+    // The caller's texture that frames are drawn into, if any.
+    root_output: Option<RootOutput>,
+
+    // The most recently rendered pixels of each pipeline the
+    // caller has enabled frame readback for, until they are taken.
+    frame_readbacks: FastHashMap<PipelineId, (DeviceUintSize, Vec<u8>)>,
+
+    // Stacking context previews waiting to be drawn.
+    pending_previews: Vec<(DocumentId, Frame)>,
+
+    // The pixels of the most recent stacking context preview of each
+    // document, until they are taken.
+    stacking_context_previews: FastHashMap<DocumentId, (DeviceUintSize, Vec<u8>)>,
+
     pub renderer_errors: Vec<RendererError>,
 
     /// List of profile results from previous frames. Can be retrieved
@@ -1461,6 +1545,14 @@ impl Renderer {
             ),
             min_texture_size,
         );
+        let device_max_instances = device.max_instances_per_draw();
+        let max_instances_per_draw = cmp::max(
+            cmp::min(
+                device_max_instances,
+                options.max_instances_per_draw.unwrap_or(device_max_instances),
+            ),
+            1,
+        );
 
         register_thread_with_profiler("Compositor".to_owned());
 
@@ -1609,6 +1701,26 @@ impl Renderer {
             default_font_render_mode,
             dual_source_blending_is_enabled: true,
             dual_source_blending_is_supported: ext_dual_source_blending,
+            frame_time_budget: options.frame_time_budget,
+            record_paint_order: options.record_paint_order,
+            cached_surface_fade_frames: options.cached_surface_fade_frames,
+            snap_stacking_context_origins: options.snap_stacking_context_origins,
+            blend_quality: options.blend_quality,
+            retain_frame_snapshot: options.retain_frame_snapshot,
+            dither_seed: options.dither_seed,
+            animate_dither: options.animate_dither,
+            composite_clamp: options.composite_clamp,
+            scroll_restore_max_size_ratio: options.scroll_restore_max_size_ratio,
+            scissor_clip_rects: options.scissor_clip_rects,
+            clamp_pinch_zoom: options.clamp_pinch_zoom,
+            resize_in_place: options.resize_in_place,
+            root_sample_count: options.root_sample_count,
+            min_render_target_size: options.min_render_target_size
+                .unwrap_or(DEFAULT_MIN_TARGET_SIZE)
+                .min(max_device_size)
+                .max(1),
+            missing_image_placeholders: options.missing_image_placeholders,
+            overdraw_grid_tile_size: options.overdraw_grid_tile_size,
         };
 
         let device_pixel_ratio = options.device_pixel_ratio;
@@ -1723,7 +1835,9 @@ impl Renderer {
             #[cfg(feature = "debug_renderer")]
             profiler: Profiler::new(),
             max_texture_size: max_device_size,
+            max_instances_per_draw,
             max_recorded_profiles: options.max_recorded_profiles,
+            stats_history: options.stats_average_window.map(RendererStatsHistory::new),
             clear_color: options.clear_color,
             enable_clear_scissor: options.enable_clear_scissor,
             last_time: 0,
@@ -1743,6 +1857,10 @@ impl Renderer {
             external_image_handler: None,
             output_image_handler: None,
             output_targets: FastHashMap::default(),
+            root_output: None,
+            frame_readbacks: FastHashMap::default(),
+            pending_previews: Vec::new(),
+            stacking_context_previews: FastHashMap::default(),
             cpu_profiles: VecDeque::new(),
             gpu_profiles: VecDeque::new(),
             gpu_cache_texture,
@@ -1780,10 +1898,54 @@ impl Renderer {
         self.pipeline_info.epochs.get(&pipeline_id).cloned()
     }
 
+    /// Returns true if the given epoch of a pipeline, or a later one, has
+    /// been rendered.
+    pub fn is_epoch_rendered(&self, pipeline_id: PipelineId, epoch: Epoch) -> bool {
+        self.pipeline_info.is_epoch_rendered(pipeline_id, epoch)
+    }
+
+    /// Returns the stats of the frames rendered recently, averaged over the
+    /// window set with `RendererOptions::stats_average_window`.
+    pub fn average_stats(&self) -> Option<RendererStats> {
+        self.stats_history.as_ref().and_then(|history| history.average())
+    }
+
     pub fn flush_pipeline_info(&mut self) -> PipelineInfo {
         mem::replace(&mut self.pipeline_info, PipelineInfo::default())
     }
 
+    /// Walks the render targets of the current frame of a document, calling
+    /// into the visitor for each draw command in the order they are rendered.
+    /// Returns false if there is no frame for this document.
+    pub fn visit_frame<V: FrameVisitor>(&self, document_id: DocumentId, visitor: &mut V) -> bool {
+        match self.active_documents.iter().find(|&&(id, _)| id == document_id) {
+            Some(&(_, ref render_doc)) => {
+                render_doc.frame.visit(visitor);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Lists the render targets used by the current frame of a document,
+    /// for debugging tools. Returns `None` if there is no frame for this
+    /// document.
+    pub fn render_targets(&self, document_id: DocumentId) -> Option<Vec<RenderTargetSummary>> {
+        self.active_documents
+            .iter()
+            .find(|&&(id, _)| id == document_id)
+            .map(|&(_, ref render_doc)| render_doc.frame.render_targets())
+    }
+
+    /// Returns the snapshot of the last frame the render backend built for a
+    /// document, if `RendererOptions::retain_frame_snapshot` is set.
+    pub fn last_frame_snapshot(&self, document_id: DocumentId) -> Option<&FrameSnapshot> {
+        self.active_documents
+            .iter()
+            .find(|&&(id, _)| id == document_id)
+            .and_then(|&(_, ref render_doc)| render_doc.snapshot.as_ref())
+    }
+
     // update the program cache with new binaries, e.g. when some of the lazy loaded
     // shader programs got activated in the mean time
     pub fn update_program_cache(&mut self, cached_programs: Rc<ProgramCache>) {
@@ -1818,7 +1980,7 @@ impl Renderer {
                             // If the document we are replacing must be drawn
                             // (in order to update the texture cache), issue
                             // a render just to off-screen targets.
-                            if self.active_documents[pos].1.frame.must_be_drawn() {
+                            if self.active_documents[pos].1.must_be_drawn() {
                                 self.render_impl(None).ok();
                             }
                             self.active_documents[pos].1 = doc;
@@ -1840,6 +2002,10 @@ impl Renderer {
                     self.pending_texture_updates.push(texture_update_list);
                     self.backend_profile_counters = profile_counters;
                 }
+                ResultMsg::PublishStackingContextPreview(document_id, frame, updates) => {
+                    self.pending_previews.push((document_id, frame));
+                    self.pending_texture_updates.push(updates);
+                }
                 ResultMsg::UpdateGpuCache(list) => {
                     self.pending_gpu_cache_updates.push(list);
                 }
@@ -2144,6 +2310,23 @@ impl Renderer {
         self.output_image_handler = Some(handler);
     }
 
+    /// Draw frames into a GL texture owned by the caller, rather than into the
+    /// framebuffer bound when rendering. The renderer never deletes the
+    /// texture. `None` goes back to drawing into the bound framebuffer.
+    pub fn set_root_output_texture(&mut self, texture_id: Option<u32>) {
+        if self.root_output.as_ref().map(|output| output.texture_id) == texture_id {
+            return;
+        }
+
+        if let Some(output) = self.root_output.take() {
+            self.device.delete_fbo(output.fbo_id);
+        }
+        if let Some(texture_id) = texture_id {
+            let fbo_id = self.device.create_fbo_for_external_texture(texture_id);
+            self.root_output = Some(RootOutput { texture_id, fbo_id });
+        }
+    }
+
     /// Retrieve (and clear) the current list of recorded frame profiles.
     pub fn get_frame_profiles(&mut self) -> (Vec<CpuProfile>, Vec<GpuProfile>) {
         let cpu_profiles = self.cpu_profiles.drain(..).collect();
@@ -2197,7 +2380,7 @@ impl Renderer {
         framebuffer_size: Option<DeviceUintSize>
     ) -> Result<RendererStats, Vec<RendererError>> {
         profile_scope!("render");
-        if self.active_documents.is_empty() {
+        if self.active_documents.is_empty() && self.pending_previews.is_empty() {
             self.last_time = precise_time_ns();
             return Ok(RendererStats::empty());
         }
@@ -2230,6 +2413,10 @@ impl Renderer {
             let frame_id = self.device.begin_frame();
             self.gpu_profile.begin_frame(frame_id);
 
+            if let Some(ref output) = self.root_output {
+                self.device.override_main_framebuffer(output.fbo_id);
+            }
+
             self.device.disable_scissor();
             self.device.disable_depth();
             self.device.set_blend(false);
@@ -2252,27 +2439,37 @@ impl Renderer {
             // sort by the document layer id
             active_documents.sort_by_key(|&(_, ref render_doc)| render_doc.frame.layer);
 
-            // don't clear the framebuffer if one of the rendered documents will overwrite it
+            // don't clear the parts of the framebuffer that the rendered
+            // documents overwrite or blend over
             if let Some(framebuffer_size) = framebuffer_size {
-                let needs_color_clear = !active_documents
-                    .iter()
-                    .any(|&(_, RenderedDocument { ref frame, .. })| {
-                        frame.background_color.is_some() &&
-                        frame.inner_rect.origin == DeviceUintPoint::zero() &&
-                        frame.inner_rect.size == framebuffer_size
-                    });
-
-                if needs_color_clear || clear_depth_value.is_some() {
-                    let clear_color = if needs_color_clear {
-                        self.clear_color.map(|color| color.to_array())
-                    } else {
-                        None
-                    };
-                    self.device.bind_draw_target(None, None);
+                let color_clear = framebuffer_clear(
+                    active_documents.iter().map(|&(_, ref doc)| &doc.frame),
+                    framebuffer_size,
+                );
+                let clear_color = self.clear_color.map(|color| color.to_array());
+                let full_clear_color = match color_clear {
+                    FramebufferClear::Full => clear_color,
+                    FramebufferClear::Rects(..) => None,
+                };
+
+                self.device.bind_draw_target(None, None);
+                if full_clear_color.is_some() || clear_depth_value.is_some() {
                     self.device.enable_depth_write();
-                    self.device.clear_target(clear_color, clear_depth_value, None);
+                    self.device.clear_target(full_clear_color, clear_depth_value, None);
                     self.device.disable_depth_write();
                 }
+                match (color_clear, clear_color) {
+                    (FramebufferClear::Rects(rects), Some(clear_color)) => {
+                        for rect in rects {
+                            // The rect needs a Y-flip before going to GL.
+                            let mut rect = rect.to_i32();
+                            rect.origin.y =
+                                framebuffer_size.height as i32 - rect.origin.y - rect.size.height;
+                            self.device.clear_target(Some(clear_color), None, Some(rect));
+                        }
+                    }
+                    _ => {}
+                }
             }
 
             #[cfg(feature = "replay")]
@@ -2280,7 +2477,12 @@ impl Renderer {
                 self.owned_external_images.iter().map(|(key, value)| (*key, value.clone()))
             );
 
-            for &mut (_, RenderedDocument { ref mut frame, .. }) in &mut active_documents {
+            for &mut (document_id, ref mut doc) in &mut active_documents {
+                stats.texture_binds += doc.texture_bind_count;
+                if let Some(ref grid) = doc.frame.overdraw_grid {
+                    stats.overdraw_grids.push((document_id, grid.clone()));
+                }
+                let frame = &mut doc.frame;
                 frame.profile_counters.reset_targets();
                 self.prepare_gpu_cache(frame);
                 assert!(frame.gpu_cache_frame_id <= self.gpu_cache_frame_id,
@@ -2298,8 +2500,23 @@ impl Renderer {
                 if self.debug_flags.contains(DebugFlags::PROFILER_DBG) {
                     frame_profiles.push(frame.profile_counters.clone());
                 }
+
+                // Overlays blend over the frame of their document, with
+                // depth tests of their own.
+                for overlay in &mut doc.overlays {
+                    self.prepare_gpu_cache(overlay);
+                    self.draw_tile_frame(
+                        overlay,
+                        framebuffer_size,
+                        false,
+                        cpu_frame_id,
+                        &mut stats
+                    );
+                }
             }
 
+            self.draw_stacking_context_previews(cpu_frame_id, &mut stats);
+
             self.unlock_external_images();
             self.active_documents = active_documents;
         });
@@ -2361,6 +2578,10 @@ impl Renderer {
         });
         self.last_time = current_time;
 
+        if let Some(ref mut history) = self.stats_history {
+            history.push(stats.clone());
+        }
+
         if self.renderer_errors.is_empty() {
             Ok(stats)
         } else {
@@ -2566,12 +2787,14 @@ impl Renderer {
         let batched = !self.debug_flags.contains(DebugFlags::DISABLE_BATCHING);
 
         if batched {
-            self.device
-                .update_vao_instances(vao, data, VertexUsageHint::Stream);
-            self.device
-                .draw_indexed_triangles_instanced_u16(6, data.len() as i32);
-            self.profile_counters.draw_calls.inc();
-            stats.total_draw_calls += 1;
+            for instances in data.chunks(self.max_instances_per_draw) {
+                self.device
+                    .update_vao_instances(vao, instances, VertexUsageHint::Stream);
+                self.device
+                    .draw_indexed_triangles_instanced_u16(6, instances.len() as i32);
+                self.profile_counters.draw_calls.inc();
+                stats.total_draw_calls += 1;
+            }
         } else {
             for i in 0 .. data.len() {
                 self.device
@@ -2717,6 +2940,38 @@ impl Renderer {
         }
     }
 
+    /// Restricts drawing to the scissor rect of a batch, if its clip rect
+    /// is applied with the scissor. The rect is relative to the top of the
+    /// target, so it's flipped for the main framebuffer. It never reaches
+    /// past the scissor rect of the batch container.
+    fn set_batch_scissor_rect(
+        &mut self,
+        scissor_rect: Option<DeviceIntRect>,
+        container_rect: Option<DeviceIntRect>,
+        render_target: Option<(&Texture, i32)>,
+        target_size: DeviceUintSize,
+    ) {
+        if let Some(mut rect) = scissor_rect {
+            if render_target.is_none() {
+                rect.origin.y = target_size.height as i32 - rect.origin.y - rect.size.height;
+            }
+            if let Some(container_rect) = container_rect {
+                rect = rect.intersection(&container_rect).unwrap_or(DeviceIntRect::zero());
+            }
+            self.device.enable_scissor();
+            self.device.set_scissor_rect(rect);
+        }
+    }
+
+    /// Goes back to the scissor state of the batch container after drawing
+    /// a batch with its own scissor rect.
+    fn reset_batch_scissor_rect(&mut self, container_rect: Option<DeviceIntRect>) {
+        match container_rect {
+            Some(rect) => self.device.set_scissor_rect(rect),
+            None => self.device.disable_scissor(),
+        }
+    }
+
     fn draw_color_target(
         &mut self,
         render_target: Option<(&Texture, i32)>,
@@ -2851,12 +3106,21 @@ impl Renderer {
                         );
 
                     let _timer = self.gpu_profile.start_timer(batch.key.kind.sampler_tag());
+                    self.set_batch_scissor_rect(
+                        batch.scissor_rect,
+                        alpha_batch_container.target_rect,
+                        render_target,
+                        target_size,
+                    );
                     self.draw_instanced_batch(
                         &batch.instances,
                         VertexArrayKind::Primitive,
                         &batch.key.textures,
                         stats
                     );
+                    if batch.scissor_rect.is_some() {
+                        self.reset_batch_scissor_rect(alpha_batch_container.target_rect);
+                    }
                 }
 
                 if alpha_batch_container.target_rect.is_some() {
@@ -2936,6 +3200,12 @@ impl Renderer {
                 }
 
                 let _timer = self.gpu_profile.start_timer(batch.key.kind.sampler_tag());
+                self.set_batch_scissor_rect(
+                    batch.scissor_rect,
+                    alpha_batch_container.target_rect,
+                    render_target,
+                    target_size,
+                );
                 self.draw_instanced_batch(
                     &batch.instances,
                     VertexArrayKind::Primitive,
@@ -2962,6 +3232,10 @@ impl Renderer {
 
                     prev_blend_mode = BlendMode::None;
                 }
+
+                if batch.scissor_rect.is_some() {
+                    self.reset_batch_scissor_rect(alpha_batch_container.target_rect);
+                }
             }
 
             if alpha_batch_container.target_rect.is_some() {
@@ -3008,6 +3282,21 @@ impl Renderer {
                 handler.unlock(output.pipeline_id);
             }
         }
+
+        // Copy the pixels of any pipelines the caller wants to read back,
+        // holding on to them until they are taken.
+        for readback in &target.pixel_readbacks {
+            let (src_rect, _) = render_tasks[readback.task_id].get_target_rect();
+            let rect = DeviceUintRect::new(
+                DeviceUintPoint::new(src_rect.origin.x as u32, src_rect.origin.y as u32),
+                DeviceUintSize::new(src_rect.size.width as u32, src_rect.size.height as u32),
+            );
+            let mut pixels = vec![0; (rect.size.width * rect.size.height * 4) as usize];
+
+            self.device.bind_read_target(render_target);
+            self.device.read_pixels_into(rect, ReadPixelsFormat::Rgba8, &mut pixels);
+            self.frame_readbacks.insert(readback.pipeline_id, (rect.size, pixels));
+        }
     }
 
     fn draw_alpha_target(
@@ -3043,8 +3332,7 @@ impl Renderer {
             );
 
             let zero_color = [0.0, 0.0, 0.0, 0.0];
-            for &task_id in &target.zero_clears {
-                let (rect, _) = render_tasks[task_id].get_target_rect();
+            for &rect in &target.zero_clears {
                 self.device.clear_target(
                     Some(zero_color),
                     None,
@@ -3146,7 +3434,7 @@ impl Renderer {
                 );
             }
             // draw box-shadow clips
-            for (mask_texture_id, items) in target.clip_batcher.box_shadows.iter() {
+            for (mask_texture_id, items) in target.clip_batcher.ordered_box_shadows() {
                 let _gm2 = self.gpu_profile.start_marker("box-shadows");
                 let textures = BatchTextures {
                     colors: [
@@ -3182,7 +3470,7 @@ impl Renderer {
             }
 
             // draw image masks
-            for (mask_texture_id, items) in target.clip_batcher.images.iter() {
+            for (mask_texture_id, items) in target.clip_batcher.ordered_images() {
                 let _gm2 = self.gpu_profile.start_marker("clip images");
                 let textures = BatchTextures {
                     colors: [
@@ -3381,49 +3669,27 @@ impl Renderer {
         list: &mut RenderTargetList<T>,
         counters: &mut FrameProfileCounters,
         frame_id: FrameId,
+        stats: &mut RendererStats,
     ) -> Option<ActiveTexture> {
         debug_assert_ne!(list.max_size, DeviceUintSize::zero());
         if list.targets.is_empty() {
             return None
         }
 
-        counters.targets_used.inc();
-
-        // First, try finding a perfect match
         let selector = TargetSelector {
             size: list.max_size,
             num_layers: list.targets.len() as _,
             format: list.format,
         };
-        let mut index = self.texture_resolver.render_target_pool
-            .iter()
-            .position(|texture| {
-                //TODO: re-use a part of a larger target, if available
-                selector == TargetSelector {
-                    size: texture.get_dimensions(),
-                    num_layers: texture.get_render_target_layer_count(),
-                    format: texture.get_format(),
-                }
-            });
-
-        // Next, try at least finding a matching format
-        if index.is_none() {
-            counters.targets_changed.inc();
-            index = self.texture_resolver.render_target_pool
-                .iter()
-                .position(|texture| texture.get_format() == list.format && !texture.used_in_frame(frame_id));
-        }
-
-        let mut texture = match index {
-            Some(pos) => {
-                self.texture_resolver.render_target_pool.swap_remove(pos)
-            }
-            None => {
-                counters.targets_created.inc();
-                // finally, give up and create a new one
-                self.device.create_texture(TextureTarget::Array, list.format)
-            }
-        };
+        let device = &mut self.device;
+        let mut texture = allocate_render_target(
+            &mut self.texture_resolver.render_target_pool,
+            selector,
+            frame_id,
+            counters,
+            stats,
+            || device.create_texture(TextureTarget::Array, list.format),
+        );
 
         self.device.init_texture::<u8>(
             &mut texture,
@@ -3472,6 +3738,57 @@ impl Renderer {
         debug_assert!(self.texture_resolver.cache_rgba8_texture.is_none());
     }
 
+    /// Draws each pending stacking context preview into a texture of its own,
+    /// and reads its pixels back.
+    fn draw_stacking_context_previews(&mut self, frame_id: FrameId, stats: &mut RendererStats) {
+        if self.pending_previews.is_empty() {
+            return;
+        }
+
+        for (document_id, mut frame) in mem::replace(&mut self.pending_previews, Vec::new()) {
+            let size = frame.window_size;
+            let mut texture = self.device
+                .create_texture(TextureTarget::Default, ImageFormat::BGRA8);
+            self.device.init_texture::<u8>(
+                &mut texture,
+                size.width,
+                size.height,
+                TextureFilter::Linear,
+                Some(RenderTargetInfo { has_depth: true }),
+                1,
+                None,
+            );
+            self.device.override_main_framebuffer_with_texture(&texture, 0);
+            self.device.enable_depth_write();
+            self.device.clear_target(Some([0.0, 0.0, 0.0, 0.0]), Some(1.0), None);
+            self.device.disable_depth_write();
+
+            self.prepare_gpu_cache(&frame);
+            self.draw_tile_frame(&mut frame, Some(size), true, frame_id, stats);
+
+            let rect = DeviceUintRect::new(DeviceUintPoint::zero(), size);
+            let mut pixels = vec![0; (size.width * size.height * 4) as usize];
+            self.device.bind_read_target(None);
+            self.device.read_pixels_into(rect, ReadPixelsFormat::Rgba8, &mut pixels);
+
+            // The main framebuffer is drawn upside down, so the rows are read
+            // back bottom first.
+            let stride = size.width as usize * 4;
+            let pixels = pixels
+                .chunks(stride)
+                .rev()
+                .flat_map(|row| row.iter().cloned())
+                .collect();
+            self.stacking_context_previews.insert(document_id, (size, pixels));
+
+            match self.root_output {
+                Some(ref output) => self.device.override_main_framebuffer(output.fbo_id),
+                None => self.device.reset_main_framebuffer_override(),
+            }
+            self.device.delete_texture(texture);
+        }
+    }
+
     fn draw_tile_frame(
         &mut self,
         frame: &mut Frame,
@@ -3513,7 +3830,20 @@ impl Renderer {
                     if let Some(framebuffer_size) = framebuffer_size {
                         stats.color_target_count += 1;
 
-                        let clear_color = frame.background_color.map(|color| color.to_array());
+                        if frame.sample_count > 1 {
+                            self.device.bind_draw_target(None, None);
+                            let available = self.device.draw_target_sample_count();
+                            if available < frame.sample_count {
+                                warn!(
+                                    "Main framebuffer has {} samples per pixel, {} requested",
+                                    available,
+                                    frame.sample_count,
+                                );
+                            }
+                            self.device.enable_multisampling();
+                        }
+
+                        let clear_color = frame.root_clear_color().map(|color| color.to_array());
                         let projection = Transform3D::ortho(
                             0.0,
                             framebuffer_size.width as f32,
@@ -3540,14 +3870,25 @@ impl Renderer {
                     (None, None)
                 }
                 RenderPassKind::OffScreen { ref mut alpha, ref mut color, ref mut texture_cache } => {
-                    let alpha_tex = self.allocate_target_texture(alpha, &mut frame.profile_counters, frame_id);
-                    let color_tex = self.allocate_target_texture(color, &mut frame.profile_counters, frame_id);
+                    let alpha_tex = self.allocate_target_texture(
+                        alpha,
+                        &mut frame.profile_counters,
+                        frame_id,
+                        stats,
+                    );
+                    let color_tex = self.allocate_target_texture(
+                        color,
+                        &mut frame.profile_counters,
+                        frame_id,
+                        stats,
+                    );
 
                     // If this frame has already been drawn, then any texture
                     // cache targets have already been updated and can be
                     // skipped this time.
                     if !frame.has_been_rendered {
-                        for (&(texture_id, target_index), target) in texture_cache {
+                        for (texture_id, target_index, target) in
+                            ordered_texture_cache_targets(texture_cache) {
                             self.draw_texture_cache_target(
                                 &texture_id,
                                 target_index,
@@ -3619,10 +3960,11 @@ impl Renderer {
             self.texture_resolver.end_pass(
                 cur_alpha,
                 cur_color,
+                stats,
             );
         }
 
-        self.texture_resolver.end_frame();
+        self.texture_resolver.end_frame(stats);
         if let Some(framebuffer_size) = framebuffer_size {
             self.draw_render_target_debug(framebuffer_size);
             self.draw_texture_cache_debug(framebuffer_size);
@@ -3631,6 +3973,9 @@ impl Renderer {
         #[cfg(feature = "debug_renderer")]
         self.draw_epoch_debug();
 
+        #[cfg(feature = "debug_renderer")]
+        self.draw_frame_highlight(frame);
+
         // Garbage collect any frame outputs that weren't used this frame.
         let device = &mut self.device;
         self.output_targets
@@ -3777,6 +4122,26 @@ impl Renderer {
         }
     }
 
+    #[cfg(feature = "debug_renderer")]
+    fn draw_frame_highlight(&mut self, frame: &Frame) {
+        let highlight = match frame.highlight {
+            Some(highlight) => highlight,
+            None => return,
+        };
+
+        let debug_renderer = self.debug.get_mut(&mut self.device);
+        let rect = highlight.rect.to_f32();
+        let color = ColorU::from(highlight.color);
+        debug_renderer.add_quad(
+            rect.min_x(),
+            rect.min_y(),
+            rect.max_x(),
+            rect.max_y(),
+            color,
+            color,
+        );
+    }
+
     #[cfg(feature = "debug_renderer")]
     fn draw_epoch_debug(&mut self) {
         if !self.debug_flags.contains(DebugFlags::EPOCHS) {
@@ -3816,6 +4181,21 @@ impl Renderer {
         self.device.read_pixels_into(rect, format, output);
     }
 
+    /// Take the RGBA8 pixels most recently rendered for a pipeline that the
+    /// caller enabled frame readback for, along with their size.
+    pub fn take_frame_readback(&mut self, pipeline_id: PipelineId) -> Option<(DeviceUintSize, Vec<u8>)> {
+        self.frame_readbacks.remove(&pipeline_id)
+    }
+
+    /// Take the RGBA8 pixels of the most recent stacking context preview of a
+    /// document, top row first, along with their size.
+    pub fn take_stacking_context_preview(
+        &mut self,
+        document_id: DocumentId,
+    ) -> Option<(DeviceUintSize, Vec<u8>)> {
+        self.stacking_context_previews.remove(&document_id)
+    }
+
     pub fn read_pixels_rgba8(&mut self, rect: DeviceUintRect) -> Vec<u8> {
         let mut pixels = vec![0; (rect.size.width * rect.size.height * 4) as usize];
         self.device.read_pixels_into(rect, ReadPixelsFormat::Rgba8, &mut pixels);
@@ -3863,6 +4243,9 @@ impl Renderer {
         for (_, target) in self.output_targets {
             self.device.delete_fbo(target.fbo_id);
         }
+        if let Some(output) = self.root_output {
+            self.device.delete_fbo(output.fbo_id);
+        }
         self.shaders.deinit(&mut self.device);
         #[cfg(feature = "capture")]
         self.device.delete_fbo(self.read_fbo);
@@ -3976,6 +4359,14 @@ pub struct RendererOptions {
     pub clear_color: Option<ColorF>,
     pub enable_clear_scissor: bool,
     pub max_texture_size: Option<u32>,
+    /// Split instanced draw calls so that no single instance buffer holds
+    /// more than this many instances. Some drivers fail to draw from very
+    /// large vertex buffers. Defaults to, and is limited to, the number of
+    /// vertices the device recommends drawing from at once.
+    pub max_instances_per_draw: Option<usize>,
+    /// Keep the stats of this many of the most recent frames, to be
+    /// averaged by `Renderer::average_stats`.
+    pub stats_average_window: Option<usize>,
     pub scatter_gpu_cache_updates: bool,
     pub upload_method: UploadMethod,
     pub workers: Option<Arc<ThreadPool>>,
@@ -3989,6 +4380,71 @@ pub struct RendererOptions {
     pub disable_dual_source_blending: bool,
     pub scene_builder_hooks: Option<Box<SceneBuilderHooks + Send>>,
     pub sampler: Option<Box<AsyncPropertySampler + Send>>,
+    /// If set, frames that take longer than this to build leave out work
+    /// that isn't needed to draw the visible content, such as high quality
+    /// blurs, to keep up a steady frame rate.
+    pub frame_time_budget: Option<Duration>,
+    /// Record which picture and primitive each batch entry came from, in
+    /// paint order, on every color render target. Useful to debug z-order.
+    pub record_paint_order: bool,
+    /// When a new scene removes a picture that was cached in the texture
+    /// cache, keep compositing the cached surface on top of the new content
+    /// for this many frames, at a decreasing opacity, before releasing it.
+    /// Zero disables the fade.
+    pub cached_surface_fade_frames: u32,
+    /// Round the origin of each stacking context to whole device pixels,
+    /// so that the edges of its content aren't blurred by landing on
+    /// fractional device positions.
+    pub snap_stacking_context_origins: bool,
+    /// Set to `BlendQuality::Low` on devices that can't afford the
+    /// framebuffer reads of the hue, saturation, color and luminosity
+    /// blend modes, to draw that content unblended.
+    pub blend_quality: BlendQuality,
+    /// Keep a small summary of the structure of each document's last
+    /// rendered frame, available from `Renderer::last_frame_snapshot`.
+    pub retain_frame_snapshot: bool,
+    /// Fix where the dither pattern of gradients starts. By default the
+    /// pattern starts at the same place in every frame.
+    pub dither_seed: Option<u32>,
+    /// Without a `dither_seed`, move the dither pattern of gradients every
+    /// frame, so that it doesn't show up as a static texture.
+    pub animate_dither: bool,
+    /// How mix-blend composites bring overflowing results, such as those
+    /// of plus-lighter, back into the range of the target.
+    pub composite_clamp: CompositeClamp,
+    /// When set, scroll offsets carried over to a new scene are clamped to
+    /// the new scroll range, and reset if the scrolled content grew or
+    /// shrank by more than this ratio.
+    pub scroll_restore_max_size_ratio: Option<f32>,
+    /// Apply the clip rects of axis-aligned primitives by drawing them in
+    /// separate batches restricted with the hardware scissor, instead of
+    /// clipping in the shaders.
+    pub scissor_clip_rects: bool,
+    /// Stop pinch zoom and pan from losing the content: documents can't be
+    /// zoomed out past the scale at which their root pipeline fits the
+    /// window, nor panned so far that an edge of it crosses the center.
+    pub clamp_pinch_zoom: bool,
+    /// Apply window resizes that keep the device pixel ratio and the origin
+    /// of the inner rect to the scene already built, instead of waiting for
+    /// a new one. Other changes to the window parameters rebuild the scene.
+    pub resize_in_place: bool,
+    /// The number of samples per pixel to draw the main framebuffer with.
+    /// The embedder has to create the framebuffer with at least as many;
+    /// intermediate targets are never multisampled.
+    pub root_sample_count: u32,
+    /// The smallest size of the intermediate render targets, which grow
+    /// to fit larger render tasks. Smaller targets save memory when the
+    /// offscreen content is small. Defaults to 2048 and is limited to the
+    /// maximum texture size.
+    pub min_render_target_size: Option<u32>,
+    /// Draw images that have no texture to be drawn from, such as those too
+    /// large for the texture cache, as white rects at the opacity of the
+    /// image instead of leaving them out of the frame.
+    pub missing_image_placeholders: bool,
+    /// When set, the renderer stats count how many batches are drawn over
+    /// each tile of a grid over the framebuffer, with tiles of this size in
+    /// device pixels. This costs time on every batched primitive.
+    pub overdraw_grid_tile_size: Option<u32>,
 }
 
 impl Default for RendererOptions {
@@ -4007,6 +4463,8 @@ impl Default for RendererOptions {
             clear_color: Some(ColorF::new(1.0, 1.0, 1.0, 1.0)),
             enable_clear_scissor: true,
             max_texture_size: None,
+            max_instances_per_draw: None,
+            stats_average_window: None,
             // Scattered GPU cache updates haven't met a test that would show their superiority yet.
             scatter_gpu_cache_updates: false,
             // This is best as `Immediate` on Angle, or `Pixelbuffer(Dynamic)` on GL,
@@ -4022,6 +4480,23 @@ impl Default for RendererOptions {
             disable_dual_source_blending: false,
             scene_builder_hooks: None,
             sampler: None,
+            frame_time_budget: None,
+            record_paint_order: false,
+            cached_surface_fade_frames: 0,
+            snap_stacking_context_origins: false,
+            blend_quality: BlendQuality::High,
+            retain_frame_snapshot: false,
+            dither_seed: None,
+            animate_dither: false,
+            composite_clamp: CompositeClamp::Clamp,
+            scroll_restore_max_size_ratio: None,
+            scissor_clip_rects: false,
+            clamp_pinch_zoom: false,
+            resize_in_place: false,
+            root_sample_count: 1,
+            min_render_target_size: None,
+            missing_image_placeholders: false,
+            overdraw_grid_tile_size: None,
         }
     }
 }
@@ -4042,10 +4517,28 @@ impl DebugServer {
 // that we can use in wrench reftests to ensure that
 // tests are batching and/or allocating on render
 // targets as we expect them to.
+#[derive(Clone, Debug, PartialEq)]
 pub struct RendererStats {
     pub total_draw_calls: usize,
     pub alpha_target_count: usize,
     pub color_target_count: usize,
+    /// Render targets whose storage was allocated this frame, because the
+    /// pool had none of the same size and format to reuse.
+    pub render_targets_allocated: usize,
+    /// Render targets this frame took from the pool with the size and
+    /// format it needed.
+    pub render_targets_reused: usize,
+    /// Render targets returned to the pool at the end of their use.
+    pub render_targets_freed: usize,
+    /// The number of times a texture is bound for the primitive batches of
+    /// the documents drawn.
+    pub texture_binds: usize,
+    /// The number of batches each document drawn covers each tile of its
+    /// window with, in row major order, if
+    /// `RendererOptions::overdraw_grid_tile_size` is set. Documents can have
+    /// windows of different sizes, so each has a grid of its own. Averaged
+    /// stats carry the grids of the most recent frame.
+    pub overdraw_grids: Vec<(DocumentId, Vec<u32>)>,
 }
 
 impl RendererStats {
@@ -4054,10 +4547,61 @@ impl RendererStats {
             total_draw_calls: 0,
             alpha_target_count: 0,
             color_target_count: 0,
+            render_targets_allocated: 0,
+            render_targets_reused: 0,
+            render_targets_freed: 0,
+            texture_binds: 0,
+            overdraw_grids: Vec::new(),
         }
     }
 }
 
+/// The stats of the most recent frames, up to a fixed number of them.
+pub struct RendererStatsHistory {
+    window: usize,
+    frames: VecDeque<RendererStats>,
+}
+
+impl RendererStatsHistory {
+    pub fn new(window: usize) -> Self {
+        RendererStatsHistory {
+            window: window.max(1),
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, stats: RendererStats) {
+        if self.frames.len() == self.window {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(stats);
+    }
+
+    /// The mean of each stat over the frames in the window, rounded to the
+    /// nearest integer. Returns None before the first frame.
+    pub fn average(&self) -> Option<RendererStats> {
+        let count = self.frames.len();
+        if count == 0 {
+            return None;
+        }
+        let mean = |stat: fn(&RendererStats) -> usize| {
+            (self.frames.iter().map(stat).sum::<usize>() + count / 2) / count
+        };
+        Some(RendererStats {
+            total_draw_calls: mean(|stats| stats.total_draw_calls),
+            alpha_target_count: mean(|stats| stats.alpha_target_count),
+            color_target_count: mean(|stats| stats.color_target_count),
+            render_targets_allocated: mean(|stats| stats.render_targets_allocated),
+            render_targets_reused: mean(|stats| stats.render_targets_reused),
+            render_targets_freed: mean(|stats| stats.render_targets_freed),
+            texture_binds: mean(|stats| stats.texture_binds),
+            overdraw_grids: self.frames
+                .back()
+                .map_or(Vec::new(), |stats| stats.overdraw_grids.clone()),
+        })
+    }
+}
+
 
 
 #[cfg(any(feature = "capture", feature = "replay"))]
@@ -4124,6 +4668,15 @@ pub struct PipelineInfo {
     pub removed_pipelines: Vec<PipelineId>,
 }
 
+impl PipelineInfo {
+    /// Returns true if a frame showing the given epoch of a pipeline, or a
+    /// later one, has been rendered. Embedders can use this to hold back
+    /// new display lists until the previous ones reached the screen.
+    pub fn is_epoch_rendered(&self, pipeline_id: PipelineId, epoch: Epoch) -> bool {
+        self.epochs.get(&pipeline_id).map_or(false, |rendered| *rendered >= epoch)
+    }
+}
+
 impl Renderer {
     #[cfg(feature = "capture")]
     fn save_texture(
@@ -4465,3 +5018,83 @@ fn get_vao<'a>(vertex_array_kind: VertexArrayKind,
         VertexArrayKind::VectorStencil | VertexArrayKind::VectorCover => unreachable!(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use api::{DeviceUintSize, ImageFormat};
+    use device::{FrameId, Texture};
+    use profiler::FrameProfileCounters;
+    use super::{RendererStats, RendererStatsHistory, TargetSelector};
+    use super::{allocate_render_target, free_render_target};
+
+    fn draw_frame(
+        pool: &mut Vec<Texture>,
+        frame_id: FrameId,
+        sizes: &[DeviceUintSize],
+    ) -> RendererStats {
+        let mut counters = FrameProfileCounters::new();
+        let mut stats = RendererStats::empty();
+        let format = ImageFormat::BGRA8;
+        let mut targets = Vec::new();
+        for &size in sizes {
+            let selector = TargetSelector { size, num_layers: 1, format };
+            targets.push(allocate_render_target(
+                pool,
+                selector,
+                frame_id,
+                &mut counters,
+                &mut stats,
+                || Texture::new_render_target_for_test(size, 1, format, frame_id),
+            ));
+        }
+        for target in targets {
+            free_render_target(pool, target, &mut stats);
+        }
+        stats
+    }
+
+    #[test]
+    fn only_targets_of_the_same_size_and_format_count_as_reused() {
+        let mut pool = Vec::new();
+
+        let sizes = [DeviceUintSize::new(256, 256), DeviceUintSize::new(512, 128)];
+        let first = draw_frame(&mut pool, FrameId::new(1), &sizes);
+        assert_eq!(first.render_targets_allocated, 2);
+        assert_eq!(first.render_targets_reused, 0);
+        assert_eq!(first.render_targets_freed, 2);
+
+        let second = draw_frame(&mut pool, FrameId::new(2), &sizes);
+        assert_eq!(second.render_targets_allocated, 0);
+        assert_eq!(second.render_targets_reused, 2);
+        assert_eq!(second.render_targets_freed, 2);
+
+        // A target of another size takes a pooled texture of the same
+        // format, but has to allocate its storage again.
+        let resized = [DeviceUintSize::new(256, 256), DeviceUintSize::new(1024, 1024)];
+        let third = draw_frame(&mut pool, FrameId::new(3), &resized);
+        assert_eq!(third.render_targets_allocated, 1);
+        assert_eq!(third.render_targets_reused, 1);
+        assert_eq!(pool.len(), 2);
+    }
+
+    #[test]
+    fn stats_are_averaged_over_the_most_recent_frames() {
+        let frame = |total_draw_calls, render_targets_allocated| RendererStats {
+            total_draw_calls,
+            render_targets_allocated,
+            ..RendererStats::empty()
+        };
+
+        let mut history = RendererStatsHistory::new(3);
+        assert_eq!(history.average(), None);
+
+        history.push(frame(100, 6));
+        history.push(frame(10, 0));
+        history.push(frame(20, 0));
+        assert_eq!(history.average(), Some(frame(43, 2)));
+
+        // The first frame drops out of the window.
+        history.push(frame(30, 3));
+        assert_eq!(history.average(), Some(frame(20, 1)));
+    }
+}
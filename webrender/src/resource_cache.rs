@@ -8,7 +8,7 @@ use api::{ClearCache, ColorF, DevicePoint, DeviceUintPoint, DeviceUintRect, Devi
 use api::{Epoch, FontInstanceKey, FontKey, FontTemplate};
 use api::{ExternalImageData, ExternalImageType};
 use api::{FontInstanceOptions, FontInstancePlatformOptions, FontVariation};
-use api::{GlyphDimensions, GlyphKey, IdNamespace};
+use api::{GlyphDimensions, GlyphKey, GradientRampKey, GradientStop, IdNamespace};
 use api::{ImageData, ImageDescriptor, ImageKey, ImageRendering};
 use api::{TileOffset, TileSize};
 use app_units::Au;
@@ -285,6 +285,13 @@ impl BlobImageResources for Resources {
 
 pub type GlyphDimensionsCache = FastHashMap<GlyphRequest, Option<GlyphDimensions>>;
 
+/// The stops currently drawn by gradients that use a ramp, and an epoch
+/// that is bumped each time they are replaced.
+pub struct GradientRamp {
+    pub stops: Vec<GradientStop>,
+    pub epoch: Epoch,
+}
+
 pub struct ResourceCache {
     cached_glyphs: GlyphCache,
     cached_images: ImageCache,
@@ -305,6 +312,13 @@ pub struct ResourceCache {
     // both blobs and regular images.
     pending_image_requests: FastHashSet<ImageRequest>,
 
+    // The set of images whose data is still being decoded elsewhere. They
+    // keep their template, but are treated as absent until the embedder
+    // calls `notify_image_ready`.
+    not_ready_images: FastHashSet<ImageKey>,
+
+    gradient_ramps: FastHashMap<GradientRampKey, GradientRamp>,
+
     blob_image_renderer: Option<Box<BlobImageRenderer>>,
 }
 
@@ -324,6 +338,8 @@ impl ResourceCache {
             state: State::Idle,
             current_frame_id: FrameId(0),
             pending_image_requests: FastHashSet::default(),
+            not_ready_images: FastHashSet::default(),
+            gradient_ramps: FastHashMap::default(),
             glyph_rasterizer,
             blob_image_renderer,
         }
@@ -393,6 +409,12 @@ impl ResourceCache {
                 ResourceUpdate::DeleteImage(img) => {
                     self.delete_image_template(img);
                 }
+                ResourceUpdate::SetImageNotReady(img) => {
+                    self.set_image_not_ready(img);
+                }
+                ResourceUpdate::NotifyImageReady(img) => {
+                    self.notify_image_ready(img);
+                }
                 ResourceUpdate::AddFont(font) => match font {
                     AddFont::Raw(id, bytes, index) => {
                         profile_counters.font_templates.inc(bytes.len());
@@ -418,6 +440,12 @@ impl ResourceCache {
                 ResourceUpdate::DeleteFontInstance(instance) => {
                     self.delete_font_instance(instance);
                 }
+                ResourceUpdate::UpdateGradientRamp(key, stops) => {
+                    self.update_gradient_ramp(key, stops);
+                }
+                ResourceUpdate::DeleteGradientRamp(key) => {
+                    self.gradient_ramps.remove(&key);
+                }
             }
         }
     }
@@ -564,6 +592,7 @@ impl ResourceCache {
 
     pub fn delete_image_template(&mut self, image_key: ImageKey) {
         let value = self.resources.image_templates.remove(image_key);
+        self.not_ready_images.remove(&image_key);
 
         self.cached_images
             .clear_keys(|request| request.key == image_key);
@@ -579,6 +608,40 @@ impl ResourceCache {
         }
     }
 
+    /// Mark an image as still being decoded. Primitives that reference it
+    /// are skipped until `notify_image_ready` is called for the key.
+    pub fn set_image_not_ready(&mut self, image_key: ImageKey) {
+        self.not_ready_images.insert(image_key);
+    }
+
+    /// Mark a previously not-ready image as decoded. The epoch of its
+    /// template is bumped so that primitives which were skipped pick it
+    /// up on the next frame build.
+    pub fn notify_image_ready(&mut self, image_key: ImageKey) {
+        if !self.not_ready_images.remove(&image_key) {
+            return;
+        }
+
+        if let Some(image) = self.resources.image_templates.get_mut(image_key) {
+            image.epoch = Epoch(image.epoch.0 + 1);
+        }
+    }
+
+    /// Replace the stops of a gradient ramp. Gradients drawing the ramp
+    /// rewrite their gradient data on the next frame build, without the
+    /// scene being rebuilt.
+    pub fn update_gradient_ramp(&mut self, key: GradientRampKey, stops: Vec<GradientStop>) {
+        let epoch = match self.gradient_ramps.get(&key) {
+            Some(ramp) => Epoch(ramp.epoch.0 + 1),
+            None => Epoch(0),
+        };
+        self.gradient_ramps.insert(key, GradientRamp { stops, epoch });
+    }
+
+    pub fn get_gradient_ramp(&self, key: GradientRampKey) -> Option<&GradientRamp> {
+        self.gradient_ramps.get(&key)
+    }
+
     pub fn request_image(
         &mut self,
         request: ImageRequest,
@@ -586,6 +649,10 @@ impl ResourceCache {
     ) {
         debug_assert_eq!(self.state, State::AddResources);
 
+        if self.not_ready_images.contains(&request.key) {
+            return;
+        }
+
         let template = match self.resources.image_templates.get(request.key) {
             Some(template) => template,
             None => {
@@ -852,6 +919,39 @@ impl ResourceCache {
         }
     }
 
+    // Keep a render task that is already in the texture cache alive
+    // for this frame. Returns false if it has been evicted.
+    pub fn request_cached_render_task(
+        &mut self,
+        handle: &RenderTaskCacheEntryHandle,
+        gpu_cache: &mut GpuCache,
+    ) -> bool {
+        self.cached_render_tasks.request_existing(
+            handle,
+            &mut self.texture_cache,
+            gpu_cache,
+        )
+    }
+
+    // Request the texture cache item for a render task only if it is
+    // already cached, without drawing it if it isn't.
+    pub fn request_cached_render_task(
+        &mut self,
+        key: &RenderTaskCacheKey,
+        gpu_cache: &mut GpuCache,
+    ) -> Option<RenderTaskCacheEntryHandle> {
+        self.cached_render_tasks.request_cached(
+            key,
+            &mut self.texture_cache,
+            gpu_cache,
+        )
+    }
+
+    // Draw a cached render task again the next time it is requested.
+    pub fn invalidate_render_task(&mut self, handle: &RenderTaskCacheEntryHandle) {
+        self.cached_render_tasks.invalidate(handle);
+    }
+
     pub fn get_cached_render_task(
         &self,
         handle: &RenderTaskCacheEntryHandle,
@@ -864,6 +964,10 @@ impl ResourceCache {
     }
 
     pub fn get_image_properties(&self, image_key: ImageKey) -> Option<ImageProperties> {
+        if self.not_ready_images.contains(&image_key) {
+            return None;
+        }
+
         let image_template = &self.resources.image_templates.get(image_key);
 
         image_template.map(|image_template| {
@@ -1093,6 +1197,8 @@ impl ResourceCache {
         self.cached_glyphs
             .clear_fonts(|font| font.font_key.0 == namespace);
 
+        self.gradient_ramps.retain(|key, _| key.0 != namespace);
+
         if let Some(ref mut r) = self.blob_image_renderer {
             r.clear_namespace(namespace);
         }
@@ -1399,6 +1505,8 @@ impl ResourceCache {
         }
 
         self.glyph_rasterizer.reset();
+        self.not_ready_images.clear();
+        self.gradient_ramps.clear();
         let res = &mut self.resources;
         res.font_templates.clear();
         *res.font_instances.write().unwrap() = resources.font_instances;
@@ -1474,3 +1582,42 @@ impl ResourceCache {
         external_images
     }
 }
+
+#[cfg(test)]
+mod test {
+    use api::{DeviceUintPoint, DeviceUintRect, DeviceUintSize, TileOffset};
+    use super::intersect_for_tile;
+
+    #[test]
+    fn dirty_rect_only_touches_the_tiles_it_overlaps() {
+        let rect = |x, y, w, h| {
+            DeviceUintRect::new(DeviceUintPoint::new(x, y), DeviceUintSize::new(w, h))
+        };
+        let dirty_tiles = |dirty| {
+            let mut tiles = Vec::new();
+            for y in 0 .. 2 {
+                for x in 0 .. 2 {
+                    let tile = TileOffset::new(x, y);
+                    if let Some(tile_rect) = intersect_for_tile(dirty, 256, 256, 256, tile) {
+                        tiles.push(((x, y), tile_rect));
+                    }
+                }
+            }
+            tiles
+        };
+
+        // A small update, like a blinking caret, affects a single tile.
+        assert_eq!(dirty_tiles(rect(300, 20, 2, 16)), vec![((1, 0), rect(44, 20, 2, 16))]);
+
+        // Across a corner it affects each tile it reaches, in tile space.
+        assert_eq!(
+            dirty_tiles(rect(250, 250, 10, 10)),
+            vec![
+                ((0, 0), rect(250, 250, 6, 6)),
+                ((1, 0), rect(0, 250, 4, 6)),
+                ((0, 1), rect(250, 0, 6, 4)),
+                ((1, 1), rect(0, 0, 4, 4)),
+            ]
+        );
+    }
+}
@@ -4,7 +4,7 @@
 
 use api::{BuiltDisplayList, ColorF, DynamicProperties, Epoch, LayoutSize};
 use api::{FilterOp, LayoutTransform, PipelineId, PropertyBinding, PropertyBindingId};
-use api::{ItemRange, MixBlendMode, StackingContext};
+use api::{ItemRange, LayerEffect, MixBlendMode, StackingContext};
 use internal_types::FastHashMap;
 use std::sync::Arc;
 
@@ -17,6 +17,7 @@ use std::sync::Arc;
 pub struct SceneProperties {
     transform_properties: FastHashMap<PropertyBindingId, LayoutTransform>,
     float_properties: FastHashMap<PropertyBindingId, f32>,
+    layer_effects: FastHashMap<PipelineId, LayerEffect>,
 }
 
 impl SceneProperties {
@@ -24,6 +25,7 @@ impl SceneProperties {
         SceneProperties {
             transform_properties: FastHashMap::default(),
             float_properties: FastHashMap::default(),
+            layer_effects: FastHashMap::default(),
         }
     }
 
@@ -63,6 +65,36 @@ impl SceneProperties {
         }
     }
 
+    /// Set or clear the effect applied to the content of a pipeline when it
+    /// is composited. Returns true if it changed.
+    pub fn set_layer_effect(
+        &mut self,
+        pipeline_id: PipelineId,
+        effect: Option<LayerEffect>,
+    ) -> bool {
+        let old_effect = match effect {
+            Some(effect) => self.layer_effects.insert(pipeline_id, effect),
+            None => self.layer_effects.remove(&pipeline_id),
+        };
+        old_effect != effect
+    }
+
+    /// Get the filter that applies the current layer effect of a pipeline.
+    pub fn resolve_layer_effect(&self, pipeline_id: PipelineId) -> Option<FilterOp> {
+        let filter = match self.layer_effects.get(&pipeline_id) {
+            Some(&LayerEffect::Opacity(amount)) => {
+                FilterOp::Opacity(PropertyBinding::Value(amount), amount)
+            }
+            Some(&LayerEffect::Blur(radius)) => FilterOp::Blur(radius),
+            None => return None,
+        };
+        if filter.is_noop() {
+            None
+        } else {
+            Some(filter)
+        }
+    }
+
     /// Get the current value for a float property.
     pub fn resolve_float(
         &self,
@@ -152,6 +184,9 @@ impl Scene {
 /// An arbitrary number which we assume opacity is invisible below.
 pub const OPACITY_EPSILON: f32 = 0.001;
 
+/// The smallest gamma a gamma filter is drawn with. The shader divides by it.
+pub const MIN_FILTER_GAMMA: f32 = 0.01;
+
 pub trait FilterOpHelpers {
     fn is_visible(&self) -> bool;
     fn is_noop(&self) -> bool;
@@ -169,7 +204,11 @@ impl FilterOpHelpers for FilterOp {
             FilterOp::Saturate(..) |
             FilterOp::Sepia(..) |
             FilterOp::DropShadow(..) |
-            FilterOp::ColorMatrix(..) => true,
+            FilterOp::ColorMatrix(..) |
+            FilterOp::Downscale(..) |
+            FilterOp::Posterize(..) |
+            FilterOp::Threshold(..) => true,
+            FilterOp::Gamma(..) => true,
             FilterOp::Opacity(_, amount) => {
                 amount > OPACITY_EPSILON
             }
@@ -197,6 +236,10 @@ impl FilterOpHelpers for FilterOp {
                            0.0, 0.0, 0.0, 1.0,
                            0.0, 0.0, 0.0, 0.0]
             }
+            FilterOp::Downscale(factor) => factor <= 1,
+            FilterOp::Posterize(levels) => levels >= 256,
+            FilterOp::Threshold(..) => false,
+            FilterOp::Gamma(gamma) => gamma == 1.0,
         }
     }
 }
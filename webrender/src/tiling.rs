@@ -4,13 +4,15 @@
 
 use api::{ColorF, DeviceIntPoint, DeviceIntRect, DeviceIntSize, DevicePixelScale, DeviceUintPoint};
 use api::{DeviceUintRect, DeviceUintSize, DocumentLayer, FilterOp, ImageFormat, LayoutRect};
-use api::{MixBlendMode, PipelineId};
-use batch::{AlphaBatchBuilder, AlphaBatchContainer, ClipBatcher, resolve_image};
+use api::{Epoch, MixBlendMode, PipelineId, PresentationBlendMode, ScrollNodeState};
+use batch::{AlphaBatchBuilder, AlphaBatchContainer, ClipBatcher, PaintOrderItem, PrimitiveBatch};
+use batch::{BatchTextures, overdraw_grid, resolve_image, texture_bind_count};
 use clip::{ClipStore};
 use clip_scroll_tree::{ClipScrollTree, ClipScrollNodeIndex};
 use device::{FrameId, Texture};
 #[cfg(feature = "pathfinder")]
 use euclid::{TypedPoint2D, TypedVector2D};
+use frame_builder::{CompositeClamp, FadingSurface};
 use gpu_cache::{GpuCache};
 use gpu_types::{BlurDirection, BlurInstance};
 use gpu_types::{ClipScrollNodeData, ZBufferIdGenerator};
@@ -28,7 +30,8 @@ use texture_allocator::GuillotineAllocator;
 #[cfg(feature = "pathfinder")]
 use webrender_api::{DevicePixel, FontRenderMode};
 
-const MIN_TARGET_SIZE: u32 = 2048;
+/// The default for `FrameBuilderConfig::min_render_target_size`.
+pub const DEFAULT_MIN_TARGET_SIZE: u32 = 2048;
 
 #[derive(Debug)]
 pub struct ScrollbarPrimitive {
@@ -50,6 +53,13 @@ pub struct RenderTargetContext<'a, 'rc> {
     pub use_dual_source_blending: bool,
     pub node_data: &'a [ClipScrollNodeData],
     pub cached_gradients: &'a [CachedGradient],
+    pub record_paint_order: bool,
+    pub fading_surfaces: &'a [FadingSurface],
+    pub dither_offset: i32,
+    pub composite_clamp: CompositeClamp,
+    pub scissor_clip_rects: bool,
+    pub missing_image_placeholders: bool,
+    pub overdraw_grid_tile_size: Option<u32>,
 }
 
 #[cfg_attr(feature = "capture", derive(Serialize))]
@@ -149,11 +159,12 @@ impl<T: RenderTarget> RenderTargetList<T> {
     fn new(
         screen_size: DeviceIntSize,
         format: ImageFormat,
+        min_size: u32,
     ) -> Self {
         RenderTargetList {
             screen_size,
             format,
-            max_size: DeviceUintSize::new(MIN_TARGET_SIZE, MIN_TARGET_SIZE),
+            max_size: DeviceUintSize::new(min_size, min_size),
             targets: Vec::new(),
             saved_index: None,
             is_shared: false,
@@ -297,6 +308,11 @@ pub struct ColorRenderTarget {
     pub blits: Vec<BlitJob>,
     // List of frame buffer outputs for this render target.
     pub outputs: Vec<FrameOutput>,
+    // List of pipelines to read the pixels of from this render target.
+    pub pixel_readbacks: Vec<FrameOutput>,
+    // The source of each batched primitive, in paint order. Only
+    // filled in if paint order recording is enabled.
+    pub paint_order: Vec<PaintOrderItem>,
     allocator: Option<TextureAllocator>,
     alpha_tasks: Vec<RenderTaskId>,
     screen_size: DeviceIntSize,
@@ -323,6 +339,8 @@ impl RenderTarget for ColorRenderTarget {
             blits: Vec::new(),
             allocator: size.map(TextureAllocator::new),
             outputs: Vec::new(),
+            pixel_readbacks: Vec::new(),
+            paint_order: Vec::new(),
             alpha_tasks: Vec::new(),
             screen_size,
         }
@@ -350,9 +368,15 @@ impl RenderTarget for ColorRenderTarget {
                             let pic = &ctx.prim_store.pictures[pic_index.0];
                             let (target_rect, _) = task.get_target_rect();
 
-                            let mut batch_builder = AlphaBatchBuilder::new(self.screen_size, target_rect);
+                            let mut batch_builder = AlphaBatchBuilder::new(
+                                self.screen_size,
+                                target_rect,
+                                ctx.record_paint_order,
+                                ctx.overdraw_grid_tile_size.is_some(),
+                            );
 
                             batch_builder.add_pic_to_batch(
+                                pic_index,
                                 pic,
                                 *task_id,
                                 ctx,
@@ -362,6 +386,23 @@ impl RenderTarget for ColorRenderTarget {
                                 &mut z_generator,
                             );
 
+                            // Surfaces removed by a new scene fade out on
+                            // top of the content of the main framebuffer.
+                            if let RenderTaskLocation::Fixed(..) = task.location {
+                                batch_builder.add_fading_surfaces(
+                                    ctx.fading_surfaces,
+                                    *task_id,
+                                    ctx,
+                                    gpu_cache,
+                                    render_tasks,
+                                    &mut z_generator,
+                                );
+                            }
+
+                            if let Some(paint_order) = batch_builder.batch_list.paint_order.take() {
+                                self.paint_order.extend(paint_order);
+                            }
+
                             if let Some(batch_container) = batch_builder.build(&mut merged_batches) {
                                 self.alpha_batch_containers.push(batch_container);
                             }
@@ -425,6 +466,14 @@ impl RenderTarget for ColorRenderTarget {
                                 task_id,
                             });
                         }
+
+                        // Likewise if the caller wants to read its pixels.
+                        if let Some(pipeline_id) = pic.readback_pipeline_id {
+                            self.pixel_readbacks.push(FrameOutput {
+                                pipeline_id,
+                                task_id,
+                            });
+                        }
                     }
                     _ => {
                         // No other primitives make use of primitive caching yet!
@@ -517,7 +566,9 @@ pub struct AlphaRenderTarget {
     pub vertical_blurs: Vec<BlurInstance>,
     pub horizontal_blurs: Vec<BlurInstance>,
     pub scalings: Vec<ScalingInfo>,
-    pub zero_clears: Vec<RenderTaskId>,
+    // Areas of the target to clear to zero, with the areas of neighbouring
+    // tasks merged wherever they form a single rectangle.
+    pub zero_clears: Vec<DeviceIntRect>,
     allocator: TextureAllocator,
 }
 
@@ -553,7 +604,8 @@ impl RenderTarget for AlphaRenderTarget {
 
         match task.clear_mode {
             ClearMode::Zero => {
-                self.zero_clears.push(task_id);
+                let (target_rect, _) = task.get_target_rect();
+                add_clear_rect(&mut self.zero_clears, target_rect);
             }
             ClearMode::One => {}
             ClearMode::Transparent => {
@@ -719,6 +771,19 @@ pub enum RenderPassKind {
     },
 }
 
+/// The texture cache targets of an off-screen pass, ordered by texture and
+/// layer, so that they are drawn in the same order on every run.
+pub fn ordered_texture_cache_targets(
+    targets: &FastHashMap<(SourceTexture, i32), TextureCacheRenderTarget>,
+) -> Vec<(SourceTexture, i32, &TextureCacheRenderTarget)> {
+    let mut ordered: Vec<_> = targets
+        .iter()
+        .map(|(&(texture_id, layer), target)| (texture_id, layer, target))
+        .collect();
+    ordered.sort_by_key(|&(texture_id, layer, _)| (texture_id.sort_key(), layer));
+    ordered
+}
+
 /// A render pass represents a set of rendering operations that don't depend on one
 /// another.
 ///
@@ -740,11 +805,13 @@ impl RenderPass {
         }
     }
 
-    pub fn new_off_screen(screen_size: DeviceIntSize) -> Self {
+    /// Creates a pass drawing into intermediate targets, which are at least
+    /// `min_target_size` pixels square and grow to fit the largest task.
+    pub fn new_off_screen(screen_size: DeviceIntSize, min_target_size: u32) -> Self {
         RenderPass {
             kind: RenderPassKind::OffScreen {
-                color: RenderTargetList::new(screen_size, ImageFormat::BGRA8),
-                alpha: RenderTargetList::new(screen_size, ImageFormat::R8),
+                color: RenderTargetList::new(screen_size, ImageFormat::BGRA8, min_target_size),
+                alpha: RenderTargetList::new(screen_size, ImageFormat::R8, min_target_size),
                 texture_cache: FastHashMap::default(),
             },
             tasks: vec![],
@@ -920,6 +987,10 @@ impl CompositeOps {
     pub fn count(&self) -> usize {
         self.filters.len() + if self.mix_blend_mode.is_some() { 1 } else { 0 }
     }
+
+    pub fn requires_framebuffer_read(&self) -> bool {
+        self.mix_blend_mode.map_or(false, |mode| mode.requires_framebuffer_read())
+    }
 }
 
 /// A rendering-oriented representation of the frame built by the render backend
@@ -932,6 +1003,9 @@ pub struct Frame {
     pub inner_rect: DeviceUintRect,
     pub background_color: Option<ColorF>,
     pub layer: DocumentLayer,
+    /// How the main framebuffer pass is composited onto the
+    /// existing contents of the output.
+    pub presentation_blend_mode: PresentationBlendMode,
     pub device_pixel_ratio: f32,
     pub passes: Vec<RenderPass>,
     #[cfg_attr(any(feature = "capture", feature = "replay"), serde(default = "FrameProfileCounters::new", skip))]
@@ -957,6 +1031,30 @@ pub struct Frame {
     /// True if this frame has been drawn by the
     /// renderer.
     pub has_been_rendered: bool,
+
+    /// A translucent rect drawn over the main framebuffer after the content
+    /// of the frame, to show the viewport of a scroll frame while debugging.
+    pub highlight: Option<FrameHighlight>,
+
+    /// The number of samples per pixel the main framebuffer pass is drawn
+    /// with. Intermediate targets, such as those of filters, always have one.
+    pub sample_count: u32,
+
+    /// True for the first frame of its document that has any content.
+    pub is_first_contentful: bool,
+
+    /// The number of primitive batches drawn over each tile of the main
+    /// framebuffer, if the frame builder is configured with a tile size.
+    /// See `Frame::compute_overdraw_grid`.
+    pub overdraw_grid: Option<Vec<u32>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "capture", derive(Serialize))]
+#[cfg_attr(feature = "replay", derive(Deserialize))]
+pub struct FrameHighlight {
+    pub rect: DeviceIntRect,
+    pub color: ColorF,
 }
 
 impl Frame {
@@ -965,6 +1063,455 @@ impl Frame {
     pub fn must_be_drawn(&self) -> bool {
         self.has_texture_cache_tasks && !self.has_been_rendered
     }
+
+    /// The color the main framebuffer is cleared to before drawing, if
+    /// any. Blending over the existing contents leaves them untouched.
+    pub fn root_clear_color(&self) -> Option<ColorF> {
+        match self.presentation_blend_mode {
+            PresentationBlendMode::Replace => self.background_color,
+            PresentationBlendMode::SourceOver => None,
+        }
+    }
+
+    /// Whether the frame draws its background over its whole rect, which
+    /// makes the renderer's own clear of that rect unneeded.
+    fn clears_own_rect(&self) -> bool {
+        self.presentation_blend_mode == PresentationBlendMode::Replace &&
+            self.background_color.is_some()
+    }
+
+    /// List the render targets this frame allocates, in the order they are
+    /// drawn. Offscreen targets of a pass share one texture array, so they
+    /// all have the size of the largest task in that pass. Targets in the
+    /// texture cache aren't included since the frame doesn't own them.
+    pub fn render_targets(&self) -> Vec<RenderTargetSummary> {
+        let mut summaries = Vec::new();
+
+        for (pass_index, pass) in self.passes.iter().enumerate() {
+            match pass.kind {
+                RenderPassKind::MainFramebuffer(ref target) => {
+                    summaries.push(RenderTargetSummary {
+                        pass_index,
+                        target: FrameTarget::MainFramebuffer,
+                        size: self.window_size,
+                        format: None,
+                        sample_count: self.sample_count,
+                        paint_order: target.paint_order.clone(),
+                    });
+                }
+                RenderPassKind::OffScreen { ref alpha, ref color, .. } => {
+                    for target_index in 0 .. alpha.targets.len() {
+                        summaries.push(RenderTargetSummary {
+                            pass_index,
+                            target: FrameTarget::Alpha(RenderTargetIndex(target_index)),
+                            size: alpha.max_size,
+                            format: Some(alpha.format),
+                            sample_count: 1,
+                            paint_order: Vec::new(),
+                        });
+                    }
+                    for (target_index, target) in color.targets.iter().enumerate() {
+                        summaries.push(RenderTargetSummary {
+                            pass_index,
+                            target: FrameTarget::Color(RenderTargetIndex(target_index)),
+                            size: color.max_size,
+                            format: Some(color.format),
+                            sample_count: 1,
+                            paint_order: target.paint_order.clone(),
+                        });
+                    }
+                }
+            }
+        }
+
+        summaries
+    }
+
+    /// The number of draw commands that `visit` walks.
+    pub fn draw_command_count(&self) -> usize {
+        struct CommandCounter(usize);
+
+        impl FrameVisitor for CommandCounter {
+            fn begin_target(&mut self, _: usize, _: FrameTarget) {}
+            fn draw_command(&mut self, _: DrawCommand) {
+                self.0 += 1;
+            }
+            fn end_target(&mut self) {}
+        }
+
+        let mut counter = CommandCounter(0);
+        self.visit(&mut counter);
+        counter.0
+    }
+
+    /// The number of times a texture is bound for the primitive batches of
+    /// this frame, in the order they are drawn. See `texture_bind_count`.
+    pub fn texture_bind_count(&self) -> usize {
+        struct BatchTextureList(Vec<BatchTextures>);
+
+        impl FrameVisitor for BatchTextureList {
+            fn begin_target(&mut self, _: usize, _: FrameTarget) {}
+            fn draw_command(&mut self, command: DrawCommand) {
+                match command {
+                    DrawCommand::OpaqueBatch(batch) | DrawCommand::AlphaBatch(batch) => {
+                        self.0.push(batch.key.textures);
+                    }
+                    _ => {}
+                }
+            }
+            fn end_target(&mut self) {}
+        }
+
+        let mut textures = BatchTextureList(Vec::new());
+        self.visit(&mut textures);
+        texture_bind_count(&textures.0)
+    }
+
+    /// The number of primitive batches drawn over each tile of the main
+    /// framebuffer, for a grid of tiles of the given size in device pixels.
+    /// The tiles are in row major order. See `overdraw_grid`. Only counts
+    /// batches built with their bounds recorded.
+    pub fn compute_overdraw_grid(&self, tile_size: u32) -> Vec<u32> {
+        let mut rects = Vec::new();
+        for pass in &self.passes {
+            let target = match pass.kind {
+                RenderPassKind::MainFramebuffer(ref target) => target,
+                RenderPassKind::OffScreen { .. } => continue,
+            };
+            for container in &target.alpha_batch_containers {
+                let origin = container.target_rect
+                    .map_or(DeviceIntPoint::zero(), |rect| rect.origin);
+                let batches = container.opaque_batches.iter().chain(&container.alpha_batches);
+                for batch in batches {
+                    rects.push(batch.drawn_rect().translate(&origin.to_vector()));
+                }
+            }
+        }
+
+        overdraw_grid(self.window_size.to_i32(), tile_size.max(1) as i32, rects)
+    }
+
+    /// Whether any batch of this frame draws at least one primitive, as
+    /// opposed to a frame that only clears its targets.
+    pub fn has_content(&self) -> bool {
+        struct ContentFinder(bool);
+
+        impl FrameVisitor for ContentFinder {
+            fn begin_target(&mut self, _: usize, _: FrameTarget) {}
+            fn draw_command(&mut self, command: DrawCommand) {
+                match command {
+                    DrawCommand::OpaqueBatch(batch) | DrawCommand::AlphaBatch(batch) => {
+                        self.0 |= !batch.instances.is_empty();
+                    }
+                    _ => {}
+                }
+            }
+            fn end_target(&mut self) {}
+        }
+
+        let mut finder = ContentFinder(false);
+        self.visit(&mut finder);
+        finder.0
+    }
+
+    /// Walk the render targets of this frame and the drawing operations
+    /// within them, in the order the renderer executes them. This allows
+    /// streaming the frame somewhere else without copying it.
+    pub fn visit<V: FrameVisitor>(&self, visitor: &mut V) {
+        self.walk(|command| match command {
+            FlatCommand::BeginTarget { pass_index, target } => {
+                visitor.begin_target(pass_index, target)
+            }
+            FlatCommand::Draw(command) => visitor.draw_command(command),
+            FlatCommand::EndTarget => visitor.end_target(),
+            FlatCommand::EndPass(..) => {}
+        });
+    }
+
+    /// The operations of this frame as a single list in the order the
+    /// renderer executes them, for replaying the frame without recursion.
+    /// Passes are ordered so that every target is drawn before the pass
+    /// that composites it samples from it.
+    pub fn flatten_commands(&self) -> Vec<FlatCommand> {
+        let mut commands = Vec::new();
+        self.walk(|command| commands.push(command));
+        commands
+    }
+
+    fn walk<'a, F: FnMut(FlatCommand<'a>)>(&'a self, mut f: F) {
+        for (pass_index, pass) in self.passes.iter().enumerate() {
+            match pass.kind {
+                RenderPassKind::MainFramebuffer(ref target) => {
+                    f(FlatCommand::BeginTarget {
+                        pass_index,
+                        target: FrameTarget::MainFramebuffer,
+                    });
+                    target.walk(&mut f);
+                    f(FlatCommand::EndTarget);
+                }
+                RenderPassKind::OffScreen { ref alpha, ref color, ref texture_cache } => {
+                    // Texture cache targets are only drawn the first time
+                    // the frame is rendered.
+                    if !self.has_been_rendered {
+                        for (texture_id, layer, target) in
+                            ordered_texture_cache_targets(texture_cache) {
+                            f(FlatCommand::BeginTarget {
+                                pass_index,
+                                target: FrameTarget::TextureCache(texture_id, layer),
+                            });
+                            target.walk(&mut f);
+                            f(FlatCommand::EndTarget);
+                        }
+                    }
+
+                    for (target_index, target) in alpha.targets.iter().enumerate() {
+                        f(FlatCommand::BeginTarget {
+                            pass_index,
+                            target: FrameTarget::Alpha(RenderTargetIndex(target_index)),
+                        });
+                        target.walk(&mut f);
+                        f(FlatCommand::EndTarget);
+                    }
+
+                    for (target_index, target) in color.targets.iter().enumerate() {
+                        f(FlatCommand::BeginTarget {
+                            pass_index,
+                            target: FrameTarget::Color(RenderTargetIndex(target_index)),
+                        });
+                        target.walk(&mut f);
+                        f(FlatCommand::EndTarget);
+                    }
+                }
+            }
+            f(FlatCommand::EndPass(pass_index));
+        }
+    }
+}
+
+/// Identifies the surface a group of draw commands renders into.
+#[derive(Debug, Copy, Clone)]
+pub enum FrameTarget {
+    MainFramebuffer,
+    Color(RenderTargetIndex),
+    Alpha(RenderTargetIndex),
+    TextureCache(SourceTexture, i32),
+}
+
+/// How the renderer clears the main framebuffer before drawing the frames of
+/// its documents.
+#[derive(Debug, PartialEq)]
+pub enum FramebufferClear {
+    /// Clear all of it.
+    Full,
+    /// Clear only these rects, keeping the rest of the existing contents.
+    Rects(Vec<DeviceUintRect>),
+}
+
+/// Decides how to clear the main framebuffer for the given frames. A frame
+/// that blends over the existing contents needs them kept, so then only the
+/// rects of the frames that neither blend over the framebuffer nor clear it
+/// with their background are cleared.
+pub fn framebuffer_clear<'a, I>(mut frames: I, framebuffer_size: DeviceUintSize) -> FramebufferClear
+where
+    I: Iterator<Item = &'a Frame> + Clone,
+{
+    let blends_over = frames
+        .clone()
+        .any(|frame| frame.presentation_blend_mode == PresentationBlendMode::SourceOver);
+    if blends_over {
+        return FramebufferClear::Rects(
+            frames
+                .filter(|frame| {
+                    frame.presentation_blend_mode == PresentationBlendMode::Replace &&
+                        !frame.clears_own_rect()
+                })
+                .map(|frame| frame.inner_rect)
+                .collect()
+        );
+    }
+
+    let framebuffer_rect = DeviceUintRect::new(DeviceUintPoint::zero(), framebuffer_size);
+    let is_covered = frames
+        .any(|frame| frame.clears_own_rect() && frame.inner_rect == framebuffer_rect);
+    if is_covered {
+        FramebufferClear::Rects(Vec::new())
+    } else {
+        FramebufferClear::Full
+    }
+}
+
+/// Describes a render target that a frame draws into.
+#[derive(Debug, Clone)]
+pub struct RenderTargetSummary {
+    pub pass_index: usize,
+    pub target: FrameTarget,
+    pub size: DeviceUintSize,
+    /// The format of the target, or `None` for the main framebuffer,
+    /// which is owned by the embedder.
+    pub format: Option<ImageFormat>,
+    pub sample_count: u32,
+    /// The source of each primitive batched into the target, in paint
+    /// order. Only filled in for color targets, when paint order recording
+    /// is enabled.
+    pub paint_order: Vec<PaintOrderItem>,
+}
+
+/// The structure of the last frame a document rendered, kept so that it can
+/// be inspected after later frames fail to build, e.g. following a crash.
+#[derive(Clone)]
+pub struct FrameSnapshot {
+    pub render_targets: Vec<RenderTargetSummary>,
+    pub draw_command_count: usize,
+    pub scroll_offsets: Vec<ScrollNodeState>,
+    pub pipeline_epochs: FastHashMap<PipelineId, Epoch>,
+}
+
+/// A single drawing operation within a render target.
+pub enum DrawCommand<'a> {
+    /// Copy from a texture or another render task into this target.
+    Blit(&'a BlitJob),
+    /// A batch of separable blur instances.
+    Blur(BlurDirection, &'a [BlurInstance]),
+    /// Downscale the contents of one render task into another.
+    Scaling(&'a ScalingInfo),
+    /// Clear an area of the target to zero.
+    ZeroClear(DeviceIntRect),
+    /// Clip mask items drawn into an alpha target.
+    ClipMasks(&'a ClipBatcher),
+    /// A batch of opaque primitives, drawn front-to-back.
+    OpaqueBatch(&'a PrimitiveBatch),
+    /// A batch of primitives that need blending, drawn back-to-front.
+    AlphaBatch(&'a PrimitiveBatch),
+    /// Restrict the batches that follow to a rect of the target, which
+    /// applies their clip rect, or lift the restriction with `None`.
+    Scissor(Option<DeviceIntRect>),
+    /// Copy of a pipeline registered as a frame output.
+    Output(&'a FrameOutput),
+    /// Glyphs rasterized on the GPU.
+    Glyphs(&'a [GlyphJob]),
+}
+
+/// An entry of the list returned by `Frame::flatten_commands`.
+pub enum FlatCommand<'a> {
+    /// Start drawing into a target. The commands up to the matching
+    /// `EndTarget` draw into it.
+    BeginTarget { pass_index: usize, target: FrameTarget },
+    Draw(DrawCommand<'a>),
+    EndTarget,
+    /// All targets of the pass are drawn, so they can be sampled by the
+    /// passes that follow, which composite them.
+    EndPass(usize),
+}
+
+/// A callback interface for `Frame::visit`.
+pub trait FrameVisitor {
+    fn begin_target(&mut self, pass_index: usize, target: FrameTarget);
+    fn draw_command(&mut self, command: DrawCommand);
+    fn end_target(&mut self);
+}
+
+impl ColorRenderTarget {
+    fn walk<'a, F: FnMut(FlatCommand<'a>)>(&'a self, f: &mut F) {
+        for blit in &self.blits {
+            f(FlatCommand::Draw(DrawCommand::Blit(blit)));
+        }
+        if !self.vertical_blurs.is_empty() {
+            let blurs = &self.vertical_blurs;
+            f(FlatCommand::Draw(DrawCommand::Blur(BlurDirection::Vertical, blurs)));
+        }
+        if !self.horizontal_blurs.is_empty() {
+            let blurs = &self.horizontal_blurs;
+            f(FlatCommand::Draw(DrawCommand::Blur(BlurDirection::Horizontal, blurs)));
+        }
+        for scaling in &self.scalings {
+            f(FlatCommand::Draw(DrawCommand::Scaling(scaling)));
+        }
+        let mut scissor_rect = None;
+        for container in &self.alpha_batch_containers {
+            for batch in container.opaque_batches.iter().rev() {
+                walk_scissor_rect(f, &mut scissor_rect, batch.scissor_rect);
+                f(FlatCommand::Draw(DrawCommand::OpaqueBatch(batch)));
+            }
+        }
+        for container in &self.alpha_batch_containers {
+            for batch in &container.alpha_batches {
+                walk_scissor_rect(f, &mut scissor_rect, batch.scissor_rect);
+                f(FlatCommand::Draw(DrawCommand::AlphaBatch(batch)));
+            }
+        }
+        walk_scissor_rect(f, &mut scissor_rect, None);
+        for output in &self.outputs {
+            f(FlatCommand::Draw(DrawCommand::Output(output)));
+        }
+    }
+}
+
+// Emit a scissor command when the batch about to be drawn is restricted to a
+// different rect than the previous one.
+fn walk_scissor_rect<'a, F: FnMut(FlatCommand<'a>)>(
+    f: &mut F,
+    current: &mut Option<DeviceIntRect>,
+    scissor_rect: Option<DeviceIntRect>,
+) {
+    if *current != scissor_rect {
+        *current = scissor_rect;
+        f(FlatCommand::Draw(DrawCommand::Scissor(scissor_rect)));
+    }
+}
+
+// Add an area to a list of areas that are cleared to the same value. If the
+// area and the previous one together cover exactly a rectangle, they are
+// merged so that both are cleared by a single call.
+fn add_clear_rect(clears: &mut Vec<DeviceIntRect>, rect: DeviceIntRect) {
+    fn area(rect: &DeviceIntRect) -> i32 {
+        rect.size.width * rect.size.height
+    }
+
+    if let Some(last) = clears.last_mut() {
+        let union = last.union(&rect);
+        let overlap = last.intersection(&rect).map_or(0, |overlap| area(&overlap));
+        if area(&union) == area(last) + area(&rect) - overlap {
+            *last = union;
+            return;
+        }
+    }
+
+    clears.push(rect);
+}
+
+impl AlphaRenderTarget {
+    fn walk<'a, F: FnMut(FlatCommand<'a>)>(&'a self, f: &mut F) {
+        for &rect in &self.zero_clears {
+            f(FlatCommand::Draw(DrawCommand::ZeroClear(rect)));
+        }
+        if !self.vertical_blurs.is_empty() {
+            let blurs = &self.vertical_blurs;
+            f(FlatCommand::Draw(DrawCommand::Blur(BlurDirection::Vertical, blurs)));
+        }
+        if !self.horizontal_blurs.is_empty() {
+            let blurs = &self.horizontal_blurs;
+            f(FlatCommand::Draw(DrawCommand::Blur(BlurDirection::Horizontal, blurs)));
+        }
+        for scaling in &self.scalings {
+            f(FlatCommand::Draw(DrawCommand::Scaling(scaling)));
+        }
+        f(FlatCommand::Draw(DrawCommand::ClipMasks(&self.clip_batcher)));
+    }
+}
+
+impl TextureCacheRenderTarget {
+    fn walk<'a, F: FnMut(FlatCommand<'a>)>(&'a self, f: &mut F) {
+        if !self.glyphs.is_empty() {
+            f(FlatCommand::Draw(DrawCommand::Glyphs(&self.glyphs)));
+        }
+        for blit in &self.blits {
+            f(FlatCommand::Draw(DrawCommand::Blit(blit)));
+        }
+        if !self.horizontal_blurs.is_empty() {
+            let blurs = &self.horizontal_blurs;
+            f(FlatCommand::Draw(DrawCommand::Blur(BlurDirection::Horizontal, blurs)));
+        }
+    }
 }
 
 impl BlurTask {
@@ -991,10 +1538,233 @@ pub struct SpecialRenderPasses {
 }
 
 impl SpecialRenderPasses {
-    pub fn new(screen_size: &DeviceIntSize) -> SpecialRenderPasses {
+    pub fn new(screen_size: &DeviceIntSize, min_target_size: u32) -> SpecialRenderPasses {
         SpecialRenderPasses {
-            alpha_glyph_pass: RenderPass::new_off_screen(*screen_size),
-            color_glyph_pass: RenderPass::new_off_screen(*screen_size),
+            alpha_glyph_pass: RenderPass::new_off_screen(*screen_size, min_target_size),
+            color_glyph_pass: RenderPass::new_off_screen(*screen_size, min_target_size),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use api::{ColorF, DeviceIntPoint, DeviceIntRect, DeviceIntSize, DeviceUintPoint};
+    use api::{DeviceUintRect, DeviceUintSize, ImageFormat, PresentationBlendMode};
+    use device::FrameId;
+    use internal_types::{CacheTextureId, FastHashMap, SourceTexture};
+    use profiler::FrameProfileCounters;
+    use render_task::{RenderTask, RenderTaskTree};
+    use super::{DEFAULT_MIN_TARGET_SIZE, Frame, FrameTarget, RenderPass, RenderPassKind};
+    use super::add_clear_rect;
+    use super::{ColorRenderTarget, RenderTargetKind, RenderTargetList, TextureCacheRenderTarget};
+    use super::ordered_texture_cache_targets;
+
+    fn empty_frame(presentation_blend_mode: PresentationBlendMode) -> Frame {
+        let window_size = DeviceUintSize::new(100, 100);
+        Frame {
+            window_size,
+            inner_rect: DeviceUintRect::new(DeviceUintPoint::zero(), window_size),
+            background_color: Some(ColorF::new(1.0, 1.0, 1.0, 1.0)),
+            layer: 0,
+            presentation_blend_mode,
+            device_pixel_ratio: 1.0,
+            passes: Vec::new(),
+            profile_counters: FrameProfileCounters::new(),
+            node_data: Vec::new(),
+            clip_chain_local_clip_rects: Vec::new(),
+            render_tasks: RenderTaskTree::new(FrameId::new(0)),
+            gpu_cache_frame_id: FrameId::new(0),
+            deferred_resolves: Vec::new(),
+            has_texture_cache_tasks: false,
+            has_been_rendered: false,
+            highlight: None,
+            sample_count: 1,
+            is_first_contentful: false,
+            overdraw_grid: None,
+        }
+    }
+
+    #[test]
+    fn texture_cache_targets_are_ordered_by_texture_and_layer() {
+        let keys = [
+            (SourceTexture::TextureCache(CacheTextureId(2)), 0),
+            (SourceTexture::TextureCache(CacheTextureId(0)), 1),
+            (SourceTexture::TextureCache(CacheTextureId(1)), 0),
+            (SourceTexture::TextureCache(CacheTextureId(0)), 0),
+        ];
+
+        let ordered_keys = |insertion_order: &[usize]| {
+            let mut targets = FastHashMap::default();
+            for &index in insertion_order {
+                targets.insert(keys[index], TextureCacheRenderTarget::new(RenderTargetKind::Color));
+            }
+            ordered_texture_cache_targets(&targets)
+                .into_iter()
+                .map(|(texture_id, layer, _)| (texture_id, layer))
+                .collect::<Vec<_>>()
+        };
+
+        let expected = vec![keys[3], keys[1], keys[2], keys[0]];
+        assert_eq!(ordered_keys(&[0, 1, 2, 3]), expected);
+        assert_eq!(ordered_keys(&[3, 2, 1, 0]), expected);
+        assert_eq!(ordered_keys(&[1, 3, 0, 2]), expected);
+    }
+
+    #[test]
+    fn source_over_presentation_keeps_existing_contents() {
+        let frame = empty_frame(PresentationBlendMode::default());
+        assert_eq!(frame.presentation_blend_mode, PresentationBlendMode::Replace);
+        assert_eq!(frame.root_clear_color(), Some(ColorF::new(1.0, 1.0, 1.0, 1.0)));
+
+        let frame = empty_frame(PresentationBlendMode::SourceOver);
+        assert_eq!(frame.root_clear_color(), None);
+    }
+
+    #[test]
+    fn source_over_frame_issues_no_clear() {
+        let framebuffer_size = DeviceUintSize::new(100, 100);
+        let no_clear = FramebufferClear::Rects(Vec::new());
+
+        // A frame that replaces the framebuffer contents only stands in for
+        // the renderer's clear if its background covers all of it.
+        let mut frame = empty_frame(PresentationBlendMode::Replace);
+        assert_eq!(framebuffer_clear(Some(&frame).into_iter(), framebuffer_size), no_clear);
+        assert_eq!(
+            framebuffer_clear(Some(&frame).into_iter(), DeviceUintSize::new(200, 100)),
+            FramebufferClear::Full
+        );
+        frame.background_color = None;
+        assert_eq!(
+            framebuffer_clear(Some(&frame).into_iter(), framebuffer_size),
+            FramebufferClear::Full
+        );
+
+        // Blending over the contents must not clear them at all, whatever
+        // the background or the size of the frame.
+        let mut frame = empty_frame(PresentationBlendMode::SourceOver);
+        assert_eq!(frame.root_clear_color(), None);
+        assert_eq!(framebuffer_clear(Some(&frame).into_iter(), framebuffer_size), no_clear);
+        frame.background_color = None;
+        frame.inner_rect.size = DeviceUintSize::new(50, 50);
+        assert_eq!(framebuffer_clear(Some(&frame).into_iter(), framebuffer_size), no_clear);
+    }
+
+    #[test]
+    fn source_over_frame_keeps_the_clears_of_other_documents() {
+        let framebuffer_size = DeviceUintSize::new(100, 100);
+
+        // The document without a background still has its own rect cleared
+        // when another one blends over the framebuffer.
+        let overlay = empty_frame(PresentationBlendMode::SourceOver);
+        let mut document = empty_frame(PresentationBlendMode::Replace);
+        document.background_color = None;
+        document.inner_rect = DeviceUintRect::new(
+            DeviceUintPoint::new(50, 0),
+            DeviceUintSize::new(50, 100),
+        );
+        let mut with_background = empty_frame(PresentationBlendMode::Replace);
+        with_background.inner_rect.size = DeviceUintSize::new(50, 100);
+
+        let frames = [overlay, document, with_background];
+        assert_eq!(
+            framebuffer_clear(frames.iter(), framebuffer_size),
+            FramebufferClear::Rects(vec![frames[1].inner_rect])
+        );
+    }
+
+    #[test]
+    fn contiguous_clears_are_merged() {
+        let rect = |x, y, w, h| DeviceIntRect::new(DeviceIntPoint::new(x, y), DeviceIntSize::new(w, h));
+        let mut clears = Vec::new();
+
+        // Three tasks stacked on top of each other are cleared in one go.
+        add_clear_rect(&mut clears, rect(0, 0, 64, 32));
+        add_clear_rect(&mut clears, rect(0, 32, 64, 32));
+        add_clear_rect(&mut clears, rect(0, 64, 64, 16));
+        assert_eq!(clears, vec![rect(0, 0, 64, 80)]);
+
+        // A task next to only part of the cleared area needs its own clear.
+        add_clear_rect(&mut clears, rect(64, 0, 32, 32));
+        assert_eq!(clears, vec![rect(0, 0, 64, 80), rect(64, 0, 32, 32)]);
+    }
+
+    #[test]
+    fn filtered_scene_reports_root_and_filter_targets() {
+        let mut frame = empty_frame(PresentationBlendMode::Replace);
+        let screen_size = DeviceIntSize::new(100, 100);
+
+        // The filtered element is drawn into a color target first, then
+        // composited into the main framebuffer.
+        let mut filter_pass = RenderPass::new_off_screen(screen_size, DEFAULT_MIN_TARGET_SIZE);
+        if let RenderPassKind::OffScreen { ref mut color, .. } = filter_pass.kind {
+            color.allocate(DeviceUintSize::new(60, 40));
+        }
+        frame.passes.push(filter_pass);
+        frame.passes.push(RenderPass::new_main_framebuffer(screen_size));
+
+        let targets = frame.render_targets();
+        assert_eq!(targets.len(), 2);
+
+        assert_eq!(targets[0].pass_index, 0);
+        match targets[0].target {
+            FrameTarget::Color(index) => assert_eq!(index.0, 0),
+            target => panic!("unexpected target {:?}", target),
+        }
+        let default_size = DeviceUintSize::new(DEFAULT_MIN_TARGET_SIZE, DEFAULT_MIN_TARGET_SIZE);
+        assert_eq!(targets[0].size, default_size);
+        assert_eq!(targets[0].format, Some(ImageFormat::BGRA8));
+
+        assert_eq!(targets[1].pass_index, 1);
+        match targets[1].target {
+            FrameTarget::MainFramebuffer => {}
+            target => panic!("unexpected target {:?}", target),
+        }
+        assert_eq!(targets[1].size, frame.window_size);
+        assert_eq!(targets[1].format, None);
+    }
+
+    #[test]
+    fn full_render_target_spills_into_another() {
+        let mut list: RenderTargetList<ColorRenderTarget> =
+            RenderTargetList::new(DeviceIntSize::new(100, 100), ImageFormat::BGRA8, 256);
+
+        // Each task takes most of a target, so every one needs its own.
+        let indices: Vec<usize> = (0 .. 3)
+            .map(|_| (list.allocate(DeviceUintSize::new(200, 200)).1).0)
+            .collect();
+        assert_eq!(indices, vec![0, 1, 2]);
+        assert_eq!(list.targets.len(), 3);
+
+        // Smaller tasks still fill the space left in the last target.
+        let (origin, index) = list.allocate(DeviceUintSize::new(40, 40));
+        assert_eq!(index.0, 2);
+        assert_ne!(origin, DeviceUintPoint::zero());
+    }
+
+    #[test]
+    fn off_screen_targets_start_at_the_minimum_size_and_grow_to_fit() {
+        let mut render_tasks = RenderTaskTree::new(FrameId::new(0));
+        let mut add_task = |width, height| {
+            let size = DeviceIntSize::new(width, height);
+            let task_id = render_tasks.add(RenderTask::new_readback(DeviceIntRect::new(
+                DeviceIntPoint::zero(),
+                size,
+            )));
+            (task_id, size)
+        };
+        let (color_task, color_size) = add_task(60, 40);
+        let (alpha_task, alpha_size) = add_task(80, 300);
+
+        let mut pass = RenderPass::new_off_screen(DeviceIntSize::new(100, 100), 256);
+        pass.add_render_task(color_task, color_size, RenderTargetKind::Color);
+        pass.add_render_task(alpha_task, alpha_size, RenderTargetKind::Alpha);
+
+        match pass.kind {
+            RenderPassKind::OffScreen { ref color, ref alpha, .. } => {
+                assert_eq!(color.max_size, DeviceUintSize::new(256, 256));
+                assert_eq!(alpha.max_size, DeviceUintSize::new(256, 300));
+            }
+            RenderPassKind::MainFramebuffer(..) => panic!("not an off-screen pass"),
         }
     }
 }
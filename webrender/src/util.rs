@@ -25,6 +25,7 @@ pub trait MatrixHelpers<Src, Dst> {
     fn transform_kind(&self) -> TransformedRectKind;
     fn is_simple_translation(&self) -> bool;
     fn is_simple_2d_translation(&self) -> bool;
+    fn project_to_2d(&self) -> Self;
 }
 
 impl<Src, Dst> MatrixHelpers<Src, Dst> for TypedTransform3D<f32, Src, Dst> {
@@ -119,6 +120,20 @@ impl<Src, Dst> MatrixHelpers<Src, Dst> for TypedTransform3D<f32, Src, Dst> {
 
         self.m43.abs() < NEARLY_ZERO
     }
+
+    // Drop the z component of the result, so that content is mapped into the
+    // plane of the target space. Points with z = 0 keep their x, y and w.
+    fn project_to_2d(&self) -> Self {
+        let mut result = *self;
+        result.m13 = 0.0;
+        result.m23 = 0.0;
+        result.m43 = 0.0;
+        result.m31 = 0.0;
+        result.m32 = 0.0;
+        result.m34 = 0.0;
+        result.m33 = 1.0;
+        result
+    }
 }
 
 pub trait RectHelpers<U>
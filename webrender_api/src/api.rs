@@ -13,14 +13,77 @@ use std::path::PathBuf;
 use std::u32;
 use {BuiltDisplayList, BuiltDisplayListDescriptor, ColorF, DeviceIntPoint, DeviceUintRect};
 use {DeviceUintSize, ExternalScrollId, FontInstanceKey, FontInstanceOptions};
-use {FontInstancePlatformOptions, FontKey, FontVariation, GlyphDimensions, GlyphKey, ImageData};
-use {ImageDescriptor, ImageKey, ItemTag, LayoutPoint, LayoutSize, LayoutTransform, LayoutVector2D};
-use {NativeFontHandle, WorldPoint};
+use {FontInstancePlatformOptions, FontKey, FontVariation, GlyphDimensions, GlyphKey};
+use {GradientRampKey, GradientStop, ImageData, ImageDescriptor, ImageKey, ItemTag, LayoutPoint};
+use {LayoutRect, LayoutSize, LayoutToWorldTransform, LayoutTransform, LayoutVector2D};
+use {MixBlendMode, NativeFontHandle};
+use {WorldPoint, WorldRect};
 
 pub type TileSize = u16;
 /// Documents are rendered in the ascending order of their associated layer values.
 pub type DocumentLayer = i8;
 
+/// How the root target of a document is composited onto whatever the
+/// output surface already contains.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum PresentationBlendMode {
+    /// The document replaces the existing contents (opaque present).
+    Replace,
+    /// The document is blended over the existing contents, for example
+    /// when drawing an overlay onto a surface owned by someone else.
+    SourceOver,
+}
+
+impl Default for PresentationBlendMode {
+    fn default() -> Self {
+        PresentationBlendMode::Replace
+    }
+}
+
+/// A brightness and gamma adjustment applied to the whole document after
+/// everything else has been composited, for example to dim the output.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct OutputTonemap {
+    pub gamma: f32,
+    pub brightness: f32,
+}
+
+/// The smallest gamma of an `OutputTonemap`. The gamma curve divides by it.
+pub const MIN_OUTPUT_TONEMAP_GAMMA: f32 = 0.01;
+
+impl OutputTonemap {
+    /// Create a tonemap, raising the gamma to at least
+    /// `MIN_OUTPUT_TONEMAP_GAMMA` and the brightness to at least zero.
+    pub fn new(gamma: f32, brightness: f32) -> Self {
+        OutputTonemap {
+            gamma: gamma.max(MIN_OUTPUT_TONEMAP_GAMMA),
+            brightness: brightness.max(0.0),
+        }
+    }
+
+    pub fn identity() -> Self {
+        OutputTonemap::new(1.0, 1.0)
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.gamma == 1.0 && self.brightness == 1.0
+    }
+}
+
+impl Default for OutputTonemap {
+    fn default() -> Self {
+        OutputTonemap::identity()
+    }
+}
+
+/// An effect applied to the whole content of a pipeline when it is
+/// composited into its parent, without the display list declaring it.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum LayerEffect {
+    Opacity(f32),
+    Blur(f32),
+}
+
 /// The resource updates for a given transaction (they must be applied in the same frame).
 #[derive(Clone, Deserialize, Serialize)]
 pub struct ResourceUpdates {
@@ -32,10 +95,14 @@ pub enum ResourceUpdate {
     AddImage(AddImage),
     UpdateImage(UpdateImage),
     DeleteImage(ImageKey),
+    SetImageNotReady(ImageKey),
+    NotifyImageReady(ImageKey),
     AddFont(AddFont),
     DeleteFont(FontKey),
     AddFontInstance(AddFontInstance),
     DeleteFontInstance(FontInstanceKey),
+    UpdateGradientRamp(GradientRampKey, Vec<GradientStop>),
+    DeleteGradientRamp(GradientRampKey),
 }
 
 impl ResourceUpdates {
@@ -79,6 +146,18 @@ impl ResourceUpdates {
         self.updates.push(ResourceUpdate::DeleteImage(key));
     }
 
+    /// Mark an image as still being decoded elsewhere. Primitives that use
+    /// it are left out of frames until `notify_image_ready` is sent for it.
+    pub fn set_image_not_ready(&mut self, key: ImageKey) {
+        self.updates.push(ResourceUpdate::SetImageNotReady(key));
+    }
+
+    /// Mark an image previously set as not ready as decoded, so that the
+    /// next frame draws the primitives that use it.
+    pub fn notify_image_ready(&mut self, key: ImageKey) {
+        self.updates.push(ResourceUpdate::NotifyImageReady(key));
+    }
+
     pub fn add_raw_font(&mut self, key: FontKey, bytes: Vec<u8>, index: u32) {
         self.updates
             .push(ResourceUpdate::AddFont(AddFont::Raw(key, bytes, index)));
@@ -117,6 +196,18 @@ impl ResourceUpdates {
         self.updates.push(ResourceUpdate::DeleteFontInstance(key));
     }
 
+    /// Replace the stops drawn by gradients that use this ramp. Only the
+    /// gradient data is regenerated; the scene is not rebuilt. The stop
+    /// offsets are relative to the gradient line the gradient was created
+    /// with, and should lie between 0 and 1.
+    pub fn update_gradient_ramp(&mut self, key: GradientRampKey, stops: Vec<GradientStop>) {
+        self.updates.push(ResourceUpdate::UpdateGradientRamp(key, stops));
+    }
+
+    pub fn delete_gradient_ramp(&mut self, key: GradientRampKey) {
+        self.updates.push(ResourceUpdate::DeleteGradientRamp(key));
+    }
+
     pub fn merge(&mut self, mut other: ResourceUpdates) {
         self.updates.append(&mut other.updates);
     }
@@ -266,6 +357,19 @@ impl Transaction {
         window_size: DeviceUintSize,
         inner_rect: DeviceUintRect,
         device_pixel_ratio: f32,
+    ) {
+        self.set_window_parameters_with_ratio(
+            window_size,
+            inner_rect,
+            DevicePixelRatio::uniform(device_pixel_ratio),
+        );
+    }
+
+    pub fn set_window_parameters_with_ratio(
+        &mut self,
+        window_size: DeviceUintSize,
+        inner_rect: DeviceUintRect,
+        device_pixel_ratio: DevicePixelRatio,
     ) {
         self.scene_ops.push(
             SceneMsg::SetWindowParameters {
@@ -284,6 +388,37 @@ impl Transaction {
         self.frame_ops.push(FrameMsg::Scroll(scroll_location, cursor));
     }
 
+    /// Send the scrolls of later `scroll` calls to the given scroll frame,
+    /// wherever the cursor is, until this is called with `None`. This keeps
+    /// a drag scrolling the frame where it started.
+    pub fn set_scroll_capture(&mut self, id: Option<ExternalScrollId>) {
+        self.frame_ops.push(FrameMsg::SetScrollCapture(id));
+    }
+
+    /// Hit test once at `cursor`, and capture the scrolls of later `scroll`
+    /// calls for the scroll frame found there until `end_scroll`. See
+    /// `set_scroll_capture`.
+    pub fn begin_scroll(&mut self, cursor: WorldPoint) {
+        self.frame_ops.push(FrameMsg::BeginScroll(cursor));
+    }
+
+    /// Stop capturing the scrolls for the scroll frame found by `begin_scroll`.
+    pub fn end_scroll(&mut self) {
+        self.set_scroll_capture(None);
+    }
+
+    /// Draw a translucent rect of the given color over the viewport of a
+    /// scroll frame, on top of the document, or stop with `None`. This is a
+    /// debugging aid, drawn only when the renderer has the `debug_renderer`
+    /// feature.
+    pub fn set_highlighted_scroll_frame(&mut self, highlight: Option<(ExternalScrollId, ColorF)>) {
+        self.frame_ops.push(FrameMsg::SetHighlightedScrollFrame(highlight));
+    }
+
+    /// Scrolls the scroll frame with the given id so that `origin` in its
+    /// content is at the frame's origin, without hit testing for it under a
+    /// cursor. With `ScrollClamping::ToContentBounds` the offset is clamped to
+    /// the content and rounded, like scrolling by a delta is.
     pub fn scroll_node_with_id(
         &mut self,
         origin: LayoutPoint,
@@ -293,6 +428,61 @@ impl Transaction {
         self.frame_ops.push(FrameMsg::ScrollNodeWithId(origin, id, clamp));
     }
 
+    /// Scrolls the scroll frame with the given id by a delta in its local
+    /// space, without hit testing for it under a cursor.
+    pub fn scroll_node_by(&mut self, id: ExternalScrollId, delta: LayoutVector2D) {
+        self.frame_ops.push(FrameMsg::ScrollNodeBy(id, delta));
+    }
+
+    /// Link two scroll frames so that scrolling either of them scrolls the
+    /// other by the same amount on the given axes, within its own bounds.
+    pub fn link_scroll_frames(
+        &mut self,
+        first: ExternalScrollId,
+        second: ExternalScrollId,
+        axes: ScrollAxes,
+    ) {
+        self.frame_ops.push(FrameMsg::LinkScrollFrames(first, second, axes));
+    }
+
+    /// Set how much of the motion of scroll input carries on after it, with
+    /// every generated frame, from zero for none (the default) to just below
+    /// one for a long glide.
+    pub fn set_scroll_friction(&mut self, scroll_friction: f32) {
+        self.frame_ops.push(FrameMsg::SetScrollFriction(scroll_friction));
+    }
+
+    /// Draw the part of a scroll frame's content within the given rect
+    /// again, for content that changed without a new display list. Only
+    /// the pictures cached in the texture cache that overlap the rect are
+    /// re-rendered; everything else keeps its cached output.
+    pub fn invalidate_rect(&mut self, id: ExternalScrollId, rect: LayoutRect) {
+        self.frame_ops.push(FrameMsg::InvalidateRect(id, rect));
+    }
+
+    /// Set whether a scroll frame can be scrolled past the edges of its
+    /// content. See `ScrollMode`.
+    pub fn set_scroll_mode(&mut self, id: ExternalScrollId, mode: ScrollMode) {
+        self.frame_ops.push(FrameMsg::SetScrollMode(id, mode));
+    }
+
+    /// Set how strictly a scroll frame snaps to its snap offsets when a
+    /// scroll gesture ends. See `ScrollSnapType`.
+    pub fn set_scroll_snap_type(&mut self, id: ExternalScrollId, snap_type: ScrollSnapType) {
+        self.frame_ops.push(FrameMsg::SetScrollSnapType(id, snap_type));
+    }
+
+    /// End the scroll gesture on a scroll frame, snapping it to the nearest
+    /// of the given offsets along the axis the gesture mostly moved on.
+    pub fn end_scroll_gesture(
+        &mut self,
+        id: ExternalScrollId,
+        snap_offsets_x: Vec<f32>,
+        snap_offsets_y: Vec<f32>,
+    ) {
+        self.frame_ops.push(FrameMsg::EndScrollGesture(id, snap_offsets_x, snap_offsets_y));
+    }
+
     pub fn set_page_zoom(&mut self, page_zoom: ZoomFactor) {
         self.scene_ops.push(SceneMsg::SetPageZoom(page_zoom));
     }
@@ -301,10 +491,66 @@ impl Transaction {
         self.scene_ops.push(SceneMsg::SetPinchZoom(pinch_zoom));
     }
 
+    /// Translate the whole document by `pan`, without any scroll frame
+    /// moving or being clamped. Panning by multiples of the window height
+    /// renders successive pages of a document taller than the window, for
+    /// printing.
     pub fn set_pan(&mut self, pan: DeviceIntPoint) {
         self.frame_ops.push(FrameMsg::SetPan(pan));
     }
 
+    /// Set how the document is composited onto the existing contents of
+    /// the output surface.
+    pub fn set_presentation_blend_mode(&mut self, mode: PresentationBlendMode) {
+        self.frame_ops.push(FrameMsg::SetPresentationBlendMode(mode));
+    }
+
+    /// Adjust the gamma and brightness of the whole document as a final
+    /// step. This rebuilds the scene. Identity values remove the adjustment.
+    pub fn set_output_tonemap(&mut self, gamma: f32, brightness: f32) {
+        self.scene_ops.push(SceneMsg::SetOutputTonemap(OutputTonemap::new(gamma, brightness)));
+    }
+
+    /// Apply an effect to the content of a pipeline when it is composited,
+    /// or remove it with `None`. Like dynamic properties, this takes effect
+    /// with the next generated frame, without rebuilding the scene.
+    pub fn set_layer_effect(&mut self, pipeline_id: PipelineId, effect: Option<LayerEffect>) {
+        self.frame_ops.push(FrameMsg::SetLayerEffect(pipeline_id, effect));
+    }
+
+    /// Render only one stacking context of the current scene and its content,
+    /// laid out from the origin of a target of the given size, e.g. for a
+    /// thumbnail of a single component. The stacking context is named by its
+    /// position among all the stacking contexts pushed in the display list of
+    /// its pipeline, empty ones included, counting from zero. Clips and scroll
+    /// frames from outside the stacking context don't apply to it. The frames
+    /// of the document are left as they are, and the pixels of the preview
+    /// are taken from the renderer with `Renderer::take_stacking_context_preview`.
+    pub fn render_stacking_context_preview(
+        &mut self,
+        pipeline_id: PipelineId,
+        index: usize,
+        size: DeviceUintSize,
+    ) {
+        self.frame_ops.push(FrameMsg::RenderStackingContextPreview(pipeline_id, index, size));
+    }
+
+    /// Draw the content of a pipeline on top of the root pipeline, relative
+    /// to its viewport, for content such as tooltips that comes and goes
+    /// without the rest of the document changing. The overlay is drawn as a
+    /// frame of its own, so adding or removing it doesn't rebuild the scene.
+    /// The display list of the overlay is sent with `set_display_list` like
+    /// any other pipeline. Overlays added later are drawn on top of earlier
+    /// ones, and a pipeline the scene draws already isn't drawn again.
+    pub fn add_overlay(&mut self, pipeline_id: PipelineId) {
+        self.scene_ops.push(SceneMsg::AddOverlay(pipeline_id));
+    }
+
+    /// Stop drawing a pipeline added with `add_overlay`.
+    pub fn remove_overlay(&mut self, pipeline_id: PipelineId) {
+        self.scene_ops.push(SceneMsg::RemoveOverlay(pipeline_id));
+    }
+
     /// Generate a new frame. When it's done and a RenderNotifier has been set
     /// in `webrender::Renderer`, [new_frame_ready()][notifier] gets called.
     /// Note that the notifier is called even if the frame generation was a
@@ -336,6 +582,12 @@ impl Transaction {
         self.frame_ops.push(FrameMsg::EnableFrameOutput(pipeline_id, enable));
     }
 
+    /// Enable reading back the pixels this pipeline id renders each frame,
+    /// which the renderer holds on to until they are taken by the caller.
+    pub fn enable_frame_readback(&mut self, pipeline_id: PipelineId, enable: bool) {
+        self.frame_ops.push(FrameMsg::EnableFrameReadback(pipeline_id, enable));
+    }
+
     /// Consumes this object and just returns the frame ops.
     pub fn get_frame_ops(self) -> Vec<FrameMsg> {
         self.frame_ops
@@ -429,6 +681,9 @@ pub struct HitTestItem {
     /// The tag of the hit display item.
     pub tag: ItemTag,
 
+    /// The nearest scroll frame that scrolls the hit display item, if any.
+    pub scroll_id: Option<ExternalScrollId>,
+
     /// The hit point in the coordinate space of the "viewport" of the display item. The
     /// viewport is the scroll node formed by the root reference frame of the display item's
     /// pipeline.
@@ -467,6 +722,9 @@ pub struct AddFontInstance {
 pub enum SceneMsg {
     UpdateEpoch(PipelineId, Epoch),
     SetPageZoom(ZoomFactor),
+    SetOutputTonemap(OutputTonemap),
+    AddOverlay(PipelineId),
+    RemoveOverlay(PipelineId),
     SetPinchZoom(ZoomFactor),
     SetRootPipeline(PipelineId),
     RemovePipeline(PipelineId),
@@ -482,7 +740,7 @@ pub enum SceneMsg {
     SetWindowParameters {
         window_size: DeviceUintSize,
         inner_rect: DeviceUintRect,
-        device_pixel_ratio: f32,
+        device_pixel_ratio: DevicePixelRatio,
     },
 }
 
@@ -491,11 +749,34 @@ pub enum SceneMsg {
 pub enum FrameMsg {
     UpdateEpoch(PipelineId, Epoch),
     HitTest(Option<PipelineId>, WorldPoint, HitTestFlags, MsgSender<HitTestResult>),
+    GetItemVisibleRect(PipelineId, ItemTag, MsgSender<Option<WorldRect>>),
     SetPan(DeviceIntPoint),
+    SetPresentationBlendMode(PresentationBlendMode),
     EnableFrameOutput(PipelineId, bool),
+    EnableFrameReadback(PipelineId, bool),
     Scroll(ScrollLocation, WorldPoint),
+    SetScrollCapture(Option<ExternalScrollId>),
+    BeginScroll(WorldPoint),
+    SetHighlightedScrollFrame(Option<(ExternalScrollId, ColorF)>),
     ScrollNodeWithId(LayoutPoint, ExternalScrollId, ScrollClamping),
+    ScrollNodeTo(LayoutPoint, ExternalScrollId, ScrollClamping, MsgSender<bool>),
+    ScrollNodeBy(ExternalScrollId, LayoutVector2D),
+    LinkScrollFrames(ExternalScrollId, ExternalScrollId, ScrollAxes),
+    SetScrollFriction(f32),
+    SetScrollMode(ExternalScrollId, ScrollMode),
+    SetScrollSnapType(ExternalScrollId, ScrollSnapType),
+    SetLayerEffect(PipelineId, Option<LayerEffect>),
+    EndScrollGesture(ExternalScrollId, Vec<f32>, Vec<f32>),
+    RenderStackingContextPreview(PipelineId, usize, DeviceUintSize),
+    InvalidateRect(ExternalScrollId, LayoutRect),
     GetScrollNodeState(MsgSender<Vec<ScrollNodeState>>),
+    GetScrollTree(MsgSender<Vec<ScrollTreeNode>>),
+    GetFramebufferReads(MsgSender<Vec<FramebufferRead>>),
+    GetFullyClippedPipelines(MsgSender<Vec<PipelineId>>),
+    GetDeviceScrollOffset(ExternalScrollId, MsgSender<Option<DeviceIntPoint>>),
+    GetScrollFrameWorldTransform(ExternalScrollId, MsgSender<Option<LayoutToWorldTransform>>),
+    GetScrollFrameClipRects(MsgSender<Vec<ScrollFrameClipRect>>),
+    IsFrameDirty(MsgSender<bool>),
     UpdateDynamicProperties(DynamicProperties),
     AppendDynamicProperties(DynamicProperties),
 }
@@ -506,6 +787,9 @@ impl fmt::Debug for SceneMsg {
             SceneMsg::UpdateEpoch(..) => "SceneMsg::UpdateEpoch",
             SceneMsg::SetDisplayList { .. } => "SceneMsg::SetDisplayList",
             SceneMsg::SetPageZoom(..) => "SceneMsg::SetPageZoom",
+            SceneMsg::SetOutputTonemap(..) => "SceneMsg::SetOutputTonemap",
+            SceneMsg::AddOverlay(..) => "SceneMsg::AddOverlay",
+            SceneMsg::RemoveOverlay(..) => "SceneMsg::RemoveOverlay",
             SceneMsg::SetPinchZoom(..) => "SceneMsg::SetPinchZoom",
             SceneMsg::RemovePipeline(..) => "SceneMsg::RemovePipeline",
             SceneMsg::SetWindowParameters { .. } => "SceneMsg::SetWindowParameters",
@@ -519,11 +803,34 @@ impl fmt::Debug for FrameMsg {
         f.write_str(match *self {
             FrameMsg::UpdateEpoch(..) => "FrameMsg::UpdateEpoch",
             FrameMsg::HitTest(..) => "FrameMsg::HitTest",
+            FrameMsg::GetItemVisibleRect(..) => "FrameMsg::GetItemVisibleRect",
             FrameMsg::SetPan(..) => "FrameMsg::SetPan",
+            FrameMsg::SetPresentationBlendMode(..) => "FrameMsg::SetPresentationBlendMode",
             FrameMsg::Scroll(..) => "FrameMsg::Scroll",
+            FrameMsg::SetScrollCapture(..) => "FrameMsg::SetScrollCapture",
+            FrameMsg::BeginScroll(..) => "FrameMsg::BeginScroll",
+            FrameMsg::SetHighlightedScrollFrame(..) => "FrameMsg::SetHighlightedScrollFrame",
             FrameMsg::ScrollNodeWithId(..) => "FrameMsg::ScrollNodeWithId",
+            FrameMsg::ScrollNodeTo(..) => "FrameMsg::ScrollNodeTo",
+            FrameMsg::ScrollNodeBy(..) => "FrameMsg::ScrollNodeBy",
+            FrameMsg::LinkScrollFrames(..) => "FrameMsg::LinkScrollFrames",
+            FrameMsg::SetScrollFriction(..) => "FrameMsg::SetScrollFriction",
+            FrameMsg::SetScrollMode(..) => "FrameMsg::SetScrollMode",
+            FrameMsg::SetScrollSnapType(..) => "FrameMsg::SetScrollSnapType",
+            FrameMsg::SetLayerEffect(..) => "FrameMsg::SetLayerEffect",
+            FrameMsg::EndScrollGesture(..) => "FrameMsg::EndScrollGesture",
+            FrameMsg::RenderStackingContextPreview(..) => "FrameMsg::RenderStackingContextPreview",
+            FrameMsg::InvalidateRect(..) => "FrameMsg::InvalidateRect",
             FrameMsg::GetScrollNodeState(..) => "FrameMsg::GetScrollNodeState",
+            FrameMsg::GetScrollTree(..) => "FrameMsg::GetScrollTree",
+            FrameMsg::GetFramebufferReads(..) => "FrameMsg::GetFramebufferReads",
+            FrameMsg::GetFullyClippedPipelines(..) => "FrameMsg::GetFullyClippedPipelines",
+            FrameMsg::GetDeviceScrollOffset(..) => "FrameMsg::GetDeviceScrollOffset",
+            FrameMsg::GetScrollFrameWorldTransform(..) => "FrameMsg::GetScrollFrameWorldTransform",
+            FrameMsg::GetScrollFrameClipRects(..) => "FrameMsg::GetScrollFrameClipRects",
+            FrameMsg::IsFrameDirty(..) => "FrameMsg::IsFrameDirty",
             FrameMsg::EnableFrameOutput(..) => "FrameMsg::EnableFrameOutput",
+            FrameMsg::EnableFrameReadback(..) => "FrameMsg::EnableFrameReadback",
             FrameMsg::UpdateDynamicProperties(..) => "FrameMsg::UpdateDynamicProperties",
             FrameMsg::AppendDynamicProperties(..) => "FrameMsg::AppendDynamicProperties",
         })
@@ -721,6 +1028,38 @@ pub enum ScrollClamping {
     NoClamping,
 }
 
+/// How a scroll frame responds to scrolling past the edges of its content.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ScrollMode {
+    /// The offset stops at the edges.
+    Clamped,
+    /// The offset moves past the edges, slowed down by `resistance` between
+    /// 0 (not at all) and 1 (not moving), and springs back with every frame
+    /// that is generated afterwards. It goes at most `max_distance` past an
+    /// edge.
+    Overscroll { resistance: f32, max_distance: f32 },
+}
+
+/// How strictly a scroll frame snaps to its snap offsets when a scroll
+/// gesture ends.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub enum ScrollSnapType {
+    /// Always move to the nearest snap offset.
+    Mandatory,
+    /// Only move to the nearest snap offset if it is at most `threshold`
+    /// away. Otherwise the offset stays where the gesture left it.
+    Proximity { threshold: f32 },
+}
+
+bitflags! {
+    /// The axes along which linked scroll frames follow each other.
+    #[derive(Deserialize, Serialize)]
+    pub struct ScrollAxes: u8 {
+        const HORIZONTAL = 0b00000001;
+        const VERTICAL = 0b00000010;
+    }
+}
+
 #[derive(Clone, Deserialize, Serialize)]
 pub struct RenderApiSender {
     api_sender: MsgSender<ApiMsg>,
@@ -834,6 +1173,12 @@ impl RenderApi {
         ImageKey::new(self.namespace_id, new_id)
     }
 
+    /// Creates a `GradientRampKey`.
+    pub fn generate_gradient_ramp_key(&self) -> GradientRampKey {
+        let new_id = self.next_unique_id();
+        GradientRampKey::new(self.namespace_id, new_id)
+    }
+
     /// Add/remove/update resources such as images and fonts.
     pub fn update_resources(&self, resources: ResourceUpdates) {
         if resources.updates.is_empty() {
@@ -940,6 +1285,20 @@ impl RenderApi {
         rx.recv().unwrap()
     }
 
+    /// Returns the part of the hit-testable items with the given tag that
+    /// is visible in the last rendered frame, in world space, after clipping
+    /// and scrolling. Returns `None` if none of it is visible.
+    pub fn get_item_visible_rect(
+        &self,
+        document_id: DocumentId,
+        pipeline_id: PipelineId,
+        tag: ItemTag,
+    ) -> Option<WorldRect> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetItemVisibleRect(pipeline_id, tag, tx));
+        rx.recv().unwrap()
+    }
+
     pub fn set_window_parameters(
         &self,
         document_id: DocumentId,
@@ -947,6 +1306,7 @@ impl RenderApi {
         inner_rect: DeviceUintRect,
         device_pixel_ratio: f32,
     ) {
+        let device_pixel_ratio = DevicePixelRatio::uniform(device_pixel_ratio);
         self.send_scene_msg(
             document_id,
             SceneMsg::SetWindowParameters { window_size, inner_rect, device_pixel_ratio, },
@@ -959,6 +1319,95 @@ impl RenderApi {
         rx.recv().unwrap()
     }
 
+    /// Returns the scroll frames of the last scene built for a document and
+    /// how they nest, e.g. to set up scrollbars. This is available as soon
+    /// as the scene is built, before a frame is rendered from it.
+    pub fn get_scroll_tree(&self, document_id: DocumentId) -> Vec<ScrollTreeNode> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetScrollTree(tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns the stacking contexts of the current scene whose composite
+    /// operation reads back the framebuffer.
+    pub fn get_framebuffer_reads(&self, document_id: DocumentId) -> Vec<FramebufferRead> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetFramebufferReads(tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns the pipelines that have content in the current scene, all of
+    /// which was clipped or culled in the last frame. Useful for finding out
+    /// why a pipeline doesn't show up.
+    pub fn get_fully_clipped_pipelines(&self, document_id: DocumentId) -> Vec<PipelineId> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetFullyClippedPipelines(tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns the scroll offset of a scroll frame in whole device pixels,
+    /// exactly as it is applied when drawing the frame's contents, or `None`
+    /// if there is no scroll frame with this id.
+    pub fn get_device_scroll_offset(
+        &self,
+        document_id: DocumentId,
+        id: ExternalScrollId,
+    ) -> Option<DeviceIntPoint> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetDeviceScrollOffset(id, tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns the transform from the content of a scroll frame to world
+    /// space in the last rendered frame, including its scroll offset and the
+    /// transforms of its ancestors, or `None` if there is no scroll frame
+    /// with this id.
+    pub fn get_scroll_frame_world_transform(
+        &self,
+        document_id: DocumentId,
+        id: ExternalScrollId,
+    ) -> Option<LayoutToWorldTransform> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetScrollFrameWorldTransform(id, tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns the clip rect that each scroll frame with an external id
+    /// applies to its contents in the last rendered frame, with the scroll
+    /// offset snapped to device pixels as it is when drawing. Useful for
+    /// finding out why content is clipped.
+    pub fn get_scroll_frame_clip_rects(&self, document_id: DocumentId) -> Vec<ScrollFrameClipRect> {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::GetScrollFrameClipRects(tx));
+        rx.recv().unwrap()
+    }
+
+    /// Returns true if anything affecting the output of the document (scene,
+    /// scroll offsets, zoom, dynamic properties or resources) changed since the
+    /// last frame was generated. Embedders rendering on demand can use this to
+    /// skip generating frames that would be identical to the previous one.
+    pub fn is_frame_dirty(&self, document_id: DocumentId) -> bool {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::IsFrameDirty(tx));
+        rx.recv().unwrap()
+    }
+
+    /// Scrolls the scroll frame with the given id like
+    /// `Transaction::scroll_node_with_id` does, e.g. to scroll to an anchor
+    /// or restore a scroll position on navigation. Returns true if the offset
+    /// changed, so that callers can skip generating a frame otherwise.
+    pub fn scroll_node_to(
+        &self,
+        document_id: DocumentId,
+        origin: LayoutPoint,
+        id: ExternalScrollId,
+        clamp: ScrollClamping,
+    ) -> bool {
+        let (tx, rx) = channel::msg_channel().unwrap();
+        self.send_frame_msg(document_id, FrameMsg::ScrollNodeTo(origin, id, clamp, tx));
+        rx.recv().unwrap()
+    }
+
     pub fn wake_scene_builder(&self) {
         self.send_message(ApiMsg::WakeSceneBuilder);
     }
@@ -1010,6 +1459,39 @@ pub struct ScrollNodeState {
     pub scroll_offset: LayoutVector2D,
 }
 
+/// A scroll frame with an external id, and the scroll frame it is nested in.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScrollTreeNode {
+    pub id: ExternalScrollId,
+    /// The nearest enclosing scroll frame with an external id, if any.
+    pub parent: Option<ExternalScrollId>,
+    pub viewport_size: LayoutSize,
+    /// The size of the contents, at least as large as the viewport.
+    pub content_size: LayoutSize,
+}
+
+/// The part of a scroll frame's contents that is visible through its
+/// viewport, as of the last time the scroll tree was updated.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct ScrollFrameClipRect {
+    pub id: ExternalScrollId,
+    /// The visible region in the space of the frame's contents, which
+    /// moves with the scroll offset.
+    pub local_rect: LayoutRect,
+    /// The same region in world space.
+    pub world_rect: WorldRect,
+}
+
+/// A stacking context whose mix-blend-mode reads back what has already been
+/// drawn into the framebuffer.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct FramebufferRead {
+    pub pipeline_id: PipelineId,
+    /// The bounds of the stacking context, relative to its parent.
+    pub bounds: LayoutRect,
+    pub mix_blend_mode: MixBlendMode,
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Serialize)]
 pub enum ScrollLocation {
     /// Scroll by a certain amount.
@@ -1036,6 +1518,37 @@ impl ZoomFactor {
     }
 }
 
+/// The number of device pixels per layout pixel along each axis. Most
+/// displays have square pixels, but some (and deliberately stretched
+/// render modes) scale differently horizontally and vertically.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct DevicePixelRatio {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl DevicePixelRatio {
+    pub fn new(x: f32, y: f32) -> Self {
+        DevicePixelRatio { x, y }
+    }
+
+    /// Construct a ratio that is the same along both axes.
+    pub fn uniform(ratio: f32) -> Self {
+        DevicePixelRatio { x: ratio, y: ratio }
+    }
+
+    /// The part of the ratio shared by both axes.
+    pub fn scale(&self) -> f32 {
+        self.x.min(self.y)
+    }
+
+    /// The per-axis stretch that remains once `scale()` is applied.
+    pub fn stretch(&self) -> (f32, f32) {
+        let scale = self.scale();
+        (self.x / scale, self.y / scale)
+    }
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize, Eq, Hash)]
 pub struct PropertyBindingId {
     namespace: IdNamespace,
@@ -1114,6 +1627,9 @@ pub trait RenderNotifier: Send {
     fn clone(&self) -> Box<RenderNotifier>;
     fn wake_up(&self);
     fn new_frame_ready(&self, DocumentId, scrolled: bool, composite_needed: bool);
+    /// Called once per document, when the first frame that draws anything
+    /// is built.
+    fn first_contentful_frame(&self, _document_id: DocumentId) {}
     fn external_event(&self, _evt: ExternalEvent) {
         unimplemented!()
     }
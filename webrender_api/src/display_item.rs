@@ -6,7 +6,7 @@
 use GlyphInstance;
 use euclid::{SideOffsets2D, TypedRect};
 use std::ops::Not;
-use {ColorF, FontInstanceKey, GlyphOptions, ImageKey, LayoutPixel, LayoutPoint};
+use {ColorF, FontInstanceKey, GlyphOptions, IdNamespace, ImageKey, LayoutPixel, LayoutPoint};
 use {LayoutRect, LayoutSize, LayoutTransform, LayoutVector2D, PipelineId, PropertyBinding};
 
 
@@ -67,6 +67,11 @@ pub struct PrimitiveInfo<T> {
     pub clip_rect: TypedRect<f32, T>,
     pub is_backface_visible: bool,
     pub tag: Option<ItemTag>,
+    /// Overrides where this item is drawn among its siblings in the
+    /// enclosing stacking context. Siblings are drawn in ascending order,
+    /// with items that have no override sitting at order 0 in display list
+    /// order. Hit testing is not affected.
+    pub paint_order: Option<i32>,
 }
 
 impl LayoutPrimitiveInfo {
@@ -83,6 +88,7 @@ impl LayoutPrimitiveInfo {
             clip_rect,
             is_backface_visible: true,
             tag: None,
+            paint_order: None,
         }
     }
 }
@@ -431,6 +437,21 @@ pub struct GradientDisplayItem {
     pub gradient: Gradient,
     pub tile_size: LayoutSize,
     pub tile_spacing: LayoutSize,
+    /// If set, the stops of this ramp are drawn in place of the stops
+    /// stored in the display list, once the ramp has been updated.
+    pub ramp: Option<GradientRampKey>,
+}
+
+/// A set of gradient stops that can be replaced through
+/// `ResourceUpdates::update_gradient_ramp` without rebuilding the scene.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct GradientRampKey(pub IdNamespace, pub u32);
+
+impl GradientRampKey {
+    pub fn new(namespace: IdNamespace, key: u32) -> Self {
+        GradientRampKey(namespace, key)
+    }
 }
 
 #[repr(C)]
@@ -476,6 +497,9 @@ pub struct StackingContext {
     pub reference_frame_id: Option<ClipId>,
     pub clip_node_id: Option<ClipId>,
     pub glyph_raster_space: GlyphRasterSpace,
+    /// How far clips defined directly inside this stacking context extend
+    /// beyond their rect, like CSS `overflow-clip-margin`.
+    pub clip_margin: f32,
 } // IMPLICIT: filters: Vec<FilterOp>
 
 
@@ -522,6 +546,15 @@ pub enum MixBlendMode {
     Saturation = 13,
     Color = 14,
     Luminosity = 15,
+    PlusLighter = 16,
+}
+
+impl MixBlendMode {
+    /// Whether compositing with this mode needs the content already drawn
+    /// underneath, which forces a read back of the framebuffer.
+    pub fn requires_framebuffer_read(&self) -> bool {
+        *self != MixBlendMode::Normal
+    }
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
@@ -537,6 +570,17 @@ pub enum FilterOp {
     Sepia(f32),
     DropShadow(LayoutVector2D, f32, ColorF),
     ColorMatrix([f32; 20]),
+    /// Render the content at 1/factor of its size and scale it back up,
+    /// averaging the source pixels rather than point sampling them.
+    Downscale(u32),
+    /// Quantize each color channel to the given number of evenly spaced levels.
+    Posterize(u32),
+    /// Replace each pixel by white if its luminance is at least the cutoff,
+    /// and by black otherwise. The alpha channel is left untouched.
+    Threshold(f32),
+    /// Raise each color channel to the power of 1 / gamma. Gammas below
+    /// 0.01 are drawn as 0.01.
+    Gamma(f32),
 }
 
 #[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
@@ -552,6 +596,7 @@ pub struct ImageDisplayItem {
     pub tile_spacing: LayoutSize,
     pub image_rendering: ImageRendering,
     pub alpha_type: AlphaType,
+    pub color_space: ImageColorSpace,
 }
 
 #[repr(u32)]
@@ -562,6 +607,15 @@ pub enum ImageRendering {
     Pixelated = 2,
 }
 
+/// The color space an image's pixels are authored in. Images are converted
+/// to sRGB when drawn.
+#[repr(u32)]
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub enum ImageColorSpace {
+    Srgb = 0,
+    DisplayP3 = 1,
+}
+
 #[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
 pub enum AlphaType {
     Alpha = 0,
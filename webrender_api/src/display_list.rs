@@ -9,6 +9,8 @@ use serde::de::Deserializer;
 #[cfg(feature = "serialize")]
 use serde::ser::{Serializer, SerializeSeq};
 use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
 use std::io::{Read, Write};
 use std::marker::PhantomData;
 use std::{io, mem, ptr, slice};
@@ -17,7 +19,8 @@ use {AlphaType, BorderDetails, BorderDisplayItem, BorderRadius, BorderWidths, Bo
 use {BoxShadowDisplayItem, ClipAndScrollInfo, ClipChainId, ClipChainItem, ClipDisplayItem, ClipId};
 use {ColorF, ComplexClipRegion, DisplayItem, ExtendMode, ExternalScrollId, FilterOp};
 use {FontInstanceKey, GlyphInstance, GlyphOptions, GlyphRasterSpace, Gradient};
-use {GradientDisplayItem, GradientStop, IframeDisplayItem, ImageDisplayItem, ImageKey, ImageMask};
+use {GradientDisplayItem, GradientRampKey, GradientStop, IframeDisplayItem, ImageDisplayItem};
+use {ImageColorSpace, ImageKey, ImageMask};
 use {ImageRendering, LayoutPoint, LayoutPrimitiveInfo, LayoutRect, LayoutSize, LayoutTransform};
 use {LayoutVector2D, LineDisplayItem, LineOrientation, LineStyle, MixBlendMode, PipelineId};
 use {PropertyBinding, PushStackingContextDisplayItem, RadialGradient, RadialGradientDisplayItem};
@@ -315,6 +318,13 @@ impl<'a> BuiltDisplayListIter<'a> {
         }
     }
 
+    /// The offset in the serialized list of the first item that hasn't been
+    /// read yet. Must not be called while peeking.
+    fn data_offset(&self) -> usize {
+        debug_assert!(self.peeking == Peek::NotPeeking);
+        self.list.data.len() - self.data.len()
+    }
+
     pub fn current_stacking_context_empty(&mut self) -> bool {
         match self.peek() {
             Some(item) => *item.item() == SpecificDisplayItem::PopStackingContext,
@@ -332,6 +342,56 @@ impl<'a> BuiltDisplayListIter<'a> {
     }
 }
 
+/// Hashes the content of stacking contexts while a display list is being
+/// traversed. Each context hashes its filters and serialized items, with the
+/// hashes of the contexts nested in it standing in for their items, so every
+/// item is only hashed once however deeply it is nested. Items are stored
+/// relative to the stacking context origin, so contexts with identical
+/// content hash the same wherever they are placed.
+#[derive(Default)]
+pub struct StackingContextHasher {
+    /// For each stacking context being traversed, the hash so far and the
+    /// offset of the first item that hasn't been hashed yet.
+    stack: Vec<(DefaultHasher, usize)>,
+}
+
+impl StackingContextHasher {
+    pub fn new() -> Self {
+        StackingContextHasher {
+            stack: Vec::new(),
+        }
+    }
+
+    /// Start hashing the stacking context that was just started. Must be
+    /// called before peeking into it.
+    pub fn push(&mut self, iter: &BuiltDisplayListIter) {
+        let offset = iter.data_offset();
+        if let Some(&mut (ref mut parent, parent_start)) = self.stack.last_mut() {
+            parent.write(&iter.list.data[parent_start .. offset]);
+        }
+
+        let mut hasher = DefaultHasher::new();
+        let filters = iter.cur_filters;
+        hasher.write(&iter.list.data[filters.start .. filters.start + filters.length]);
+        self.stack.push((hasher, offset));
+    }
+
+    /// Finish hashing the stacking context whose end was just read, and
+    /// return its hash.
+    pub fn pop(&mut self, iter: &BuiltDisplayListIter) -> u64 {
+        let offset = iter.data_offset();
+        let (mut hasher, start) = self.stack.pop().expect("bug: mismatched stacking contexts");
+        hasher.write(&iter.list.data[start .. offset]);
+        let hash = hasher.finish();
+
+        if let Some(&mut (ref mut parent, ref mut parent_start)) = self.stack.last_mut() {
+            parent.write_u64(hash);
+            *parent_start = offset;
+        }
+        hash
+    }
+}
+
 // Some of these might just become ItemRanges
 impl<'a, 'b> DisplayItemRef<'a, 'b> {
     pub fn display_item(&self) -> &DisplayItem {
@@ -349,6 +409,7 @@ impl<'a, 'b> DisplayItemRef<'a, 'b> {
             clip_rect: info.clip_rect.translate(offset),
             is_backface_visible: info.is_backface_visible,
             tag: info.tag,
+            paint_order: info.paint_order,
         }
     }
 
@@ -1020,6 +1081,29 @@ impl DisplayListBuilder {
         image_rendering: ImageRendering,
         alpha_type: AlphaType,
         key: ImageKey,
+    ) {
+        self.push_image_with_color_space(
+            info,
+            stretch_size,
+            tile_spacing,
+            image_rendering,
+            alpha_type,
+            key,
+            ImageColorSpace::Srgb,
+        );
+    }
+
+    /// Push an image whose pixels are in the given color space rather than
+    /// in sRGB.
+    pub fn push_image_with_color_space(
+        &mut self,
+        info: &LayoutPrimitiveInfo,
+        stretch_size: LayoutSize,
+        tile_spacing: LayoutSize,
+        image_rendering: ImageRendering,
+        alpha_type: AlphaType,
+        key: ImageKey,
+        color_space: ImageColorSpace,
     ) {
         let item = SpecificDisplayItem::Image(ImageDisplayItem {
             image_key: key,
@@ -1027,6 +1111,7 @@ impl DisplayListBuilder {
             tile_spacing,
             image_rendering,
             alpha_type,
+            color_space,
         });
 
         self.push_item(item, info);
@@ -1248,11 +1333,28 @@ impl DisplayListBuilder {
         gradient: Gradient,
         tile_size: LayoutSize,
         tile_spacing: LayoutSize,
+    ) {
+        self.push_gradient_with_ramp(info, gradient, None, tile_size, tile_spacing);
+    }
+
+    /// Pushes a linear gradient whose stops can later be replaced by
+    /// updating `ramp`, without sending a new display list.
+    ///
+    /// The stops the gradient was created with are drawn until the ramp
+    /// is first updated.
+    pub fn push_gradient_with_ramp(
+        &mut self,
+        info: &LayoutPrimitiveInfo,
+        gradient: Gradient,
+        ramp: Option<GradientRampKey>,
+        tile_size: LayoutSize,
+        tile_spacing: LayoutSize,
     ) {
         let item = SpecificDisplayItem::Gradient(GradientDisplayItem {
             gradient,
             tile_size,
             tile_spacing,
+            ramp,
         });
 
         self.push_item(item, info);
@@ -1287,6 +1389,31 @@ impl DisplayListBuilder {
         mix_blend_mode: MixBlendMode,
         filters: Vec<FilterOp>,
         glyph_raster_space: GlyphRasterSpace,
+    ) -> Option<ClipId> {
+        self.push_stacking_context_with_clip_margin(
+            info,
+            clip_node_id,
+            transform,
+            transform_style,
+            perspective,
+            mix_blend_mode,
+            filters,
+            glyph_raster_space,
+            0.0,
+        )
+    }
+
+    pub fn push_stacking_context_with_clip_margin(
+        &mut self,
+        info: &LayoutPrimitiveInfo,
+        clip_node_id: Option<ClipId>,
+        transform: Option<PropertyBinding<LayoutTransform>>,
+        transform_style: TransformStyle,
+        perspective: Option<LayoutTransform>,
+        mix_blend_mode: MixBlendMode,
+        filters: Vec<FilterOp>,
+        glyph_raster_space: GlyphRasterSpace,
+        clip_margin: f32,
     ) -> Option<ClipId> {
         let reference_frame_id = if transform.is_some() || perspective.is_some() {
             Some(self.generate_clip_id())
@@ -1303,6 +1430,7 @@ impl DisplayListBuilder {
                 reference_frame_id,
                 clip_node_id,
                 glyph_raster_space,
+                clip_margin,
             },
         });
 
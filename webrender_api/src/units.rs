@@ -24,6 +24,7 @@ pub struct DevicePixel;
 pub type DeviceIntRect = TypedRect<i32, DevicePixel>;
 pub type DeviceIntPoint = TypedPoint2D<i32, DevicePixel>;
 pub type DeviceIntSize = TypedSize2D<i32, DevicePixel>;
+pub type DeviceIntVector2D = TypedVector2D<i32, DevicePixel>;
 pub type DeviceIntLength = Length<i32, DevicePixel>;
 pub type DeviceIntSideOffsets = TypedSideOffsets2D<i32, DevicePixel>;
 
@@ -177,6 +177,8 @@ impl JsonFrameWriter {
                     );
                 }
                 ResourceUpdate::DeleteFontInstance(_) => {}
+                ResourceUpdate::UpdateGradientRamp(..) |
+                ResourceUpdate::DeleteGradientRamp(_) => {}
             }
         }
     }
@@ -129,6 +129,8 @@ impl RonFrameWriter {
                 ResourceUpdate::DeleteImage(img) => {
                     self.images.remove(&img);
                 }
+                ResourceUpdate::SetImageNotReady(_) |
+                ResourceUpdate::NotifyImageReady(_) => {}
                 ResourceUpdate::AddFont(ref font) => match font {
                     &AddFont::Raw(key, ref bytes, index) => {
                         self.fonts
@@ -141,6 +143,8 @@ impl RonFrameWriter {
                 ResourceUpdate::DeleteFont(_) => {}
                 ResourceUpdate::AddFontInstance(_) => {}
                 ResourceUpdate::DeleteFontInstance(_) => {}
+                ResourceUpdate::UpdateGradientRamp(..) |
+                ResourceUpdate::DeleteGradientRamp(_) => {}
             }
         }
     }
@@ -1121,7 +1121,23 @@ impl YamlFrameReader {
                 item
             ),
         };
-        dl.push_image(&info, stretch_size, tile_spacing, rendering, alpha_type, image_key);
+        let color_space = match item["color-space"].as_str() {
+            Some("srgb") | None => ImageColorSpace::Srgb,
+            Some("display-p3") => ImageColorSpace::DisplayP3,
+            Some(_) => panic!(
+                "ImageColorSpace can be srgb or display-p3 -- got {:?}",
+                item
+            ),
+        };
+        dl.push_image_with_color_space(
+            &info,
+            stretch_size,
+            tile_spacing,
+            rendering,
+            alpha_type,
+            image_key,
+            color_space,
+        );
     }
 
     fn handle_text(
@@ -1323,6 +1339,7 @@ impl YamlFrameReader {
             let mut info = LayoutPrimitiveInfo::with_clip_rect(LayoutRect::zero(), clip_rect);
             info.is_backface_visible = item["backface-visible"].as_bool().unwrap_or(true);;
             info.tag = self.to_hit_testing_tag(&item["hit-testing-tag"]);
+            info.paint_order = item["paint-order"].as_i64().map(|order| order as i32);
 
             match item_type {
                 "rect" => self.handle_rect(dl, item, &mut info),
@@ -1550,6 +1567,7 @@ impl YamlFrameReader {
         let glyph_raster_space = yaml["glyph-raster-space"]
             .as_glyph_raster_space()
             .unwrap_or(GlyphRasterSpace::Screen);
+        let clip_margin = yaml["clip-margin"].as_f32().unwrap_or(0.0);
 
         if is_root {
             if let Some(size) = yaml["scroll-offset"].as_point() {
@@ -1562,7 +1580,7 @@ impl YamlFrameReader {
         info.rect = bounds;
         info.clip_rect = bounds;
 
-        let reference_frame_id = dl.push_stacking_context(
+        let reference_frame_id = dl.push_stacking_context_with_clip_margin(
             &info,
             clip_node_id,
             transform.into(),
@@ -1571,6 +1589,7 @@ impl YamlFrameReader {
             mix_blend_mode,
             filters,
             glyph_raster_space,
+            clip_margin,
         );
 
         let numeric_id = yaml["reference-frame-id"].as_i64();
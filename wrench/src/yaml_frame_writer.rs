@@ -199,6 +199,10 @@ fn write_stacking_context(
     };
     str_node(parent, "glyph-raster-space", &glyph_raster_space);
 
+    if sc.clip_margin != 0.0 {
+        f32_node(parent, "clip-margin", sc.clip_margin);
+    }
+
     if let Some(clip_node_id) = sc.clip_node_id {
         yaml_node(parent, "clip-node", clip_id_mapper.map_id(&clip_node_id));
     }
@@ -236,6 +240,18 @@ fn write_stacking_context(
             FilterOp::ColorMatrix(matrix) => {
                 filters.push(Yaml::String(format!("color-matrix({:?})", matrix)))
             }
+            FilterOp::Downscale(factor) => {
+                filters.push(Yaml::String(format!("downscale({})", factor)))
+            }
+            FilterOp::Posterize(levels) => {
+                filters.push(Yaml::String(format!("posterize({})", levels)))
+            }
+            FilterOp::Threshold(cutoff) => {
+                filters.push(Yaml::String(format!("threshold({})", cutoff)))
+            }
+            FilterOp::Gamma(gamma) => {
+                filters.push(Yaml::String(format!("gamma({})", gamma)))
+            }
         }
     }
 
@@ -556,6 +572,8 @@ impl YamlFrameWriter {
                 ResourceUpdate::DeleteImage(img) => {
                     self.images.remove(&img);
                 }
+                ResourceUpdate::SetImageNotReady(_) |
+                ResourceUpdate::NotifyImageReady(_) => {}
                 ResourceUpdate::AddFont(ref font) => match font {
                     &AddFont::Raw(key, ref bytes, index) => {
                         self.fonts
@@ -576,6 +594,8 @@ impl YamlFrameWriter {
                     );
                 }
                 ResourceUpdate::DeleteFontInstance(_) => {}
+                ResourceUpdate::UpdateGradientRamp(..) |
+                ResourceUpdate::DeleteGradientRamp(_) => {}
             }
         }
     }
@@ -717,6 +737,10 @@ impl YamlFrameWriter {
                 );
             }
 
+            if let Some(order) = info.paint_order {
+                yaml_node(&mut v, "paint-order", Yaml::Integer(order as i64));
+            }
+
             yaml_node(&mut v, "clip-and-scroll", clip_id_mapper.map_info(&base.clip_and_scroll()));
             bool_node(&mut v, "backface-visible", base.is_backface_visible());
 
@@ -813,6 +837,10 @@ impl YamlFrameWriter {
                         AlphaType::PremultipliedAlpha => str_node(&mut v, "alpha-type", "premultiplied-alpha"),
                         AlphaType::Alpha => str_node(&mut v, "alpha-type", "alpha"),
                     };
+                    match item.color_space {
+                        ImageColorSpace::Srgb => (),
+                        ImageColorSpace::DisplayP3 => str_node(&mut v, "color-space", "display-p3"),
+                    };
                 }
                 YuvImage(_) => {
                     str_node(&mut v, "type", "yuv-image");
@@ -117,7 +117,8 @@ define_string_enum!(
         Hue = "hue",
         Saturation = "saturation",
         Color = "color",
-        Luminosity = "luminosity"
+        Luminosity = "luminosity",
+        PlusLighter = "plus-lighter"
     ]
 );
 
@@ -586,6 +587,18 @@ impl YamlHelper for Yaml {
                     matrix.clone_from_slice(&m);
                     Some(FilterOp::ColorMatrix(matrix))
                 }
+                ("downscale", ref args, _) if args.len() == 1 => {
+                    Some(FilterOp::Downscale(args[0].parse().unwrap()))
+                }
+                ("posterize", ref args, _) if args.len() == 1 => {
+                    Some(FilterOp::Posterize(args[0].parse().unwrap()))
+                }
+                ("threshold", ref args, _) if args.len() == 1 => {
+                    Some(FilterOp::Threshold(args[0].parse().unwrap()))
+                }
+                ("gamma", ref args, _) if args.len() == 1 => {
+                    Some(FilterOp::Gamma(args[0].parse().unwrap()))
+                }
                 (_, _, _) => None,
             }
         } else {